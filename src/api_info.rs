@@ -0,0 +1,84 @@
+//! The `api-info` command: every API the primary NAS offers, with the
+//! versions it takes and the one this program would use, for reports of
+//! DSM-compatibility problems.
+
+use crate::client::Mode;
+use crate::config::Transport;
+use crate::dsm::Dsm;
+use crate::{build_client, query_api_info, ApiInfo, Config, API_VERSIONS};
+use anyhow::{anyhow, Result};
+use serde_json::json;
+
+pub fn run(config: &Config, mode: Mode, json: bool) -> Result<()> {
+    if config.nas.transport != Transport::Api {
+        return Err(anyhow!(
+            "api-info asks DSM's web API, which the primary target doesn't use"
+        ));
+    }
+    let client = build_client(&config.nas, mode);
+    let mut apis = query_api_info(&client, "all")?;
+    apis.sort_by(|a, b| a.name.cmp(&b.name));
+    let dsm = Dsm::detect(&apis);
+
+    if json {
+        let apis = apis
+            .iter()
+            .map(|api| {
+                json!({
+                    "name": api.name,
+                    "path": api.path,
+                    "min_version": api.min_version,
+                    "max_version": api.max_version,
+                    "used_version": used_version(api, dsm.as_ref().ok()),
+                })
+            })
+            .collect::<Vec<_>>();
+        let dsm = dsm.map(|d| d.generation.to_string()).ok();
+        println!("{:#}", json!({"dsm": dsm, "apis": apis}));
+        return Ok(());
+    }
+
+    println!("{:<40}{:>5}{:>5}{:>6}  PATH", "API", "MIN", "MAX", "USED");
+    for api in &apis {
+        let used = match used_version(api, dsm.as_ref().ok()) {
+            Some(version) if (api.min_version..=api.max_version).contains(&version) => {
+                version.to_string()
+            }
+            // Outside the range the NAS offers, so the feature won't work.
+            Some(version) => format!("{version}!"),
+            None => "-".into(),
+        };
+        println!(
+            "{:<40}{:>5}{:>5}{:>6}  {}",
+            api.name, api.min_version, api.max_version, used, api.path
+        );
+    }
+    let missing = API_VERSIONS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !apis.iter().any(|api| api.name == *name))
+        .collect::<Vec<_>>();
+    println!();
+    match &dsm {
+        Ok(dsm) => println!("{} APIs, {}", apis.len(), dsm.generation),
+        Err(e) => println!("{} APIs; {e:#}", apis.len()),
+    }
+    if !missing.is_empty() {
+        println!(
+            "Not offered but used by this program: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The version of `api` this program speaks, if it uses it at all.
+fn used_version(api: &ApiInfo, dsm: Option<&Dsm>) -> Option<u8> {
+    if api.name == "SYNO.API.Auth" {
+        return dsm.map(|d| d.auth_version);
+    }
+    API_VERSIONS
+        .iter()
+        .find(|(name, _)| *name == api.name)
+        .map(|(_, version)| *version)
+}
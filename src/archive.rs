@@ -0,0 +1,1623 @@
+use crate::chain::{Differential, Index};
+use crate::checksum::{self, Algorithm, HashedFile, Hashing};
+use crate::limits::{self, Timed};
+use crate::sparse;
+use crate::xattrs;
+use chrono::{Datelike, Timelike};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Cursor, IsTerminal, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use unicode_normalization::UnicodeNormalization;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// How file names become zip entry names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeNames {
+    /// Keep names exactly as the filesystem reports them, e.g. NFD on macOS
+    #[default]
+    Raw,
+    /// Compose names to NFC, which is what Linux and DSM users expect to see
+    Nfc,
+}
+
+/// How files are compressed, written like `"deflate"`, `"deflate:9"`,
+/// `"zstd:3"`, `"bzip2"` or `"stored"`. Without a level, the codec's default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    Stored,
+    /// What every unzip opens, and so the default
+    #[default]
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Stored => "stored",
+            Codec::Deflate => "deflate",
+            Codec::Bzip2 => "bzip2",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    fn method(self) -> CompressionMethod {
+        match self {
+            Codec::Stored => CompressionMethod::Stored,
+            Codec::Deflate => CompressionMethod::Deflated,
+            Codec::Bzip2 => CompressionMethod::Bzip2,
+            Codec::Zstd => CompressionMethod::Zstd,
+        }
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Compression> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s.as_str(), None),
+        };
+        let (codec, levels) = match name {
+            "stored" => (Codec::Stored, None),
+            "deflate" => (Codec::Deflate, Some(0..=9)),
+            "bzip2" => (Codec::Bzip2, Some(1..=9)),
+            "zstd" => (Codec::Zstd, Some(1..=22)),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "unknown compression {s:?}; expected stored, deflate, bzip2 or zstd"
+                ))
+            }
+        };
+        let level = match (level, levels) {
+            (None, _) => None,
+            (Some(level), Some(levels)) => Some(
+                level
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|l| levels.contains(l))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "{name} takes a level from {} to {}, got {level:?}",
+                            levels.start(),
+                            levels.end()
+                        )
+                    })?,
+            ),
+            (Some(_), None) => return Err(anyhow::anyhow!("stored takes no level")),
+        };
+        Ok(Compression { codec, level })
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.codec.name())?;
+        match self.level {
+            Some(level) => write!(f, ":{level}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Compression {
+    /// Zip entry options compressing this way.
+    pub fn options(self) -> SimpleFileOptions {
+        SimpleFileOptions::default()
+            .compression_method(self.codec.method())
+            .compression_level(self.level)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveOptions {
+    pub unicode_names: UnicodeNames,
+    /// How often to re-read a file that changed while it was being read
+    pub changed_file_retries: u32,
+    /// How deep to descend below the backed up directory, or without limit
+    pub max_depth: Option<usize>,
+    /// Worker threads reading and compressing files
+    pub parallelism: usize,
+    /// Bound on files and directories held open at once
+    pub max_open_files: usize,
+    /// Don't descend into directories on other filesystems, like tar's `--one-file-system`
+    pub one_file_system: bool,
+    /// How the files are compressed
+    pub compression: Compression,
+    /// Extensions of files to store rather than compress, compared ignoring case
+    pub store_extensions: Vec<String>,
+    /// Hash the files with this as they are read and list them in a manifest
+    pub manifest: Option<Algorithm>,
+    /// Store a file with several hard links in the tree once, see [`LINKS`]
+    pub dedup_hardlinks: bool,
+    /// Store files with the same contents once, listing the others only in
+    /// the manifest, which this needs
+    pub dedup_contents: bool,
+    /// Keep the extended attributes and ACLs of the files and the folders
+    /// above them, see [`XATTRS`]
+    pub xattrs: bool,
+    /// List the files with alternate data streams, which aren't kept, on Windows
+    pub ads_warnings: bool,
+    /// Record the size and modification time of every file in the report's `index`
+    pub index: bool,
+    /// Leave out the files unchanged since this full archive, see [`DIFFERENTIAL`]
+    pub base: Option<Index>,
+    /// For a synthetic full archive, the folder it names for its copies
+    pub volumes: Option<String>,
+    /// Add files in the order of their paths rather than as they are found
+    pub deterministic: bool,
+    /// Globs of files and directories to leave out, see [`excluded`]
+    pub exclude: Vec<String>,
+    /// Files larger than this are left out
+    pub max_file_size: Option<u64>,
+    /// When to give up, from the job's `max_duration`
+    pub deadline: Option<Instant>,
+    /// Read the files from this copy of the input path, like the snapshot of
+    /// the job's `snapshot`, naming them as the input path's
+    pub read_from: Option<PathBuf>,
+    /// Further files and folders archived along with the input path, like the
+    /// volumes of a `docker` job's containers
+    pub also: Vec<PathBuf>,
+    /// The containers of a `docker` job, for the [`DOCKER`] entry
+    pub docker: Option<serde_json::Value>,
+}
+
+/// Formats that are compressed already, so deflating them again costs time
+/// and saves next to nothing.
+pub const STORED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    "mp3", "m4a", "aac", "ogg", "opus", "flac", "zip", "gz", "tgz", "bz2", "xz", "zst", "7z",
+    "rar", "lz4", "jar", "docx", "xlsx", "pptx", "odt", "ods", "epub",
+];
+
+impl ArchiveOptions {
+    /// Whether the file at `path` goes into the archive uncompressed.
+    pub fn stores(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| {
+            let ext = ext.to_string_lossy();
+            self.store_extensions
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(&ext))
+        })
+    }
+}
+
+/// What happened while building an archive, for the run summary.
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    pub files: u64,
+    pub bytes: u64,
+    /// Files that kept changing while they were read, even after retrying
+    pub unstable: Vec<PathBuf>,
+    /// Files and directories left out by `exclude`
+    pub excluded: Vec<PathBuf>,
+    /// Files and directories that couldn't be read, with the error
+    pub unreadable: Vec<(PathBuf, String)>,
+    /// Files left out by `max_file_size`, with their size
+    pub too_large: Vec<(PathBuf, u64)>,
+    /// Sparse files as (path, apparent size, allocated size)
+    pub sparse: Vec<(PathBuf, u64, u64)>,
+    /// By lowercase file extension, or `""` for files without one
+    pub types: BTreeMap<String, TypeStats>,
+    /// Entry names and their checksums, for the manifest
+    pub hashes: Vec<(String, String)>,
+    /// Of the whole archive, hashed as it was written
+    pub sha256: Option<String>,
+    /// Entry names of further hard links to a file, and the entry it is stored as
+    pub links: BTreeMap<String, String>,
+    /// Files left to the manifest, as another with the same contents is stored
+    pub duplicates: Vec<(PathBuf, u64)>,
+    /// Size and modification time of every file in the tree, by entry name,
+    /// if the options ask for them
+    pub index: BTreeMap<String, (u64, i64)>,
+    /// The file each entry of `index` was read from
+    pub sources: BTreeMap<String, PathBuf>,
+    /// Files left out as unchanged since the base
+    pub unchanged: usize,
+    /// How many files and folders had extended attributes or ACLs to keep
+    pub attributes: usize,
+    /// Files and folders whose extended attributes couldn't be read, with the error
+    pub attributes_unread: Vec<(PathBuf, String)>,
+    /// Files and folders with what the archive can't keep, and what that is
+    pub unkept: Vec<(PathBuf, String)>,
+}
+
+/// How well the files of one type compressed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TypeStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub compressed: u64,
+}
+
+/// How many file types the summary lists before lumping the rest together.
+const SUMMARY_TYPES: usize = 5;
+
+/// `part` as a percentage of `whole`, for compression ratios.
+fn percent(part: u64, whole: u64) -> String {
+    match whole {
+        0 => "-".to_string(),
+        _ => format!("{:.0}%", part as f64 * 100.0 / whole as f64),
+    }
+}
+
+impl ArchiveReport {
+    fn merge(&mut self, other: ArchiveReport) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.unstable.extend(other.unstable);
+        self.excluded.extend(other.excluded);
+        self.unreadable.extend(other.unreadable);
+        self.too_large.extend(other.too_large);
+        self.sparse.extend(other.sparse);
+        self.hashes.extend(other.hashes);
+        self.duplicates.extend(other.duplicates);
+    }
+
+    /// Counts the file at `path` as archived, though only the manifest lists
+    /// it, as entry `name` with the checksum `hash`.
+    fn duplicate(&mut self, name: &str, path: &Path, len: u64, hash: String) {
+        self.files += 1;
+        self.bytes += len;
+        self.hashes.push((name.to_string(), hash));
+        self.duplicates.push((path.to_path_buf(), len));
+    }
+
+    /// What the run prints about the archive. The breakdown by file type is
+    /// limited to the largest types unless `all_types` is set.
+    pub fn summary(&self, all_types: bool) -> String {
+        let compressed = self.types.values().map(|t| t.compressed).sum();
+        let mut out = format!(
+            "Archived {} files ({}), compressed to {} ({})",
+            self.files,
+            crate::format_bytes(self.bytes),
+            crate::format_bytes(compressed),
+            percent(compressed, self.bytes)
+        );
+        let mut types = self.types.iter().collect::<Vec<_>>();
+        types.sort_by_key(|(_, t)| std::cmp::Reverse(t.bytes));
+        // Lumping a single type together would only rename it.
+        let shown = if all_types || types.len() <= SUMMARY_TYPES + 1 {
+            types.len()
+        } else {
+            SUMMARY_TYPES
+        };
+        let mut lines = types
+            .iter()
+            .take(shown)
+            .map(|(ext, t)| {
+                let ext = if ext.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    format!(".{ext}")
+                };
+                (ext, **t)
+            })
+            .collect::<Vec<_>>();
+        if types.len() > shown {
+            let mut rest = TypeStats::default();
+            for (_, t) in &types[shown..] {
+                rest.files += t.files;
+                rest.bytes += t.bytes;
+                rest.compressed += t.compressed;
+            }
+            lines.push((format!("{} other types", types.len() - shown), rest));
+        }
+        if types.len() > 1 || all_types {
+            out += "\nBy file type:";
+            for (ext, t) in lines {
+                out += &format!(
+                    "\n  {ext}: {} files, {} compressed to {} ({})",
+                    t.files,
+                    crate::format_bytes(t.bytes),
+                    crate::format_bytes(t.compressed),
+                    percent(t.compressed, t.bytes)
+                );
+            }
+        }
+        if !self.sparse.is_empty() {
+            let apparent = self.sparse.iter().map(|x| x.1).sum();
+            let allocated = self.sparse.iter().map(|x| x.2).sum();
+            out += &format!(
+                "\n{} sparse files take {} on disk but {} in the archive before compression:",
+                self.sparse.len(),
+                crate::format_bytes(allocated),
+                crate::format_bytes(apparent)
+            );
+            for (path, apparent, allocated) in &self.sparse {
+                out += &format!(
+                    "\n  {} ({} of {} allocated)",
+                    path.display(),
+                    crate::format_bytes(*allocated),
+                    crate::format_bytes(*apparent)
+                );
+            }
+        }
+        if self.unchanged > 0 {
+            out += &format!(
+                "\n{} files unchanged since the full archive were left out",
+                self.unchanged
+            );
+        }
+        if !self.duplicates.is_empty() {
+            let bytes = self.duplicates.iter().map(|x| x.1).sum();
+            out += &format!(
+                "\n{} files with the same contents as another were stored once, saving {}",
+                self.duplicates.len(),
+                crate::format_bytes(bytes)
+            );
+        }
+        if !self.links.is_empty() {
+            out += &format!(
+                "\n{} hard links to files archived under another name were stored once",
+                self.links.len()
+            );
+        }
+        if self.attributes > 0 {
+            out += &format!(
+                "\n{} files and folders had extended attributes or ACLs, which were kept",
+                self.attributes
+            );
+        }
+        if !self.attributes_unread.is_empty() {
+            out += &format!(
+                "\n{} files and folders were archived without their extended attributes, which could not be read:",
+                self.attributes_unread.len()
+            );
+            for (path, error) in &self.attributes_unread {
+                out += &format!("\n  {}: {error}", path.display());
+            }
+        }
+        if !self.unkept.is_empty() {
+            out += &format!(
+                "\n{} files and folders have what the archive can't keep, which a restore won't bring back:",
+                self.unkept.len()
+            );
+            for (path, what) in &self.unkept {
+                out += &format!("\n  {}: {what}", path.display());
+            }
+        }
+        if !self.unstable.is_empty() {
+            out += &format!(
+                "\n{} files changed while being read and may be inconsistent:",
+                self.unstable.len()
+            );
+            for path in &self.unstable {
+                out += &format!("\n  {}", path.display());
+            }
+        }
+        let skipped = [
+            (self.excluded.len(), "excluded by `exclude`"),
+            (self.too_large.len(), "larger than max_file_size"),
+            (self.unreadable.len(), "unreadable"),
+        ]
+        .into_iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, why)| format!("{n} {why}"))
+        .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            out += &format!("\nSkipped {}", skipped.join(", "));
+        }
+        if !self.unreadable.is_empty() {
+            out += "\nThese could not be read and are missing from the archive:";
+            for (path, error) in &self.unreadable {
+                out += &format!("\n  {}: {error}", path.display());
+            }
+        }
+        out
+    }
+
+    /// One `category<TAB>path<TAB>detail` line per file that was left out or
+    /// may be inconsistent, sorted by path within each category.
+    pub fn details(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut add = |category: &str, mut rows: Vec<(&Path, String)>| {
+            rows.sort();
+            for (path, detail) in rows {
+                lines.push(format!("{category}\t{}\t{detail}", path.display()));
+            }
+        };
+        fn unit(paths: &[PathBuf]) -> Vec<(&Path, String)> {
+            paths.iter().map(|p| (p.as_path(), String::new())).collect()
+        }
+        add("excluded", unit(&self.excluded));
+        add(
+            "too_large",
+            self.too_large
+                .iter()
+                .map(|(p, size)| (p.as_path(), size.to_string()))
+                .collect(),
+        );
+        add(
+            "unreadable",
+            self.unreadable
+                .iter()
+                .map(|(p, error)| (p.as_path(), error.clone()))
+                .collect(),
+        );
+        add("changed", unit(&self.unstable));
+        lines
+    }
+}
+
+/// Whether `exclude` leaves out the file or directory at `relative`, its path
+/// below the backed up directory. Patterns without a `/` are matched against
+/// the name alone, so `*.tmp` and `node_modules` apply at every level; others
+/// against the whole relative path, like `photos/**/*.raw`. `*` and `?` don't
+/// match a `/`, `**` matches anything.
+pub fn excluded(exclude: &[String], relative: &Path) -> bool {
+    matches_any(exclude, relative)
+}
+
+/// Whether any of `patterns` matches `relative`, with the rules of [`excluded`].
+pub fn matches_any(patterns: &[String], relative: &Path) -> bool {
+    let path = entry_name(relative).chars().collect::<Vec<_>>();
+    let name = relative
+        .file_name()
+        .map(|n| n.to_string_lossy().chars().collect::<Vec<_>>())
+        .unwrap_or_default();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim_end_matches('/').chars().collect::<Vec<_>>();
+        let text = if pattern.contains(&'/') { &path } else { &name };
+        glob(&pattern, text)
+    })
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // Also no directory at all, so `**/x` matches `x`.
+            glob(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && glob(rest, &text[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            let component = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+            (0..=component).any(|i| glob(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => text.first().is_some_and(|c| *c != '/') && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// Remembers whether reading failed, to tell an unreadable file from an
+/// archive that can't be written.
+struct ReadFailed<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: std::io::Read> std::io::Read for ReadFailed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf);
+        self.failed |= read.is_err();
+        read
+    }
+}
+
+#[cfg(test)]
+type ReadHook = Box<dyn FnMut(&Path)>;
+
+#[cfg(test)]
+thread_local! {
+    /// Called with the file before each attempt at reading it, for tests to change it
+    static BEFORE_READ: std::cell::RefCell<Option<ReadHook>> = std::cell::RefCell::new(None);
+}
+
+/// Size and modification time, which change whenever a file is written to.
+fn fingerprint(meta: &std::fs::Metadata) -> (u64, Option<std::time::SystemTime>) {
+    (meta.len(), meta.modified().ok())
+}
+
+/// `options` with the file's modification time, in local time as zip keeps
+/// it, and on Unix its permissions, for a restore to put back.
+fn with_metadata(mut options: SimpleFileOptions, meta: &std::fs::Metadata) -> SimpleFileOptions {
+    if let Ok(modified) = meta.modified() {
+        let local = chrono::DateTime::<chrono::Local>::from(modified);
+        let time = zip::DateTime::from_date_and_time(
+            local.year().try_into().unwrap_or(0),
+            local.month() as u8,
+            local.day() as u8,
+            local.hour() as u8,
+            local.minute() as u8,
+            local.second() as u8,
+        );
+        // Zip can't hold times before 1980; those get the time of the backup.
+        if let Ok(time) = time {
+            options = options.last_modified_time(time);
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(meta.permissions().mode() & 0o7777);
+    }
+    options
+}
+
+/// Streams one file into a new zip entry. If the file changed between the
+/// initial stat and EOF, the entry is discarded and the file re-read, up to
+/// `retries` times; a file still changing after that is kept and recorded in the report.
+/// One deleted or replaced meanwhile counts as changed, and is reported
+/// unreadable if it can't be opened again.
+fn archive_file<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    path: &Path,
+    options: SimpleFileOptions,
+    archive_options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+) -> Result<(), Box<dyn Error>> {
+    let retries = archive_options.changed_file_retries;
+    let mut attempt = 0;
+    loop {
+        if limits::expired(archive_options.deadline) {
+            return Err(limits::timed_out().into());
+        }
+        let opened = File::open(path).and_then(|file| Ok((file.metadata()?, file)));
+        let (meta, file) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                report.unreadable.push((path.to_path_buf(), e.to_string()));
+                return Ok(());
+            }
+        };
+        let before = fingerprint(&meta);
+        zip.start_file(name, with_metadata(options, &meta))?;
+        let mut reader = ReadFailed {
+            inner: Hashing::new(
+                Timed::new(sparse::reader(file, &meta), archive_options.deadline),
+                archive_options.manifest,
+            ),
+            failed: false,
+        };
+        #[cfg(test)]
+        BEFORE_READ.with_borrow_mut(|hook| hook.as_mut().map(|hook| hook(path)));
+        let copied = match std::io::copy(&mut reader, zip) {
+            Ok(copied) => copied,
+            Err(e) if reader.failed && !limits::expired(archive_options.deadline) => {
+                zip.abort_file()?;
+                report.unreadable.push((path.to_path_buf(), e.to_string()));
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let after = std::fs::metadata(path).ok().map(|meta| fingerprint(&meta));
+        let stable = after == Some(before) && copied == before.0;
+        if stable || attempt >= retries {
+            if !stable {
+                report.unstable.push(path.to_path_buf());
+            }
+            if sparse::is_sparse(&meta) {
+                let allocated = sparse::allocated_size(&meta).unwrap_or(meta.len());
+                report
+                    .sparse
+                    .push((path.to_path_buf(), meta.len(), allocated));
+            }
+            report.files += 1;
+            report.bytes += copied;
+            if let Some(hash) = reader.inner.finish() {
+                report.hashes.push((name.to_string(), hash));
+            }
+            return Ok(());
+        }
+        zip.abort_file()?;
+        attempt += 1;
+    }
+}
+
+/// How often the progress is redrawn on a terminal
+const PROGRESS_REDRAW: Duration = Duration::from_millis(250);
+/// How often a progress line is printed when stderr goes to a log instead
+const PROGRESS_LOG: Duration = Duration::from_secs(30);
+
+/// Shows how far archiving has got on stderr: redrawn in place on a terminal,
+/// and as an occasional line otherwise, so a long run in a log shows it is alive.
+struct Progress<'a> {
+    total: usize,
+    files: usize,
+    bytes: u64,
+    /// The archive being written, whose size is the bytes written so far
+    output: &'a Path,
+    terminal: bool,
+    /// When the progress was last shown, or archiving started
+    shown: Instant,
+    /// Whether a line is waiting to be ended on the terminal
+    drawn: bool,
+}
+
+impl<'a> Progress<'a> {
+    fn new(total: usize, output: &'a Path) -> Self {
+        Progress {
+            total,
+            files: 0,
+            bytes: 0,
+            output,
+            terminal: std::io::stderr().is_terminal(),
+            shown: Instant::now(),
+            drawn: false,
+        }
+    }
+
+    /// Counts the entry `name` of `bytes` bytes as archived.
+    fn entry_done(&mut self, name: &str, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        let Some(line) = self.line(name) else {
+            return;
+        };
+        if self.terminal {
+            eprint!("\r\x1b[K{line}");
+            self.drawn = true;
+        } else {
+            eprintln!("{line}");
+        }
+    }
+
+    /// What to show after the entry `name`, if it is time to show anything.
+    fn line(&mut self, name: &str) -> Option<String> {
+        let every = if self.terminal {
+            PROGRESS_REDRAW
+        } else {
+            PROGRESS_LOG
+        };
+        if self.shown.elapsed() < every {
+            return None;
+        }
+        self.shown = Instant::now();
+        let written = std::fs::metadata(self.output).map_or(0, |m| m.len());
+        let line = format!(
+            "Archiving: {}/{} files, {} read, {} written",
+            self.files,
+            self.total,
+            crate::format_bytes(self.bytes),
+            crate::format_bytes(written)
+        );
+        if self.terminal {
+            // Keep the line short enough not to wrap, which would defeat the redraw.
+            let room = 100usize.saturating_sub(line.len() + 2);
+            let skip = name.chars().count().saturating_sub(room);
+            let name = name.chars().skip(skip).collect::<String>();
+            Some(format!("{line}: {name}"))
+        } else {
+            Some(format!("{line}, now at {name}"))
+        }
+    }
+
+    fn finish(&self) {
+        if self.drawn {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// A counting semaphore bounding how many files are open or buffered at once.
+struct Semaphore {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            free: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.released.wait(free).unwrap();
+        }
+        *free -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    name: String,
+    len: u64,
+    options: SimpleFileOptions,
+}
+
+/// Name of the entry that lists the hard links stored once, as a JSON object
+/// from the entry name of each further link to the entry of the file. Only
+/// `restore` makes the links again; unzip leaves them out.
+pub const LINKS: &str = ".synology_backuper_links.json";
+
+/// Name of the entry that holds the extended attributes and ACLs of a job
+/// with `xattrs`, as a JSON object from entry names to [`Attributes`].
+/// Folders, which have no entries, are named with a `/` at the end. Only
+/// `restore` sets them again; unzip leaves them out.
+pub const XATTRS: &str = ".synology_backuper_xattrs.json";
+
+/// Name of the entry that records the containers of a `docker` job, with
+/// their images, as a JSON list, for setting them up again around restored
+/// volumes.
+pub const DOCKER: &str = ".synology_backuper_docker.json";
+
+/// Name of the entry that makes an archive a differential, a JSON
+/// [`Differential`]: it holds only the files that changed since the full
+/// archive it names, and lists those deleted since.
+pub const DIFFERENTIAL: &str = ".synology_backuper_differential.json";
+
+/// Whether the entry `name` is one the archive holds about its files, rather
+/// than one of them.
+pub fn is_metadata(name: &str) -> bool {
+    name == LINKS
+        || name == XATTRS
+        || name == DOCKER
+        || name == DIFFERENTIAL
+        || Algorithm::of_manifest(name).is_some()
+}
+
+/// When the file at `path` was last modified, in nanoseconds since 1970.
+pub fn modified_nanos(path: &Path) -> i64 {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+/// Splits off the entries that are further hard links to the file of an
+/// earlier entry, as pairs of the link and the file's entry.
+#[cfg(unix)]
+fn split_hardlinks(entries: Vec<Entry>) -> (Vec<Entry>, Vec<(Entry, String)>) {
+    use std::os::unix::fs::MetadataExt;
+    let mut first = HashMap::<(u64, u64), String>::new();
+    let (mut files, mut links) = (Vec::new(), Vec::new());
+    for entry in entries {
+        let inode = std::fs::metadata(&entry.path)
+            .ok()
+            .filter(|meta| meta.nlink() > 1)
+            .map(|meta| (meta.dev(), meta.ino()));
+        match inode.and_then(|inode| first.get(&inode)) {
+            Some(name) => links.push((entry, name.clone())),
+            None => {
+                if let Some(inode) = inode {
+                    first.insert(inode, entry.name.clone());
+                }
+                files.push(entry);
+            }
+        }
+    }
+    (files, links)
+}
+
+/// Elsewhere hard links aren't told apart from files.
+#[cfg(not(unix))]
+fn split_hardlinks(entries: Vec<Entry>) -> (Vec<Entry>, Vec<(Entry, String)>) {
+    (entries, Vec::new())
+}
+
+/// Compresses the contents of a directory into a zip file
+/// If the input path is a file, it will be compressed into a zip file
+pub fn compress_iter(
+    input_path: &Path,
+    output_path: &Path,
+    archive_options: &ArchiveOptions,
+) -> Result<ArchiveReport, Box<dyn Error>> {
+    let mut zip = ZipWriter::new(HashedFile::create(output_path)?);
+    let options = archive_options.compression.options().large_file(false);
+    let mut report = ArchiveReport::default();
+    let named = extended_path(input_path);
+    let root = match &archive_options.read_from {
+        Some(copy) => extended_path(copy),
+        None => named.clone(),
+    };
+    let name_of = |path: &Path| {
+        let name = match path.strip_prefix(&root) {
+            Ok(relative) if root != named => entry_name(&named.join(relative)),
+            _ => entry_name(path),
+        };
+        match archive_options.unicode_names {
+            UnicodeNames::Raw => name,
+            UnicodeNames::Nfc => name.nfc().collect(),
+        }
+    };
+    let entry = |path: PathBuf, len: u64| {
+        let name = name_of(&path);
+        let mut options = options.large_file(len >= u32::MAX as u64);
+        if archive_options.stores(&path) {
+            options = options
+                .compression_method(CompressionMethod::Stored)
+                .compression_level(None);
+        }
+        Entry {
+            path,
+            name,
+            len,
+            options,
+        }
+    };
+
+    // See `device` for why one_file_system needs the serial walk outside Unix.
+    let serial_walk =
+        archive_options.parallelism <= 1 || (archive_options.one_file_system && !cfg!(unix));
+    // The input path first, then the further ones, each walked as a whole.
+    let roots = std::iter::once(root.clone())
+        .chain(archive_options.also.iter().map(|path| extended_path(path)))
+        .collect::<Vec<_>>();
+    let mut files = Vec::new();
+    for root in &roots {
+        if serial_walk {
+            files.extend(walk_serial(root, archive_options, &mut report)?);
+        } else {
+            let mut found = walk_parallel(root, archive_options, &mut report)?;
+            if archive_options.deterministic {
+                // Component-wise, which is the order a sorted depth-first walk visits them in.
+                found.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            files.extend(found);
+        }
+    }
+    let (files, too_large) = files.into_iter().partition::<Vec<_>, _>(|(_, len)| {
+        archive_options.max_file_size.is_none_or(|max| *len <= max)
+    });
+    report.too_large = too_large;
+    // Entry names are UTF-8, and making one up could give two files the same.
+    let (files, unnamed) = files
+        .into_iter()
+        .partition::<Vec<_>, _>(|(path, _)| path.to_str().is_some());
+    for (path, _) in unnamed {
+        report.unreadable.push((
+            path,
+            "its name is not valid UTF-8, as entry names have to be, so it was left out rather than renamed".to_string(),
+        ));
+    }
+    let mut entries = files
+        .into_iter()
+        .map(|(path, len)| entry(path, len))
+        .collect::<Vec<_>>();
+    if archive_options.index || archive_options.base.is_some() {
+        for entry in &entries {
+            let stamp = (entry.len, modified_nanos(&entry.path));
+            report.index.insert(entry.name.clone(), stamp);
+            let source = match entry.path.strip_prefix(&root) {
+                Ok(relative) if root != named => named.join(relative),
+                _ => entry.path.clone(),
+            };
+            report.sources.insert(entry.name.clone(), source);
+        }
+    }
+    // Every folder above a file, including those a differential has no
+    // files of, so the newest attributes of those come from it.
+    let mut folders = BTreeSet::new();
+    if archive_options.xattrs {
+        for entry in &entries {
+            for dir in entry.path.ancestors().skip(1) {
+                let inside = roots.iter().any(|root| dir.starts_with(root));
+                if !inside || !folders.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+    }
+    let differential = archive_options.base.as_ref().map(|base| {
+        let before = entries.len();
+        entries.retain(|e| base.files.get(&e.name) != report.index.get(&e.name));
+        report.unchanged = before - entries.len();
+        Differential {
+            base: base.full.clone(),
+            deleted: base
+                .files
+                .keys()
+                .filter(|name| !report.index.contains_key(*name))
+                .cloned()
+                .collect(),
+            volumes: archive_options.volumes.clone(),
+        }
+    });
+    let mut attributes = BTreeMap::new();
+    if archive_options.xattrs || archive_options.ads_warnings {
+        let files = entries.iter().map(|e| (e.name.clone(), e.path.clone()));
+        let folders = folders
+            .into_iter()
+            .map(|dir| (format!("{}/", name_of(&dir)), dir));
+        for (name, path) in files.chain(folders) {
+            let unkept =
+                xattrs::unkept(&path, archive_options.xattrs, archive_options.ads_warnings);
+            match unkept {
+                Ok(unkept) if unkept.is_empty() => {}
+                Ok(unkept) => report.unkept.push((path.clone(), unkept.join(", "))),
+                Err(e) => report.attributes_unread.push((path.clone(), e.to_string())),
+            }
+            if !archive_options.xattrs {
+                continue;
+            }
+            match xattrs::read(&path) {
+                Ok(found) if found.is_empty() => {}
+                Ok(found) => {
+                    attributes.insert(name, found);
+                }
+                Err(e) => report.attributes_unread.push((path, e.to_string())),
+            }
+        }
+    }
+    let (entries, links) = match archive_options.dedup_hardlinks {
+        true => split_hardlinks(entries),
+        false => (entries, Vec::new()),
+    };
+
+    // Knowing every file up front is what lets the progress show a total.
+    let mut progress = Progress::new(entries.len(), output_path);
+    if archive_options.parallelism <= 1 {
+        // Only a file of the same size as another can have the same
+        // contents, so only those are read once more to be hashed first.
+        let mut sizes = HashMap::<u64, usize>::new();
+        if archive_options.dedup_contents {
+            for entry in &entries {
+                *sizes.entry(entry.len).or_default() += 1;
+            }
+        }
+        let mut stored = HashMap::new();
+        for entry in &entries {
+            let before = report.bytes;
+            let hash = match archive_options.manifest {
+                Some(algorithm) if entry.len > 0 && sizes.get(&entry.len) > Some(&1) => {
+                    hash_file(&entry.path, algorithm).ok()
+                }
+                _ => None,
+            };
+            match hash {
+                Some(hash) if stored.contains_key(&hash) => {
+                    report.duplicate(&entry.name, &entry.path, entry.len, hash);
+                }
+                _ => {
+                    archive_file(
+                        &mut zip,
+                        &entry.name,
+                        &entry.path,
+                        entry.options,
+                        archive_options,
+                        &mut report,
+                    )?;
+                    if archive_options.dedup_contents {
+                        remember(&mut stored, &report, &entry.name);
+                    }
+                }
+            }
+            progress.entry_done(&entry.name, report.bytes - before);
+        }
+    } else {
+        compress_parallel(
+            &mut zip,
+            output_path,
+            &entries,
+            archive_options,
+            &mut report,
+            &mut progress,
+        )?;
+    }
+    progress.finish();
+
+    // A link to a file that couldn't be read would lead nowhere.
+    let lost = entries
+        .iter()
+        .filter(|e| report.unreadable.iter().any(|(path, _)| *path == e.path))
+        .map(|e| e.name.as_str())
+        .collect::<Vec<_>>();
+    for (link, name) in links {
+        if lost.contains(&name.as_str()) {
+            report.unreadable.push((
+                link.path,
+                format!("a hard link to {name}, which could not be read"),
+            ));
+        } else {
+            report.links.insert(link.name, name);
+        }
+    }
+    if !report.links.is_empty() {
+        zip.start_file(LINKS, options)?;
+        zip.write_all(serde_json::to_string_pretty(&report.links)?.as_bytes())?;
+    }
+    if !attributes.is_empty() {
+        report.attributes = attributes.len();
+        zip.start_file(XATTRS, options)?;
+        zip.write_all(serde_json::to_string_pretty(&attributes)?.as_bytes())?;
+    }
+    if let Some(containers) = &archive_options.docker {
+        zip.start_file(DOCKER, options)?;
+        zip.write_all(serde_json::to_string_pretty(containers)?.as_bytes())?;
+    }
+    if let Some(differential) = differential {
+        zip.start_file(DIFFERENTIAL, options)?;
+        zip.write_all(serde_json::to_string_pretty(&differential)?.as_bytes())?;
+    }
+    // A file that couldn't be read has to be read again by the next differential.
+    for (path, _) in &report.unreadable {
+        if let Some(e) = entries.iter().find(|e| e.path == *path) {
+            report.index.remove(&e.name);
+        }
+    }
+    if let Some(algorithm) = archive_options.manifest {
+        zip.start_file(algorithm.manifest_name(), options)?;
+        zip.write_all(checksum::manifest(&report.hashes).as_bytes())?;
+    }
+    let (_, sha256) = zip.finish()?.finish()?;
+    report.sha256 = Some(sha256);
+    report.types = file_types(output_path)?;
+    Ok(report)
+}
+
+/// Lists the files below `root` with their sizes in one thread, skipping
+/// symlinks, with what `exclude` leaves out and what can't be read recorded
+/// in `report`.
+fn walk_serial(
+    root: &Path,
+    archive_options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+) -> Result<Vec<(PathBuf, u64)>, Box<dyn Error>> {
+    let mut walk = walkdir::WalkDir::new(root)
+        .max_open(archive_options.max_open_files.max(1))
+        .same_file_system(archive_options.one_file_system);
+    if let Some(depth) = archive_options.max_depth {
+        walk = walk.max_depth(depth);
+    }
+    if archive_options.deterministic {
+        walk = walk.sort_by_file_name();
+    }
+    let mut files = Vec::new();
+    let walk = walk.into_iter().filter_entry(|e| {
+        let relative = e.path().strip_prefix(root).unwrap_or(e.path());
+        let keep = e.depth() == 0 || !excluded(&archive_options.exclude, relative);
+        if !keep {
+            report.excluded.push(e.path().to_path_buf());
+        }
+        keep
+    });
+    for entry in walk {
+        match entry {
+            // A file deleted since its directory was read is reported, not fatal.
+            Ok(entry) if entry.file_type().is_file() => match entry.metadata() {
+                Ok(meta) => files.push((entry.into_path(), meta.len())),
+                Err(e) => report.unreadable.push((entry.into_path(), e.to_string())),
+            },
+            Ok(_) => {}
+            Err(e) => {
+                let path = e.path().unwrap_or(root).to_path_buf();
+                report.unreadable.push((path, e.to_string()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Lists the files below `root` with their sizes on `parallelism` threads
+/// that each read one directory at a time. On network filesystems, where every
+/// directory listing is a round trip, this is much faster than reading one
+/// directory after the other.
+///
+/// The files come in the order the serial walk yields them: depth first, each
+/// directory in the order the filesystem lists it. Like the serial walk it
+/// skips symlinks, records what `exclude` leaves out and the directories and
+/// files it can't read in `report`, honours `max_depth` and `one_file_system`,
+/// and has at most one directory open per thread.
+fn walk_parallel(
+    root: &Path,
+    options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let meta = std::fs::metadata(root)?;
+    if !meta.is_dir() {
+        return Ok(vec![(root.to_path_buf(), meta.len())]);
+    }
+    let device = options.one_file_system.then(|| device(&meta)).flatten();
+    let max_depth = options.max_depth.unwrap_or(usize::MAX);
+    if max_depth == 0 {
+        return Ok(Vec::new());
+    }
+
+    enum Child {
+        File(PathBuf, u64),
+        Dir(PathBuf),
+    }
+    struct Queue {
+        /// Directories waiting to be read, with their depth below `root`
+        dirs: Vec<(PathBuf, usize)>,
+        /// Threads reading a directory, which may queue more
+        busy: usize,
+        /// What each directory read so far holds, in listing order
+        listed: HashMap<PathBuf, Vec<Child>>,
+        /// What was excluded, unreadable or gone before it was looked at
+        skipped: ArchiveReport,
+    }
+    let queue = Mutex::new(Queue {
+        dirs: vec![(root.to_path_buf(), 0)],
+        busy: 0,
+        listed: HashMap::new(),
+        skipped: ArchiveReport::default(),
+    });
+    let changed = Condvar::new();
+    std::thread::scope(|scope| {
+        for _ in 0..options.parallelism {
+            scope.spawn(|| loop {
+                let (dir, depth) = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        if let Some(next) = queue.dirs.pop() {
+                            queue.busy += 1;
+                            break next;
+                        }
+                        if queue.busy == 0 {
+                            return;
+                        }
+                        queue = changed.wait(queue).unwrap();
+                    }
+                };
+                let mut children = Vec::new();
+                let mut skipped = ArchiveReport::default();
+                (|| {
+                    let entries = match std::fs::read_dir(&dir) {
+                        Ok(entries) => entries,
+                        Err(e) => {
+                            skipped.unreadable.push((dir.clone(), e.to_string()));
+                            return;
+                        }
+                    };
+                    for entry in entries {
+                        let entry = match entry {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                skipped.unreadable.push((dir.clone(), e.to_string()));
+                                continue;
+                            }
+                        };
+                        let path = entry.path();
+                        let relative = path.strip_prefix(root).unwrap_or(&path);
+                        if excluded(&options.exclude, relative) {
+                            skipped.excluded.push(path);
+                            continue;
+                        }
+                        let file_type = match entry.file_type() {
+                            Ok(file_type) => file_type,
+                            Err(e) => {
+                                skipped.unreadable.push((path, e.to_string()));
+                                continue;
+                            }
+                        };
+                        if file_type.is_file() {
+                            match entry.metadata() {
+                                Ok(meta) => children.push(Child::File(path, meta.len())),
+                                Err(e) => skipped.unreadable.push((path, e.to_string())),
+                            }
+                        } else if file_type.is_dir() && depth + 1 < max_depth {
+                            let other_device = device.is_some() && device != device_of(&path);
+                            if !other_device {
+                                children.push(Child::Dir(path));
+                            }
+                        }
+                    }
+                })();
+                let mut queue = queue.lock().unwrap();
+                queue.busy -= 1;
+                queue.skipped.merge(skipped);
+                for child in &children {
+                    if let Child::Dir(path) = child {
+                        queue.dirs.push((path.clone(), depth + 1));
+                    }
+                }
+                queue.listed.insert(dir, children);
+                changed.notify_all();
+            });
+        }
+    });
+    let mut queue = queue.into_inner().unwrap();
+    report.merge(queue.skipped);
+
+    let mut files = Vec::new();
+    let mut stack = vec![queue.listed.remove(root).unwrap_or_default().into_iter()];
+    while let Some(children) = stack.last_mut() {
+        match children.next() {
+            Some(Child::File(path, len)) => files.push((path, len)),
+            Some(Child::Dir(path)) => {
+                let children = queue.listed.remove(&path).unwrap_or_default();
+                stack.push(children.into_iter());
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn device(meta: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+/// Elsewhere the parallel walk can't tell filesystems apart, so
+/// `one_file_system` keeps to the serial walk there.
+#[cfg(not(unix))]
+fn device(_: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn device_of(path: &Path) -> Option<u64> {
+    std::fs::symlink_metadata(path)
+        .ok()
+        .as_ref()
+        .and_then(device)
+}
+
+/// Sizes before and after compression per file extension, read back from the
+/// finished archive's central directory.
+fn file_types(archive: &Path) -> Result<BTreeMap<String, TypeStats>, Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+    let mut types = BTreeMap::<String, TypeStats>::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        if is_metadata(file.name()) {
+            continue;
+        }
+        let ext = Path::new(file.name())
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let stats = types.entry(ext).or_default();
+        stats.files += 1;
+        stats.bytes += file.size();
+        stats.compressed += file.compressed_size();
+    }
+    Ok(types)
+}
+
+/// How much the entries waiting to be copied into the archive may hold in
+/// memory together. Each gets an equal share; larger ones wait in a file.
+const IN_MEMORY: u64 = 256 << 20;
+
+/// A compressed entry waiting to be copied into the archive.
+enum Spool {
+    Memory(Vec<u8>),
+    File(Spooled),
+}
+
+/// A file next to the archive that a large entry waits in, removed once dropped.
+struct Spooled(PathBuf);
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Where entry `i` of the archive at `output_path` waits if it's too large to
+/// wait in memory.
+fn spool_path(output_path: &Path, i: usize) -> PathBuf {
+    let name = output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    output_path.with_file_name(format!(".{name}.{i}.spool"))
+}
+
+/// Copies the entry of the single-entry zip `single` into `zip`, returning
+/// whether it had one.
+fn copy_single<W: Write + Seek, R: Read + Seek>(
+    zip: &mut ZipWriter<W>,
+    single: R,
+) -> Result<bool, Box<dyn Error>> {
+    let mut single = zip::ZipArchive::new(single)?;
+    // Empty if the file turned out to be unreadable.
+    if single.is_empty() {
+        return Ok(false);
+    }
+    zip.raw_copy_file(single.by_index_raw(0)?)?;
+    Ok(true)
+}
+
+/// Compresses each entry into its own single-entry zip on a pool of worker
+/// threads, and copies the finished entries into `zip` in walk order. A
+/// permit is held from opening a file until its entry is copied, so at most
+/// `max_open_files` files are open or waiting at any moment; those waiting
+/// hold at most [`IN_MEMORY`] in memory, and larger ones wait in a file next
+/// to the archive at `output_path`.
+fn compress_parallel<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    output_path: &Path,
+    entries: &[Entry],
+    archive_options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    type Compressed = Result<(Spool, ArchiveReport), String>;
+    let permits = Semaphore::new(archive_options.max_open_files);
+    let in_memory = IN_MEMORY / archive_options.max_open_files.max(1) as u64;
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let (done, finished) = mpsc::channel::<(usize, Compressed, Permit)>();
+        for _ in 0..archive_options.parallelism {
+            let done = done.clone();
+            let (permits, next) = (&permits, &next);
+            scope.spawn(move || loop {
+                // Taking the permit before the index means every lower index
+                // is already being worked on, so the writer can't starve.
+                let permit = permits.acquire();
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(entry) = entries.get(i) else { return };
+                let spool = (entry.len > in_memory).then(|| spool_path(output_path, i));
+                let compressed = compress_one(entry, archive_options, spool)
+                    .map_err(|e| format!("{}: {e}", entry.path.display()));
+                if done.send((i, compressed, permit)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(done);
+
+        let mut pending = BTreeMap::new();
+        let mut written = 0;
+        // Entry names by checksum, for dedup_contents
+        let mut stored = HashMap::new();
+        for (i, compressed, permit) in finished {
+            pending.insert(i, (compressed, permit));
+            while let Some((compressed, _permit)) = pending.remove(&written) {
+                let (spool, mut part) = compressed?;
+                let entry = &entries[written];
+                let duplicate = archive_options.dedup_contents
+                    && part.bytes > 0
+                    && part
+                        .hashes
+                        .first()
+                        .is_some_and(|(_, hash)| stored.contains_key(hash));
+                if duplicate {
+                    // Hashed on the way like any other, so dropping it costs nothing more.
+                    let (name, hash) = part.hashes.pop().unwrap();
+                    let bytes = std::mem::take(&mut part.bytes);
+                    part.files -= 1;
+                    part.duplicate(&name, &entry.path, bytes, hash);
+                } else {
+                    let copied = match spool {
+                        Spool::Memory(buffer) => copy_single(zip, Cursor::new(buffer))?,
+                        Spool::File(spooled) => copy_single(zip, File::open(&spooled.0)?)?,
+                    };
+                    if copied {
+                        remember(&mut stored, &part, &entry.name);
+                    }
+                }
+                progress.entry_done(&entry.name, part.bytes);
+                report.merge(part);
+                written += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Records the checksum of entry `name`, if `report` has just archived it,
+/// as one whose contents are stored.
+fn remember(stored: &mut HashMap<String, String>, report: &ArchiveReport, name: &str) {
+    if let Some((archived, hash)) = report.hashes.last().filter(|(n, _)| n == name) {
+        stored
+            .entry(hash.clone())
+            .or_insert_with(|| archived.clone());
+    }
+}
+
+/// The checksum of the file at `path`.
+pub fn hash_file(path: &Path, algorithm: Algorithm) -> std::io::Result<String> {
+    let mut reader = Hashing::new(File::open(path)?, Some(algorithm));
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.finish().unwrap_or_default())
+}
+
+/// Compresses `entry` into a single-entry zip, in memory or, given a
+/// `spool` path, in a file there.
+fn compress_one(
+    entry: &Entry,
+    archive_options: &ArchiveOptions,
+    spool: Option<PathBuf>,
+) -> Result<(Spool, ArchiveReport), Box<dyn Error>> {
+    let mut report = ArchiveReport::default();
+    let Some(path) = spool else {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        archive_file(
+            &mut zip,
+            &entry.name,
+            &entry.path,
+            entry.options,
+            archive_options,
+            &mut report,
+        )?;
+        return Ok((Spool::Memory(zip.finish()?.into_inner()), report));
+    };
+    let spooled = Spooled(path);
+    let mut zip = ZipWriter::new(File::create(&spooled.0)?);
+    archive_file(
+        &mut zip,
+        &entry.name,
+        &entry.path,
+        entry.options,
+        archive_options,
+        &mut report,
+    )?;
+    zip.finish()?;
+    Ok((Spool::File(spooled), report))
+}
+
+/// The zip entry name for a file: its path without drive, root or `\\?\` prefix,
+/// with `/` separators as the zip format requires on every platform.
+pub fn entry_name(path: &Path) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    parts.join("/")
+}
+
+/// On Windows, turns an absolute path into its `\\?\` form so the 260 character
+/// `MAX_PATH` limit doesn't apply to anything below it. Elsewhere the path is returned as is.
+#[cfg(windows)]
+fn extended_path(path: &Path) -> PathBuf {
+    // canonicalize returns verbatim (`\\?\C:\...` or `\\?\UNC\...`) paths on Windows.
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(not(windows))]
+fn extended_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Job;
+
+    /// Archives `path` with `change` called before each read with the number
+    /// of the read, and returns the reads and the report.
+    fn archive_changing(
+        path: &Path,
+        retries: u32,
+        mut change: impl FnMut(&Path, usize) + 'static,
+    ) -> (usize, ArchiveReport) {
+        let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let seen = reads.clone();
+        BEFORE_READ.set(Some(Box::new(move |path| {
+            seen.set(seen.get() + 1);
+            change(path, seen.get());
+        })));
+        let mut options = Job::default().archive_options();
+        options.changed_file_retries = retries;
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let mut report = ArchiveReport::default();
+        archive_file(
+            &mut zip,
+            "notes.txt",
+            path,
+            SimpleFileOptions::default(),
+            &options,
+            &mut report,
+        )
+        .unwrap();
+        BEFORE_READ.set(None);
+        (reads.get(), report)
+    }
+
+    #[test]
+    fn rereads_a_file_that_changed_while_it_was_read() {
+        let dir = std::env::temp_dir().join(format!("archive_changed_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+
+        std::fs::write(&path, "hello").unwrap();
+        let (reads, report) = archive_changing(&path, 1, |path, read| {
+            if read == 1 {
+                append(path);
+            }
+        });
+        assert_eq!(reads, 2);
+        assert!(report.unstable.is_empty());
+        assert_eq!(report.files, 1);
+        assert_eq!(report.bytes, "hello more".len() as u64);
+
+        std::fs::write(&path, "hello").unwrap();
+        let (reads, report) = archive_changing(&path, 1, |path, _| append(path));
+        assert_eq!(reads, 2);
+        assert_eq!(report.unstable, std::slice::from_ref(&path));
+        assert_eq!(report.files, 1);
+        assert_eq!(report.details(), [format!("changed\t{}\t", path.display())]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn append(path: &Path) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b" more").unwrap();
+    }
+
+    // Windows doesn't let an open file be deleted.
+    #[cfg(unix)]
+    #[test]
+    fn reports_a_file_deleted_while_it_was_read() {
+        let dir = std::env::temp_dir().join(format!("archive_deleted_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, "hello").unwrap();
+
+        let (reads, report) = archive_changing(&path, 1, |path, _| {
+            std::fs::remove_file(path).unwrap();
+        });
+        assert_eq!(reads, 1);
+        assert_eq!(report.files, 0);
+        assert!(report.unstable.is_empty());
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].0, path);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_counts_files_and_bytes_and_keeps_quiet_in_between() {
+        let dir = std::env::temp_dir().join(format!("archive_progress_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.zip");
+        std::fs::write(&output, vec![0; 3000]).unwrap();
+
+        let mut progress = Progress::new(3, &output);
+        progress.terminal = false;
+        // Lines go to logs only every PROGRESS_LOG.
+        progress.entry_done("a.txt", 1000);
+        assert!(!progress.drawn);
+        progress.files += 1;
+        progress.bytes += 2048;
+        assert_eq!(progress.line("docs/b.txt"), None);
+        progress.shown -= PROGRESS_LOG;
+        assert_eq!(
+            progress.line("docs/b.txt").as_deref(),
+            Some("Archiving: 2/3 files, 3.0 KiB read, 2.9 KiB written, now at docs/b.txt")
+        );
+        assert_eq!(progress.line("docs/b.txt"), None);
+
+        // A terminal gets the line redrawn more often, cut to fit.
+        progress.terminal = true;
+        progress.shown -= PROGRESS_REDRAW;
+        let name = format!("docs/{}.txt", "x".repeat(200));
+        let line = progress.line(&name).unwrap();
+        assert!(
+            line.starts_with("Archiving: 2/3 files, 3.0 KiB read, 2.9 KiB written: "),
+            "{line}"
+        );
+        assert!(line.ends_with("xxx.txt"), "{line}");
+        assert!(line.chars().count() <= 100, "{line}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Integrity audits: checking now and then that archives uploaded earlier are
+//! still on their targets, unchanged, to catch bit rot and files deleted by
+//! hand on the NAS.
+
+use crate::backend::download_resuming;
+use crate::client::Mode;
+use crate::runlog::{self, Upload};
+use crate::{job_folder, run_hook, Config, Sessions};
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The SHA-256 of the file at `path`, in lowercase hex.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Runs one audit against the uploads the run log records, and logs it.
+///
+/// The newest recorded upload of each job must still be on each target it
+/// went to. One upload that is still there, the newest included, is picked
+/// at random, downloaded and compared with the checksum recorded when it was
+/// made.
+/// Problems are printed and passed to the job's `on_failure` hook. Returns
+/// whether there were none.
+pub fn run(config: &Config, mode: Mode) -> Result<bool> {
+    let uploads = runlog::recorded_uploads(&runlog::path(config)?)?;
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let mut problems = Vec::new();
+    let mut candidates = Vec::new();
+    // Listing to see they're there is what a write_only job never does.
+    for job in config.jobs.iter().filter(|j| !j.write_only) {
+        for name in &job.targets {
+            let recorded = uploads
+                .iter()
+                .filter(|u| u.job == job.name && &u.target == name)
+                .collect::<Vec<_>>();
+            let Some(newest) = recorded.last() else {
+                continue;
+            };
+            let (target, remote) = sessions.get(name);
+            let present = remote.map_err(|e| anyhow!("{e}")).and_then(|remote| {
+                let (folder, _) = job_folder(remote, job, target)?;
+                let backups = remote.list_backups(job, &folder, false)?;
+                Ok(backups
+                    .into_iter()
+                    .map(|b| b.file.path)
+                    .collect::<HashSet<_>>())
+            });
+            let present = match present {
+                Ok(present) => present,
+                Err(e) => {
+                    problems.push((job, format!("could not list {name}: {e:#}")));
+                    continue;
+                }
+            };
+            if !present.contains(&newest.path) {
+                problems.push((job, format!("{} is gone", newest.location())));
+            }
+            candidates.extend(
+                recorded
+                    .into_iter()
+                    .filter(|u| u.sha256.is_some() && present.contains(&u.path)),
+            );
+        }
+    }
+
+    let checked = candidates.choose(&mut rand::thread_rng()).copied();
+    if let Some(upload) = checked {
+        let job = config.jobs.iter().find(|j| j.name == upload.job).unwrap();
+        let (_, remote) = sessions.get(&upload.target);
+        let copy = std::env::temp_dir().join(format!(
+            ".synology_backuper_audit_{}.zip",
+            std::process::id()
+        ));
+        let sha256 = remote
+            .map_err(|e| anyhow!("{e}"))
+            .and_then(|remote| download_resuming(remote, &upload.path, &copy, 0))
+            .and_then(|()| sha256_file(&copy));
+        let _ = std::fs::remove_file(&copy);
+        match sha256 {
+            Ok(sha256) if Some(&sha256) == upload.sha256.as_ref() => {
+                println!("Audit: {} matches its checksum", upload.location())
+            }
+            Ok(_) => problems.push((
+                job,
+                format!("{} differs from what was uploaded", upload.location()),
+            )),
+            Err(e) => problems.push((job, format!("could not check {}: {e:#}", upload.location()))),
+        }
+    }
+    sessions.logout();
+
+    for (job, problem) in &problems {
+        eprintln!("Audit of job {}: {problem}", job.name);
+        run_hook(
+            job,
+            job.on_failure.as_deref(),
+            &[],
+            &format!("audit: {problem}"),
+            "",
+        );
+    }
+    let record = runlog::Audit {
+        audit: runlog::now(),
+        result: if problems.is_empty() { "ok" } else { "alert" },
+        checked: checked.map(Upload::location),
+        problems: problems
+            .iter()
+            .map(|(job, problem)| format!("{}: {problem}", job.name))
+            .collect(),
+    };
+    if let Err(e) = runlog::append(&runlog::path(config)?, &record) {
+        eprintln!("Could not write the run log: {e:#}");
+    }
+    Ok(problems.is_empty())
+}
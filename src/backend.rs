@@ -0,0 +1,156 @@
+//! Where archives go: a common interface over the ways of reaching a target,
+//! so the backup, listing and retention code doesn't care which one it talks to.
+
+use crate::client::Mode;
+use crate::config::{Connection, Job, Transport};
+use crate::limits::ByteRate;
+use crate::{
+    format_bytes, job_backups, local, sftp, webdav, Backup, RemoteFile, Session, SharedFolder,
+};
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::time::Instant;
+
+/// One logged-in target. Paths are absolute and start with a share, like
+/// `/backup/Documents_20240101_030000.zip`, whatever the transport.
+pub trait StorageBackend {
+    /// What the target is reached through, for messages
+    fn kind(&self) -> &'static str;
+
+    /// The shares, or top-level folders, archives can go into.
+    fn shares(&self) -> &[SharedFolder];
+
+    /// Whether [`StorageBackend::shares`] is complete. If not, a share is
+    /// taken to be at `/<share_name>` and the upload finds out if it isn't.
+    fn shares_listed(&self) -> bool {
+        true
+    }
+
+    /// The files in `folder`, and in its subfolders if `recursive`, in no particular order.
+    fn list(&self, folder: &str, recursive: bool) -> Result<Vec<RemoteFile>>;
+
+    /// Uploads `local` into `folder` as `name`, replacing a file of that name.
+    fn upload(
+        &self,
+        folder: &str,
+        local: &Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()>;
+
+    /// Like [`StorageBackend::upload`], but fails if `folder` holds a file
+    /// named `name` already, rather than replacing it.
+    fn upload_new(
+        &self,
+        _folder: &str,
+        _local: &Path,
+        _name: &str,
+        _rate_limit: Option<ByteRate>,
+        _deadline: Option<Instant>,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "{} can't upload without replacing a file of the same name",
+            self.kind()
+        ))
+    }
+
+    /// Downloads the file at `path` to `local`.
+    fn download(&self, path: &str, local: &Path) -> Result<()>;
+
+    /// Downloads the rest of the file at `path` into `local`, which holds its
+    /// first `offset` bytes already, as a download cut short leaves it.
+    fn resume_download(&self, path: &str, local: &Path, offset: u64) -> Result<()> {
+        if offset > 0 {
+            return Err(anyhow!("{} can't resume a download", self.kind()));
+        }
+        self.download(path, local)
+    }
+
+    /// The `len` bytes of the file at `path` from `offset` on, so a zip's
+    /// table of contents, or a few of its files, can be read without
+    /// downloading the whole of it.
+    fn read_range(&self, _path: &str, _offset: u64, _len: u64) -> Result<Vec<u8>> {
+        Err(anyhow!("{} can't download part of a file", self.kind()))
+    }
+
+    fn delete(&self, paths: &[&str]) -> Result<()>;
+
+    /// Creates `folder` under an existing parent folder. An existing folder is fine.
+    fn create_folder(&self, folder: &str) -> Result<()>;
+
+    /// Checks that the account may write `file_name` into `folder`, where the
+    /// transport can tell without trying.
+    fn check_write_permission(&self, _folder: &str, _file_name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// The job's archives in `folder`, newest first.
+    fn list_backups(&self, job: &Job, folder: &str, recursive: bool) -> Result<Vec<Backup>> {
+        Ok(job_backups(job, self.list(folder, recursive)?))
+    }
+
+    /// The web API session behind the target, if it has one.
+    fn session(&self) -> Option<&Session> {
+        None
+    }
+
+    /// The web API session, for the features only FileStation offers.
+    fn api(&self, what: &str) -> Result<&Session> {
+        self.session().ok_or_else(|| {
+            anyhow!(
+                "{what} needs the DSM web API, but this target uses {}",
+                self.kind()
+            )
+        })
+    }
+
+    fn logout(&self) {}
+}
+
+/// How often a download that broke off is carried on
+const RESUMES: u32 = 3;
+
+/// Downloads the file at `path` to `local`, carrying on from where the
+/// connection broke off rather than starting over, a few times. With
+/// `offset` above 0, `local` holds that much of it from an earlier attempt.
+pub fn download_resuming(
+    remote: &dyn StorageBackend,
+    path: &str,
+    local: &Path,
+    offset: u64,
+) -> Result<()> {
+    let mut result = if offset > 0 {
+        remote.resume_download(path, local, offset)
+    } else {
+        remote.download(path, local)
+    };
+    for _ in 0..RESUMES {
+        let Err(e) = &result else {
+            break;
+        };
+        let have = std::fs::metadata(local).map_or(0, |m| m.len());
+        if have == 0 {
+            break;
+        }
+        eprintln!(
+            "The download of {path} broke off after {} ({e:#}); resuming",
+            format_bytes(have)
+        );
+        result = remote.resume_download(path, local, have);
+    }
+    result
+}
+
+/// Connects to `nas` through its configured transport.
+pub fn open(nas: &Connection, mode: Mode) -> Result<Box<dyn StorageBackend>> {
+    if nas.transport != Transport::Api && !matches!(mode, Mode::Live) {
+        return Err(anyhow!("--record and --replay only cover the DSM web API"));
+    }
+    Ok(match nas.transport {
+        Transport::Api => Box::new(Session::open(nas, mode)?),
+        Transport::Webdav => Box::new(webdav::WebDav::open(nas)?),
+        Transport::Sftp => Box::new(sftp::Sftp::open(nas)?),
+        Transport::Local => Box::new(local::LocalDir::open(nas)?),
+    })
+}
@@ -0,0 +1,656 @@
+//! The `list`, `prune` and `check` commands: a job's archives on its targets,
+//! thinning them out according to the job's retention settings, and making
+//! sure they keep coming.
+
+use crate::backend::StorageBackend;
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Job;
+use crate::limits::HumanDuration;
+use crate::{catalog, chain, fleet, runlog, worm};
+use crate::{
+    format_bytes, job_folder, selected_jobs, sidecar_archive, Backup, Config, Sessions, PIN_SUFFIX,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Runs `each` with the backups of every selected job on every one of its targets.
+fn for_each_target(
+    config: &Config,
+    mode: Mode,
+    jobs: &[&Job],
+    recursive: bool,
+    tag: Option<&str>,
+    each: impl FnMut(&Job, &str, &dyn StorageBackend, &str, Vec<Backup>) -> Result<()>,
+) -> Result<()> {
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let result = for_each_target_in(&mut sessions, jobs, recursive, tag, each);
+    sessions.logout();
+    result
+}
+
+/// Like [`for_each_target`], with `sessions` that are already open.
+fn for_each_target_in(
+    sessions: &mut Sessions,
+    jobs: &[&Job],
+    recursive: bool,
+    tag: Option<&str>,
+    mut each: impl FnMut(&Job, &str, &dyn StorageBackend, &str, Vec<Backup>) -> Result<()>,
+) -> Result<()> {
+    let mut failed = false;
+    for job in jobs {
+        if job.write_only {
+            eprintln!(
+                "Job {} is write_only, so its archives are never listed or deleted from here",
+                job.name
+            );
+            continue;
+        }
+        for name in &job.targets {
+            let (target, remote) = sessions.get(name);
+            let result = remote.map_err(|e| anyhow!("{e}")).and_then(|remote| {
+                let (folder, _) = job_folder(remote, job, target)?;
+                let mut backups = catalog::list_backups(remote, job, name, &folder, recursive)?;
+                if tag.is_some() {
+                    backups.retain(|b| b.tag.as_deref() == tag);
+                }
+                each(job, name, remote, &folder, backups)
+            });
+            if let Err(e) = result {
+                eprintln!("Job {} on {name}: {e:#}", job.name);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err(anyhow!("Some targets could not be processed"));
+    }
+    Ok(())
+}
+
+/// Deletes `paths` from `folder` on `target`, unless the folder keeps its
+/// files write-once, or turns out to by refusing. Returns whether they were.
+fn delete(
+    config: &Config,
+    target: &str,
+    folder: &str,
+    remote: &dyn StorageBackend,
+    paths: &[&str],
+) -> Result<bool> {
+    let write_once = config
+        .targets
+        .iter()
+        .find(|t| t.name == target)
+        .is_some_and(|t| worm::write_once(t, folder));
+    if write_once {
+        eprintln!(
+            "{target}:{folder} is write-once (WORM), so nothing there is deleted; the NAS frees the files once their locks expire"
+        );
+        return Ok(false);
+    }
+    match remote.delete(paths) {
+        Ok(()) => Ok(true),
+        Err(e) if worm::refused(&e) => {
+            worm::record(target, folder, &e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn list(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let jobs = selected_jobs(config, args)?;
+    let recursive = args.flag("recursive");
+    let mut sessions = Sessions::new(&config.targets, mode);
+    // With --fleet, the machine whose run log records each archive's upload
+    let mut hosts = HashMap::new();
+    if args.flag("fleet") {
+        for (host, runs) in fleet::runs(config, &mut sessions)? {
+            for upload in runlog::uploads(&runs) {
+                hosts.insert(upload.path, host.clone());
+            }
+        }
+    }
+    let listed = for_each_target_in(
+        &mut sessions,
+        &jobs,
+        recursive,
+        args.value("tag"),
+        |job, target, _, _, backups| {
+            for backup in backups {
+                let host = match args.flag("fleet") {
+                    true => format!(
+                        "\t{}",
+                        hosts.get(&backup.file.path).map_or("", String::as_str)
+                    ),
+                    false => String::new(),
+                };
+                println!(
+                    "{}\t{target}:{}\t{}\t{}\t{}\t{}{host}",
+                    job.name,
+                    backup.file.path,
+                    backup.file.size.map(format_bytes).unwrap_or_default(),
+                    backup.time.format("%Y-%m-%d %H:%M:%S"),
+                    backup.tag.as_deref().unwrap_or_default(),
+                    if backup.pinned { "pinned" } else { "" }
+                );
+            }
+            Ok(())
+        },
+    );
+    sessions.logout();
+    listed
+}
+
+/// Backups that retention keeps, each with the rules that keep it.
+type Kept = Vec<(Backup, Vec<String>)>;
+
+/// The calendar period a backup made at `time` falls in for the
+/// grandfather-father-son `setting`, like `2024-W05` for `keep_weekly`.
+fn period(setting: &str, time: DateTime<Local>) -> String {
+    match setting {
+        "keep_daily" => time.format("%Y-%m-%d").to_string(),
+        "keep_weekly" => {
+            let week = time.iso_week();
+            format!("{}-W{:02}", week.year(), week.week())
+        }
+        "keep_monthly" => time.format("%Y-%m").to_string(),
+        _ => time.format("%Y").to_string(),
+    }
+}
+
+/// Splits backups, newest first, into those the job's retention keeps, each
+/// with the rules that keep it, and those it drops.
+///
+/// A backup is kept if any rule asks for it: it is one of the `keep_last`
+/// newest, younger than `keep_within`, or the newest backup of one of the
+/// `keep_daily` newest days that have one, and likewise for weeks, months and
+/// years. Pinned backups, and with `keep_tagged` tagged ones, are kept too,
+/// without counting towards any rule.
+///
+/// With `max_total_size` as the only setting, every backup passes these rules
+/// and [`fit_budget`] alone decides.
+fn retain(job: &Job, backups: Vec<Backup>) -> (Kept, Vec<Backup>) {
+    let only_budget = !job.has_keep_rules();
+    let now = chrono::Utc::now();
+    let mut kept = Vec::new();
+    let mut dropped = Vec::new();
+    let mut counted = 0;
+    // The periods each calendar rule has kept a backup for so far, newest first.
+    let mut periods = [
+        ("keep_daily", job.keep_daily, Vec::new()),
+        ("keep_weekly", job.keep_weekly, Vec::new()),
+        ("keep_monthly", job.keep_monthly, Vec::new()),
+        ("keep_yearly", job.keep_yearly, Vec::new()),
+    ];
+    for backup in backups {
+        if backup.pinned {
+            kept.push((backup, vec!["pinned".to_string()]));
+            continue;
+        }
+        if job.keep_tagged && backup.tag.is_some() {
+            kept.push((backup, vec!["keep_tagged".to_string()]));
+            continue;
+        }
+        let mut reasons = Vec::new();
+        if job.keep_last.is_some_and(|n| counted < n) {
+            reasons.push("keep_last".to_string());
+        }
+        counted += 1;
+        let young_enough = job.keep_within.is_some_and(|within| {
+            // A timestamp in the future is as young as it gets.
+            (now - backup.time)
+                .to_std()
+                .map_or(true, |age| age < within.0)
+        });
+        if young_enough {
+            reasons.push("keep_within".to_string());
+        }
+        let local = backup.time.with_timezone(&Local);
+        for (setting, count, seen) in &mut periods {
+            let Some(count) = *count else {
+                continue;
+            };
+            let period = period(setting, local);
+            // Newest first, so the first backup of a period is its newest.
+            if seen.len() < count && seen.last() != Some(&period) {
+                reasons.push(format!("{setting} {period}"));
+                seen.push(period);
+            }
+        }
+        if only_budget {
+            reasons.push("max_total_size".to_string());
+        }
+        if reasons.is_empty() {
+            dropped.push(backup);
+        } else {
+            kept.push((backup, reasons));
+        }
+    }
+    (kept, dropped)
+}
+
+/// Drops the oldest of the kept backups until the rest add up to at most the
+/// job's `max_total_size`. Pinned, and with `keep_tagged` tagged, backups count
+/// towards the total but stay, and so does the newest backup.
+fn fit_budget(job: &Job, kept: Kept) -> Result<(Kept, Vec<Backup>)> {
+    let Some(budget) = job.max_total_size else {
+        return Ok((kept, Vec::new()));
+    };
+    let mut total = 0;
+    let mut full = false;
+    let mut within = Vec::new();
+    let mut over = Vec::new();
+    for (backup, reasons) in kept {
+        let size = backup.file.size.ok_or_else(|| {
+            anyhow!(
+                "max_total_size needs archive sizes, but the target doesn't report the size of {}",
+                backup.file.path
+            )
+        })?;
+        let protected = backup.pinned || (job.keep_tagged && backup.tag.is_some());
+        // Newest first, so once the budget is spent every older backup goes.
+        full |= !within.is_empty() && total + size > budget.0;
+        if full && !protected {
+            over.push(backup);
+        } else {
+            total += size;
+            within.push((backup, reasons));
+        }
+    }
+    Ok((within, over))
+}
+
+/// Keeps, out of `dropped` and `over_budget`, the full archives a kept
+/// differential builds on, as `chain.json` records them, since it can't be
+/// restored without. A kept archive the chain doesn't know, like one made on
+/// another machine or before the state was lost, may be a differential too,
+/// so everything older than it is kept.
+fn keep_bases(
+    job: &Job,
+    mut kept: Kept,
+    dropped: Vec<Backup>,
+    over_budget: Vec<Backup>,
+) -> (Kept, Vec<Backup>, Vec<Backup>) {
+    if job.differential.is_none() {
+        return (kept, dropped, over_budget);
+    }
+    let chain = match chain::load() {
+        Ok(chain) => chain,
+        Err(e) => {
+            eprintln!("Job {}: {e:#}", job.name);
+            Vec::new()
+        }
+    };
+    let unknown = kept
+        .iter()
+        .filter(|(backup, _)| {
+            !chain
+                .iter()
+                .any(|l| l.job == job.name && l.name == backup.file.name)
+        })
+        .map(|(backup, _)| (backup.time, backup.file.name.clone()))
+        .min();
+    let bases = kept
+        .iter()
+        .filter_map(|(backup, _)| {
+            let link = chain
+                .iter()
+                .find(|l| l.job == job.name && l.name == backup.file.name)?;
+            Some((link.base.clone()?, backup.file.name.clone()))
+        })
+        .collect::<Vec<_>>();
+    let mut keep = |backups: Vec<Backup>| {
+        let mut gone = Vec::new();
+        for backup in backups {
+            let mut of = bases
+                .iter()
+                .filter(|(base, _)| *base == backup.file.name)
+                .map(|(_, name)| format!("base of {name}"))
+                .collect::<Vec<_>>();
+            if let Some((_, name)) = unknown.as_ref().filter(|(time, _)| backup.time < *time) {
+                of.push(format!("maybe the base of {name}"));
+            }
+            if of.is_empty() {
+                gone.push(backup);
+            } else {
+                kept.push((backup, of));
+            }
+        }
+        gone
+    };
+    let dropped = keep(dropped);
+    let over_budget = keep(over_budget);
+    let older = kept
+        .iter()
+        .filter(|(_, reasons)| reasons.iter().any(|r| r.starts_with("maybe the base of")))
+        .count();
+    if let Some((_, name)) = unknown.as_ref().filter(|_| older > 0) {
+        eprintln!(
+            "Job {}: chain.json doesn't record what {name} builds on, so the {older} archives older than it are kept in case one is its full archive; prune where the archives were made to delete them",
+            job.name
+        );
+    }
+    kept.sort_by_key(|(backup, _)| std::cmp::Reverse(backup.time));
+    (kept, dropped, over_budget)
+}
+
+/// The copies on `target` that the synthetic full archives among `backups`
+/// build on, which go with them.
+fn volumes(job: &Job, target: &str, backups: &[Backup]) -> Vec<String> {
+    if job.synthetic_full.is_none() {
+        return Vec::new();
+    }
+    let chain = chain::load().unwrap_or_default();
+    backups
+        .iter()
+        .filter_map(|backup| {
+            chain
+                .iter()
+                .find(|l| l.job == job.name && l.name == backup.file.name)
+        })
+        .flat_map(|link| &link.volumes)
+        .filter_map(|copy| copy.strip_prefix(target)?.strip_prefix(':'))
+        .map(String::from)
+        .collect()
+}
+
+pub fn prune(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let dry_run = args.flag("dry-run");
+    let explain = args.flag("explain");
+    let jobs = selected_jobs(config, args)?;
+    for_each_target(
+        config,
+        mode,
+        &jobs,
+        false,
+        args.value("tag"),
+        |job, target, remote, folder, backups| {
+            if !job.has_retention() {
+                eprintln!(
+                    "Job {} has no keep_* or max_total_size setting; nothing to prune",
+                    job.name
+                );
+                return Ok(());
+            }
+            let (kept, dropped) = retain(job, backups);
+            let (kept, over_budget) = fit_budget(job, kept)?;
+            let (kept, mut dropped, over_budget) = keep_bases(job, kept, dropped, over_budget);
+            if explain {
+                explain_retention(&kept, &dropped, &over_budget);
+            }
+            dropped.extend(over_budget);
+            dropped.sort_by_key(|backup| std::cmp::Reverse(backup.time));
+            eprintln!(
+                "Job {} on {target}: keeping {}, {} {}",
+                job.name,
+                kept.len(),
+                if dry_run { "would delete" } else { "deleting" },
+                dropped.len()
+            );
+            // Sidecars go with their archive, so none are left behind.
+            let copies = volumes(job, target, &dropped);
+            let paths = dropped
+                .iter()
+                .flat_map(|b| std::iter::once(&b.file.path).chain(&b.sidecars))
+                .chain(&copies)
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if !explain {
+                for path in &paths {
+                    println!("{path}");
+                }
+            }
+            if dry_run || paths.is_empty() {
+                return Ok(());
+            }
+            if !delete(config, target, folder, remote, &paths)? {
+                return Ok(());
+            }
+            let record = runlog::Prune {
+                prune: runlog::now(),
+                job: job.name.clone(),
+                target: target.to_string(),
+                kept: kept.len(),
+                deleted: dropped.iter().map(|b| b.file.path.clone()).collect(),
+            };
+            if let Err(e) = runlog::path(config).and_then(|path| runlog::append(&path, &record)) {
+                eprintln!("Could not write the run log: {e:#}");
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Prints every backup, newest first, with the rules that keep it or why it goes.
+fn explain_retention(kept: &[(Backup, Vec<String>)], dropped: &[Backup], over_budget: &[Backup]) {
+    let mut lines = kept
+        .iter()
+        .map(|(backup, reasons)| (backup, reasons.join(", ")))
+        .chain(dropped.iter().map(|backup| (backup, "delete".to_string())))
+        .chain(
+            over_budget
+                .iter()
+                .map(|backup| (backup, "delete, over max_total_size".to_string())),
+        )
+        .collect::<Vec<_>>();
+    lines.sort_by_key(|(backup, _)| std::cmp::Reverse(backup.time));
+    for (backup, reason) in lines {
+        println!("{}\t{reason}", backup.file.path);
+    }
+}
+
+/// Deletes the sidecars whose archive is gone, like the `.pinned` file of an
+/// archive deleted by hand.
+pub fn orphans(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let dry_run = args.flag("dry-run");
+    let recursive = args.flag("recursive");
+    let jobs = selected_jobs(config, args)?;
+    for_each_target(
+        config,
+        mode,
+        &jobs,
+        recursive,
+        None,
+        |job, target, remote, folder, backups| {
+            let archives = backups
+                .iter()
+                .map(|b| b.file.path.as_str())
+                .collect::<HashSet<_>>();
+            let files = remote.list(folder, recursive)?;
+            let orphans = files
+                .iter()
+                .filter(|f| sidecar_archive(job, f).is_some_and(|a| !archives.contains(a.as_str())))
+                .map(|f| f.path.as_str())
+                .collect::<Vec<_>>();
+            eprintln!(
+                "Job {} on {target}: {} {} orphaned files",
+                job.name,
+                if dry_run { "would delete" } else { "deleting" },
+                orphans.len()
+            );
+            for path in &orphans {
+                println!("{path}");
+            }
+            if dry_run || orphans.is_empty() {
+                return Ok(());
+            }
+            delete(config, target, folder, remote, &orphans).map(drop)
+        },
+    )
+}
+
+/// Checks that every selected job has a successful backup younger than
+/// `--max-age`, printing a Nagios-style status line. Returns whether they all do.
+///
+/// The times come from the run log. Jobs it has nothing on, or all jobs with
+/// `--remote`, are looked up on their targets instead.
+pub fn check(config: &Config, mode: Mode, args: &Args) -> Result<bool> {
+    let max_age = args
+        .value("max-age")
+        .ok_or_else(|| anyhow!("check needs --max-age, e.g. --max-age 26h"))?;
+    let max_age = HumanDuration::try_from(max_age.to_string())?;
+    if args.flag("fleet") {
+        return check_fleet(config, mode, args.value("job"), max_age);
+    }
+    let jobs = selected_jobs(config, args)?;
+
+    let mut newest = HashMap::new();
+    if !args.flag("remote") {
+        newest = runlog::newest_successes(&runlog::path(config)?)?;
+    }
+    let unlogged = jobs
+        .iter()
+        .copied()
+        .filter(|j| !newest.contains_key(&j.name))
+        .collect::<Vec<_>>();
+    if !unlogged.is_empty() {
+        // A target that can't be listed leaves its jobs without a time, which fails the check.
+        let _ = for_each_target(
+            config,
+            mode,
+            &unlogged,
+            false,
+            None,
+            |job, _, _, _, backups| {
+                if let Some(backup) = backups.first() {
+                    let time = newest.entry(job.name.clone()).or_insert(backup.time);
+                    *time = (*time).max(backup.time);
+                }
+                Ok(())
+            },
+        );
+    }
+
+    let ages = jobs
+        .iter()
+        .map(|job| (job.name.clone(), newest.get(&job.name).copied()))
+        .collect::<Vec<_>>();
+    Ok(report_ages(&ages, max_age, ""))
+}
+
+/// Like [`check`], for every job in the run logs of the machines sharing the
+/// config's `fleet` folder, or those named `job`, as `host/job`.
+fn check_fleet(
+    config: &Config,
+    mode: Mode,
+    job: Option<&str>,
+    max_age: HumanDuration,
+) -> Result<bool> {
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let runs = fleet::runs(config, &mut sessions);
+    sessions.logout();
+    let runs = runs?;
+    let mut ages = Vec::new();
+    for (host, runs) in &runs {
+        let newest = runlog::successes(runs);
+        for name in runlog::job_names(runs) {
+            if job.is_some_and(|job| job != name) {
+                continue;
+            }
+            let time = newest.get(&name).copied();
+            ages.push((format!("{host}/{name}"), time));
+        }
+    }
+    if ages.is_empty() {
+        return Err(anyhow!("The fleet's run logs record no job to check"));
+    }
+    Ok(report_ages(
+        &ages,
+        max_age,
+        &format!(" on {} machines", runs.len()),
+    ))
+}
+
+/// Prints the Nagios-style status of jobs, named with the time of their
+/// newest successful backup, if any, and returns whether all are younger
+/// than `max_age`. `scope` goes after the count of jobs in the status line.
+fn report_ages(
+    ages: &[(String, Option<DateTime<chrono::Utc>>)],
+    max_age: HumanDuration,
+    scope: &str,
+) -> bool {
+    let now = chrono::Utc::now();
+    let mut problems = Vec::new();
+    let mut details = Vec::new();
+    for (name, time) in ages {
+        match time {
+            Some(time) => {
+                let age = (now - *time).to_std().unwrap_or_default();
+                // Whole minutes are precise enough to read.
+                let shown = HumanDuration(Duration::from_secs(age.as_secs() / 60 * 60));
+                details.push(format!(
+                    "{name}: last backup {} ({shown} ago)",
+                    time.format("%Y-%m-%d %H:%M:%S")
+                ));
+                if age > max_age.0 {
+                    problems.push(format!("{name} last backed up {shown} ago"));
+                }
+            }
+            None => {
+                details.push(format!("{name}: no backup found"));
+                problems.push(format!("{name} has no backup"));
+            }
+        }
+    }
+    if problems.is_empty() {
+        println!("OK - {} jobs{scope} backed up within {max_age}", ages.len());
+    } else {
+        println!("CRITICAL - {}", problems.join("; "));
+    }
+    for line in details {
+        println!("{line}");
+    }
+    problems.is_empty()
+}
+
+/// Pins the archive named by the positional argument, or unpins it, on every
+/// target of the selected jobs that has it.
+pub fn pin(config: &Config, mode: Mode, args: &Args, pinned: bool) -> Result<()> {
+    let name = args.positional.as_deref().unwrap();
+    let jobs = selected_jobs(config, args)?;
+    let mut found = false;
+    for_each_target(
+        config,
+        mode,
+        &jobs,
+        args.flag("recursive"),
+        None,
+        |_, target, remote, _, backups| {
+            let Some(backup) = backups.iter().find(|b| b.file.name == name) else {
+                return Ok(());
+            };
+            found = true;
+            let (folder, file_name) = backup.file.path.rsplit_once('/').unwrap();
+            let pin = format!("{}{PIN_SUFFIX}", file_name.strip_suffix(".zip").unwrap());
+            if pinned {
+                // Only its presence counts; it holds when the archive was pinned.
+                let local = std::env::temp_dir().join(format!(".synology_backuper_{pin}"));
+                std::fs::write(&local, crate::runlog::now())?;
+                let uploaded = remote.upload(folder, &local, &pin, None, None);
+                let _ = std::fs::remove_file(&local);
+                uploaded?;
+            } else if !delete(
+                config,
+                target,
+                folder,
+                remote,
+                &[&format!("{folder}/{pin}")],
+            )? {
+                return Ok(());
+            }
+            eprintln!(
+                "{} {target}:{}",
+                if pinned { "Pinned" } else { "Unpinned" },
+                backup.file.path
+            );
+            Ok(())
+        },
+    )?;
+    if !found {
+        return Err(anyhow!("No job has an archive named {name}"));
+    }
+    Ok(())
+}
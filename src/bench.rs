@@ -0,0 +1,356 @@
+//! The `bench` command uploads a file of random bytes to each of a job's
+//! targets a few times, deleting it after each upload, and reports the
+//! throughput and how long small requests take. `bench-compress` compresses
+//! a sample of a job's files with several settings and compares them. Both
+//! are to tune the config by measurement rather than guesswork.
+
+use crate::archive::{excluded, ArchiveOptions, Codec, Compression};
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Job;
+use crate::limits::{parse_bytes, ByteRate};
+use crate::{format_bytes, selected_jobs, writable_share, Config, Sessions};
+use anyhow::{anyhow, Context, Result};
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const DEFAULT_SIZE: u64 = 16 << 20;
+const DEFAULT_COUNT: usize = 3;
+/// Folder listings timed for the request latency.
+const LATENCY_SAMPLES: usize = 10;
+
+pub fn run(config: &Config, mode: Mode, args: &Args) -> Result<bool> {
+    let job = *selected_jobs(config, args)?
+        .first()
+        .ok_or_else(|| anyhow!("There is no job to take the targets and share from"))?;
+    let size = args.value("size").map(parse_bytes).transpose()?;
+    let size = size.unwrap_or(DEFAULT_SIZE);
+    let count = match args.value("count") {
+        Some(count) => count
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow!("--count takes a number of uploads, got {count:?}"))?,
+        None => DEFAULT_COUNT,
+    };
+    let rate_limit = match args.value("rate-limit") {
+        Some(rate) => Some(ByteRate::try_from(rate.to_string())?),
+        None => job.upload_rate_limit,
+    };
+
+    let name = format!(
+        ".synology_backuper_bench_{}",
+        chrono::Utc::now().format("%Y%m%d_%H%M%S")
+    );
+    let local = std::env::temp_dir().join(&name);
+    write_random(&local, size)?;
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let mut ok = true;
+    for target in &job.targets {
+        if let Err(e) = bench_target(&mut sessions, job, target, &local, &name, count, rate_limit) {
+            println!("  {e:#}");
+            ok = false;
+        }
+    }
+    sessions.logout();
+    let _ = std::fs::remove_file(&local);
+    Ok(ok)
+}
+
+fn bench_target(
+    sessions: &mut Sessions,
+    job: &Job,
+    target: &str,
+    local: &Path,
+    name: &str,
+    count: usize,
+    rate_limit: Option<ByteRate>,
+) -> Result<()> {
+    let size = std::fs::metadata(local)?.len();
+    println!("Target {target}:");
+    let folder = writable_share(sessions, job, target, name).map_err(|e| anyhow!("{e}"))?;
+    let remote = sessions.get(target).1.map_err(|e| anyhow!("{e}"))?;
+    let limit = match rate_limit {
+        Some(rate) => format!(", limited to {}/s", format_bytes(rate.0)),
+        None => String::new(),
+    };
+    println!(
+        "  {} to {folder} over {}, {count} times{limit}",
+        format_bytes(size),
+        remote.kind()
+    );
+
+    let path = format!("{folder}/{name}");
+    let mut rates = Vec::new();
+    for i in 1..=count {
+        let start = Instant::now();
+        let uploaded = remote.upload(&folder, local, name, rate_limit, None);
+        let elapsed = start.elapsed();
+        // Whatever happened, leave nothing behind.
+        if let Err(e) = remote.delete(&[&path]) {
+            eprintln!("Could not delete {path}: {e:#}");
+        }
+        uploaded.with_context(|| format!("upload {i} failed"))?;
+        let rate = size as f64 / elapsed.as_secs_f64().max(1e-6);
+        println!(
+            "  Upload {i}: {:.2}s, {}/s",
+            elapsed.as_secs_f64(),
+            format_bytes(rate as u64)
+        );
+        rates.push(rate);
+    }
+    rates.sort_by(f64::total_cmp);
+    println!(
+        "  Throughput: min {}/s, median {}/s, max {}/s",
+        format_bytes(rates[0] as u64),
+        format_bytes(percentile(&rates, 50.0) as u64),
+        format_bytes(rates[rates.len() - 1] as u64)
+    );
+
+    let mut latencies = Vec::new();
+    for _ in 0..LATENCY_SAMPLES {
+        let start = Instant::now();
+        remote.list(&folder, false)?;
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!(
+        "  Latency of {LATENCY_SAMPLES} folder listings: p50 {:.0} ms, p90 {:.0} ms, p99 {:.0} ms",
+        ms(percentile(&latencies, 50.0)),
+        ms(percentile(&latencies, 90.0)),
+        ms(percentile(&latencies, 99.0))
+    );
+    Ok(())
+}
+
+/// The `p`th percentile of the sorted `values`, by nearest rank.
+fn percentile<T: Copy>(values: &[T], p: f64) -> T {
+    let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// Random bytes, so compression on the way can't flatter the numbers.
+fn write_random(path: &Path, size: u64) -> Result<()> {
+    let mut file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    let mut buf = vec![0; 1 << 20];
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(buf.len() as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut buf[..n]);
+        file.write_all(&buf[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+const DEFAULT_SAMPLE: u64 = 256 << 20;
+/// Settings `bench-compress` tries, besides the job's own.
+const CANDIDATES: &[&str] = &[
+    "stored",
+    "deflate:1",
+    "deflate",
+    "deflate:9",
+    "bzip2:9",
+    "zstd:1",
+    "zstd:3",
+    "zstd:9",
+    "zstd:19",
+];
+/// Archives at most this much larger than the smallest count as small.
+const SIZE_TOLERANCE: f64 = 1.02;
+
+/// One file of the sample, read into memory so the disk doesn't count.
+struct Sampled {
+    name: String,
+    stored: bool,
+    data: Vec<u8>,
+}
+
+pub fn compress(config: &Config, args: &Args) -> Result<()> {
+    let sample = args.value("sample").map(parse_bytes).transpose()?;
+    let sample = sample.unwrap_or(DEFAULT_SAMPLE);
+    for job in selected_jobs(config, args)? {
+        compress_job(job, sample)?;
+    }
+    Ok(())
+}
+
+fn compress_job(job: &Job, sample: u64) -> Result<()> {
+    let options = job.archive_options();
+    let (files, total) = sample_files(job, &options, sample)?;
+    let sampled = files.iter().map(|f| f.data.len() as u64).sum::<u64>();
+    println!(
+        "Job {}: {} of {} in {} files",
+        job.name,
+        format_bytes(sampled),
+        format_bytes(total),
+        files.len()
+    );
+    let mut candidates = CANDIDATES
+        .iter()
+        .map(|c| Compression::try_from(c.to_string()).expect("the candidates parse"))
+        .collect::<Vec<_>>();
+    if !candidates.contains(&job.compression) {
+        candidates.push(job.compression);
+    }
+
+    println!(
+        "  {:<12}{:>12}{:>8}{:>9}{:>14}",
+        "SETTING", "SIZE", "RATIO", "TIME", "SPEED"
+    );
+    let mut results = Vec::new();
+    for compression in candidates {
+        let start = Instant::now();
+        let size = compressed_size(&files, compression)?;
+        let elapsed = start.elapsed().as_secs_f64().max(1e-6);
+        let current = if compression == job.compression {
+            "  (current)"
+        } else {
+            ""
+        };
+        println!(
+            "  {:<12}{:>12}{:>7.1}%{:>8.2}s{:>12}/s{current}",
+            compression.to_string(),
+            format_bytes(size),
+            100.0 * size as f64 / sampled.max(1) as f64,
+            elapsed,
+            format_bytes((sampled as f64 / elapsed) as u64),
+        );
+        results.push((compression, size, elapsed));
+    }
+
+    // The fastest of the settings that come close to the smallest archive
+    let smallest = results.iter().map(|r| r.1).min().unwrap_or(0);
+    let (best, _, _) = results
+        .iter()
+        .filter(|r| r.1 as f64 <= smallest as f64 * SIZE_TOLERANCE)
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+        .copied()
+        .expect("the smallest is among them");
+    if best == job.compression {
+        println!("  The current setting is the fastest of those within 2% of the smallest");
+    } else {
+        println!(
+            "  Recommended: \"compression\": \"{best}\", the fastest within 2% of the smallest"
+        );
+    }
+    if matches!(best.codec, Codec::Bzip2 | Codec::Zstd) {
+        println!("  Windows Explorer and DSM's File Station can't open {} archives; restore with this program or 7-Zip", best.codec.name());
+    }
+    if options.parallelism > 1 {
+        println!(
+            "  Times are on one thread; backups spread the files over {} threads",
+            options.parallelism
+        );
+    }
+    Ok(())
+}
+
+/// Up to `limit` bytes of the job's files, picked at random so the sample
+/// spans the whole tree, and the size of all of them. Files whose extension
+/// is stored are stored by every setting, as in the backups.
+fn sample_files(job: &Job, options: &ArchiveOptions, limit: u64) -> Result<(Vec<Sampled>, u64)> {
+    let root = Path::new(&job.filename);
+    let mut walk = walkdir::WalkDir::new(root).follow_links(false);
+    if let Some(depth) = options.max_depth {
+        walk = walk.max_depth(depth);
+    }
+    let mut paths = Vec::new();
+    let mut total = 0;
+    let walk = walk.into_iter().filter_entry(|e| {
+        let relative = e.path().strip_prefix(root).unwrap_or(e.path());
+        e.depth() == 0 || !excluded(&options.exclude, relative)
+    });
+    for entry in walk {
+        let entry = entry.with_context(|| format!("Could not read {}", root.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let len = entry.metadata()?.len();
+        if options.max_file_size.is_some_and(|max| len > max) {
+            continue;
+        }
+        total += len;
+        paths.push(entry.into_path());
+    }
+    paths.shuffle(&mut rand::thread_rng());
+
+    let mut files = Vec::new();
+    let mut left = limit;
+    for path in paths {
+        if left == 0 {
+            break;
+        }
+        let mut data = Vec::new();
+        let Ok(file) = std::fs::File::open(&path) else {
+            continue;
+        };
+        file.take(left).read_to_end(&mut data)?;
+        left -= data.len() as u64;
+        files.push(Sampled {
+            name: path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .display()
+                .to_string(),
+            stored: options.stores(&path),
+            data,
+        });
+    }
+    Ok((files, total))
+}
+
+/// How large a zip of `files` compressed with `compression` comes out.
+fn compressed_size(files: &[Sampled], compression: Compression) -> Result<u64> {
+    let mut zip = ZipWriter::new(Counter::default());
+    for file in files {
+        let options = if file.stored {
+            SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .compression_level(None)
+        } else {
+            compression.options()
+        };
+        let options = options.large_file(file.data.len() as u64 >= u32::MAX as u64);
+        zip.start_file(file.name.as_str(), options)?;
+        zip.write_all(&file.data)?;
+    }
+    Ok(zip.finish()?.len)
+}
+
+/// A writer that keeps nothing but how much was written, and where.
+#[derive(Default)]
+struct Counter {
+    position: u64,
+    len: u64,
+}
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Counter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.len.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+        };
+        Ok(self.position)
+    }
+}
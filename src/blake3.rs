@@ -0,0 +1,340 @@
+//! BLAKE3, unkeyed and with the default 32-byte output, after the portable
+//! reference implementation in the BLAKE3 specification. Slower than the
+//! SIMD versions of the `blake3` crate, but still quicker than SHA-256
+//! without hardware support, and a dependency less.
+
+const OUT_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+/// The mixing function, on columns and then diagonals of the state.
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn permute(m: &mut [u32; 16]) {
+    let mut permuted = [0; 16];
+    for (i, word) in permuted.iter_mut().enumerate() {
+        *word = m[MSG_PERMUTATION[i]];
+    }
+    *m = permuted;
+}
+
+fn compress(
+    chaining_value: &[u32; 8],
+    block_words: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        chaining_value[0],
+        chaining_value[1],
+        chaining_value[2],
+        chaining_value[3],
+        chaining_value[4],
+        chaining_value[5],
+        chaining_value[6],
+        chaining_value[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut block = *block_words;
+    for i in 0..7 {
+        round(&mut state, &block);
+        if i < 6 {
+            permute(&mut block);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(words: [u32; 16]) -> [u32; 8] {
+    words[..8].try_into().unwrap()
+}
+
+fn words_from_le_bytes(bytes: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+/// What a chunk or parent node compresses to: its chaining value, or with
+/// `ROOT` the hash.
+struct Output {
+    input_chaining_value: [u32; 8],
+    block_words: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(compress(
+            &self.input_chaining_value,
+            &self.block_words,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root_hash(&self) -> [u8; OUT_LEN] {
+        let words = compress(
+            &self.input_chaining_value,
+            &self.block_words,
+            0,
+            self.block_len,
+            self.flags | ROOT,
+        );
+        let mut hash = [0; OUT_LEN];
+        for (bytes, word) in hash.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        hash
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> Self {
+        ChunkState {
+            chaining_value: IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // The last block stays, since it is compressed with CHUNK_END.
+            if self.block_len == BLOCK_LEN {
+                self.chaining_value = first_8_words(compress(
+                    &self.chaining_value,
+                    &words_from_le_bytes(&self.block),
+                    self.chunk_counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..][..take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            input_chaining_value: self.chaining_value,
+            block_words: words_from_le_bytes(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block_words = [0; 16];
+    block_words[..8].copy_from_slice(&left);
+    block_words[8..].copy_from_slice(&right);
+    Output {
+        input_chaining_value: IV,
+        block_words,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+/// An incremental BLAKE3 hash.
+pub struct Hasher {
+    chunk_state: ChunkState,
+    /// The chaining values of the complete subtrees on the left, one per set
+    /// bit of the number of chunks so far
+    cv_stack: Vec<[u32; 8]>,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher {
+            chunk_state: ChunkState::new(0),
+            cv_stack: Vec::new(),
+        }
+    }
+
+    fn add_chunk_chaining_value(&mut self, mut cv: [u32; 8], mut total_chunks: u64) {
+        // Each trailing zero bit of the count completes a subtree.
+        while total_chunks & 1 == 0 {
+            cv = parent_output(self.cv_stack.pop().unwrap(), cv).chaining_value();
+            total_chunks >>= 1;
+        }
+        self.cv_stack.push(cv);
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // A full chunk is only finished once more input shows it isn't the last.
+            if self.chunk_state.len() == CHUNK_LEN {
+                let cv = self.chunk_state.output().chaining_value();
+                let total_chunks = self.chunk_state.chunk_counter + 1;
+                self.add_chunk_chaining_value(cv, total_chunks);
+                self.chunk_state = ChunkState::new(total_chunks);
+            }
+            let take = (CHUNK_LEN - self.chunk_state.len()).min(input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; OUT_LEN] {
+        let mut output = self.chunk_state.output();
+        for left in self.cv_stack.iter().rev() {
+            output = parent_output(*left, output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hasher;
+
+    /// The hashes of the official test vectors, whose inputs are the bytes
+    /// 0, 1, ..., 250, 0, 1, ... cut to length, around each way chunks and
+    /// parent nodes combine. 31745, one past the vectors' 31744, is as the
+    /// specification's reference implementation hashes it.
+    const VECTORS: &[(usize, &str)] = &[
+        (
+            0,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        ),
+        (
+            1,
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+        ),
+        (
+            1023,
+            "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+        ),
+        (
+            1024,
+            "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+        ),
+        (
+            1025,
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+        ),
+        (
+            2048,
+            "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+        ),
+        (
+            2049,
+            "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+        ),
+        (
+            3072,
+            "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+        ),
+        (
+            4097,
+            "9b4052b38f1c5fc8b1f9ff7ac7b27cd242487b3d890d15c96a1c25b8aa0fb995",
+        ),
+        (
+            31744,
+            "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47",
+        ),
+        (
+            31745,
+            "5c80ce0c3bbe9a6f432a1c6c2ccbde45923d23249386988a30f512d23919eb98",
+        ),
+    ];
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn hashes_the_test_vectors_whole_and_in_pieces() {
+        for &(len, expected) in VECTORS {
+            let input = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let mut whole = Hasher::new();
+            whole.update(&input);
+            assert_eq!(hex(whole.finalize()), expected, "{len} bytes at once");
+            // Pieces that straddle block and chunk boundaries.
+            let mut pieces = Hasher::new();
+            for piece in input.chunks(1000) {
+                pieces.update(piece);
+            }
+            assert_eq!(hex(pieces.finalize()), expected, "{len} bytes in pieces");
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! The `adopt` command and the catalog it keeps: archives on a target that a
+//! job didn't make, uploaded by hand or from another machine, registered
+//! with the job so that `list`, `prune`, `usage` and `restore` count them
+//! among its own. Their names needn't follow the job's: when each was made
+//! is read from whatever timestamp its name holds. The catalog lives in the
+//! state directory, in `catalog.json`.
+
+use crate::backend::StorageBackend;
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Job;
+use crate::{
+    backup_stamp, file_md5, format_bytes, paths, selected_jobs, Backup, Config, RemoteFile,
+    Sessions, PIN_SUFFIX,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Timestamps read from the names of adopted archives, with their lengths,
+/// longest first so a date is only taken alone when no time follows it.
+const STAMPS: &[(&str, usize)] = &[
+    ("%Y-%m-%dT%H:%M:%S", 19),
+    ("%Y-%m-%d_%H-%M-%S", 19),
+    ("%Y-%m-%dT%H-%M-%S", 19),
+    ("%Y-%m-%d %H-%M-%S", 19),
+    ("%Y-%m-%d_%H%M%S", 17),
+    ("%Y%m%d_%H%M%S", 15),
+    ("%Y%m%d-%H%M%S", 15),
+    ("%Y%m%dT%H%M%S", 15),
+];
+const DATES: &[(&str, usize)] = &[("%Y-%m-%d", 10), ("%Y%m%d", 8)];
+
+/// An archive registered with a job by `adopt`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adopted {
+    pub job: String,
+    pub target: String,
+    pub path: String,
+    /// When it was made, in RFC 3339
+    pub time: String,
+    pub size: Option<u64>,
+    /// As FileStation computed it when it was adopted
+    pub md5: Option<String>,
+}
+
+pub fn path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("catalog.json"))
+}
+
+/// The adopted archives, none if nothing was adopted yet.
+pub fn load() -> Result<Vec<Adopted>> {
+    let path = path()?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    serde_json::from_str(&text).with_context(|| format!("{} is not a catalog", path.display()))
+}
+
+fn store(adopted: &[Adopted]) -> Result<()> {
+    let path = path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(adopted)?)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// When the archive named `name` was made, from the first timestamp in it,
+/// read as UTC like the stamps of the job's own archives.
+pub fn parse_time(name: &str) -> Option<DateTime<Utc>> {
+    let bytes = name.as_bytes();
+    let starts = (0..bytes.len())
+        .filter(|&i| bytes[i].is_ascii_digit() && (i == 0 || !bytes[i - 1].is_ascii_digit()));
+    for start in starts {
+        for (format, len) in STAMPS {
+            let time = name
+                .get(start..start + len)
+                .and_then(|s| NaiveDateTime::parse_from_str(s, format).ok());
+            if let Some(time) = time {
+                return Some(time.and_utc());
+            }
+        }
+        for (format, len) in DATES {
+            let date = name
+                .get(start..start + len)
+                .filter(|_| !bytes.get(start + len).is_some_and(u8::is_ascii_digit))
+                .and_then(|s| NaiveDate::parse_from_str(s, format).ok());
+            if let Some(date) = date {
+                return Some(date.and_time(chrono::NaiveTime::MIN).and_utc());
+            }
+        }
+    }
+    None
+}
+
+/// The archives of `job` on `target` in `folder`, as
+/// [`StorageBackend::list_backups`] finds them, along with those adopted
+/// for it that are still there, newest first. A `write_only` job's are
+/// never listed.
+pub fn list_backups(
+    remote: &dyn StorageBackend,
+    job: &Job,
+    target: &str,
+    folder: &str,
+    recursive: bool,
+) -> Result<Vec<Backup>> {
+    if job.write_only {
+        return Err(anyhow!(
+            "job {} is write_only, so its archives are never listed from here; a config without write_only, for an account that may read them, can",
+            job.name
+        ));
+    }
+    let mut backups = remote.list_backups(job, folder, recursive)?;
+    let adopted = load()?
+        .into_iter()
+        .filter(|a| a.job == job.name && a.target == target)
+        .collect::<Vec<_>>();
+    let folders = adopted
+        .iter()
+        .filter_map(|a| {
+            a.path
+                .rsplit_once('/')
+                .map(|(folder, _)| folder.to_string())
+        })
+        .collect::<BTreeSet<_>>();
+    for folder in folders {
+        let files = remote.list(&folder, false)?;
+        for file in &files {
+            let Some(entry) = adopted.iter().find(|a| a.path == file.path) else {
+                continue;
+            };
+            if backups.iter().any(|b| b.file.path == file.path) {
+                continue;
+            }
+            let Ok(time) = DateTime::parse_from_rfc3339(&entry.time) else {
+                continue;
+            };
+            let pin = format!(
+                "{}{PIN_SUFFIX}",
+                file.path.strip_suffix(".zip").unwrap_or(&file.path)
+            );
+            let sidecars = files
+                .iter()
+                .filter(|f| f.path == pin)
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>();
+            backups.push(Backup {
+                file: file.clone(),
+                time: time.with_timezone(&Utc),
+                tag: None,
+                pinned: !sidecars.is_empty(),
+                sidecars,
+            });
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.time));
+    Ok(backups)
+}
+
+pub fn adopt(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let share = args
+        .value("share")
+        .ok_or_else(|| anyhow!("adopt needs --share, the share the archives are on"))?;
+    let folder = match args.value("dir") {
+        Some(dir) => format!("/{share}/{}", dir.trim_matches('/')),
+        None => format!("/{share}"),
+    };
+    let job = *selected_jobs(config, args)?
+        .first()
+        .ok_or_else(|| anyhow!("There is no job to adopt the archives into"))?;
+    if job.write_only {
+        return Err(anyhow!(
+            "Job {} is write_only and never lists or reads its targets, so it can't adopt",
+            job.name
+        ));
+    }
+    // A job with its own account names its targets `target@usr`.
+    let ours = |name: &str, target: &str| {
+        name == target
+            || name
+                .strip_prefix(target)
+                .is_some_and(|usr| usr.starts_with('@'))
+    };
+    let target = match args.value("target") {
+        Some(target) => match job.targets.iter().find(|t| ours(t, target)) {
+            Some(name) => name.as_str(),
+            None => {
+                return Err(anyhow!(
+                    "Job {} doesn't go to target {target}; it goes to {}",
+                    job.name,
+                    job.targets.join(", ")
+                ))
+            }
+        },
+        None => job.targets[0].as_str(),
+    };
+
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let result = sessions
+        .get(target)
+        .1
+        .map_err(|e| anyhow!("{e}"))
+        .and_then(|remote| {
+            let files = remote
+                .list(&folder, args.flag("recursive"))
+                .with_context(|| format!("Could not list {folder} on {target}"))?;
+            adopt_files(remote, job, target, files)
+        });
+    sessions.logout();
+    result
+}
+
+fn adopt_files(
+    remote: &dyn StorageBackend,
+    job: &Job,
+    target: &str,
+    mut files: Vec<RemoteFile>,
+) -> Result<()> {
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut catalog = load()?;
+    let (mut adopted, mut skipped) = (0, 0);
+    let mut md5_missing = None;
+    for file in files {
+        if !file.name.ends_with(".zip") {
+            skipped += 1;
+            continue;
+        }
+        if backup_stamp(job, &file.name).is_some() {
+            println!(
+                "{} is one of job {}'s own archives already",
+                file.path, job.name
+            );
+            continue;
+        }
+        let time = match parse_time(&file.name) {
+            Some(time) => time,
+            None => match file.mtime.and_then(|t| DateTime::from_timestamp(t, 0)) {
+                Some(time) => {
+                    println!(
+                        "{} has no timestamp in its name; going by its modification time",
+                        file.path
+                    );
+                    time
+                }
+                None => {
+                    eprintln!(
+                        "Skipped {}, which has neither a timestamp in its name nor a modification time",
+                        file.path
+                    );
+                    continue;
+                }
+            },
+        };
+        let md5 = remote
+            .api("MD5")
+            .and_then(|s| file_md5(&s.client, &s.api_info, &file.path));
+        let md5 = match md5 {
+            Ok(md5) => Some(md5),
+            Err(e) => {
+                md5_missing = Some(e);
+                None
+            }
+        };
+        println!(
+            "Adopted {}, {}, made {}{}",
+            file.path,
+            file.size.map(format_bytes).unwrap_or_default(),
+            time.format("%Y-%m-%d %H:%M:%S"),
+            md5.as_deref()
+                .map(|m| format!(", MD5 {m}"))
+                .unwrap_or_default()
+        );
+        catalog.retain(|a| !(a.target == target && a.path == file.path));
+        catalog.push(Adopted {
+            job: job.name.clone(),
+            target: target.to_string(),
+            path: file.path,
+            time: time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            size: file.size,
+            md5,
+        });
+        adopted += 1;
+    }
+    store(&catalog)?;
+    if let Some(e) = md5_missing {
+        eprintln!("Adopted without MD5s: {e:#}");
+    }
+    if skipped > 0 {
+        println!("Skipped {skipped} files that are not zip archives");
+    }
+    println!(
+        "Adopted {adopted} archives into job {} on {target}; list, prune and restore count them from now on",
+        job.name
+    );
+    Ok(())
+}
@@ -0,0 +1,342 @@
+//! The chain of full and differential archives of jobs with `differential`,
+//! kept with the catalog in the state directory: `chain.json` records which
+//! full archive each differential holds the changes since, and
+//! `index/<job>.json` the size and modification time of every file in the
+//! job's last full archive, which the next differential compares against.
+//! A differential also names its full archive in a [`DIFFERENTIAL`] entry,
+//! so `restore` finds it from any machine.
+//!
+//! With `synthetic_full`, a new full archive is mostly made on the NAS: the
+//! last full archive is copied there, with FileStation's CopyMove, into a
+//! folder next to the new one, which itself holds only what changed since,
+//! like a differential. `restore` layers them the same way.
+//!
+//! `check-chain` makes sure that what the chain records can still be
+//! restored from every target: that each archive there still has the full
+//! archive and copies it builds on, and a checksum to check it against.
+//!
+//! [`DIFFERENTIAL`]: crate::archive::DIFFERENTIAL
+
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Job;
+use crate::schedule::Weekday;
+use crate::{paths, selected_jobs, Config, Sessions};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Full,
+    Differential,
+}
+
+/// An archive of a job with `differential`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    pub job: String,
+    /// The archive's file name
+    pub name: String,
+    pub kind: Kind,
+    /// Of a differential, the name of the full archive it builds on
+    pub base: Option<String>,
+    /// When it was made, in RFC 3339
+    pub time: String,
+    pub sha256: Option<String>,
+    /// Where it was uploaded, as `target:path`
+    pub uploaded: Vec<String>,
+    /// Of a synthetic full archive, the copies of the archives it builds on,
+    /// as `target:path`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+}
+
+/// The files of a full archive.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Index {
+    /// The full archive's file name
+    pub full: String,
+    /// Size and modification time, in nanoseconds since 1970, by entry name
+    pub files: BTreeMap<String, (u64, i64)>,
+}
+
+/// What a differential's [`DIFFERENTIAL`](crate::archive::DIFFERENTIAL) entry holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Differential {
+    /// The name of the full archive it holds the changes since
+    pub base: String,
+    /// Entries of the full archive whose files have gone since
+    pub deleted: Vec<String>,
+    /// Of a synthetic full archive, the folder next to it that the archives
+    /// it builds on are copied into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<String>,
+}
+
+/// What today's archive builds on.
+pub struct Base {
+    pub index: Index,
+    /// Whether it is to be a synthetic full archive rather than a differential
+    pub synthetic: bool,
+}
+
+/// The folder a synthetic full archive named `name` has its copies in.
+pub fn volumes_folder(name: &str) -> String {
+    format!("{}.volumes", name.strip_suffix(".zip").unwrap_or(name))
+}
+
+pub fn path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("chain.json"))
+}
+
+fn index_path(job: &Job) -> Result<PathBuf> {
+    Ok(paths::state_dir()?
+        .join("index")
+        .join(format!("{}.json", job.name)))
+}
+
+fn read<T: for<'de> Deserialize<'de>>(path: &PathBuf) -> Result<Option<T>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Writes `value` to a file next to `path` and renames it into place, so a
+/// crash or a full disk leaves the old contents rather than half the new.
+fn write(path: &PathBuf, value: &impl Serialize) -> Result<()> {
+    let dir = path
+        .parent()
+        .expect("state files are in the state directory");
+    std::fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dir.join(format!(".{name}.{}", std::process::id()));
+    let contents = serde_json::to_string_pretty(value)?;
+    let written = (|| {
+        let mut file = std::fs::File::create(&partial)?;
+        std::io::Write::write_all(&mut file, contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&partial, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written.with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Every archive recorded, oldest first.
+pub fn load() -> Result<Vec<Link>> {
+    Ok(read(&path()?)?.unwrap_or_default())
+}
+
+/// Records `link`, and with `index` the files of a new full archive.
+pub fn record(job: &Job, link: Link, index: Option<&Index>) -> Result<()> {
+    let mut chain = load()?;
+    chain.push(link);
+    write(&path()?, &chain)?;
+    match index {
+        Some(index) => write(&index_path(job)?, index),
+        None => Ok(()),
+    }
+}
+
+/// The files of `job`'s last full archive for today's run to build on, or
+/// `None` if today's is to be full: on `full_on`, and whenever there is no
+/// full archive to build on yet. With `synthetic_full`, `full_on` and the
+/// run after that many differentials make a synthetic full archive instead.
+pub fn base(job: &Job, full_on: Weekday) -> Option<Base> {
+    let full_day = Weekday::of(chrono::Local::now()) == full_on;
+    if full_day && job.synthetic_full.is_none() {
+        eprintln!(
+            "Job {}: making a full archive, as it is {}",
+            job.name,
+            full_on.long_name()
+        );
+        return None;
+    }
+    let index = index_path(job).and_then(|path| read::<Index>(&path));
+    match index {
+        Ok(Some(index)) => {
+            let differentials = load()
+                .unwrap_or_default()
+                .iter()
+                .filter(|l| l.job == job.name && l.base.as_ref() == Some(&index.full))
+                .count();
+            let synthetic = full_day || job.synthetic_full.is_some_and(|n| differentials >= n);
+            if synthetic {
+                eprintln!(
+                    "Job {}: making a synthetic full archive, of a copy of {} on the NAS and what changed since",
+                    job.name, index.full
+                );
+            } else {
+                eprintln!(
+                    "Job {}: archiving what changed since the full archive {}",
+                    job.name, index.full
+                );
+            }
+            Some(Base { index, synthetic })
+        }
+        Ok(None) => {
+            eprintln!(
+                "Job {}: making a full archive, as there is none to build a differential on",
+                job.name
+            );
+            None
+        }
+        Err(e) => {
+            eprintln!("Job {}: making a full archive, as {e:#}", job.name);
+            None
+        }
+    }
+}
+
+impl Link {
+    /// Where it was uploaded on `target`, if it was.
+    fn path_on(&self, target: &str) -> Option<&str> {
+        on(target, &self.uploaded).first().copied()
+    }
+}
+
+/// The paths among `copies`, each `target:path`, that are on `target`.
+fn on<'c>(target: &str, copies: &'c [String]) -> Vec<&'c str> {
+    copies
+        .iter()
+        .filter_map(|copy| copy.strip_prefix(target)?.strip_prefix(':'))
+        .collect()
+}
+
+/// Checks for every selected job with `differential` that each archive the
+/// chain records on its targets is still there along with what it builds
+/// on, and that its checksum was recorded, printing a Nagios-style status
+/// line. Returns whether nothing is missing.
+pub fn check(config: &Config, mode: Mode, args: &Args) -> Result<bool> {
+    let jobs = selected_jobs(config, args)?;
+    let chain = load()?;
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let mut problems = Vec::new();
+    let mut details = Vec::new();
+    let mut checked = 0;
+    for job in jobs.iter().filter(|job| job.differential.is_some()) {
+        checked += 1;
+        let links = chain
+            .iter()
+            .filter(|l| l.job == job.name)
+            .collect::<Vec<_>>();
+        let Some(current) = links.iter().rposition(|l| l.kind == Kind::Full) else {
+            details.push(format!("{}: no full archive recorded yet", job.name));
+            problems.push(format!("{} has no full archive", job.name));
+            continue;
+        };
+        for target in &job.targets {
+            let remote = match sessions.get(target).1 {
+                Ok(remote) => remote,
+                Err(e) => {
+                    problems.push(format!("{} on {target}: {e}", job.name));
+                    continue;
+                }
+            };
+            // Every folder the chain has something in on this target
+            let folders = links
+                .iter()
+                .flat_map(|l| [on(target, &l.uploaded), on(target, &l.volumes)].concat())
+                .filter_map(|path| Some(path.rsplit_once('/')?.0))
+                .collect::<BTreeSet<_>>();
+            let mut present = HashSet::new();
+            for folder in folders {
+                match remote.list(folder, false) {
+                    Ok(files) => present.extend(files.into_iter().map(|f| f.path)),
+                    Err(e) => details.push(format!(
+                        "{} on {target}: could not list {folder}: {e:#}",
+                        job.name
+                    )),
+                }
+            }
+            let there = |path: &str| present.contains(path);
+            let full = links[current];
+            match full.path_on(target) {
+                Some(path) if there(path) => {}
+                Some(_) => problems.push(format!(
+                    "{} on {target}: the full archive {}, which the next differentials build on, is gone; the job's next full archive replaces it",
+                    job.name, full.name
+                )),
+                None => problems.push(format!(
+                    "{} on {target}: the full archive {}, which the next differentials build on, was never uploaded there",
+                    job.name, full.name
+                )),
+            }
+            let mut count = 0;
+            for (i, link) in links.iter().enumerate() {
+                let Some(path) = link.path_on(target) else {
+                    continue;
+                };
+                if !there(path) {
+                    // Pruned, unless it is one of the current chain's.
+                    if i > current {
+                        details.push(format!(
+                            "{} on {target}: the differential {} is gone",
+                            job.name, link.name
+                        ));
+                    }
+                    continue;
+                }
+                count += 1;
+                if link.sha256.is_none() {
+                    problems.push(format!(
+                        "{} on {target}: no checksum was recorded for {}",
+                        job.name, link.name
+                    ));
+                }
+                if let Some(base) = &link.base {
+                    let full = links
+                        .iter()
+                        .rev()
+                        .find(|l| &l.name == base && l.kind == Kind::Full);
+                    match full.and_then(|full| full.path_on(target)) {
+                        Some(path) if there(path) => {}
+                        _ => problems.push(format!(
+                            "{} on {target}: {} can't be restored, as the full archive {base} it builds on is gone",
+                            job.name, link.name
+                        )),
+                    }
+                }
+                for copy in on(target, &link.volumes)
+                    .into_iter()
+                    .filter(|copy| !there(copy))
+                {
+                    problems.push(format!(
+                        "{} on {target}: {} can't be restored, as the copy {copy} it builds on is gone",
+                        job.name, link.name
+                    ));
+                }
+            }
+            details.push(format!(
+                "{} on {target}: {count} archives of the chain there, building on the full archive {} with {} differentials since",
+                job.name,
+                full.name,
+                links.len() - current - 1
+            ));
+        }
+    }
+    sessions.logout();
+    if checked == 0 {
+        return Err(anyhow!(
+            "No selected job has `differential`, so there is no chain to check"
+        ));
+    }
+    if problems.is_empty() {
+        println!("OK - the chains of {checked} jobs are whole");
+    } else {
+        println!("CRITICAL - {}", problems.join("; "));
+    }
+    for line in details {
+        println!("{line}");
+    }
+    Ok(problems.is_empty())
+}
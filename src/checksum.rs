@@ -0,0 +1,261 @@
+//! Checksums of the files in an archive, computed while they are read for
+//! compressing, and the manifest that lists them as the archive's last
+//! entry. The manifest is in the format of `b3sum` and `sha256sum`, so
+//! `b3sum -c` or `sha256sum -c` in the folder an archive was unpacked into
+//! checks it, and `restore` checks what it restores against it.
+//! [`HashedFile`] likewise hashes the archive itself as it is written.
+
+use crate::blake3;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Name of the manifest entry, before the extension of its algorithm
+const MANIFEST: &str = ".synology_backuper_manifest";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// Fast, and what `b3sum` computes
+    #[default]
+    Blake3,
+    /// For tools that know nothing else, like `sha256sum` and DSM's
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "BLAKE3",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "b3",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+            Algorithm::Sha256 => Hasher::Sha256(ring::digest::Context::new(&ring::digest::SHA256)),
+        }
+    }
+
+    /// The name of the entry holding the manifest of this algorithm.
+    pub fn manifest_name(self) -> String {
+        format!("{MANIFEST}.{}", self.extension())
+    }
+
+    /// The algorithm of the manifest entry named `name`, if it is one.
+    pub fn of_manifest(name: &str) -> Option<Algorithm> {
+        [Algorithm::Blake3, Algorithm::Sha256]
+            .into_iter()
+            .find(|a| a.manifest_name() == name)
+    }
+}
+
+pub enum Hasher {
+    Blake3(blake3::Hasher),
+    Sha256(ring::digest::Context),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => hasher.update(bytes),
+            Hasher::Sha256(context) => context.update(bytes),
+        }
+    }
+
+    /// The checksum in lowercase hex.
+    pub fn finish(self) -> String {
+        let bytes = match self {
+            Hasher::Blake3(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(context) => context.finish().as_ref().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// Hashes what is read through it, if it has a hasher.
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Option<Hasher>,
+}
+
+impl<R> Hashing<R> {
+    pub fn new(inner: R, algorithm: Option<Algorithm>) -> Self {
+        Hashing {
+            inner,
+            hasher: algorithm.map(Algorithm::hasher),
+        }
+    }
+
+    /// The checksum of everything read.
+    pub fn finish(self) -> Option<String> {
+        self.hasher.map(Hasher::finish)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// The lines of a manifest for `hashes`, pairs of entry name and checksum.
+pub fn manifest(hashes: &[(String, String)]) -> String {
+    hashes
+        .iter()
+        .map(|(name, hash)| format!("{hash}  {name}\n"))
+        .collect()
+}
+
+/// The checksums a manifest holds, by entry name.
+pub fn parse_manifest(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, name)| (name.to_string(), hash.to_string()))
+        .collect()
+}
+
+/// How much of what was last written [`HashedFile`] keeps in memory, where
+/// the zip writer can still change it at no cost.
+const HELD: usize = 16 << 20;
+
+/// A file being written that keeps the SHA-256 of its contents on the way,
+/// so that nothing reads it again for the hash. The zip writer goes back to
+/// fill in the checksum and sizes of each entry in its header once the data
+/// is written, and over an entry it abandons, so the last [`HELD`] bytes
+/// are held unhashed, where such rewrites land on the copy too. A rewrite
+/// further back, after an entry larger than that, returns to the hash as it
+/// was before the rewritten bytes and reads the file again from there.
+pub struct HashedFile {
+    file: File,
+    pos: u64,
+    len: u64,
+    /// Everything before this is in `context`
+    hashed: u64,
+    context: ring::digest::Context,
+    /// The file's bytes from `hashed` on, as far as they are known; those
+    /// after them up to `len` are only in the file
+    held: Vec<u8>,
+    /// Where the hash was before each stretch of `held` went into it
+    checkpoints: Vec<(u64, ring::digest::Context)>,
+}
+
+impl HashedFile {
+    pub fn create(path: &Path) -> std::io::Result<HashedFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(HashedFile {
+            file,
+            pos: 0,
+            len: 0,
+            hashed: 0,
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            held: Vec::new(),
+            checkpoints: Vec::new(),
+        })
+    }
+
+    fn known(&self) -> u64 {
+        self.hashed + self.held.len() as u64
+    }
+
+    /// Hashes the first `n` held bytes.
+    fn settle(&mut self, n: usize) {
+        self.checkpoints.push((self.hashed, self.context.clone()));
+        self.context.update(&self.held[..n]);
+        self.held.drain(..n);
+        self.hashed += n as u64;
+    }
+
+    /// Reads what is only in the file, up to `end`, into `held`.
+    fn catch_up(&mut self, end: u64) -> std::io::Result<()> {
+        if self.known() >= end {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(self.known()))?;
+        let mut buf = vec![0; 1 << 16];
+        while self.known() < end {
+            let want = buf.len().min((end - self.known()) as usize);
+            self.file.read_exact(&mut buf[..want])?;
+            self.held.extend_from_slice(&buf[..want]);
+            if self.held.len() > HELD {
+                self.settle(HELD / 2);
+            }
+        }
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+
+    /// The file and the SHA-256 of what it holds, in lowercase hex.
+    pub fn finish(mut self) -> std::io::Result<(File, String)> {
+        self.catch_up(self.len)?;
+        self.settle(self.held.len());
+        let hash = self.context.finish();
+        Ok((
+            self.file,
+            hash.as_ref().iter().map(|b| format!("{b:02x}")).collect(),
+        ))
+    }
+}
+
+impl Write for HashedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.pos == self.len {
+            self.catch_up(self.pos)?;
+        }
+        let n = self.file.write(buf)?;
+        let (start, end) = (self.pos, self.pos + n as u64);
+        if start < self.hashed {
+            // Back to the hash before the rewritten bytes; those after have to be read again.
+            let i = self
+                .checkpoints
+                .iter()
+                .rposition(|(at, _)| *at <= start)
+                .unwrap_or(0);
+            (self.hashed, self.context) = self.checkpoints[i].clone();
+            self.checkpoints.truncate(i + 1);
+            self.held.clear();
+        }
+        if start <= self.known() {
+            let from = (start - self.hashed) as usize;
+            let overlap = (self.held.len() - from).min(n);
+            self.held[from..from + overlap].copy_from_slice(&buf[..overlap]);
+            self.held.extend_from_slice(&buf[overlap..n]);
+        }
+        self.pos = end;
+        self.len = self.len.max(end);
+        if self.held.len() > HELD {
+            self.settle(self.held.len() - HELD / 2);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for HashedFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.file.seek(pos)?;
+        Ok(self.pos)
+    }
+}
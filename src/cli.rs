@@ -0,0 +1,755 @@
+//! Command line parsing.
+//!
+//! Commands and options are described by the static tables below; the parser,
+//! the help text and the shell completions are all generated from them.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub options: &'static [OptSpec],
+    /// Name of the positional argument the command takes, if any.
+    pub positional: Option<&'static str>,
+    /// Left out of help and completions.
+    pub hidden: bool,
+}
+
+pub struct OptSpec {
+    pub long: &'static str,
+    /// Name of the value the option takes, or `None` for a flag.
+    pub value: Option<&'static str>,
+    pub about: &'static str,
+}
+
+pub const GLOBAL_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "record",
+        value: Some("DIR"),
+        about: "Record API interactions as redacted fixtures in DIR",
+    },
+    OptSpec {
+        long: "replay",
+        value: Some("DIR"),
+        about: "Answer API calls from fixtures in DIR instead of the NAS",
+    },
+    OptSpec {
+        long: "config",
+        value: Some("FILE"),
+        about: "Read the config from FILE instead of the default location",
+    },
+    OptSpec {
+        long: "profile",
+        value: Some("PROFILE"),
+        about: "Apply the overrides of the named profile in the config",
+    },
+    OptSpec {
+        long: "help",
+        value: None,
+        about: "Print help",
+    },
+];
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "backup",
+        about: "Compress the configured files and upload them (the default)",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "all",
+                value: None,
+                about: "Every job, which is also what happens without --job",
+            },
+            OptSpec {
+                long: "tag",
+                value: Some("TAG"),
+                about: "Add TAG to the archive names, e.g. pre-upgrade",
+            },
+            OptSpec {
+                long: "verbose",
+                value: None,
+                about: "Show the compression of every file type, not just the largest",
+            },
+            OptSpec {
+                long: "deterministic",
+                value: None,
+                about: "Add files to the archive sorted by path, not as they are found",
+            },
+            OptSpec {
+                long: "report",
+                value: Some("FILE"),
+                about: "List the files left out of the archives, and why, in FILE",
+            },
+            OptSpec {
+                long: "offline",
+                value: None,
+                about: "Only archive, and queue the archives for upload-pending",
+            },
+            OptSpec {
+                long: "force",
+                value: None,
+                about: "Run jobs that would wait for their windows, mains power or another connection",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "list",
+        about: "List each job's archives on its targets, newest first",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also look in subfolders of the share",
+            },
+            OptSpec {
+                long: "tag",
+                value: Some("TAG"),
+                about: "Only archives made with --tag TAG",
+            },
+            OptSpec {
+                long: "fleet",
+                value: None,
+                about: "Add which machine made each archive, from the shared run logs",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "prune",
+        about: "Delete archives that the job's retention settings no longer keep",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "dry-run",
+                value: None,
+                about: "Print what would be deleted without deleting it",
+            },
+            OptSpec {
+                long: "explain",
+                value: None,
+                about: "Print every archive with the rules that keep it",
+            },
+            OptSpec {
+                long: "tag",
+                value: Some("TAG"),
+                about: "Only consider archives made with --tag TAG",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "check",
+        about: "Fail unless every job has a successful backup younger than --max-age",
+        options: &[
+            OptSpec {
+                long: "max-age",
+                value: Some("AGE"),
+                about: "Oldest acceptable backup, e.g. 26h",
+            },
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "remote",
+                value: None,
+                about: "List the targets instead of reading the run log",
+            },
+            OptSpec {
+                long: "fleet",
+                value: None,
+                about: "Check every machine's jobs, from the run logs shared in `fleet`",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "status",
+        about: "Print when each job last succeeded and how its archive sizes went",
+        options: &[OptSpec {
+            long: "fleet",
+            value: None,
+            about: "Every machine sharing its run log in `fleet`, not just this one",
+        }],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "report",
+        about: "Summarize the run log: success rates, archive sizes, failures and what prune deleted",
+        options: &[
+            OptSpec {
+                long: "since",
+                value: Some("DURATION"),
+                about: "How far back the report goes, like 30d (the default)",
+            },
+            OptSpec {
+                long: "format",
+                value: Some("FORMAT"),
+                about: "markdown (the default) or html, a page that needs nothing else",
+            },
+            OptSpec {
+                long: "job",
+                value: Some("NAME"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "out",
+                value: Some("FILE"),
+                about: "Write the report to FILE instead of printing it",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "check-chain",
+        about: "Fail unless the archives of the differential jobs still have what they build on",
+        options: &[OptSpec {
+            long: "job",
+            value: Some("JOB"),
+            about: "Only this job",
+        }],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "orphans",
+        about: "Delete the files left next to archives that are gone",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also look in subfolders of the share",
+            },
+            OptSpec {
+                long: "dry-run",
+                value: None,
+                about: "Print what would be deleted without deleting it",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "restore",
+        about: "Download an archive and extract all or some of its files",
+        options: &[
+            OptSpec {
+                long: "name",
+                value: Some("NAME"),
+                about: "The archive, like notes.txt_20240101_030000.zip",
+            },
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only look among this job's archives",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also look in subfolders of the share",
+            },
+            OptSpec {
+                long: "path",
+                value: Some("PATTERN"),
+                about: "Only extract the files matching PATTERN, like docs/invoices/**; may be repeated",
+            },
+            OptSpec {
+                long: "to",
+                value: Some("DIR"),
+                about: "Extract into DIR instead of the working directory",
+            },
+            OptSpec {
+                long: "overwrite",
+                value: None,
+                about: "Replace files that exist already",
+            },
+            OptSpec {
+                long: "list",
+                value: None,
+                about: "Only print the files in the archive, reading just its table of contents",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "queue",
+        about: "List the archives waiting to be uploaded",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "flush",
+                value: None,
+                about: "Upload them now",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "upload-pending",
+        about: "Upload the archives waiting in the queue",
+        options: &[OptSpec {
+            long: "job",
+            value: Some("JOB"),
+            about: "Only this job",
+        }],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "pin",
+        about: "Protect the archive named NAME from prune",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only look among this job's archives",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also look in subfolders of the share",
+            },
+        ],
+        positional: Some("NAME"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "unpin",
+        about: "Let prune delete the archive named NAME again",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only look among this job's archives",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also look in subfolders of the share",
+            },
+        ],
+        positional: Some("NAME"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "usage",
+        about: "Show how much space each job's backups take on the NAS",
+        options: &[],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "find",
+        about: "Search the configured shares for files matching a glob PATTERN",
+        options: &[],
+        positional: Some("PATTERN"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "doctor",
+        about: "Check connectivity, login and permissions against the NAS",
+        options: &[],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "bench",
+        about: "Time uploads of random data and small requests to a job's targets",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Use this job's targets, share and rate limit instead of the first job's",
+            },
+            OptSpec {
+                long: "size",
+                value: Some("SIZE"),
+                about: "Upload this much, like 100MiB (default 16MiB)",
+            },
+            OptSpec {
+                long: "count",
+                value: Some("N"),
+                about: "Upload N times (default 3)",
+            },
+            OptSpec {
+                long: "rate-limit",
+                value: Some("RATE"),
+                about: "Upload at most RATE per second instead of the job's limit",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "bench-compress",
+        about: "Compress a sample of each job's files with several settings and compare",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "sample",
+                value: Some("SIZE"),
+                about: "Sample this much of the files, like 500MB (default 256MiB)",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "api-info",
+        about: "List the APIs the NAS offers, their versions and the ones used",
+        options: &[OptSpec {
+            long: "json",
+            value: None,
+            about: "Print JSON instead of a table",
+        }],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "audit",
+        about: "Check that an uploaded archive still matches its recorded checksum",
+        options: &[],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "verify-local",
+        about: "Check the source files against the last archive's manifest for silent corruption",
+        options: &[
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Only this job",
+            },
+            OptSpec {
+                long: "parallelism",
+                value: Some("N"),
+                about: "Hash on N threads (default one per processor)",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "daemon",
+        about: "Stay running, back up jobs at their schedules and audit regularly",
+        options: &[],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "install-systemd",
+        about: "Install a systemd service and timer for the job schedules",
+        options: &[
+            OptSpec {
+                long: "user",
+                value: None,
+                about: "Install into the user's systemd instance",
+            },
+            OptSpec {
+                long: "system",
+                value: None,
+                about: "Install system-wide into /etc/systemd/system",
+            },
+            OptSpec {
+                long: "print",
+                value: None,
+                about: "Print the units instead of installing them",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "install-schedule",
+        about: "Register the job schedules with Task Scheduler or launchd",
+        options: &[
+            OptSpec {
+                long: "platform",
+                value: Some("PLATFORM"),
+                about: "windows or macos; defaults to the running system",
+            },
+            OptSpec {
+                long: "print",
+                value: None,
+                about: "Print the task definition instead of registering it",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "key",
+        about: "Manage the encryption keys with `key generate`, `key rotate` or `key export`",
+        options: &[
+            OptSpec {
+                long: "out",
+                value: Some("FILE"),
+                about: "Write the export to FILE instead of printing it",
+            },
+            OptSpec {
+                long: "reseal",
+                value: Some("FILE"),
+                about: "After rotating, seal the recovery bundle FILE again with the new key; may repeat",
+            },
+        ],
+        positional: Some("KEY_ACTION"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "adopt",
+        about: "Register archives made elsewhere with a job, for list, prune and restore",
+        options: &[
+            OptSpec {
+                long: "share",
+                value: Some("SHARE"),
+                about: "The share the archives are on",
+            },
+            OptSpec {
+                long: "dir",
+                value: Some("DIR"),
+                about: "The folder in the share they are in",
+            },
+            OptSpec {
+                long: "job",
+                value: Some("JOB"),
+                about: "Adopt them into this job instead of the first",
+            },
+            OptSpec {
+                long: "target",
+                value: Some("TARGET"),
+                about: "The target they are on, instead of the job's first",
+            },
+            OptSpec {
+                long: "recursive",
+                value: None,
+                about: "Also adopt archives in subfolders",
+            },
+        ],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "export-recovery",
+        about: "Write an encrypted bundle of the config, run log and restore instructions",
+        options: &[OptSpec {
+            long: "out",
+            value: Some("FILE"),
+            about: "Write the bundle to FILE instead of a dated file here",
+        }],
+        positional: None,
+        hidden: false,
+    },
+    CommandSpec {
+        name: "open-recovery",
+        about: "Decrypt and unpack the bundle FILE that export-recovery wrote",
+        options: &[
+            OptSpec {
+                long: "keys",
+                value: Some("FILE"),
+                about: "Open it with the keyring export FILE instead of keys.json",
+            },
+            OptSpec {
+                long: "passphrase-file",
+                value: Some("FILE"),
+                about: "Open a bundle of self_backup with the passphrase in FILE",
+            },
+            OptSpec {
+                long: "to",
+                value: Some("DIR"),
+                about: "Unpack into DIR instead of the working directory",
+            },
+        ],
+        positional: Some("FILE"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "config",
+        about: "Print the JSON Schema of the config file with `config schema`",
+        options: &[],
+        positional: Some("ACTION"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "completions",
+        about: "Print a shell completion script",
+        options: &[],
+        positional: Some("SHELL"),
+        hidden: false,
+    },
+    CommandSpec {
+        name: "__complete",
+        about: "Print dynamic completion candidates",
+        options: &[],
+        positional: Some("KIND"),
+        hidden: true,
+    },
+];
+
+/// Fixed choices for option and positional values, by value name.
+pub fn value_choices(value: &str) -> Option<&'static [&'static str]> {
+    match value {
+        "SHELL" => Some(&["bash", "zsh", "fish", "powershell"]),
+        "KIND" => Some(&["jobs"]),
+        "ACTION" => Some(&["schema"]),
+        "KEY_ACTION" => Some(&["generate", "rotate", "export"]),
+        "PLATFORM" => Some(&["windows", "macos"]),
+        _ => None,
+    }
+}
+
+pub struct Args {
+    pub command: &'static CommandSpec,
+    options: HashMap<&'static str, Vec<String>>,
+    pub positional: Option<String>,
+}
+
+impl Args {
+    pub fn flag(&self, long: &str) -> bool {
+        self.options.contains_key(long)
+    }
+
+    /// Every value of an option that may be given more than once.
+    pub fn values(&self, long: &str) -> impl Iterator<Item = &str> {
+        self.options
+            .get(long)
+            .into_iter()
+            .flatten()
+            .map(|x| x.as_str())
+    }
+
+    pub fn value(&self, long: &str) -> Option<&str> {
+        self.options
+            .get(long)
+            .and_then(|x| x.last())
+            .map(|x| x.as_str())
+    }
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut args = args.into_iter();
+    let mut command = None;
+    let mut options: HashMap<&'static str, Vec<String>> = HashMap::new();
+    let mut positional = None;
+    while let Some(arg) = args.next() {
+        if let Some(long) = arg.strip_prefix("--") {
+            let (long, inline) = match long.split_once('=') {
+                Some((k, v)) => (k, Some(v.to_string())),
+                None => (long, None),
+            };
+            let spec = command
+                .iter()
+                .flat_map(|c: &&CommandSpec| c.options.iter())
+                .chain(GLOBAL_OPTIONS)
+                .find(|o| o.long == long)
+                .ok_or_else(|| anyhow!("Unknown option --{long}"))?;
+            let value = match (spec.value, inline) {
+                (None, None) => String::new(),
+                (None, Some(_)) => return Err(anyhow!("--{long} takes no value")),
+                (Some(_), Some(v)) => v,
+                (Some(name), None) => args
+                    .next()
+                    .ok_or_else(|| anyhow!("--{long} needs a value {name}"))?,
+            };
+            if let Some(choices) = spec.value.and_then(value_choices) {
+                if !choices.contains(&value.as_str()) {
+                    return Err(anyhow!(
+                        "--{long} must be one of {}, not {value}",
+                        choices.join(", ")
+                    ));
+                }
+            }
+            options.entry(spec.long).or_default().push(value);
+        } else if command.is_none() {
+            command = Some(
+                COMMANDS
+                    .iter()
+                    .find(|c| c.name == arg)
+                    .ok_or_else(|| anyhow!("Unknown command {arg}"))?,
+            );
+        } else if command.is_some_and(|c| c.positional.is_some()) && positional.is_none() {
+            positional = Some(arg);
+        } else {
+            return Err(anyhow!("Unexpected argument {arg}"));
+        }
+    }
+    let command = command.unwrap_or(&COMMANDS[0]);
+    if let (Some(name), None) = (command.positional, &positional) {
+        return Err(anyhow!("{} needs an argument {name}", command.name));
+    }
+    if let (Some(choices), Some(value)) = (command.positional.and_then(value_choices), &positional)
+    {
+        if !choices.contains(&value.as_str()) {
+            return Err(anyhow!("{value} is not one of {}", choices.join(", ")));
+        }
+    }
+    Ok(Args {
+        command,
+        options,
+        positional,
+    })
+}
+
+pub fn help() -> String {
+    let mut out = String::from("Usage: synology_backuper [OPTIONS] [COMMAND]\n\nCommands:\n");
+    for c in COMMANDS.iter().filter(|c| !c.hidden) {
+        let name = match c.positional {
+            Some(p) => format!("{} <{}>", c.name, p),
+            None => c.name.to_string(),
+        };
+        out += &format!("  {:<22}{}\n", name, c.about);
+    }
+    out += "\nOptions:\n";
+    let all = GLOBAL_OPTIONS.iter().map(|o| (None, o)).chain(
+        COMMANDS
+            .iter()
+            .flat_map(|c| c.options.iter().map(|o| (Some(c.name), o))),
+    );
+    for (command, o) in all {
+        let name = match o.value {
+            Some(v) => format!("--{} <{}>", o.long, v),
+            None => format!("--{}", o.long),
+        };
+        let scope = command.map(|c| format!(" [{c}]")).unwrap_or_default();
+        out += &format!("  {:<26}{}{}\n", name, o.about, scope);
+    }
+    out
+}
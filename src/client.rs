@@ -0,0 +1,370 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Keys whose values never make it into a fixture file.
+const REDACTED_KEYS: &[&str] = &[
+    "account",
+    "passwd",
+    "otp_code",
+    "sid",
+    "_sid",
+    "did",
+    "device_id",
+    "synotoken",
+];
+
+/// Tries a request gets when the server or the way to it fails in a way that
+/// may pass, waiting [`RETRY_DELAY`], then twice that, between them.
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How much of a response body an error quotes.
+const SNIPPET_LEN: usize = 200;
+
+pub struct Client {
+    pub client: reqwest::blocking::Client,
+    pub base_url: String,
+    pub mode: Mode,
+    /// How long to wait for a FileStation background task before giving up on it
+    pub task_timeout: Duration,
+}
+
+/// How API calls reach the NAS.
+pub enum Mode {
+    Live,
+    /// Talk to the NAS and write every request/response pair to a fixture directory.
+    Record(Recorder),
+    /// Never touch the network; answer from a previously recorded fixture directory.
+    Replay(Replayer),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SynoResponse {
+    pub success: bool,
+    pub data: Option<serde_json::Value>,
+    pub error: Option<serde_json::Value>,
+}
+
+/// A request that got no answer from the DSM API, as opposed to one DSM
+/// answered with an error code.
+#[derive(Debug)]
+pub struct HttpError {
+    pub api: String,
+    pub method: String,
+    pub kind: HttpErrorKind,
+    /// The start of the response body, or what the transport said
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpErrorKind {
+    /// No connection could be made, or it broke
+    Connect,
+    Timeout,
+    /// TLS failed, say because the certificate was refused
+    Tls,
+    /// An HTTP status other than success, like a 502 from a reverse proxy
+    Status(u16),
+    /// A success status with a body that isn't DSM's JSON
+    Body,
+}
+
+impl HttpError {
+    fn new(api: &str, method: &str, kind: HttpErrorKind, detail: String) -> HttpError {
+        HttpError {
+            api: api.to_string(),
+            method: method.to_string(),
+            kind,
+            detail,
+        }
+    }
+
+    fn transport(api: &str, method: &str, e: reqwest::Error) -> HttpError {
+        let mut messages = Vec::new();
+        let mut source: Option<&dyn std::error::Error> = Some(&e);
+        while let Some(error) = source {
+            messages.push(error.to_string());
+            source = error.source();
+        }
+        let detail = messages.join(": ");
+        let lower = detail.to_lowercase();
+        let kind = if e.is_timeout() {
+            HttpErrorKind::Timeout
+        } else if ["certificate", "tls", "ssl"]
+            .iter()
+            .any(|x| lower.contains(x))
+        {
+            HttpErrorKind::Tls
+        } else {
+            HttpErrorKind::Connect
+        };
+        HttpError::new(api, method, kind, detail)
+    }
+
+    /// An answer DSM sent in its JSON envelope that lacks what it should hold,
+    /// like an error without a code or a listing without paths.
+    pub fn malformed(api: &str, method: &str, body: &serde_json::Value) -> HttpError {
+        HttpError::new(api, method, HttpErrorKind::Body, snippet(&body.to_string()))
+    }
+
+    /// Whether trying again may help: a server that is restarting or a busy
+    /// proxy may recover, a refused certificate or a wrong path won't.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            HttpErrorKind::Connect
+                | HttpErrorKind::Timeout
+                | HttpErrorKind::Status(429 | 502 | 503 | 504)
+        )
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: ", self.api, self.method)?;
+        match self.kind {
+            HttpErrorKind::Connect => write!(f, "could not connect ({})", self.detail),
+            HttpErrorKind::Timeout => write!(f, "timed out ({})", self.detail),
+            HttpErrorKind::Tls => write!(f, "TLS failed ({})", self.detail),
+            HttpErrorKind::Status(status) if self.detail.is_empty() => {
+                write!(f, "HTTP {status}")
+            }
+            HttpErrorKind::Status(status) => write!(f, "HTTP {status}: {}", self.detail),
+            HttpErrorKind::Body => write!(f, "the answer isn't DSM's JSON: {}", self.detail),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// The start of `body` on one line, for error messages.
+fn snippet(body: &str) -> String {
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match body.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body,
+    }
+}
+
+impl Client {
+    pub fn get(&self, api_path: &str) -> reqwest::blocking::RequestBuilder {
+        self.client.get(format!("{}/{}", &self.base_url, api_path))
+    }
+
+    pub fn post(&self, api_path: &str) -> reqwest::blocking::RequestBuilder {
+        self.client.post(format!("{}/{}", &self.base_url, api_path))
+    }
+
+    /// Sends a request whose answer is a file, such as a download. DSM still
+    /// answers with its JSON envelope when the request fails, which is returned
+    /// as the error. Files are not recorded, so this fails when replaying.
+    pub fn send_for_file(
+        &self,
+        api_name: &str,
+        method: &str,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<std::result::Result<reqwest::blocking::Response, SynoResponse>> {
+        if let Mode::Replay(_) = &self.mode {
+            return Err(anyhow!("{api_name} {method} can't be replayed"));
+        }
+        let resp = request
+            .send()
+            .map_err(|e| HttpError::transport(api_name, method, e))?;
+        let is_json = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.starts_with("application/json"));
+        if is_json {
+            let text = resp
+                .text()
+                .map_err(|e| HttpError::transport(api_name, method, e))?;
+            return serde_json::from_str(&text).map(Err).map_err(|_| {
+                HttpError::new(api_name, method, HttpErrorKind::Body, snippet(&text)).into()
+            });
+        }
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().unwrap_or_default();
+            let kind = HttpErrorKind::Status(status.as_u16());
+            return Err(HttpError::new(api_name, method, kind, snippet(&body)).into());
+        }
+        Ok(Ok(resp))
+    }
+
+    /// Sends a request built with [`Client::get`] or [`Client::post`] and parses the DSM envelope.
+    /// Failures that may pass are tried again, unless the body was streamed
+    /// and so can't be sent twice, like an upload's.
+    pub fn send(
+        &self,
+        api_name: &str,
+        method: &str,
+        mut request: reqwest::blocking::RequestBuilder,
+    ) -> Result<SynoResponse> {
+        if let Mode::Replay(replayer) = &self.mode {
+            return replayer.next(api_name, method);
+        }
+        let mut delay = RETRY_DELAY;
+        for _ in 1..ATTEMPTS {
+            let Some(again) = request.try_clone() else {
+                break;
+            };
+            match self.send_once(api_name, method, request) {
+                Err(e)
+                    if e.downcast_ref::<HttpError>()
+                        .is_some_and(HttpError::retryable) =>
+                {
+                    eprintln!("{e}; trying again in {}s", delay.as_secs());
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    request = again;
+                }
+                result => return result,
+            }
+        }
+        self.send_once(api_name, method, request)
+    }
+
+    fn send_once(
+        &self,
+        api_name: &str,
+        method: &str,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<SynoResponse> {
+        let request = request.build()?;
+        let http_method = request.method().to_string();
+        let url = request.url().clone();
+        let resp = self
+            .client
+            .execute(request)
+            .map_err(|e| HttpError::transport(api_name, method, e))?;
+        let status = resp.status().as_u16();
+        let text = resp
+            .text()
+            .map_err(|e| HttpError::transport(api_name, method, e))?;
+        if !(200..300).contains(&status) {
+            let kind = HttpErrorKind::Status(status);
+            return Err(HttpError::new(api_name, method, kind, snippet(&text)).into());
+        }
+        let invalid = || HttpError::new(api_name, method, HttpErrorKind::Body, snippet(&text));
+        let body = serde_json::from_str::<serde_json::Value>(&text).map_err(|_| invalid())?;
+        if let Mode::Record(recorder) = &self.mode {
+            recorder.record(Fixture {
+                api: api_name.to_string(),
+                method: method.to_string(),
+                http_method,
+                path: url.path().to_string(),
+                query: url
+                    .query_pairs()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                status,
+                response: body.clone(),
+            })?;
+        }
+        Ok(serde_json::from_value(body).map_err(|_| invalid())?)
+    }
+}
+
+/// One recorded API interaction, stored as a JSON file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    api: String,
+    method: String,
+    http_method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    status: u16,
+    response: serde_json::Value,
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    count: Cell<usize>,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> Result<Recorder> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create fixture directory {}", dir.display()))?;
+        Ok(Recorder {
+            dir,
+            count: Cell::new(0),
+        })
+    }
+
+    fn record(&self, mut fixture: Fixture) -> Result<()> {
+        for (k, v) in fixture.query.iter_mut() {
+            if REDACTED_KEYS.contains(&k.as_str()) {
+                *v = "REDACTED".into();
+            }
+        }
+        redact(&mut fixture.response);
+        let n = self.count.get();
+        self.count.set(n + 1);
+        let path = self
+            .dir
+            .join(format!("{:04}_{}_{}.json", n, fixture.api, fixture.method));
+        std::fs::write(&path, serde_json::to_string_pretty(&fixture)?)?;
+        Ok(())
+    }
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if REDACTED_KEYS.contains(&k.as_str()) {
+                    *v = "REDACTED".into();
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+pub struct Replayer {
+    fixtures: RefCell<HashMap<(String, String), VecDeque<Fixture>>>,
+}
+
+impl Replayer {
+    pub fn load(dir: PathBuf) -> Result<Replayer> {
+        let mut paths = std::fs::read_dir(&dir)
+            .with_context(|| format!("Could not read fixture directory {}", dir.display()))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|x| x == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut fixtures: HashMap<_, VecDeque<_>> = HashMap::new();
+        for path in paths {
+            let fixture = serde_json::from_str::<Fixture>(&std::fs::read_to_string(&path)?)
+                .with_context(|| format!("Invalid fixture {}", path.display()))?;
+            fixtures
+                .entry((fixture.api.clone(), fixture.method.clone()))
+                .or_default()
+                .push_back(fixture);
+        }
+        Ok(Replayer {
+            fixtures: RefCell::new(fixtures),
+        })
+    }
+
+    /// Interactions are replayed in recorded order per API and method.
+    fn next(&self, api_name: &str, method: &str) -> Result<SynoResponse> {
+        let fixture = self
+            .fixtures
+            .borrow_mut()
+            .get_mut(&(api_name.to_string(), method.to_string()))
+            .and_then(|x| x.pop_front())
+            .ok_or_else(|| anyhow!("No recorded response left for {api_name} {method}"))?;
+        Ok(serde_json::from_value(fixture.response)?)
+    }
+}
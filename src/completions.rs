@@ -0,0 +1,281 @@
+//! Shell completion scripts, generated from the tables in [`crate::cli`].
+//!
+//! Values named `JOB` are completed dynamically by calling the binary's hidden
+//! `__complete jobs` command, so the scripts always offer the job names of the
+//! config in the current directory.
+
+use crate::cli::{value_choices, CommandSpec, OptSpec, COMMANDS, GLOBAL_OPTIONS};
+
+const BIN: &str = "synology_backuper";
+
+fn visible_commands() -> impl Iterator<Item = &'static CommandSpec> {
+    COMMANDS.iter().filter(|c| !c.hidden)
+}
+
+fn all_options() -> impl Iterator<Item = &'static OptSpec> {
+    GLOBAL_OPTIONS
+        .iter()
+        .chain(COMMANDS.iter().flat_map(|c| c.options.iter()))
+}
+
+fn options_of(command: &CommandSpec) -> impl Iterator<Item = &'static OptSpec> + '_ {
+    GLOBAL_OPTIONS.iter().chain(command.options.iter())
+}
+
+/// Single-quotes `s` for POSIX shells and fish.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+pub fn generate(shell: &str) -> String {
+    match shell {
+        "bash" => bash(),
+        "zsh" => zsh(),
+        "fish" => fish(),
+        "powershell" => powershell(),
+        _ => unreachable!("shell names are validated by the parser"),
+    }
+}
+
+fn bash() -> String {
+    let commands = visible_commands().map(|c| c.name).collect::<Vec<_>>();
+    let mut value_cases = String::new();
+    for o in all_options() {
+        let Some(value) = o.value else { continue };
+        let action = match value {
+            "DIR" => r#"COMPREPLY=($(compgen -d -- "$cur"))"#.to_string(),
+            "JOB" => format!(
+                r#"COMPREPLY=($(compgen -W "$({BIN} __complete jobs 2>/dev/null)" -- "$cur"))"#
+            ),
+            v => match value_choices(v) {
+                Some(choices) => format!(
+                    r#"COMPREPLY=($(compgen -W "{}" -- "$cur"))"#,
+                    choices.join(" ")
+                ),
+                None => r#"COMPREPLY=($(compgen -f -- "$cur"))"#.to_string(),
+            },
+        };
+        value_cases += &format!("        --{}) {action}; return ;;\n", o.long);
+    }
+    let mut command_cases = String::new();
+    for c in visible_commands() {
+        let mut words = options_of(c)
+            .map(|o| format!("--{}", o.long))
+            .collect::<Vec<_>>();
+        if let Some(choices) = c.positional.and_then(value_choices) {
+            words.extend(choices.iter().map(|x| x.to_string()));
+        }
+        command_cases += &format!("        {}) opts=\"{}\" ;;\n", c.name, words.join(" "));
+    }
+    let top = commands
+        .iter()
+        .map(|x| x.to_string())
+        .chain(GLOBAL_OPTIONS.iter().map(|o| format!("--{}", o.long)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"_{BIN}() {{
+    local cur prev cmd opts w
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    cmd=""
+    for w in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        case "$w" in
+            {pattern}) cmd="$w"; break ;;
+        esac
+    done
+    case "$prev" in
+{value_cases}    esac
+    case "$cmd" in
+{command_cases}        *) opts="{top}" ;;
+    esac
+    COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+}}
+complete -F _{BIN} {BIN}
+"#,
+        pattern = commands.join("|"),
+    )
+}
+
+fn zsh_action(value: &str) -> String {
+    match value {
+        "DIR" => "_files -/".to_string(),
+        "JOB" => format!(r#"compadd -- ${{(f)"$({BIN} __complete jobs 2>/dev/null)"}}"#),
+        v => match value_choices(v) {
+            Some(choices) => format!("compadd -- {}", choices.join(" ")),
+            None => "_files".to_string(),
+        },
+    }
+}
+
+fn zsh_describe(words: impl Iterator<Item = (String, &'static str)>) -> String {
+    words
+        .map(|(word, about)| quote(&format!("{}:{}", word.replace(':', r"\:"), about)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn zsh() -> String {
+    let commands = visible_commands().map(|c| c.name).collect::<Vec<_>>();
+    let mut value_cases = String::new();
+    for o in all_options() {
+        if let Some(value) = o.value {
+            value_cases += &format!("    --{}) {}; return ;;\n", o.long, zsh_action(value));
+        }
+    }
+    let mut command_cases = String::new();
+    for c in visible_commands() {
+        let options = zsh_describe(options_of(c).map(|o| (format!("--{}", o.long), o.about)));
+        let positional = match c.positional {
+            Some(p) => format!("; {}", zsh_action(p)),
+            None => String::new(),
+        };
+        command_cases += &format!(
+            "    {}) opts=({options}); _describe 'option' opts{positional} ;;\n",
+            c.name
+        );
+    }
+    let top_commands = zsh_describe(visible_commands().map(|c| (c.name.to_string(), c.about)));
+    let top_options = zsh_describe(
+        GLOBAL_OPTIONS
+            .iter()
+            .map(|o| (format!("--{}", o.long), o.about)),
+    );
+    format!(
+        r#"#compdef {BIN}
+
+_{BIN}() {{
+  local cmd w
+  local -a opts commands
+  for w in ${{words[2,CURRENT-1]}}; do
+    case $w in
+      {pattern}) cmd=$w; break ;;
+    esac
+  done
+  case ${{words[CURRENT-1]}} in
+{value_cases}  esac
+  case $cmd in
+{command_cases}    *)
+      commands=({top_commands})
+      opts=({top_options})
+      _describe 'command' commands
+      _describe 'option' opts
+      ;;
+  esac
+}}
+
+compdef _{BIN} {BIN}
+"#,
+        pattern = commands.join("|"),
+    )
+}
+
+fn fish() -> String {
+    let mut out = format!("complete -c {BIN} -f\n");
+    for c in visible_commands() {
+        out += &format!(
+            "complete -c {BIN} -n __fish_use_subcommand -a {} -d {}\n",
+            c.name,
+            quote(c.about)
+        );
+        if let Some(choices) = c.positional.and_then(value_choices) {
+            out += &format!(
+                "complete -c {BIN} -n {} -a {}\n",
+                quote(&format!("__fish_seen_subcommand_from {}", c.name)),
+                quote(&choices.join(" "))
+            );
+        }
+    }
+    let scoped = GLOBAL_OPTIONS
+        .iter()
+        .map(|o| (None, o))
+        .chain(visible_commands().flat_map(|c| c.options.iter().map(move |o| (Some(c.name), o))));
+    for (command, o) in scoped {
+        let condition = command
+            .map(|c| format!(" -n {}", quote(&format!("__fish_seen_subcommand_from {c}"))))
+            .unwrap_or_default();
+        let value = match o.value {
+            None => String::new(),
+            Some("DIR") => " -r -a '(__fish_complete_directories)'".to_string(),
+            Some("JOB") => format!(" -x -a '({BIN} __complete jobs 2>/dev/null)'"),
+            Some(v) => match value_choices(v) {
+                Some(choices) => format!(" -x -a {}", quote(&choices.join(" "))),
+                None => " -r -F".to_string(),
+            },
+        };
+        out += &format!(
+            "complete -c {BIN}{condition} -l {}{value} -d {}\n",
+            o.long,
+            quote(o.about)
+        );
+    }
+    out
+}
+
+fn powershell() -> String {
+    let ps_list = |words: Vec<String>| {
+        words
+            .iter()
+            .map(|w| format!("'{}'", w.replace('\'', "''")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let commands = visible_commands()
+        .map(|c| c.name.to_string())
+        .collect::<Vec<_>>();
+    let mut command_cases = String::new();
+    for c in visible_commands() {
+        let mut words = options_of(c)
+            .map(|o| format!("--{}", o.long))
+            .collect::<Vec<_>>();
+        if let Some(choices) = c.positional.and_then(value_choices) {
+            words.extend(choices.iter().map(|x| x.to_string()));
+        }
+        command_cases += &format!("                '{}' {{ @({}) }}\n", c.name, ps_list(words));
+    }
+    let mut value_cases = String::new();
+    for o in all_options() {
+        let Some(value) = o.value else { continue };
+        let candidates = match value {
+            "JOB" => format!("@(& {BIN} __complete jobs 2>$null)"),
+            v => match value_choices(v) {
+                Some(choices) => format!(
+                    "@({})",
+                    ps_list(choices.iter().map(|x| x.to_string()).collect())
+                ),
+                // Fall back to PowerShell's own path completion.
+                None => "$null".to_string(),
+            },
+        };
+        value_cases += &format!("        '--{}' {{ {candidates} }}\n", o.long);
+    }
+    let top = commands
+        .iter()
+        .cloned()
+        .chain(GLOBAL_OPTIONS.iter().map(|o| format!("--{}", o.long)))
+        .collect::<Vec<_>>();
+    format!(
+        r#"Register-ArgumentCompleter -Native -CommandName '{BIN}' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})
+    $done = if ($wordToComplete) {{ $words.Count - 1 }} else {{ $words.Count }}
+    $prev = if ($done -ge 2) {{ $words[$done - 1] }} else {{ '' }}
+    $commands = @({commands})
+    $cmd = $words[1..([Math]::Max(1, $done - 1))] | Where-Object {{ $commands -contains $_ }} | Select-Object -First 1
+    $candidates = switch ($prev) {{
+{value_cases}        default {{
+            switch ($cmd) {{
+{command_cases}                default {{ @({top}) }}
+            }}
+        }}
+    }}
+    if ($null -eq $candidates) {{ return }}
+    $candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+        commands = ps_list(commands.clone()),
+        top = ps_list(top),
+    )
+}
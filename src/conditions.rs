@@ -0,0 +1,160 @@
+//! Whether a job may run now: inside its `windows` and outside its
+//! `blackout`, and, with `min_battery` or `skip_metered`, not on a battery
+//! below the job's threshold or online through a connection that is paid by
+//! the byte, like a phone's hotspot. What can't be found out counts as fine,
+//! so a desktop without a battery or a machine without NetworkManager backs
+//! up as always.
+
+use crate::config::Job;
+use crate::schedule;
+use chrono::{DateTime, Local};
+use std::process::Command;
+
+/// The state of the battery, for machines with one.
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    /// Charge left, in percent
+    pub percent: u8,
+    /// Running on it rather than on mains power
+    pub discharging: bool,
+}
+
+/// Why `job` should wait rather than run now, if it should.
+pub fn unmet(job: &Job) -> Option<String> {
+    let now = Local::now().naive_local();
+    if !schedule::allowed(&job.windows, &job.blackout, now) {
+        let list = |windows: &[schedule::Window]| {
+            windows
+                .iter()
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        return Some(match job.blackout.iter().find(|w| w.contains(now)) {
+            Some(window) => format!("in its blackout {window}"),
+            None => format!("outside its windows {}", list(&job.windows)),
+        });
+    }
+    if let Some(min) = job.min_battery {
+        if let Some(battery) = battery().filter(|b| b.discharging && b.percent < min) {
+            return Some(format!(
+                "on battery at {}%, below min_battery {min}%",
+                battery.percent
+            ));
+        }
+    }
+    if job.skip_metered && metered() {
+        return Some("on a metered connection and skip_metered is set".to_string());
+    }
+    None
+}
+
+/// The first time from `t` on that `job`'s windows allow it to run, if any.
+pub fn next_allowed(job: &Job, t: DateTime<Local>) -> Option<DateTime<Local>> {
+    schedule::next_allowed(&job.windows, &job.blackout, t)
+}
+
+/// The output of `program` with `args`, if it ran and succeeded.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The batteries in `/sys/class/power_supply`, taken together.
+#[cfg(target_os = "linux")]
+pub fn battery() -> Option<Battery> {
+    let read = |path: std::path::PathBuf| std::fs::read_to_string(path).ok();
+    let mut found = None::<Battery>;
+    for entry in std::fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+        let dir = entry.path();
+        if read(dir.join("type")).as_deref().map(str::trim) != Some("Battery") {
+            continue;
+        }
+        let Some(percent) = read(dir.join("capacity")).and_then(|c| c.trim().parse().ok()) else {
+            continue;
+        };
+        let discharging = read(dir.join("status")).as_deref().map(str::trim) == Some("Discharging");
+        found = Some(match found {
+            Some(b) => Battery {
+                percent: b.percent.min(percent),
+                discharging: b.discharging || discharging,
+            },
+            None => Battery {
+                percent,
+                discharging,
+            },
+        });
+    }
+    found
+}
+
+/// From `pmset -g batt`: `Now drawing from 'Battery Power'` and a line with the percentage.
+#[cfg(target_os = "macos")]
+pub fn battery() -> Option<Battery> {
+    let text = output("pmset", &["-g", "batt"])?;
+    let percent = text
+        .split_whitespace()
+        .find_map(|word| word.trim_end_matches(';').strip_suffix('%')?.parse().ok())?;
+    Some(Battery {
+        percent,
+        discharging: text.contains("'Battery Power'"),
+    })
+}
+
+/// From `Win32_Battery`, whose `BatteryStatus` is 2 on mains power.
+#[cfg(windows)]
+pub fn battery() -> Option<Battery> {
+    let text = output(
+        "powershell",
+        &[
+            "-NoProfile",
+            "-Command",
+            "Get-CimInstance Win32_Battery | ForEach-Object { \"$($_.BatteryStatus) $($_.EstimatedChargeRemaining)\" }",
+        ],
+    )?;
+    let (status, percent) = text.lines().next()?.trim().split_once(' ')?;
+    Some(Battery {
+        percent: percent.parse().ok()?,
+        discharging: status != "2",
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn battery() -> Option<Battery> {
+    None
+}
+
+/// Whether NetworkManager counts the connection as metered, for sure or by
+/// its guess (`Metered` 1 or 3).
+#[cfg(not(windows))]
+pub fn metered() -> bool {
+    output(
+        "busctl",
+        &[
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ],
+    )
+    .is_some_and(|text| matches!(text.trim(), "u 1" | "u 3"))
+}
+
+/// Whether the cost of the internet connection is `Fixed` or `Variable`,
+/// as Windows has it for a connection set as metered.
+#[cfg(windows)]
+pub fn metered() -> bool {
+    output(
+        "powershell",
+        &[
+            "-NoProfile",
+            "-Command",
+            "[void][Windows.Networking.Connectivity.NetworkInformation,Windows,ContentType=WindowsRuntime]; [Windows.Networking.Connectivity.NetworkInformation]::GetInternetConnectionProfile().GetConnectionCost().NetworkCostType",
+        ],
+    )
+    .is_some_and(|text| matches!(text.trim(), "Fixed" | "Variable"))
+}
@@ -0,0 +1,956 @@
+use crate::archive::{ArchiveOptions, Compression, UnicodeNames, STORED_EXTENSIONS};
+use crate::checksum::Algorithm;
+use crate::docker::Docker;
+use crate::limits::{ByteRate, ByteSize, HumanDuration, IoNice, Priority};
+use crate::pinning;
+use crate::preconditions::Precondition;
+use crate::schedule::{Schedule, Weekday, Window};
+use crate::snapshot::Snapshot;
+use crate::wake;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+/// Name of the credential holding the password when run under systemd's `LoadCredential=`.
+pub const PASSWORD_CREDENTIAL: &str = "synology_backuper_pwd";
+
+/// Name of the target made from the top-level connection settings.
+pub const PRIMARY_TARGET: &str = "primary";
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// The primary NAS
+    #[serde(flatten)]
+    pub nas: Connection,
+    /// Default share for jobs that don't name their own
+    pub share_name: Option<String>,
+    /// Share on the primary NAS to use when a job's share doesn't exist there
+    pub fallback_share: Option<String>,
+    /// The primary NAS keeps its files write-once, see [`Target::worm`]
+    #[serde(default)]
+    pub worm: bool,
+    /// Shorthand for a single job named `default`
+    pub filename: Option<String>,
+    /// Further NAS jobs can upload to; the primary one is inserted first as `primary`
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+    /// Leave the remaining jobs of a run alone once one has failed
+    #[serde(default)]
+    pub stop_on_error: bool,
+    /// Leave the remaining jobs of a run alone once a target has refused the login
+    #[serde(default)]
+    pub stop_on_auth_failure: bool,
+    /// Log in to each target once for all the jobs of a run, rather than once per job
+    #[serde(default = "default_reuse_session")]
+    pub reuse_session: bool,
+    /// How often the daemon audits the uploads, e.g. `"24h"`
+    pub audit_interval: Option<HumanDuration>,
+    /// JSON Lines file each run is appended to; `runs.jsonl` in the state directory by default
+    pub run_log: Option<String>,
+    /// Where the machines backing up to the same NAS share their run logs
+    pub fleet: Option<Fleet>,
+    /// Where each run uploads an encrypted copy of the config and state
+    pub self_backup: Option<SelfBackup>,
+    /// The file this was read from
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// A folder on a target the run logs of several machines are shared in, see [`crate::fleet`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fleet {
+    /// Like `/backup/synology_backuper`
+    pub folder: String,
+    #[serde(default = "default_fleet_target")]
+    pub target: String,
+    /// This machine's name there; the host name by default
+    pub host: Option<String>,
+}
+
+/// A folder on a target each run puts a sealed bundle of the config, the run
+/// log, the catalog and the other state in, see [`crate::recovery::upload`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct SelfBackup {
+    /// Like `/backup/synology_backuper`
+    pub folder: String,
+    #[serde(default = "default_fleet_target")]
+    pub target: String,
+    /// File holding a passphrase to seal the bundle with instead of the
+    /// current key, so that it can hold the keyring as well
+    pub passphrase_file: Option<String>,
+}
+
+fn default_fleet_target() -> String {
+    "primary".to_string()
+}
+
+/// How to reach and log in to one NAS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Connection {
+    /// Host name of the NAS; not needed for the `local` transport, like `port` and `usr`
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default)]
+    pub usr: String,
+    /// Password; may be left out in favour of `pwd_file` or a systemd credential
+    #[serde(default)]
+    pub pwd: String,
+    pub pwd_file: Option<String>,
+    /// Talk plain HTTP instead of HTTPS, e.g. for DSM's port 5000 or a test server
+    #[serde(default = "default_https")]
+    pub https: bool,
+    /// How archives reach this NAS; may also be written `type`
+    #[serde(default, alias = "type")]
+    pub transport: Transport,
+    /// SSH key for the `sftp` transport, instead of the default keys and agent
+    pub identity_file: Option<String>,
+    /// Whether the `sftp` transport sizes its requests by the throughput it
+    /// measures, as it does by default
+    pub adaptive_chunks: Option<bool>,
+    /// Folder of the `local` transport, whose subfolders are its shares
+    pub path: Option<String>,
+    /// Shares to use as they are instead of asking DSM, for accounts that
+    /// may upload but not list the shares
+    #[serde(default)]
+    pub shares: Vec<String>,
+    /// Addresses to use for host names instead of asking DNS, like curl's
+    /// `--resolve`, so `domain` can be the name on the certificate
+    #[serde(default)]
+    pub resolve: BTreeMap<String, IpAddr>,
+    /// Keys to trust the NAS's certificate by instead of a CA, as base64
+    /// SHA-256 hashes of its SubjectPublicKeyInfo
+    #[serde(default)]
+    pub pin_sha256: Vec<String>,
+    /// Sent instead of `synology_backuper/<version>`, to tell the program's
+    /// requests apart in DSM's logs or a proxy's
+    pub user_agent: Option<String>,
+    /// Further headers sent with every request, such as a proxy's credentials
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// MAC address to send a Wake-on-LAN packet to when the NAS doesn't answer
+    pub wake_mac: Option<String>,
+    /// Where that packet goes, like the LAN's broadcast address `192.168.1.255:9`
+    pub wake_address: Option<SocketAddr>,
+    /// How long the NAS may take to answer once woken
+    #[serde(default = "default_wake_timeout")]
+    pub wake_timeout: HumanDuration,
+    /// How long a FileStation background task, like a copy or a delete,
+    /// may run before it is stopped and taken for failed
+    #[serde(default = "default_task_timeout")]
+    pub task_timeout: HumanDuration,
+    /// Shut the NAS down again after a run that had to wake it
+    #[serde(default)]
+    pub shutdown_after_wake: bool,
+    /// Shut the NAS down after every run that used it and succeeded, for an
+    /// offsite NAS that should only be online for the backups
+    #[serde(default)]
+    pub shutdown_after_success: bool,
+}
+
+impl Connection {
+    /// The host to resolve or connect to, an IPv6 address without the
+    /// brackets it may be written in.
+    pub fn host(&self) -> &str {
+        self.domain.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    /// The host as it goes into a URL or an SSH destination, where an IPv6
+    /// address needs brackets to keep its colons apart from the port's.
+    pub fn url_host(&self) -> String {
+        match self.host() {
+            host if host.contains(':') => format!("[{host}]"),
+            host => host.to_string(),
+        }
+    }
+
+    /// The addresses to connect to: the one `resolve` gives for the host, or
+    /// else all those DNS knows.
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>> {
+        if let Some(ip) = self.resolve.get(self.host()) {
+            return Ok(vec![SocketAddr::new(*ip, self.port)]);
+        }
+        Ok((self.host(), self.port).to_socket_addrs()?.collect())
+    }
+
+    /// `scheme://host:port` of the NAS's web server.
+    pub fn origin(&self) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        format!("{scheme}://{}:{}", self.url_host(), self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// DSM's web API (FileStation)
+    #[default]
+    Api,
+    /// HTTP PUT to DSM's WebDAV server, for NAS that only expose that
+    Webdav,
+    /// The system's `sftp`, logging in with SSH keys
+    Sftp,
+    /// A folder on this machine
+    Local,
+}
+
+impl Transport {
+    /// Whether logging in takes `pwd`; SFTP uses keys and a local folder nothing.
+    pub fn needs_password(self) -> bool {
+        matches!(self, Transport::Api | Transport::Webdav)
+    }
+}
+
+/// A NAS a job can upload to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target {
+    pub name: String,
+    #[serde(flatten)]
+    pub nas: Connection,
+    /// Share on this NAS; jobs' own `share_name` is used if left out
+    pub share_name: Option<String>,
+    /// Share whose folder named after a missing share takes that share's place
+    pub fallback_share: Option<String>,
+    /// The shares keep their files write-once (WORM), like with DSM's
+    /// WriteOnce, so nothing there is replaced or deleted
+    #[serde(default)]
+    pub worm: bool,
+}
+
+/// One thing to back up and where it goes.
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    pub name: String,
+    pub filename: String,
+    share_name: Option<String>,
+    /// When the system scheduler should run the job
+    pub schedule: Option<Schedule>,
+    /// `"nfc"` to normalize zip entry names, `"raw"` to keep them as the filesystem has them
+    #[serde(default)]
+    pub unicode_names: UnicodeNames,
+    /// How often to re-read a file that changed while it was archived
+    #[serde(default)]
+    pub changed_file_retries: u32,
+    /// How many directory levels below `filename` to archive; unlimited if left out
+    pub max_depth: Option<usize>,
+    /// Threads reading and compressing files at the same time
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    /// Upper bound on files and directories the archiver holds open at once
+    #[serde(default = "default_max_open_files")]
+    pub max_open_files: usize,
+    /// Stay on the filesystem `filename` is on, skipping mounts below it
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// Globs of files and directories to leave out, like `"*.tmp"` or `"cache/**"`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Files larger than this are left out
+    pub max_file_size: Option<ByteSize>,
+    /// Fail rather than upload an archive of fewer files than this
+    pub min_files: Option<u64>,
+    /// Fail rather than upload an archive of files smaller than this together
+    pub min_size: Option<ByteSize>,
+    /// A file below `filename` that must be there, or the job fails before
+    /// archiving, like a sentinel on a disk that must be mounted
+    pub require_marker_file: Option<String>,
+    /// What else must hold before the job archives, checked in order
+    #[serde(default)]
+    pub preconditions: Vec<Precondition>,
+    /// A filesystem snapshot to archive from, for files as they were at one moment
+    pub snapshot: Option<Snapshot>,
+    /// Containers and volumes to archive along with `filename`
+    pub docker: Option<Docker>,
+    /// Wait while the machine runs on its battery with less charge than
+    /// this, in percent
+    pub min_battery: Option<u8>,
+    /// Wait while the machine is online through a metered connection
+    #[serde(default)]
+    pub skip_metered: bool,
+    /// When the job may run, if not at any time
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    /// When the job may not run
+    #[serde(default)]
+    pub blackout: Vec<Window>,
+    /// Start up to this much later than the `schedule` says, at random, so
+    /// that machines on the same schedule don't all reach the NAS at once
+    pub jitter: Option<HumanDuration>,
+    /// How often an upload is tried again when the NAS says it is too busy
+    #[serde(default = "default_busy_retries")]
+    pub busy_retries: u32,
+    /// How long to wait before the first of those, doubled for each next one
+    #[serde(default = "default_busy_delay")]
+    pub busy_delay: HumanDuration,
+    /// When the NAS stays too busy, keep the archive in the queue instead of
+    /// failing, for the daemon to try again this much later
+    pub busy_reschedule: Option<HumanDuration>,
+    /// How the other files are compressed, like `"zstd:3"`
+    #[serde(default)]
+    pub compression: Compression,
+    /// Extensions of files stored without compression, as they are compressed already
+    #[serde(default = "default_store_extensions")]
+    pub store_extensions: Vec<String>,
+    /// End the archive with a manifest of the checksums of its files
+    #[serde(default)]
+    pub manifest: bool,
+    /// `"blake3"` or `"sha256"`, for the manifest
+    #[serde(default)]
+    pub checksum: Algorithm,
+    /// Store a file with several hard links in the tree once
+    #[serde(default)]
+    pub dedup_hardlinks: bool,
+    /// Store files with the same contents once, which implies `manifest`
+    #[serde(default)]
+    pub dedup_contents: bool,
+    /// Keep extended attributes and ACLs in the archive, and restore them
+    #[serde(default)]
+    pub xattrs: bool,
+    /// On Windows, list files with alternate data streams, which aren't kept
+    #[serde(default)]
+    pub ads_warnings: bool,
+    /// The weekday of the full archive; on other days, only what changed since
+    pub differential: Option<Weekday>,
+    /// With `differential`, make the full archive on the NAS from a copy of
+    /// the last one after this many differentials, and on its weekday
+    pub synthetic_full: Option<usize>,
+    /// Niceness (0 to 19) for archiving, like `nice -n`
+    pub nice: Option<i32>,
+    /// IO class for archiving, like `ionice`: `"idle"` or `"best-effort 0"` to `"best-effort 7"`
+    pub ionice: Option<IoNice>,
+    /// Upload bandwidth cap such as `"2MiB"` per second
+    pub upload_rate_limit: Option<ByteRate>,
+    /// Stop the job when it runs longer than this, e.g. `"2h"`
+    pub max_duration: Option<HumanDuration>,
+    /// Names of the targets to upload to, in order of preference; a job
+    /// with its own `usr` gets its own copy of each, named `target@usr`
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Account to log in to the targets as instead of theirs, like a
+    /// write-only user for each machine
+    pub usr: Option<String>,
+    pwd: Option<String>,
+    pwd_file: Option<String>,
+    /// Upload to every reachable target instead of only the first
+    #[serde(default)]
+    pub mirror: bool,
+    /// Folders on the NAS, like `/backup2/archives`, that the uploaded archive is copied into
+    #[serde(default)]
+    pub copies: Vec<String>,
+    /// `prune` keeps at least this many of the newest archives
+    pub keep_last: Option<usize>,
+    /// `prune` keeps every archive younger than this, e.g. `"30d"`
+    pub keep_within: Option<HumanDuration>,
+    /// `prune` keeps the newest archive of each of this many days that have one
+    pub keep_daily: Option<usize>,
+    /// Likewise per ISO week
+    pub keep_weekly: Option<usize>,
+    /// Likewise per calendar month
+    pub keep_monthly: Option<usize>,
+    /// Likewise per calendar year
+    pub keep_yearly: Option<usize>,
+    /// `prune` keeps every archive made with `--tag`
+    #[serde(default)]
+    pub keep_tagged: bool,
+    /// `prune` deletes the oldest archives until the rest fit in this, e.g. `"500GB"`
+    pub max_total_size: Option<ByteSize>,
+    /// Read each upload back and compare it with the archive
+    #[serde(default)]
+    pub verify: bool,
+    /// Only ever upload, under names not taken yet: never list, delete or
+    /// replace anything on the targets, so that whoever takes over this
+    /// machine can't destroy the backups with it
+    #[serde(default)]
+    pub write_only: bool,
+    /// Keep an archive that reached no target for the next run to upload
+    #[serde(default = "default_queue")]
+    pub queue: bool,
+    /// Warn when an archive is this many percent smaller or larger than the
+    /// average of the job's recent ones; 0 never warns
+    #[serde(default = "default_size_anomaly")]
+    pub size_anomaly: u32,
+    /// Jobs that must succeed before this one runs, like a database dump
+    /// before the archive of the folder it is written to
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Shell command run after the job uploaded its archive
+    pub on_success: Option<String>,
+    /// Shell command run after the job failed, timed out or was skipped
+    pub on_failure: Option<String>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Job {
+            name: String::new(),
+            filename: String::new(),
+            share_name: None,
+            schedule: None,
+            unicode_names: UnicodeNames::default(),
+            changed_file_retries: 0,
+            max_depth: None,
+            parallelism: default_parallelism(),
+            max_open_files: default_max_open_files(),
+            one_file_system: false,
+            exclude: Vec::new(),
+            max_file_size: None,
+            min_files: None,
+            min_size: None,
+            require_marker_file: None,
+            preconditions: Vec::new(),
+            snapshot: None,
+            docker: None,
+            min_battery: None,
+            skip_metered: false,
+            windows: Vec::new(),
+            blackout: Vec::new(),
+            jitter: None,
+            busy_retries: default_busy_retries(),
+            busy_delay: default_busy_delay(),
+            busy_reschedule: None,
+            compression: Compression::default(),
+            store_extensions: default_store_extensions(),
+            manifest: false,
+            dedup_hardlinks: false,
+            dedup_contents: false,
+            xattrs: false,
+            ads_warnings: false,
+            differential: None,
+            synthetic_full: None,
+            checksum: Algorithm::default(),
+            nice: None,
+            ionice: None,
+            upload_rate_limit: None,
+            max_duration: None,
+            targets: Vec::new(),
+            usr: None,
+            pwd: None,
+            pwd_file: None,
+            mirror: false,
+            copies: Vec::new(),
+            keep_last: None,
+            keep_within: None,
+            keep_daily: None,
+            keep_weekly: None,
+            keep_monthly: None,
+            keep_yearly: None,
+            keep_tagged: false,
+            max_total_size: None,
+            verify: false,
+            write_only: false,
+            queue: true,
+            size_anomaly: default_size_anomaly(),
+            after: Vec::new(),
+            on_success: None,
+            on_failure: None,
+        }
+    }
+}
+
+impl Job {
+    /// Whether any setting gives `prune` something to go by.
+    pub fn has_retention(&self) -> bool {
+        self.has_keep_rules() || self.max_total_size.is_some()
+    }
+
+    /// Whether a `keep_*` setting says which backups to keep.
+    pub fn has_keep_rules(&self) -> bool {
+        self.keep_last.is_some()
+            || self.keep_within.is_some()
+            || [
+                self.keep_daily,
+                self.keep_weekly,
+                self.keep_monthly,
+                self.keep_yearly,
+            ]
+            .iter()
+            .any(Option::is_some)
+    }
+
+    /// The share the job uploads to on `target`.
+    pub fn share_name<'a>(&'a self, target: &'a Target) -> &'a str {
+        target
+            .share_name
+            .as_deref()
+            .or(self.share_name.as_deref())
+            .expect("share names are checked when the config is loaded")
+    }
+
+    pub fn priority(&self) -> Priority {
+        Priority {
+            nice: self.nice,
+            ionice: self.ionice,
+        }
+    }
+
+    pub fn archive_options(&self) -> ArchiveOptions {
+        ArchiveOptions {
+            unicode_names: self.unicode_names,
+            changed_file_retries: self.changed_file_retries,
+            max_depth: self.max_depth,
+            parallelism: self.parallelism,
+            max_open_files: self.max_open_files,
+            one_file_system: self.one_file_system,
+            compression: self.compression,
+            store_extensions: self.store_extensions.clone(),
+            manifest: (self.manifest || self.dedup_contents).then_some(self.checksum),
+            dedup_hardlinks: self.dedup_hardlinks,
+            dedup_contents: self.dedup_contents,
+            xattrs: self.xattrs,
+            ads_warnings: self.ads_warnings,
+            index: self.differential.is_some() || self.manifest || self.dedup_contents,
+            base: None,
+            volumes: None,
+            deterministic: false,
+            exclude: self.exclude.clone(),
+            max_file_size: self.max_file_size.map(|x| x.0),
+            deadline: None,
+            read_from: None,
+            also: Vec::new(),
+            docker: None,
+        }
+    }
+}
+
+/// The job `name` as it can go into task names, launchd labels and the names
+/// of snapshots. Not one-to-one, so names that come out the same are refused
+/// when the config is loaded.
+pub fn safe_name(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// `jobs` reordered so each comes after the jobs it names in `after`, and
+/// otherwise in the order given. Prerequisites outside `jobs` are ignored.
+pub fn run_order<'a>(jobs: &[&'a Job]) -> Result<Vec<&'a Job>> {
+    let mut pending = jobs.to_vec();
+    let mut ordered = Vec::with_capacity(jobs.len());
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|job| {
+            job.after
+                .iter()
+                .all(|name| !pending.iter().any(|x| &x.name == name))
+        });
+        match ready {
+            Some(i) => ordered.push(pending.remove(i)),
+            None => {
+                let names = pending.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "Jobs {} wait for each other in a cycle of `after`",
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+fn default_https() -> bool {
+    true
+}
+
+fn default_queue() -> bool {
+    true
+}
+
+fn default_busy_retries() -> u32 {
+    3
+}
+
+fn default_wake_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(300))
+}
+
+fn default_task_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(60 * 60))
+}
+
+fn default_busy_delay() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(60))
+}
+
+fn default_size_anomaly() -> u32 {
+    40
+}
+
+fn default_reuse_session() -> bool {
+    true
+}
+
+fn default_parallelism() -> usize {
+    1
+}
+
+fn default_max_open_files() -> usize {
+    64
+}
+
+fn default_store_extensions() -> Vec<String> {
+    STORED_EXTENSIONS.iter().map(|x| x.to_string()).collect()
+}
+
+/// Loads the config, with the overrides of `profile` laid over the shared settings.
+pub fn load_config(path: &Path, profile: Option<&str>) -> Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read config file {}", path.display()))?;
+    let display = path.display();
+    let mut value = serde_json::from_str::<serde_json::Value>(&text)
+        .with_context(|| format!("Could not parse config file {display}"))?;
+    crate::schema::check_fields(&value)
+        .with_context(|| format!("Invalid config file {display}"))?;
+    let profiles = value.as_object_mut().and_then(|x| x.remove("profiles"));
+    if let Some(name) = profile {
+        let mut profiles = match profiles {
+            Some(serde_json::Value::Object(profiles)) => profiles,
+            _ => return Err(anyhow!("{display} defines no profiles")),
+        };
+        let overrides = profiles.remove(name).ok_or_else(|| {
+            let names = profiles.keys().cloned().collect::<Vec<_>>();
+            anyhow!(
+                "{display} has no profile {name}; it has {}",
+                names.join(", ")
+            )
+        })?;
+        merge(&mut value, overrides);
+    }
+    let mut config = serde_json::from_value::<Config>(value)
+        .with_context(|| format!("Could not parse config file {display}"))?;
+
+    if let Some(filename) = config.filename.take() {
+        config.jobs.insert(
+            0,
+            Job {
+                name: "default".into(),
+                filename,
+                ..Default::default()
+            },
+        );
+    }
+    if config.nas.pwd.is_empty() && config.nas.transport.needs_password() {
+        config.nas.pwd = read_password(config.nas.pwd_file.as_deref())?;
+    }
+    for target in config.targets.iter_mut() {
+        if target.nas.pwd.is_empty() && target.nas.transport.needs_password() {
+            let pwd_file = target.nas.pwd_file.as_deref().ok_or_else(|| {
+                anyhow!("Target {} has neither `pwd` nor `pwd_file`", target.name)
+            })?;
+            target.nas.pwd = read_password(Some(pwd_file))?;
+        }
+    }
+    config.targets.insert(
+        0,
+        Target {
+            name: PRIMARY_TARGET.into(),
+            nas: config.nas.clone(),
+            share_name: None,
+            fallback_share: config.fallback_share.clone(),
+            worm: config.worm,
+        },
+    );
+    for (i, target) in config.targets.iter().enumerate() {
+        if config.targets[..i].iter().any(|x| x.name == target.name) {
+            return Err(anyhow!("Target name {} is used twice", target.name));
+        }
+        check_connection(&target.name, &target.nas)?;
+    }
+    if config.jobs.is_empty() {
+        return Err(anyhow!(
+            "The config defines no jobs; set `filename` or `jobs`"
+        ));
+    }
+    for job in config.jobs.iter_mut() {
+        if job.share_name.is_none() {
+            job.share_name = config.share_name.clone();
+        }
+        if job.keep_last == Some(0) {
+            return Err(anyhow!(
+                "Job {} has keep_last 0, which would let prune delete every archive",
+                job.name
+            ));
+        }
+        if job.write_only && job.has_retention() {
+            return Err(anyhow!(
+                "Job {} is write_only, so its archives are never pruned; take out its keep_* and max_total_size settings",
+                job.name
+            ));
+        }
+        if job.targets.is_empty() {
+            job.targets.push(PRIMARY_TARGET.into());
+        }
+        for name in &job.targets {
+            let target = config
+                .targets
+                .iter()
+                .find(|t| &t.name == name)
+                .ok_or_else(|| anyhow!("Job {} uploads to unknown target {name}", job.name))?;
+            if job.share_name.is_none() && target.share_name.is_none() {
+                return Err(anyhow!(
+                    "Job {} has no share_name for target {name}",
+                    job.name
+                ));
+            }
+            if job.write_only && target.nas.transport == Transport::Sftp {
+                return Err(anyhow!(
+                    "Job {} is write_only, but target {name} uses sftp, which can't upload without replacing a file of the same name",
+                    job.name
+                ));
+            }
+        }
+        if let Some(usr) = job.usr.clone() {
+            job.targets = job_targets(job, &usr, &mut config.targets)?;
+        }
+    }
+    for (i, job) in config.jobs.iter().enumerate() {
+        if config.jobs[..i].iter().any(|x| x.name == job.name) {
+            return Err(anyhow!("Job name {} is used twice", job.name));
+        }
+        let safe = safe_name(&job.name);
+        if let Some(other) = config.jobs[..i].iter().find(|x| safe_name(&x.name) == safe) {
+            return Err(anyhow!(
+                "Jobs {} and {} both come out as {safe} in the names of scheduled tasks and snapshots",
+                other.name,
+                job.name
+            ));
+        }
+        for name in &job.after {
+            if !config.jobs.iter().any(|x| &x.name == name) {
+                return Err(anyhow!("Job {} runs after unknown job {name}", job.name));
+            }
+        }
+    }
+    for job in &config.jobs {
+        if job.synthetic_full.is_some() && job.differential.is_none() {
+            return Err(anyhow!(
+                "Job {} has `synthetic_full` but no `differential`, which it builds on",
+                job.name
+            ));
+        }
+        if let Some(snapshot) = &job.snapshot {
+            snapshot
+                .check(&job.filename)
+                .with_context(|| format!("Job {} can't have its snapshot", job.name))?;
+        }
+        if let Some(docker) = &job.docker {
+            if docker.containers.is_empty() && docker.volumes.is_empty() {
+                return Err(anyhow!(
+                    "Job {} has `docker` without containers or volumes to back up",
+                    job.name
+                ));
+            }
+        }
+    }
+    // Archives are told apart by their names alone, so pruning one of two
+    // jobs whose archives are named alike in one share would count, and
+    // delete, the other's too.
+    for (i, job) in config.jobs.iter().enumerate() {
+        for other in &config.jobs[i + 1..] {
+            if !(job.has_retention() || other.has_retention())
+                || crate::backup_prefix(job) != crate::backup_prefix(other)
+            {
+                continue;
+            }
+            let shared = config.targets.iter().find(|t| {
+                job.targets.contains(&t.name)
+                    && other.targets.contains(&t.name)
+                    && job.share_name(t) == other.share_name(t)
+            });
+            if let Some(target) = shared {
+                return Err(anyhow!(
+                    "Jobs {} and {} both name their archives {}* in share {} on target {}, so pruning one would delete the other's; give one a `share_name` of its own",
+                    job.name,
+                    other.name,
+                    crate::backup_prefix(job),
+                    job.share_name(target),
+                    target.name
+                ));
+            }
+        }
+    }
+    if let Some(fleet) = &config.fleet {
+        if !config.targets.iter().any(|t| t.name == fleet.target) {
+            return Err(anyhow!(
+                "`fleet` shares the run logs on unknown target {}",
+                fleet.target
+            ));
+        }
+    }
+    if let Some(self_backup) = &config.self_backup {
+        if !config.targets.iter().any(|t| t.name == self_backup.target) {
+            return Err(anyhow!(
+                "`self_backup` uploads to unknown target {}",
+                self_backup.target
+            ));
+        }
+    }
+    run_order(&config.jobs.iter().collect::<Vec<_>>())?;
+    config.path = crate::paths::absolute(path);
+    Ok(config)
+}
+
+/// The names of the copies of `job`'s targets that log in as `usr`, each
+/// added to `targets` the first time a job asks for it, so that jobs with
+/// the same account share a session and others have their own.
+fn job_targets(job: &mut Job, usr: &str, targets: &mut Vec<Target>) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for name in &job.targets {
+        let own = format!("{name}@{usr}");
+        if let Some(existing) = targets.iter().find(|t| t.name == own) {
+            if existing.nas.usr != usr {
+                return Err(anyhow!(
+                    "Job {} would log in to {name} as target {own}, but a target of that name logs in as {}",
+                    job.name,
+                    existing.nas.usr
+                ));
+            }
+            names.push(own);
+            continue;
+        }
+        let base = targets.iter().find(|t| &t.name == name).unwrap();
+        if base.nas.transport == Transport::Local {
+            return Err(anyhow!(
+                "Job {} has `usr`, but target {name} is a local folder, which no one logs in to",
+                job.name
+            ));
+        }
+        let mut target = base.clone();
+        target.name = own.clone();
+        target.nas.usr = usr.to_string();
+        target.nas.pwd_file = job.pwd_file.clone();
+        target.nas.pwd = match (&job.pwd, target.nas.transport.needs_password()) {
+            (_, false) => String::new(),
+            (Some(pwd), true) => pwd.clone(),
+            (None, true) => {
+                let pwd_file = job.pwd_file.as_deref().ok_or_else(|| {
+                    anyhow!(
+                        "Job {} has `usr` but neither `pwd` nor `pwd_file`",
+                        job.name
+                    )
+                })?;
+                read_password(Some(pwd_file))?
+            }
+        };
+        targets.push(target);
+        names.push(own);
+    }
+    Ok(names)
+}
+
+/// Lays `over` onto `base`: objects are merged key by key, lists of objects
+/// with a `name` (jobs, targets) are merged by name, and anything else in
+/// `over` replaces what `base` has.
+fn merge(base: &mut serde_json::Value, over: serde_json::Value) {
+    use serde_json::Value;
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(over))
+            if !over.is_empty() && over.iter().all(|x| x.get("name").is_some()) =>
+        {
+            for item in over {
+                match base.iter_mut().find(|x| x.get("name") == item.get("name")) {
+                    Some(existing) => merge(existing, item),
+                    None => base.push(item),
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Every transport but `local` needs to know where the NAS is and whom to log in as.
+fn check_connection(name: &str, nas: &Connection) -> Result<()> {
+    if nas.transport == Transport::Local {
+        if nas.path.is_none() {
+            return Err(anyhow!("Target {name} is local but has no `path`"));
+        }
+        return Ok(());
+    }
+    for (field, missing) in [
+        ("domain", nas.domain.is_empty()),
+        ("port", nas.port == 0),
+        ("usr", nas.usr.is_empty()),
+    ] {
+        if missing {
+            return Err(anyhow!("Target {name} has no `{field}`"));
+        }
+    }
+    if let Some(pin) = nas.pin_sha256.iter().find(|p| pinning::parse(p).is_none()) {
+        return Err(anyhow!(
+            "Target {name} pins {pin}, which is not a base64 SHA-256 hash"
+        ));
+    }
+    for (header, value) in &nas.headers {
+        if reqwest::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+            return Err(anyhow!(
+                "Target {name} has a header {header:?}, which is not a valid name"
+            ));
+        }
+        if reqwest::header::HeaderValue::from_str(value).is_err() {
+            return Err(anyhow!(
+                "Target {name} has a value for header {header} that can't be sent"
+            ));
+        }
+    }
+    let user_agent = nas.user_agent.as_deref().unwrap_or_default();
+    if reqwest::header::HeaderValue::from_str(user_agent).is_err() {
+        return Err(anyhow!(
+            "Target {name} has a `user_agent` that can't be sent"
+        ));
+    }
+    if let Some(mac) = nas.wake_mac.as_deref() {
+        if wake::parse_mac(mac).is_none() {
+            return Err(anyhow!(
+                "Target {name} has a `wake_mac` {mac:?}, which is not a MAC address like 00:11:32:aa:bb:cc"
+            ));
+        }
+    }
+    for (field, set) in [
+        ("shutdown_after_wake", nas.shutdown_after_wake),
+        ("shutdown_after_success", nas.shutdown_after_success),
+    ] {
+        if set && nas.transport != Transport::Api {
+            return Err(anyhow!(
+                "Target {name} has `{field}`, which needs the DSM web API"
+            ));
+        }
+    }
+    if nas.adaptive_chunks.is_some() && nas.transport != Transport::Sftp {
+        return Err(anyhow!(
+            "Target {name} has `adaptive_chunks`, which only the sftp transport has; the web API and WebDAV send an archive in a single request"
+        ));
+    }
+    if !nas.pin_sha256.is_empty() && !nas.https {
+        return Err(anyhow!("Target {name} has `pin_sha256` but not `https`"));
+    }
+    Ok(())
+}
+
+fn read_password(pwd_file: Option<&str>) -> Result<String> {
+    let path = match (pwd_file, std::env::var_os("CREDENTIALS_DIRECTORY")) {
+        (Some(path), _) => std::path::PathBuf::from(path),
+        (None, Some(dir)) => std::path::Path::new(&dir).join(PASSWORD_CREDENTIAL),
+        (None, None) => return Err(anyhow!("The config has neither `pwd` nor `pwd_file`")),
+    };
+    let pwd = std::fs::read_to_string(&path)
+        .with_context(|| format!("Could not read the password from {}", path.display()))?;
+    Ok(pwd.trim_end_matches(['\r', '\n']).to_string())
+}
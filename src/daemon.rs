@@ -0,0 +1,186 @@
+//! The `daemon` command: runs each job at its `schedule` and audits the
+//! targets every `audit_interval`, for machines whose scheduler can't be used.
+//! Archives left in the queue by a NAS too busy to take them are uploaded
+//! `busy_reschedule` later.
+
+use crate::client::Mode;
+use crate::config::{self, Job};
+use crate::schedule::Schedule;
+use crate::{audit, conditions, flush_queue, queue, run_jobs, Config, RunOptions};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use rand::Rng;
+use std::time::Duration;
+
+/// How soon a job waiting for mains power, another connection or its
+/// window is looked at again, at the most
+const RETRY: Duration = Duration::from_secs(10 * 60);
+
+/// When `job` next starts after `t`: when its schedule next fires, plus a
+/// random part of its `jitter`.
+fn next_start(job: &Job, schedule: Schedule, t: DateTime<Local>) -> DateTime<Local> {
+    let at = schedule.next_after(t);
+    match job.jitter.map(|j| j.0.as_secs()).filter(|&j| j > 0) {
+        Some(jitter) => at + Duration::from_secs(rand::thread_rng().gen_range(0..jitter)),
+        None => at,
+    }
+}
+
+pub fn run(config: &Config, mode: Mode) -> Result<()> {
+    if !matches!(mode, Mode::Live) {
+        return Err(anyhow!("The daemon doesn't work with --record or --replay"));
+    }
+    let scheduled = config
+        .jobs
+        .iter()
+        .filter_map(|j| j.schedule.map(|s| (j, s)))
+        .collect::<Vec<(&Job, Schedule)>>();
+    let audit_interval = config
+        .audit_interval
+        .map(|i| chrono::Duration::from_std(i.0))
+        .transpose()?;
+    if scheduled.is_empty() && audit_interval.is_none() {
+        return Err(anyhow!(
+            "No job has a `schedule` and there is no `audit_interval`, so the daemon has nothing to do"
+        ));
+    }
+
+    let start = Local::now();
+    let mut next = scheduled
+        .iter()
+        .map(|(job, s)| next_start(job, *s, start))
+        .collect::<Vec<DateTime<Local>>>();
+    let mut next_audit = audit_interval.map(|i| start + i);
+    // When to upload what the queue holds of each job, after the NAS was busy
+    let mut next_flush = scheduled.iter().map(|_| None).collect::<Vec<_>>();
+    eprintln!(
+        "Daemon started with {} scheduled jobs{}",
+        scheduled.len(),
+        match config.audit_interval {
+            Some(i) => format!(", auditing every {i}"),
+            None => String::new(),
+        }
+    );
+    loop {
+        let wake = next
+            .iter()
+            .copied()
+            .chain(next_audit)
+            .chain(next_flush.iter().flatten().copied())
+            .min()
+            .unwrap();
+        let now = Local::now();
+        if now < wake {
+            // Sleep in short steps, so a changed clock or a suspend is noticed.
+            let left = (wake - now).to_std().unwrap_or_default();
+            std::thread::sleep(left.min(Duration::from_secs(60)));
+            continue;
+        }
+
+        let due = scheduled
+            .iter()
+            .zip(&next)
+            .filter(|(_, at)| **at <= now)
+            .map(|((job, _), _)| *job)
+            .collect::<Vec<_>>();
+        // Those that would wait aren't run, and are tried again in a while.
+        let mut waiting = Vec::new();
+        let due = due
+            .into_iter()
+            .filter(|job| match conditions::unmet(job) {
+                Some(reason) => {
+                    eprintln!(
+                        "{}: job {} waits, {reason}",
+                        Local::now().format("%F %T"),
+                        job.name
+                    );
+                    waiting.push(job.name.as_str());
+                    false
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        if !due.is_empty() {
+            let due = config::run_order(&due).expect("cycles are refused when loading");
+            let names = due.iter().map(|j| j.name.as_str()).collect::<Vec<_>>();
+            eprintln!(
+                "{}: running {}",
+                Local::now().format("%F %T"),
+                names.join(", ")
+            );
+            run_jobs(config, Mode::Live, &due, RunOptions::default());
+        }
+        let flush = scheduled
+            .iter()
+            .zip(&next_flush)
+            .filter(|(_, at)| at.is_some_and(|at| at <= now))
+            .map(|((job, _), _)| *job)
+            .collect::<Vec<_>>();
+        if !flush.is_empty() {
+            if let Err(e) = flush_queue(config, Mode::Live, &flush) {
+                eprintln!("Could not upload the queue: {e:#}");
+            }
+        }
+        let audit_due = next_audit.is_some_and(|at| at <= now);
+        if audit_due {
+            if let Err(e) = audit::run(config, Mode::Live) {
+                eprintln!("Audit failed: {e:#}");
+            }
+        }
+
+        // Firings missed while the jobs ran are folded into the next one.
+        let after = Local::now();
+        for ((job, schedule), at) in scheduled.iter().zip(next.iter_mut()) {
+            if *at > now {
+                continue;
+            }
+            *at = next_start(job, *schedule, after);
+            if waiting.contains(&job.name.as_str()) {
+                let retry = after + RETRY;
+                let open = conditions::next_allowed(job, after).unwrap_or(retry);
+                *at = (*at).min(retry.max(open));
+            }
+        }
+        let queued = queue::dir()
+            .and_then(|dir| queue::pending(&dir))
+            .unwrap_or_default();
+        for ((job, _), at) in scheduled.iter().zip(next_flush.iter_mut()) {
+            let ran = due.iter().chain(&flush).any(|j| j.name == job.name);
+            match job.busy_reschedule {
+                Some(delay) if queued.iter().any(|item| item.job == job.name) => {
+                    if ran {
+                        *at = Some(after + delay.0);
+                    }
+                }
+                _ => *at = None,
+            }
+        }
+        if audit_due {
+            next_audit = audit_interval.map(|i| after + i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::HumanDuration;
+
+    #[test]
+    fn jitter_delays_a_start_within_itself() {
+        let schedule = "daily 03:00".parse::<Schedule>().unwrap();
+        let t = Local::now();
+        let at = schedule.next_after(t);
+        let jitter = Duration::from_secs(15 * 60);
+        let mut job = Job::default();
+        job.jitter = Some(HumanDuration(jitter));
+        let starts = (0..1000)
+            .map(|_| next_start(&job, schedule, t))
+            .collect::<Vec<_>>();
+        assert!(starts.iter().all(|&s| at <= s && s < at + jitter));
+        assert!(starts.iter().any(|&s| s != starts[0]), "not random");
+
+        job.jitter = None;
+        assert_eq!(next_start(&job, schedule, t), at);
+    }
+}
@@ -0,0 +1,180 @@
+//! A job's `docker`: containers and volumes to back up along with the job's
+//! `filename`, usually the folder with the compose file and bind mounts. The
+//! volumes the containers mount, and those named, are archived where Docker
+//! keeps them, and `stop` pauses or stops the containers that are running
+//! for as long as that takes. The archive records each container's image, by
+//! tag and by ID, for setting it up again around the restored volumes.
+//!
+//! Everything goes through the `docker` command, so `DOCKER_HOST` and the
+//! like apply as they do in a shell.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// What happens to running containers while their volumes are archived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Halt {
+    /// They keep running, which suits volumes nothing writes to meanwhile
+    #[default]
+    None,
+    /// Frozen, and the same processes carry on afterwards
+    Pause,
+    /// Stopped and started again, so they write out what they hold
+    Stop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Docker {
+    #[serde(default)]
+    pub containers: Vec<String>,
+    /// Further volumes, by name
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    #[serde(default)]
+    pub stop: Halt,
+}
+
+/// A container as the archive records it.
+#[derive(Debug, Serialize)]
+struct Container {
+    name: String,
+    /// As it was started from, like `nextcloud:27.1`
+    image: String,
+    /// What that was then, `sha256:` and its hash
+    image_id: String,
+    /// Like `running` or `exited`, before it was paused or stopped
+    state: String,
+    /// Its volumes by name, and where in the container they are mounted
+    volumes: Vec<(String, String)>,
+}
+
+/// The containers of a job, paused or stopped until this is dropped, with
+/// what to archive.
+pub struct Prepared {
+    /// The volumes' folders on this machine
+    pub paths: Vec<PathBuf>,
+    /// The [`Container`]s, for the archive
+    pub record: Value,
+    stop: Halt,
+    /// Those to unpause or start again
+    halted: Vec<String>,
+}
+
+impl Drop for Prepared {
+    fn drop(&mut self) {
+        if self.halted.is_empty() {
+            return;
+        }
+        let verb = match self.stop {
+            Halt::Pause => "unpause",
+            _ => "start",
+        };
+        match docker(&[&[verb], as_strs(&self.halted).as_slice()].concat()) {
+            Ok(_) => eprintln!("Ran docker {verb} {}", self.halted.join(" ")),
+            Err(e) => eprintln!("Could not {verb} the containers again: {e:#}"),
+        }
+    }
+}
+
+fn as_strs(names: &[String]) -> Vec<&str> {
+    names.iter().map(String::as_str).collect()
+}
+
+/// Runs `docker` with `args` and returns what it printed.
+fn docker(args: &[&str]) -> Result<String> {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .context("Could not run docker")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "docker {} failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `docker inspect` or `docker volume inspect` of `names`, a list of objects.
+fn inspect(command: &[&str], names: &[String]) -> Result<Vec<Value>> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let text = docker(&[command, as_strs(names).as_slice()].concat())?;
+    serde_json::from_str(&text)
+        .with_context(|| format!("docker {} printed {text:?}", command.join(" ")))
+}
+
+/// Finds the volumes of `docker` and pauses or stops its running containers.
+pub fn prepare(docker_job: &Docker) -> Result<Prepared> {
+    let mut containers = Vec::new();
+    let mut paths = Vec::new();
+    let mut running = Vec::new();
+    for found in inspect(&["inspect"], &docker_job.containers)? {
+        let text = |pointer: &str| {
+            found
+                .pointer(pointer)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let name = text("/Name").trim_start_matches('/').to_string();
+        let mut volumes = Vec::new();
+        for mount in found["Mounts"].as_array().into_iter().flatten() {
+            let field = |key: &str| mount[key].as_str().unwrap_or_default().to_string();
+            if field("Type") != "volume" {
+                continue;
+            }
+            volumes.push((field("Name"), field("Destination")));
+            let source = PathBuf::from(field("Source"));
+            if !paths.contains(&source) {
+                paths.push(source);
+            }
+        }
+        if found.pointer("/State/Running") == Some(&Value::Bool(true))
+            && found.pointer("/State/Paused") != Some(&Value::Bool(true))
+        {
+            running.push(name.clone());
+        }
+        containers.push(Container {
+            name,
+            image: text("/Config/Image"),
+            image_id: text("/Image"),
+            state: text("/State/Status"),
+            volumes,
+        });
+    }
+    for volume in inspect(&["volume", "inspect"], &docker_job.volumes)? {
+        let mountpoint = volume["Mountpoint"]
+            .as_str()
+            .ok_or_else(|| anyhow!("docker volume inspect gave no Mountpoint: {volume}"))?;
+        let source = PathBuf::from(mountpoint);
+        if !paths.contains(&source) {
+            paths.push(source);
+        }
+    }
+    let mut prepared = Prepared {
+        paths,
+        record: serde_json::to_value(&containers)?,
+        stop: docker_job.stop,
+        halted: Vec::new(),
+    };
+    let verb = match docker_job.stop {
+        Halt::None => return Ok(prepared),
+        Halt::Pause => "pause",
+        Halt::Stop => "stop",
+    };
+    if !running.is_empty() {
+        // Set first, so those it did halt are brought back if it fails half way.
+        prepared.halted = running;
+        docker(&[&[verb], as_strs(&prepared.halted).as_slice()].concat())?;
+        eprintln!("Ran docker {verb} {}", prepared.halted.join(" "));
+    }
+    Ok(prepared)
+}
@@ -0,0 +1,271 @@
+//! The `doctor` command: a quick pass/fail walk through everything a backup needs.
+
+use crate::client::{Client, Mode};
+use crate::dsm::Dsm;
+use crate::{
+    build_client, check_required_apis, delete_files, format_bytes, get_api_versions,
+    list_fileshares, login_counted, logout, named_shares, permission_denied, upload_file, ApiInfo,
+    Config, SharedFolder,
+};
+use anyhow::{anyhow, Result};
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+struct Row {
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+#[derive(Default)]
+struct Report {
+    rows: Vec<Row>,
+}
+
+impl Report {
+    /// Runs `check` if its prerequisite passed, recording the outcome either way.
+    fn check<T>(
+        &mut self,
+        name: impl Into<String>,
+        prerequisite: bool,
+        check: impl FnOnce() -> Result<(T, String)>,
+    ) -> Option<T> {
+        let name = name.into();
+        if !prerequisite {
+            self.rows.push(Row {
+                name,
+                status: Status::Skip,
+                detail: "an earlier check failed".into(),
+            });
+            return None;
+        }
+        match check() {
+            Ok((value, detail)) => {
+                self.rows.push(Row {
+                    name,
+                    status: Status::Pass,
+                    detail,
+                });
+                Some(value)
+            }
+            Err(e) => {
+                self.rows.push(Row {
+                    name,
+                    status: Status::Fail,
+                    detail: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, name: impl Into<String>, detail: &str) {
+        self.rows.push(Row {
+            name: name.into(),
+            status: Status::Skip,
+            detail: detail.into(),
+        });
+    }
+
+    fn print(&self) {
+        println!("{:<30}{:<8}DETAIL", "CHECK", "RESULT");
+        for row in &self.rows {
+            let status = match row.status {
+                Status::Pass => "PASS",
+                Status::Fail => "FAIL",
+                Status::Skip => "SKIP",
+            };
+            println!("{:<30}{:<8}{}", row.name, status, row.detail);
+        }
+    }
+
+    fn passed(&self) -> bool {
+        self.rows.iter().all(|r| r.status != Status::Fail)
+    }
+}
+
+/// Runs all checks and prints the table. Returns false if any check failed.
+pub fn run(config: &Config, mode: Mode) -> bool {
+    let mut report = Report::default();
+    let offline = matches!(mode, Mode::Replay(_));
+    let client = build_client(&config.nas, mode);
+
+    report.check("State directory", true, || {
+        let dir = crate::paths::state_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        Ok(((), dir.display().to_string()))
+    });
+
+    let reachable = if offline {
+        for name in ["DNS resolution", "TCP connect", "HTTP(S) handshake"] {
+            report.skip(name, "replaying recorded fixtures");
+        }
+        true
+    } else {
+        let addrs = report.check("DNS resolution", true, || {
+            let addrs = config.nas.socket_addrs()?;
+            if addrs.is_empty() {
+                return Err(anyhow!("{} has no addresses", config.nas.domain));
+            }
+            let ips = addrs.iter().map(|a| a.ip().to_string()).collect::<Vec<_>>();
+            let source = if config.nas.resolve.contains_key(config.nas.host()) {
+                " (from resolve)"
+            } else {
+                ""
+            };
+            let detail = format!("{} -> {}{source}", config.nas.domain, ips.join(", "));
+            Ok((addrs, detail))
+        });
+        // Like the HTTP client, take whichever address answers, so a
+        // dual-stack name works when only one of its families is routed.
+        let tcp = report.check("TCP connect", addrs.is_some(), || {
+            let mut failures = Vec::new();
+            for addr in addrs.unwrap() {
+                match TcpStream::connect_timeout(&addr, Duration::from_secs(10)) {
+                    Ok(_) => return Ok(((), format!("connected to {addr}"))),
+                    Err(e) => failures.push(format!("{addr}: {e}")),
+                }
+            }
+            Err(anyhow!("{}", failures.join("; ")))
+        });
+        let name = if config.nas.https {
+            "TLS handshake"
+        } else {
+            "HTTP request"
+        };
+        report
+            .check(name, tcp.is_some(), || {
+                let resp = client
+                    .client
+                    .get(format!("{}/query.cgi", client.base_url))
+                    .send()
+                    // Say why, such as which key a pinned server has.
+                    .map_err(|e| anyhow!("{:#}", anyhow::Error::from(e)))?;
+                Ok(((), format!("HTTP {}", resp.status())))
+            })
+            .is_some()
+    };
+
+    let apis = report.check("API info", reachable, || {
+        let apis = get_api_versions(&client)?;
+        check_required_apis(&apis)?;
+        let dsm = Dsm::detect(&apis)?;
+        let detail = format!("{} APIs reported, {}", apis.len(), dsm.generation);
+        Ok((apis, detail))
+    });
+    let apis = apis.as_deref().unwrap_or(&[]);
+    let logged_in = report
+        .check("Login", !apis.is_empty(), || {
+            login_counted(&client, apis, &config.nas, true)?;
+            Ok(((), format!("logged in as {}", config.nas.usr)))
+        })
+        .is_some();
+
+    // Only the primary NAS is checked; it is always the first target.
+    let primary = &config.targets[0];
+    let mut share_names = config
+        .jobs
+        .iter()
+        .filter(|j| j.targets.contains(&primary.name))
+        .map(|j| j.share_name(primary))
+        .collect::<Vec<_>>();
+    share_names.sort();
+    share_names.dedup();
+    let shares = report.check("Share listing", logged_in, || {
+        if !config.nas.shares.is_empty() {
+            return Ok((
+                named_shares(&config.nas.shares),
+                "taken from `shares`".into(),
+            ));
+        }
+        match list_fileshares(&client, apis) {
+            Ok(shares) => {
+                let detail = format!("{} shares visible", shares.len());
+                Ok((shares, detail))
+            }
+            // Backups work without the listing, so only the uploads can tell.
+            Err(e) if permission_denied(&e) => Ok((
+                named_shares(&share_names),
+                "not allowed; shares are taken to be at /<share_name>".into(),
+            )),
+            Err(e) => Err(e),
+        }
+    });
+    for share_name in share_names {
+        check_share(&mut report, &client, apis, shares.as_deref(), share_name);
+    }
+
+    if logged_in {
+        let _ = logout(&client, apis);
+    }
+    report.print();
+    report.passed()
+}
+
+fn check_share(
+    report: &mut Report,
+    client: &Client,
+    apis: &[ApiInfo],
+    shares: Option<&[SharedFolder]>,
+    share_name: &str,
+) {
+    let share = report.check(format!("Share {share_name}"), shares.is_some(), || {
+        let share = shares
+            .unwrap()
+            .iter()
+            .find(|x| x.name == share_name)
+            .ok_or_else(|| anyhow!("no share named {share_name}"))?;
+        Ok((share, format!("found at {}", share.path)))
+    });
+
+    report.check(
+        format!("Write permission {share_name}"),
+        share.is_some(),
+        || {
+            let share = share.unwrap();
+            let probe_name = format!(
+                ".synology_backuper_probe_{}",
+                chrono::Utc::now().format("%Y%m%d_%H%M%S")
+            );
+            let local = std::env::temp_dir().join(&probe_name);
+            std::fs::write(&local, b"synology_backuper write probe\n")?;
+            let uploaded = upload_file(
+                client,
+                apis,
+                &share.path,
+                &local,
+                &probe_name,
+                None,
+                None,
+                true,
+            );
+            let _ = std::fs::remove_file(&local);
+            uploaded?;
+            let remote = format!("{}/{}", share.path, probe_name);
+            delete_files(client, apis, &[&remote])
+                .map_err(|e| anyhow!("uploaded {remote} but could not delete it: {e}"))?;
+            Ok(((), format!("uploaded and deleted {remote}")))
+        },
+    );
+
+    let name = format!("Free space {share_name}");
+    match share.map(|x| x.free_space) {
+        Some(None) => report.skip(name, "not reported by DSM"),
+        _ => {
+            report.check(name, share.is_some(), || {
+                let free = share.unwrap().free_space.unwrap();
+                if free == 0 {
+                    return Err(anyhow!("the volume is full"));
+                }
+                Ok(((), format!("{} free", format_bytes(free))))
+            });
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! What differs between DSM releases, worked out from the API versions a NAS
+//! reports, so the same binary talks to DSM 6.2 and DSM 7.x alike.
+
+use crate::ApiInfo;
+use anyhow::{anyhow, Result};
+use std::ops::RangeInclusive;
+
+/// `SYNO.API.Auth` versions this program knows how to log in with.
+const AUTH_VERSIONS: RangeInclusive<u8> = 3..=7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Generation {
+    /// DSM 6.1 and older, whose Auth API stops before version 6
+    Dsm6Early,
+    Dsm62,
+    Dsm7,
+}
+
+impl std::fmt::Display for Generation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Generation::Dsm6Early => "DSM 6.1 or older",
+            Generation::Dsm62 => "DSM 6.2",
+            Generation::Dsm7 => "DSM 7",
+        })
+    }
+}
+
+/// How to talk to one NAS.
+#[derive(Debug, Clone, Copy)]
+pub struct Dsm {
+    pub generation: Generation,
+    /// The `SYNO.API.Auth` version used for both login and logout
+    pub auth_version: u8,
+}
+
+impl Dsm {
+    /// DSM 7 brought `SYNO.API.Auth` version 7 and DSM 6.2 version 6, which is
+    /// the most reliable sign of the release available before logging in.
+    pub fn detect(apis: &[ApiInfo]) -> Result<Dsm> {
+        let auth = apis
+            .iter()
+            .find(|x| x.name == "SYNO.API.Auth")
+            .ok_or_else(|| anyhow!("The NAS reports no SYNO.API.Auth"))?;
+        let generation = match auth.max_version {
+            7.. => Generation::Dsm7,
+            6 => Generation::Dsm62,
+            _ => Generation::Dsm6Early,
+        };
+        let auth_version = auth.max_version.min(*AUTH_VERSIONS.end());
+        if auth_version < auth.min_version || !AUTH_VERSIONS.contains(&auth_version) {
+            return Err(anyhow!(
+                "The NAS offers SYNO.API.Auth versions {} to {}, but this program needs one of {} to {}",
+                auth.min_version,
+                auth.max_version,
+                AUTH_VERSIONS.start(),
+                AUTH_VERSIONS.end()
+            ));
+        }
+        Ok(Dsm {
+            generation,
+            auth_version,
+        })
+    }
+
+    /// Login parameters besides the account and password.
+    ///
+    /// Both releases keep the session in a cookie. DSM 7 would otherwise hand
+    /// out a device token for skipping 2-step verification, which an
+    /// unattended backup has no use for.
+    pub fn login_params(&self) -> &'static [(&'static str, &'static str)] {
+        match self.generation {
+            Generation::Dsm7 => &[
+                ("session", "FileStation"),
+                ("format", "cookie"),
+                ("enable_device_token", "no"),
+            ],
+            Generation::Dsm62 | Generation::Dsm6Early => {
+                &[("session", "FileStation"), ("format", "cookie")]
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! The `find` command: search every share the jobs upload to for matching files.
+
+use crate::client::Mode;
+use crate::{format_bytes, search_files, Config, Sessions};
+use anyhow::{anyhow, Result};
+
+pub fn run(config: &Config, mode: Mode, pattern: &str) -> Result<()> {
+    let mut searched = Vec::new();
+    for job in &config.jobs {
+        for name in &job.targets {
+            let target = config.targets.iter().find(|t| &t.name == name).unwrap();
+            let pair = (name.as_str(), job.share_name(target));
+            if !searched.contains(&pair) {
+                searched.push(pair);
+            }
+        }
+    }
+
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let mut found = 0;
+    let mut failed = false;
+    for target in &config.targets {
+        let shares = searched
+            .iter()
+            .filter(|(name, _)| *name == target.name)
+            .map(|(_, share)| *share)
+            .collect::<Vec<_>>();
+        if shares.is_empty() {
+            continue;
+        }
+        let (_, session) = sessions.get(&target.name);
+        let session = match session {
+            Ok(remote) => match remote.api("find") {
+                Ok(session) => session,
+                Err(e) => {
+                    eprintln!("Skipping target {}: {e}", target.name);
+                    failed = true;
+                    continue;
+                }
+            },
+            Err(e) => {
+                eprintln!("Skipping target {}: {e}", target.name);
+                failed = true;
+                continue;
+            }
+        };
+        let folders = shares
+            .iter()
+            .filter_map(|share| session.shares.iter().find(|s| s.name == *share))
+            .map(|s| s.path.as_str())
+            .collect::<Vec<_>>();
+        let mut files = match search_files(&session.client, &session.api_info, &folders, pattern) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Searching {} failed: {e:#}", target.name);
+                failed = true;
+                continue;
+            }
+        };
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        for file in &files {
+            let size = file.size.map(format_bytes).unwrap_or_default();
+            let mtime = file
+                .mtime
+                .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            println!("{}:{}\t{size}\t{mtime}", target.name, file.path);
+        }
+        found += files.len();
+    }
+    sessions.logout();
+
+    eprintln!("{found} files match {pattern}");
+    if failed {
+        return Err(anyhow!("Some targets could not be searched"));
+    }
+    Ok(())
+}
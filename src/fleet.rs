@@ -0,0 +1,206 @@
+//! Run logs shared by several machines through a folder on a target, so
+//! `check --fleet`, `list --fleet` and `status --fleet` show how every
+//! machine's backups are doing wherever they run. After each run, a machine uploads its run log
+//! into the folder as `runs-<host>.jsonl`. Each only ever writes its own
+//! file, so machines finishing at the same time can't lose each other's
+//! records, and reading merges them all.
+
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Fleet;
+use crate::limits::HumanDuration;
+use crate::runlog;
+use crate::{format_bytes, Config, Sessions};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Sizes of the runs a trend is drawn from, the last ones
+const TREND_RUNS: usize = 8;
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// This machine's name in the fleet folder: `host` from the config, or
+/// the host name, with what doesn't belong in a file name replaced.
+pub fn host(fleet: &Fleet) -> String {
+    machine(fleet.host.as_deref())
+}
+
+/// `host`, or else the host name, with what doesn't belong in a file name replaced.
+pub fn machine(host: Option<&str>) -> String {
+    let name = match host {
+        Some(host) => host.to_string(),
+        None => hostname().unwrap_or_else(|| "unknown".to_string()),
+    };
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is as long as we say it is.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned()).filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+fn file_name(host: &str) -> String {
+    format!("runs-{host}.jsonl")
+}
+
+/// Uploads this machine's run log into the fleet folder, if the config has
+/// one. A failure is only reported, as the run itself went as it went.
+pub fn publish(config: &Config, sessions: &mut Sessions) {
+    let Some(fleet) = &config.fleet else {
+        return;
+    };
+    let published = runlog::path(config).and_then(|log| {
+        let remote = sessions.get(&fleet.target).1.map_err(|e| anyhow!("{e}"))?;
+        remote.create_folder(&fleet.folder)?;
+        remote.upload(&fleet.folder, &log, &file_name(&host(fleet)), None, None)
+    });
+    if let Err(e) = published {
+        eprintln!(
+            "Could not share the run log in {}:{}: {e:#}",
+            fleet.target, fleet.folder
+        );
+    }
+}
+
+/// The runs of every machine in the fleet folder, by host name, this one's
+/// from its own run log, which is never behind the copy it uploaded.
+pub fn runs(config: &Config, sessions: &mut Sessions) -> Result<Vec<(String, Vec<Value>)>> {
+    let fleet = config.fleet.as_ref().ok_or_else(|| {
+        anyhow!(
+            "--fleet needs `fleet` in the config, the folder the machines share their run logs in"
+        )
+    })?;
+    let own = host(fleet);
+    let mut runs = vec![(own.clone(), runlog::read_runs(&runlog::path(config)?)?)];
+    let read = sessions
+        .get(&fleet.target)
+        .1
+        .map_err(|e| anyhow!("{e}"))
+        .and_then(|remote| {
+            let files = remote
+                .list(&fleet.folder, false)
+                .with_context(|| format!("Could not list {}:{}", fleet.target, fleet.folder))?;
+            let mut hosts = Vec::new();
+            for file in files {
+                let Some(host) = file
+                    .name
+                    .strip_prefix("runs-")
+                    .and_then(|name| name.strip_suffix(".jsonl"))
+                else {
+                    continue;
+                };
+                if host == own {
+                    continue;
+                }
+                let local = std::env::temp_dir().join(format!(
+                    ".synology_backuper_fleet_{}_{}",
+                    std::process::id(),
+                    file.name
+                ));
+                let text = remote
+                    .download(&file.path, &local)
+                    .and_then(|()| Ok(std::fs::read_to_string(&local)?));
+                let _ = std::fs::remove_file(&local);
+                match text {
+                    Ok(text) => hosts.push((host.to_string(), runlog::parse_runs(&text))),
+                    Err(e) => eprintln!("Could not read the run log of {host}: {e:#}"),
+                }
+            }
+            Ok(hosts)
+        });
+    runs.extend(read?);
+    runs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(runs)
+}
+
+/// Prints a table of the jobs of this machine, or with `--fleet` of every
+/// machine sharing the `fleet` folder: when each last succeeded and how big
+/// its archives have been of late.
+pub fn status(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let runs = if args.flag("fleet") {
+        let mut sessions = Sessions::new(&config.targets, mode);
+        let runs = runs(config, &mut sessions);
+        sessions.logout();
+        runs?
+    } else {
+        let host = config
+            .fleet
+            .as_ref()
+            .map_or("this machine".to_string(), host);
+        vec![(host, runlog::read_runs(&runlog::path(config)?)?)]
+    };
+    let now = chrono::Utc::now();
+    println!(
+        "{:<16}{:<20}{:<21}{:<10}{:<12}TREND",
+        "MACHINE", "JOB", "LAST SUCCESS", "AGE", "SIZE"
+    );
+    for (host, runs) in &runs {
+        // A machine that shares a log but hasn't run a job yet still shows.
+        if runs.is_empty() {
+            println!("{host:<16}{:<20}{:<21}", "", "never");
+            continue;
+        }
+        let successes = runlog::job_successes(runs);
+        for name in runlog::job_names(runs) {
+            let Some((time, size)) = successes.get(&name).and_then(|s| s.last()) else {
+                println!("{host:<16}{name:<20}{:<21}", "never");
+                continue;
+            };
+            let age = (now - *time).to_std().unwrap_or_default();
+            let age = HumanDuration(Duration::from_secs(age.as_secs() / 60 * 60));
+            let sizes = successes[&name]
+                .iter()
+                .rev()
+                .take(TREND_RUNS)
+                .rev()
+                .filter_map(|(_, size)| *size)
+                .collect::<Vec<_>>();
+            println!(
+                "{host:<16}{name:<20}{:<21}{:<10}{:<12}{}",
+                time.format("%Y-%m-%d %H:%M:%S"),
+                age.to_string(),
+                size.map(format_bytes).unwrap_or_default(),
+                trend(&sizes)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A bar per size, scaled between the smallest and the largest, and how
+/// much the last differs from the first.
+fn trend(sizes: &[u64]) -> String {
+    let (Some(&first), Some(&last)) = (sizes.first(), sizes.last()) else {
+        return String::new();
+    };
+    let (min, max) = (*sizes.iter().min().unwrap(), *sizes.iter().max().unwrap());
+    let bars = sizes
+        .iter()
+        .map(|&size| match max - min {
+            0 => BARS[0],
+            range => BARS[((size - min) * (BARS.len() as u64 - 1) / range) as usize],
+        })
+        .collect::<String>();
+    if sizes.len() < 2 || first == 0 {
+        return bars;
+    }
+    let change = (last as f64 / first as f64 - 1.0) * 100.0;
+    format!("{bars} {change:+.0}%")
+}
@@ -0,0 +1,27 @@
+//! A job's `on_success` and `on_failure` commands.
+
+use anyhow::{anyhow, Context, Result};
+use std::process::Command;
+
+/// Runs `command` through the system shell with `env` added to the
+/// environment, waiting for it to exit.
+pub fn run(command: &str, env: &[(&str, &str)]) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .envs(env.iter().copied())
+        .status()
+        .with_context(|| format!("Could not run {command}"))?;
+    if !status.success() {
+        return Err(anyhow!("{command} failed with {status}"));
+    }
+    Ok(())
+}
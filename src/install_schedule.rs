@@ -0,0 +1,320 @@
+//! `install-schedule`: register the job schedules with Windows Task Scheduler or macOS launchd,
+//! as a task or launch agent per scheduled job that runs `backup --job` with it.
+
+use crate::cli::Args;
+use crate::config::{safe_name, Config, Job};
+use crate::schedule::Schedule;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+const TASK_NAME: &str = "synology_backuper";
+const LAUNCHD_LABEL: &str = "com.github.el-hult.synology_backuper";
+
+fn scheduled_jobs(config: &Config) -> Result<Vec<(&Job, Schedule)>> {
+    let jobs = config
+        .jobs
+        .iter()
+        .filter_map(|j| j.schedule.map(|s| (j, s)))
+        .collect::<Vec<_>>();
+    if jobs.is_empty() {
+        return Err(anyhow!(
+            "No job has a `schedule`, so there is nothing to register"
+        ));
+    }
+    Ok(jobs)
+}
+
+fn task_name(job: &Job) -> String {
+    format!("{TASK_NAME}_{}", safe_name(&job.name))
+}
+
+fn launchd_label(job: &Job) -> String {
+    format!("{LAUNCHD_LABEL}.{}", safe_name(&job.name))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn task_trigger(schedule: Schedule, jitter: Option<u64>) -> String {
+    let delay = jitter
+        .filter(|&secs| secs > 0)
+        .map(|secs| format!("      <RandomDelay>PT{secs}S</RandomDelay>\n"))
+        .unwrap_or_default();
+    // Any date in the past works as the first occurrence; only the time of day matters.
+    match schedule {
+        Schedule::Hourly { minute } => format!(
+            "    <TimeTrigger>
+      <StartBoundary>2024-01-01T00:{minute:02}:00</StartBoundary>
+      <Repetition><Interval>PT1H</Interval></Repetition>
+{delay}    </TimeTrigger>
+"
+        ),
+        Schedule::Daily { hour, minute } => format!(
+            "    <CalendarTrigger>
+      <StartBoundary>2024-01-01T{hour:02}:{minute:02}:00</StartBoundary>
+{delay}      <ScheduleByDay><DaysInterval>1</DaysInterval></ScheduleByDay>
+    </CalendarTrigger>
+"
+        ),
+        Schedule::Weekly {
+            weekday,
+            hour,
+            minute,
+        } => format!(
+            "    <CalendarTrigger>
+      <StartBoundary>2024-01-01T{hour:02}:{minute:02}:00</StartBoundary>
+{delay}      <ScheduleByWeek>
+        <DaysOfWeek><{day} /></DaysOfWeek>
+        <WeeksInterval>1</WeeksInterval>
+      </ScheduleByWeek>
+    </CalendarTrigger>
+",
+            day = weekday.long_name()
+        ),
+    }
+}
+
+fn task_xml(config: &Config, job: &Job, schedule: Schedule, exe: &Path, workdir: &Path) -> String {
+    let triggers = task_trigger(schedule, job.jitter.map(|d| d.0.as_secs()));
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-16"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Back up job {job} to a Synology NAS</Description>
+  </RegistrationInfo>
+  <Triggers>
+{triggers}  </Triggers>
+  <Principals>
+    <Principal id="Author">
+      <LogonType>InteractiveToken</LogonType>
+      <RunLevel>LeastPrivilege</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <StartWhenAvailable>true</StartWhenAvailable>
+    <RunOnlyIfNetworkAvailable>true</RunOnlyIfNetworkAvailable>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>{exe}</Command>
+      <Arguments>--config &quot;{config}&quot; backup --job &quot;{job}&quot;</Arguments>
+      <WorkingDirectory>{workdir}</WorkingDirectory>
+    </Exec>
+  </Actions>
+</Task>
+"#,
+        job = xml_escape(&job.name),
+        exe = xml_escape(&exe.display().to_string()),
+        config = xml_escape(&config.path.display().to_string()),
+        workdir = xml_escape(&workdir.display().to_string()),
+    )
+}
+
+fn launchd_interval(schedule: Schedule) -> String {
+    let entries = match schedule {
+        Schedule::Hourly { minute } => vec![("Minute", minute)],
+        Schedule::Daily { hour, minute } => vec![("Hour", hour), ("Minute", minute)],
+        Schedule::Weekly {
+            weekday,
+            hour,
+            minute,
+        } => vec![
+            ("Weekday", weekday.days_from_sunday()),
+            ("Hour", hour),
+            ("Minute", minute),
+        ],
+    };
+    let keys = entries
+        .iter()
+        .map(|(k, v)| format!("      <key>{k}</key><integer>{v}</integer>\n"))
+        .collect::<String>();
+    format!("    <dict>\n{keys}    </dict>\n")
+}
+
+fn launchd_plist(
+    config: &Config,
+    job: &Job,
+    schedule: Schedule,
+    exe: &Path,
+    workdir: &Path,
+    log: &Path,
+) -> String {
+    let intervals = launchd_interval(schedule);
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key><string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{exe}</string>
+    <string>--config</string>
+    <string>{config}</string>
+    <string>backup</string>
+    <string>--job</string>
+    <string>{job}</string>
+  </array>
+  <key>WorkingDirectory</key><string>{workdir}</string>
+  <key>StartCalendarInterval</key>
+  <array>
+{intervals}  </array>
+  <key>StandardOutPath</key><string>{log}</string>
+  <key>StandardErrorPath</key><string>{log}</string>
+  <key>ProcessType</key><string>Background</string>
+</dict>
+</plist>
+"#,
+        label = launchd_label(job),
+        job = xml_escape(&job.name),
+        exe = xml_escape(&exe.display().to_string()),
+        config = xml_escape(&config.path.display().to_string()),
+        workdir = xml_escape(&workdir.display().to_string()),
+        log = xml_escape(&log.display().to_string()),
+    )
+}
+
+/// The names of the tasks registered before, by this or by the single task
+/// that started every job before there was one per job.
+fn registered_tasks() -> Vec<String> {
+    let Ok(output) = std::process::Command::new("schtasks")
+        .args(["/Query", "/FO", "CSV", "/NH"])
+        .output()
+    else {
+        return Vec::new();
+    };
+    let mut names = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split(',').next())
+        .map(|name| name.trim_matches('"').trim_start_matches('\\').to_string())
+        .filter(|name| name == TASK_NAME || name.starts_with(&format!("{TASK_NAME}_")))
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = std::process::Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Could not run {program}"))?;
+    if !status.success() {
+        return Err(anyhow!("{program} {} failed with {status}", args.join(" ")));
+    }
+    Ok(())
+}
+
+fn home() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("HOME is not set"))
+}
+
+pub fn install(config: &Config, args: &Args) -> Result<()> {
+    let platform = match args.value("platform") {
+        Some(p) => p,
+        None if cfg!(windows) => "windows",
+        None if cfg!(target_os = "macos") => "macos",
+        None => {
+            return Err(anyhow!(
+                "install-schedule supports Windows and macOS; use install-systemd on Linux"
+            ))
+        }
+    };
+    let exe = std::env::current_exe()?;
+    let workdir = std::env::current_dir()?;
+    let jobs = scheduled_jobs(config)?;
+    match platform {
+        "windows" => {
+            let tasks = jobs
+                .iter()
+                .map(|&(job, s)| (task_name(job), task_xml(config, job, s, &exe, &workdir)))
+                .collect::<Vec<_>>();
+            if args.flag("print") {
+                for (name, xml) in &tasks {
+                    print!("<!-- task {name} -->\n{xml}");
+                }
+                return Ok(());
+            }
+            for stale in registered_tasks() {
+                if !tasks.iter().any(|(name, _)| *name == stale) {
+                    run("schtasks", &["/Delete", "/TN", &stale, "/F"])?;
+                    eprintln!("Removed the scheduled task {stale}");
+                }
+            }
+            for (name, xml) in &tasks {
+                // schtasks wants the task definition as UTF-16 with a byte order mark.
+                let path = std::env::temp_dir().join(format!("{name}_task.xml"));
+                let bytes = std::iter::once(0xFEFF)
+                    .chain(xml.encode_utf16())
+                    .flat_map(|u| u.to_le_bytes())
+                    .collect::<Vec<u8>>();
+                std::fs::write(&path, bytes)?;
+                let result = run(
+                    "schtasks",
+                    &["/Create", "/TN", name, "/XML", path.to_str().unwrap(), "/F"],
+                );
+                let _ = std::fs::remove_file(&path);
+                result?;
+                eprintln!("Registered the scheduled task {name}");
+            }
+        }
+        "macos" => {
+            for (job, _) in jobs.iter().filter(|(job, _)| job.jitter.is_some()) {
+                eprintln!(
+                    "launchd can't delay a start at random, so job {}'s `jitter` is left out",
+                    job.name
+                );
+            }
+            let log = home()?.join("Library/Logs/synology_backuper.log");
+            let agents = jobs
+                .iter()
+                .map(|&(job, s)| {
+                    let plist = launchd_plist(config, job, s, &exe, &workdir, &log);
+                    (format!("{}.plist", launchd_label(job)), plist)
+                })
+                .collect::<Vec<_>>();
+            if args.flag("print") {
+                for (name, plist) in &agents {
+                    print!("<!-- {name} -->\n{plist}");
+                }
+                return Ok(());
+            }
+            let dir = home()?.join("Library/LaunchAgents");
+            std::fs::create_dir_all(&dir)?;
+            // The agents of jobs that lost their schedule, and the single agent
+            // that started every job before there was one per job.
+            let stale = std::fs::read_dir(&dir)?
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with(&format!("{LAUNCHD_LABEL}.")))
+                .filter(|name| name.ends_with(".plist"))
+                .filter(|name| !agents.iter().any(|(agent, _)| agent == name))
+                .collect::<Vec<_>>();
+            for name in stale {
+                let path = dir.join(&name);
+                let _ = run("launchctl", &["unload", path.to_str().unwrap()]);
+                std::fs::remove_file(&path)?;
+                eprintln!("Removed {}", path.display());
+            }
+            for (name, plist) in &agents {
+                let path = dir.join(name);
+                // Unloading a previous version may fail if none was loaded; that's fine.
+                let _ = run("launchctl", &["unload", path.to_str().unwrap()]);
+                std::fs::write(&path, plist)?;
+                run("launchctl", &["load", "-w", path.to_str().unwrap()])?;
+                eprintln!("Installed and loaded {}", path.display());
+            }
+        }
+        _ => unreachable!("platforms are validated by the parser"),
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! The `key` command and the keyring it manages: the secret keys client-side
+//! encryption seals data with. The keyring lives next to the config, in
+//! `keys.json`, readable only by its owner. Rotating adds a key and makes it
+//! the one new data is sealed with; the older keys stay, since whatever was
+//! sealed with them can only be opened with them. Losing the keyring loses
+//! everything encrypted with it, hence `key export`, and why it's only ever
+//! replaced whole. Sealed data names the key that sealed it; `key rotate
+//! --reseal` seals recovery bundles again with the new key, so the older keys
+//! needn't be kept for them.
+
+use crate::cli::Args;
+use crate::paths;
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use ring::aead;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Bytes in a key, for AES-256.
+const KEY_LEN: usize = 32;
+/// What data sealed with a key starts with, before the key's id and the nonce
+const SEALED_MAGIC: &[u8; 4] = b"SBK1";
+/// Characters in a key's id
+const ID_LEN: usize = 8;
+/// What data sealed with a passphrase starts with, before the salt and the nonce
+const PASSPHRASE_MAGIC: &[u8; 4] = b"SBP1";
+const SALT_LEN: usize = 16;
+/// PBKDF2-HMAC-SHA256 rounds a passphrase goes through, as OWASP advises
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Keyring {
+    /// The id of the key new data is sealed with
+    pub current: String,
+    pub keys: Vec<Key>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Key {
+    /// The start of the key's SHA-256 in hex, recorded with what it sealed
+    pub id: String,
+    /// When it was made, in RFC 3339
+    pub created: String,
+    /// The key in base64
+    key: String,
+}
+
+impl Keyring {
+    /// `plaintext` encrypted with AES-256-GCM under the current key, which
+    /// the result names so that [`Keyring::open`] finds it after a rotation.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id == self.current)
+            .expect("load checks the current key is there");
+        let mut nonce = [0; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("The system has no randomness to make a nonce from"))?;
+        let mut sealed = SEALED_MAGIC.to_vec();
+        sealed.extend_from_slice(key.id.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        let aad = aead::Aad::from(sealed.clone());
+        let mut data = plaintext.to_vec();
+        key.aead()?
+            .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aad, &mut data)
+            .map_err(|_| anyhow!("Could not encrypt"))?;
+        sealed.extend_from_slice(&data);
+        Ok(sealed)
+    }
+
+    /// The plaintext of what [`Keyring::seal`] returned, with whichever key
+    /// of the keyring sealed it.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let header = SEALED_MAGIC.len() + ID_LEN + aead::NONCE_LEN;
+        if sealed.len() < header + aead::MAX_TAG_LEN || !sealed.starts_with(SEALED_MAGIC) {
+            return Err(anyhow!("This is not data encrypted by synology_backuper"));
+        }
+        let id = String::from_utf8_lossy(&sealed[SEALED_MAGIC.len()..SEALED_MAGIC.len() + ID_LEN]);
+        let key = self.keys.iter().find(|k| k.id == id).ok_or_else(|| {
+            anyhow!("This was encrypted with key {id}, which the keyring doesn't hold")
+        })?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(&sealed[header - aead::NONCE_LEN..header])
+                .map_err(|_| anyhow!("The nonce is cut off"))?;
+        let mut data = sealed[header..].to_vec();
+        let plaintext = key
+            .aead()?
+            .open_in_place(nonce, aead::Aad::from(&sealed[..header]), &mut data)
+            .map_err(|_| anyhow!("Key {id} does not open this; it was changed or damaged"))?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// Whether `sealed` was sealed with [`seal_with_passphrase`] rather than a key.
+pub fn passphrase_sealed(sealed: &[u8]) -> bool {
+    sealed.starts_with(PASSPHRASE_MAGIC)
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8]) -> aead::LessSafeKey {
+    let mut bytes = [0; KEY_LEN];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        std::num::NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut bytes,
+    );
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &bytes).expect("the key is 32 bytes long");
+    aead::LessSafeKey::new(key)
+}
+
+/// `plaintext` encrypted with AES-256-GCM under a key derived from
+/// `passphrase`, for what has to open without the keyring, like the keyring.
+pub fn seal_with_passphrase(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; aead::NONCE_LEN];
+    let random = SystemRandom::new();
+    random
+        .fill(&mut salt)
+        .and_then(|()| random.fill(&mut nonce))
+        .map_err(|_| anyhow!("The system has no randomness to make a salt from"))?;
+    let mut sealed = PASSPHRASE_MAGIC.to_vec();
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    let aad = aead::Aad::from(sealed.clone());
+    let mut data = plaintext.to_vec();
+    passphrase_key(passphrase, &salt)
+        .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aad, &mut data)
+        .map_err(|_| anyhow!("Could not encrypt"))?;
+    sealed.extend_from_slice(&data);
+    Ok(sealed)
+}
+
+/// The plaintext of what [`seal_with_passphrase`] returned.
+pub fn open_with_passphrase(passphrase: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+    let header = PASSPHRASE_MAGIC.len() + SALT_LEN + aead::NONCE_LEN;
+    if sealed.len() < header + aead::MAX_TAG_LEN || !passphrase_sealed(sealed) {
+        return Err(anyhow!(
+            "This is not data encrypted by synology_backuper with a passphrase"
+        ));
+    }
+    let salt = &sealed[PASSPHRASE_MAGIC.len()..PASSPHRASE_MAGIC.len() + SALT_LEN];
+    let nonce = aead::Nonce::try_assume_unique_for_key(&sealed[header - aead::NONCE_LEN..header])
+        .map_err(|_| anyhow!("The nonce is cut off"))?;
+    let mut data = sealed[header..].to_vec();
+    let plaintext = passphrase_key(passphrase, salt)
+        .open_in_place(nonce, aead::Aad::from(&sealed[..header]), &mut data)
+        .map_err(|_| anyhow!("The passphrase does not open this, or it was changed or damaged"))?;
+    Ok(plaintext.to_vec())
+}
+
+impl Key {
+    fn aead(&self) -> Result<aead::LessSafeKey> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&self.key)
+            .with_context(|| format!("Key {} is not base64", self.id))?;
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, &bytes)
+            .map_err(|_| anyhow!("Key {} is not {KEY_LEN} bytes long", self.id))?;
+        Ok(aead::LessSafeKey::new(key))
+    }
+
+    fn generate() -> Result<Self> {
+        let mut bytes = [0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| anyhow!("The system has no randomness to make a key from"))?;
+        let hash = ring::digest::digest(&ring::digest::SHA256, &bytes);
+        Ok(Key {
+            id: hash.as_ref()[..4]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+            created: chrono::Local::now().to_rfc3339(),
+            key: base64::engine::general_purpose::STANDARD.encode(bytes),
+        })
+    }
+}
+
+pub fn path() -> Result<PathBuf> {
+    Ok(paths::config_dir()?.join("keys.json"))
+}
+
+/// The keyring, or `None` if no key was generated yet.
+pub fn load() -> Result<Option<Keyring>> {
+    let path = path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    load_from(&path).map(Some)
+}
+
+/// The keyring in the file at `path`, such as `keys.json` or an export of it.
+pub fn load_from(path: &Path) -> Result<Keyring> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let keyring: Keyring = serde_json::from_str(&text)
+        .with_context(|| format!("{} is not a keyring", path.display()))?;
+    if !keyring.keys.iter().any(|k| k.id == keyring.current) {
+        return Err(anyhow!(
+            "{} names {} as its current key but has no such key",
+            path.display(),
+            keyring.current
+        ));
+    }
+    Ok(keyring)
+}
+
+fn store(path: &Path, keyring: &Keyring) -> Result<()> {
+    write_private(path, serde_json::to_string_pretty(keyring)?.as_bytes())
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Writes `contents` to `path`, readable only by its owner, by renaming a
+/// file written next to it, so a crash or a full disk leaves what was there.
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dir.join(format!(".{name}.{}", std::process::id()));
+    let written = (|| {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&partial)?;
+        std::io::Write::write_all(&mut file, contents)?;
+        file.sync_all()?;
+        std::fs::rename(&partial, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    Ok(written?)
+}
+
+/// Seals the recovery bundle at `path` again with `keyring`'s current key.
+fn reseal(keyring: &Keyring, path: &Path) -> Result<()> {
+    let sealed =
+        std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    if passphrase_sealed(&sealed) {
+        return Err(anyhow!(
+            "{} is sealed with a passphrase, not a key",
+            path.display()
+        ));
+    }
+    let plaintext = keyring
+        .open(&sealed)
+        .with_context(|| format!("Could not decrypt {}", path.display()))?;
+    write_private(path, &keyring.seal(&plaintext)?)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let path = path()?;
+    match args.positional.as_deref().unwrap() {
+        "generate" => {
+            if load()?.is_some() {
+                return Err(anyhow!(
+                    "{} already holds a keyring; `key rotate` adds a key to it",
+                    path.display()
+                ));
+            }
+            let key = Key::generate()?;
+            println!("Generated key {} in {}", key.id, path.display());
+            store(
+                &path,
+                &Keyring {
+                    current: key.id.clone(),
+                    keys: vec![key],
+                },
+            )?;
+            println!("Keep a copy elsewhere with `key export`: without it, nothing encrypted with the key can be restored");
+        }
+        "rotate" => {
+            let mut keyring = load()?.ok_or_else(|| {
+                anyhow!(
+                    "There is no keyring at {} to rotate; `key generate` makes one",
+                    path.display()
+                )
+            })?;
+            let key = Key::generate()?;
+            println!(
+                "Key {} replaces {} for new data in {}; the older keys stay to open what they sealed",
+                key.id,
+                keyring.current,
+                path.display()
+            );
+            keyring.current = key.id.clone();
+            keyring.keys.push(key);
+            store(&path, &keyring)?;
+            println!("Export the keyring again with `key export`");
+            let mut failed = false;
+            for bundle in args.values("reseal") {
+                match reseal(&keyring, Path::new(bundle)) {
+                    Ok(()) => println!("Sealed {bundle} again with key {}", keyring.current),
+                    Err(e) => {
+                        eprintln!("{e:#}");
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                return Err(anyhow!("Some bundles are still sealed with the older keys"));
+            }
+        }
+        "export" => {
+            let keyring = load()?.ok_or_else(|| {
+                anyhow!(
+                    "There is no keyring at {}; `key generate` makes one",
+                    path.display()
+                )
+            })?;
+            let text = serde_json::to_string_pretty(&keyring)?;
+            match args.value("out") {
+                Some(out) => {
+                    write_private(Path::new(out), text.as_bytes())
+                        .with_context(|| format!("Could not write {out}"))?;
+                    println!("Wrote {} keys to {out}", keyring.keys.len());
+                }
+                None => println!("{text}"),
+            }
+            eprintln!("Store the export away from this machine and the NAS, like in a password manager; anyone holding it can decrypt the backups");
+        }
+        _ => unreachable!("key actions are validated by the parser"),
+    }
+    Ok(())
+}
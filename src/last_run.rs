@@ -0,0 +1,97 @@
+//! `last_run.json`: how each job's latest run went, one file per job in
+//! `jobs/<job>/` under the state directory, for schedulers and scripts to
+//! poll instead of reading the run log or the messages. It is replaced
+//! whole, by renaming a file written next to it, so a reader never sees
+//! half of it.
+
+use crate::paths;
+use crate::runlog::JobRun;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LastRun {
+    job: String,
+    /// `"running"` while it runs, then the result the run log records
+    status: String,
+    start: String,
+    end: Option<String>,
+    error: Option<String>,
+    warning: Option<String>,
+    /// Where the archive was uploaded to, as `target:path`
+    uploaded: Vec<String>,
+    archive_bytes: Option<u64>,
+    /// When the job last succeeded, this run or an earlier one
+    last_success: Option<String>,
+    /// The process that wrote it, to tell a run that is still going from one that died
+    pid: u32,
+}
+
+pub fn path(job: &str) -> Result<PathBuf> {
+    Ok(paths::state_dir()?
+        .join("jobs")
+        .join(job)
+        .join("last_run.json"))
+}
+
+/// Marks `job` as running since `start`.
+pub fn running(job: &str, start: &str) -> Result<()> {
+    let path = path(job)?;
+    let last = LastRun {
+        job: job.to_string(),
+        status: "running".to_string(),
+        start: start.to_string(),
+        end: None,
+        error: None,
+        warning: None,
+        uploaded: Vec::new(),
+        archive_bytes: None,
+        last_success: last_success(&path),
+        pid: std::process::id(),
+    };
+    write(&path, &last)
+}
+
+/// Records how `run` went as its job's last run.
+pub fn finished(run: &JobRun) -> Result<()> {
+    let path = path(&run.job)?;
+    let last = LastRun {
+        job: run.job.clone(),
+        status: run.result.to_string(),
+        start: run.start.clone(),
+        end: Some(run.end.clone()),
+        error: run.error.clone(),
+        warning: run.warning.clone(),
+        uploaded: run.uploaded.clone(),
+        archive_bytes: run.archive_bytes,
+        last_success: match run.result {
+            "ok" => Some(run.end.clone()),
+            _ => last_success(&path),
+        },
+        pid: std::process::id(),
+    };
+    write(&path, &last)
+}
+
+/// The `last_success` of the file at `path`, if it has one.
+fn last_success(path: &Path) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str::<LastRun>(&text).ok()?.last_success
+}
+
+fn write(path: &Path, last: &LastRun) -> Result<()> {
+    let dir = path.parent().unwrap();
+    let partial = dir.join(format!(".last_run.json.{}", std::process::id()));
+    let written = std::fs::create_dir_all(dir).and_then(|()| {
+        let mut file = std::fs::File::create(&partial)?;
+        file.write_all(serde_json::to_string_pretty(last)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&partial, path)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written.with_context(|| format!("Could not write {}", path.display()))
+}
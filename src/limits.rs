@@ -0,0 +1,284 @@
+//! Per-job resource limits: CPU and IO priority while archiving, and the upload bandwidth.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// An IO scheduling class as `ionice` knows it, written `"idle"` or `"best-effort 7"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum IoNice {
+    Idle,
+    /// Best effort with a level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+impl TryFrom<String> for IoNice {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<IoNice> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["idle"] => Ok(IoNice::Idle),
+            ["best-effort"] => Ok(IoNice::BestEffort(4)),
+            ["best-effort", level] => match level.parse::<u8>() {
+                Ok(level) if level <= 7 => Ok(IoNice::BestEffort(level)),
+                _ => Err(anyhow!("best-effort levels go from 0 to 7, not {level}")),
+            },
+            _ => Err(anyhow!(
+                "invalid ionice {s:?}; use \"idle\" or \"best-effort LEVEL\""
+            )),
+        }
+    }
+}
+
+/// Scheduling priority for the thread that archives a job.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub ionice: Option<IoNice>,
+}
+
+/// Lowers the priority of the calling thread. Threads it spawns inherit the
+/// priority, so the archiver's workers run at it too.
+///
+/// Linux schedules threads individually, so the rest of the process keeps its
+/// priority and later jobs are unaffected. Elsewhere this only warns.
+pub fn lower_current_thread(priority: Priority) {
+    if priority.nice.is_none() && priority.ionice.is_none() {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    if let Err(e) = linux::lower_current_thread(priority) {
+        eprintln!("Could not lower the archiving priority: {e}");
+    }
+    #[cfg(not(target_os = "linux"))]
+    eprintln!("nice and ionice are only supported on Linux; archiving at normal priority");
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{IoNice, Priority};
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    pub fn lower_current_thread(priority: Priority) -> std::io::Result<()> {
+        let tid = unsafe { libc::gettid() };
+        if let Some(nice) = priority.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(ionice) = priority.ionice {
+            let prio = match ionice {
+                IoNice::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+                IoNice::BestEffort(level) => {
+                    IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | level as libc::c_int
+                }
+            };
+            let done =
+                unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, prio) };
+            if done != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a byte count like `"512"`, `"500KiB"`, `"2MiB"` or `"1.5MB"`.
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| anyhow!("expected a size like 2MiB, got {s:?}"))?;
+    let factor = match unit.trim() {
+        "" | "B" => 1u64,
+        "KB" | "kB" => 1000,
+        "KiB" | "K" => 1 << 10,
+        "MB" => 1000 * 1000,
+        "MiB" | "M" => 1 << 20,
+        "GB" => 1000 * 1000 * 1000,
+        "GiB" | "G" => 1 << 30,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "TiB" | "T" => 1 << 40,
+        unit => return Err(anyhow!("unknown size unit {unit:?} in {s:?}")),
+    };
+    Ok((number * factor as f64) as u64)
+}
+
+/// Bytes per second, written like `"2MiB"` or `"2MiB/s"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteRate(pub u64);
+
+impl TryFrom<String> for ByteRate {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<ByteRate> {
+        let rate = parse_bytes(s.strip_suffix("/s").unwrap_or(&s))?;
+        if rate == 0 {
+            return Err(anyhow!("a rate limit of 0 would never finish"));
+        }
+        Ok(ByteRate(rate))
+    }
+}
+
+/// A number of bytes, written like `"500GB"` or `"2GiB"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteSize(pub u64);
+
+impl TryFrom<String> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<ByteSize> {
+        Ok(ByteSize(parse_bytes(&s)?))
+    }
+}
+
+/// A reader that sleeps as needed to average at most `rate` bytes per second.
+pub struct Throttled<R> {
+    inner: R,
+    rate: u64,
+    start: Instant,
+    read: u64,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, rate: ByteRate) -> Self {
+        Throttled {
+            inner,
+            rate: rate.0,
+            start: Instant::now(),
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Small chunks keep the stream smooth instead of bursting then pausing.
+        let chunk = buf.len().min((self.rate / 10).max(1) as usize);
+        let n = self.inner.read(&mut buf[..chunk])?;
+        self.read += n as u64;
+        let due = Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        Ok(n)
+    }
+}
+
+/// A duration written like `"90s"`, `"45m"`, `"2h"` or `"1h30m"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HumanDuration(pub Duration);
+
+impl TryFrom<String> for HumanDuration {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<HumanDuration> {
+        let mut total = 0u64;
+        let mut number = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let unit = match c {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => {
+                    return Err(anyhow!(
+                        "invalid duration {s:?}; use e.g. \"90m\" or \"1h30m\""
+                    ))
+                }
+            };
+            let n = number
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid duration {s:?}; a number must precede {c}"))?;
+            total += n * unit;
+            number.clear();
+        }
+        if !number.is_empty() || total == 0 {
+            return Err(anyhow!(
+                "invalid duration {s:?}; use e.g. \"90m\" or \"1h30m\""
+            ));
+        }
+        Ok(HumanDuration(Duration::from_secs(total)))
+    }
+}
+
+impl std::fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if h > 0 {
+            write!(f, "{h}h")?;
+        }
+        if m > 0 {
+            write!(f, "{m}m")?;
+        }
+        if s > 0 || secs == 0 {
+            write!(f, "{s}s")?;
+        }
+        Ok(())
+    }
+}
+
+/// The error readers return once a job's `max_duration` has run out.
+pub fn timed_out() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "the job ran longer than its max_duration",
+    )
+}
+
+pub fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+/// A reader that fails with [`timed_out`] once `deadline` has passed.
+pub struct Timed<R> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R> Timed<R> {
+    pub fn new(inner: R, deadline: Option<Instant>) -> Self {
+        Timed { inner, deadline }
+    }
+}
+
+impl<R: Read> Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if expired(self.deadline) {
+            return Err(timed_out());
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Wraps an upload body so it respects the job's rate limit and deadline.
+pub fn limited<R: Read + Send + 'static>(
+    inner: R,
+    rate_limit: Option<ByteRate>,
+    deadline: Option<Instant>,
+) -> Box<dyn Read + Send> {
+    let timed = Timed::new(inner, deadline);
+    match rate_limit {
+        Some(rate) => Box::new(Throttled::new(timed, rate)),
+        None => Box::new(timed),
+    }
+}
@@ -0,0 +1,185 @@
+//! A folder on this machine standing in for a NAS, with its subfolders as shares.
+
+use crate::backend::StorageBackend;
+use crate::config::Connection;
+use crate::limits::{self, ByteRate};
+use crate::{RemoteFile, SharedFolder};
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+pub struct LocalDir {
+    root: PathBuf,
+    shares: Vec<SharedFolder>,
+}
+
+impl LocalDir {
+    pub fn open(nas: &Connection) -> Result<LocalDir> {
+        let root = PathBuf::from(
+            nas.path
+                .as_deref()
+                .ok_or_else(|| anyhow!("The local transport needs a `path`"))?,
+        );
+        let mut shares = Vec::new();
+        let entries = std::fs::read_dir(&root)
+            .with_context(|| format!("Could not read {}", root.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                shares.push(SharedFolder {
+                    path: format!("/{name}"),
+                    name,
+                    free_space: None,
+                });
+            }
+        }
+        Ok(LocalDir { root, shares })
+    }
+
+    fn local_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Copies `local` into `folder` as `name`, with `new` only if nothing has
+    /// that name yet.
+    fn copy_in(
+        &self,
+        folder: &str,
+        local: &Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+        new: bool,
+    ) -> Result<()> {
+        let dest = self.local_path(&format!("{folder}/{name}"));
+        eprintln!("Copying file {} to {}", local.display(), dest.display());
+        // Copy under a temporary name, so an interrupted copy never looks like an archive.
+        let partial = dest.with_file_name(format!(".{name}.partial"));
+        let mut source = limits::limited(File::open(local)?, rate_limit, deadline);
+        let copied = File::create(&partial)
+            .and_then(|mut file| std::io::copy(&mut source, &mut file).and(file.sync_all()))
+            .and_then(|()| {
+                // A hard link, unlike a rename, fails where the name is taken.
+                if new {
+                    std::fs::hard_link(&partial, &dest)
+                        .and_then(|()| std::fs::remove_file(&partial))
+                } else {
+                    std::fs::rename(&partial, &dest)
+                }
+            });
+        if let Err(e) = copied {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e).with_context(|| format!("Could not copy to {}", dest.display()));
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for LocalDir {
+    fn kind(&self) -> &'static str {
+        "a local folder"
+    }
+
+    fn shares(&self) -> &[SharedFolder] {
+        &self.shares
+    }
+
+    fn list(&self, folder: &str, recursive: bool) -> Result<Vec<RemoteFile>> {
+        let mut files = Vec::new();
+        let mut folders = vec![folder.trim_end_matches('/').to_string()];
+        while let Some(folder) = folders.pop() {
+            for entry in std::fs::read_dir(self.local_path(&folder))? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = format!("{folder}/{name}");
+                if meta.is_dir() {
+                    if recursive {
+                        folders.push(path);
+                    }
+                    continue;
+                }
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64);
+                files.push(RemoteFile {
+                    name,
+                    path,
+                    size: Some(meta.len()),
+                    mtime,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn upload(
+        &self,
+        folder: &str,
+        local: &Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.copy_in(folder, local, name, rate_limit, deadline, false)
+    }
+
+    fn upload_new(
+        &self,
+        folder: &str,
+        local: &Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.copy_in(folder, local, name, rate_limit, deadline, true)
+    }
+
+    fn download(&self, path: &str, local: &Path) -> Result<()> {
+        std::fs::copy(self.local_path(path), local)?;
+        Ok(())
+    }
+
+    fn resume_download(&self, path: &str, local: &Path, offset: u64) -> Result<()> {
+        let mut source = File::open(self.local_path(path))?;
+        source.seek(SeekFrom::Start(offset))?;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(local)?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        std::io::copy(&mut source, &mut file)?;
+        Ok(())
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut source = File::open(self.local_path(path))?;
+        source.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        source.take(len).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn delete(&self, paths: &[&str]) -> Result<()> {
+        for path in paths {
+            match std::fs::remove_file(self.local_path(path)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(anyhow!("Could not delete {path}: {e}"))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn create_folder(&self, folder: &str) -> Result<()> {
+        Ok(std::fs::create_dir_all(self.local_path(folder))?)
+    }
+}
@@ -0,0 +1,108 @@
+//! Refused logins, counted across runs for each account and NAS. DSM's auto
+//! block bans an IP address after a number of failed logins within a few
+//! minutes, 10 within 5 by default, which a timer retrying a wrong password
+//! soon reaches; the machine then can't back up even once the password is
+//! fixed. So after a few refusals in a row the program stops trying for a while.
+
+use crate::paths;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Refused logins in a row after which no more are tried for [`COOL_DOWN_MINUTES`].
+pub const LIMIT: u32 = 3;
+pub const COOL_DOWN_MINUTES: i64 = 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Refusals {
+    count: u32,
+    /// When the last one was, in RFC 3339
+    last: String,
+}
+
+/// A login that was not tried because the ones before it were refused.
+#[derive(Debug)]
+pub struct Paused {
+    pub count: u32,
+    pub until: DateTime<Local>,
+}
+
+impl std::fmt::Display for Paused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the last {} logins were refused, so none is tried until {} lest DSM's auto block bans this machine; fix the password and run `doctor`, which tries anyway",
+            self.count,
+            self.until.format("%H:%M")
+        )
+    }
+}
+
+impl std::error::Error for Paused {}
+
+fn path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("refused_logins.json"))
+}
+
+/// The refusals on record; a missing or unreadable file counts as none.
+fn load() -> BTreeMap<String, Refusals> {
+    path()
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn store(refusals: &BTreeMap<String, Refusals>) -> Result<()> {
+    let path = path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create {}", dir.display()))?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(refusals)?)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Fails with [`Paused`] while logging in as `key` should be left alone.
+pub fn check(key: &str) -> Result<(), Paused> {
+    let refusals = load();
+    let Some(entry) = refusals.get(key).filter(|r| r.count >= LIMIT) else {
+        return Ok(());
+    };
+    let Ok(last) = DateTime::parse_from_rfc3339(&entry.last) else {
+        return Ok(());
+    };
+    let until = last.with_timezone(&Local) + Duration::minutes(COOL_DOWN_MINUTES);
+    if Local::now() < until {
+        return Err(Paused {
+            count: entry.count,
+            until,
+        });
+    }
+    Ok(())
+}
+
+/// Counts a refused login as `key` and returns how many there were in a row.
+pub fn refused(key: &str) -> Result<u32> {
+    let mut refusals = load();
+    let entry = refusals.entry(key.to_string()).or_insert(Refusals {
+        count: 0,
+        last: String::new(),
+    });
+    entry.count += 1;
+    entry.last = Local::now().to_rfc3339();
+    let count = entry.count;
+    store(&refusals)?;
+    Ok(count)
+}
+
+/// Forgets the refusals of `key` once a login worked.
+pub fn succeeded(key: &str) -> Result<()> {
+    let mut refusals = load();
+    if refusals.remove(key).is_some() {
+        store(&refusals)?;
+    }
+    Ok(())
+}
@@ -1,27 +1,65 @@
 use anyhow::{anyhow, Result};
 use core::panic;
 use reqwest::blocking::multipart::{Form, Part};
-use serde::Deserialize;
-use std::error::Error;
 use std::fs::File;
 use std::io::{Read, Write};
-use zip::write::SimpleFileOptions;
-use zip::ZipWriter;
+use std::time::{Duration, Instant};
 
-struct Client {
-    client: reqwest::blocking::Client,
-    base_url: String,
-}
-
-impl Client {
-    fn get(&self, api_path: &str) -> reqwest::blocking::RequestBuilder {
-        self.client.get(format!("{}/{}", &self.base_url, api_path))
-    }
-
-    fn post(&self, api_path: &str) -> reqwest::blocking::RequestBuilder {
-        self.client.post(format!("{}/{}", &self.base_url, api_path))
-    }
-}
+mod api_info;
+mod archive;
+mod audit;
+mod backend;
+mod backups;
+mod bench;
+mod blake3;
+mod catalog;
+mod chain;
+mod checksum;
+mod cli;
+mod client;
+mod completions;
+mod conditions;
+mod config;
+mod daemon;
+mod docker;
+mod doctor;
+mod dsm;
+mod find;
+mod fleet;
+mod hooks;
+mod install_schedule;
+mod keys;
+mod last_run;
+mod limits;
+mod local;
+mod logins;
+mod paths;
+mod pinning;
+mod preconditions;
+mod queue;
+mod recovery;
+mod report;
+mod restore;
+mod runlog;
+mod schedule;
+mod schema;
+mod sftp;
+mod snapshot;
+mod sparse;
+mod systemd;
+mod usage;
+mod verify_local;
+mod wake;
+mod webdav;
+mod worm;
+mod xattrs;
+mod zip_index;
+use archive::{compress_iter, ArchiveOptions};
+use backend::StorageBackend;
+use client::{Client, HttpError, Mode, Recorder, Replayer, SynoResponse};
+use config::{load_config, Config, Connection, Job, Target, Transport};
+use dsm::Dsm;
+use limits::{ByteRate, HumanDuration};
 
 fn file_station_upload_error_str(code: i64) -> String {
     match code {
@@ -35,6 +73,37 @@ fn file_station_upload_error_str(code: i64) -> String {
     }.into()
 }
 
+fn file_station_delete_error_str(code: i64) -> String {
+    match code {
+        900 => "Failed to delete file(s)/folder(s)",
+        _ => return file_station_common_error_str(code),
+    }
+    .into()
+}
+
+fn file_station_copy_move_error_str(code: i64) -> String {
+    match code {
+        1000 => "Failed to copy files/folders. More information in <errors> object.",
+        1001 => "Failed to move files/folders. More information in <errors> object.",
+        1002 => "An error occurred at the destination. More information in <errors> object.",
+        1003 => "Cannot overwrite or skip the existing file because no overwrite parameter is given.",
+        1004 => "File cannot overwrite a folder with the same name, or folder cannot overwrite a file with the same name.",
+        1006 => "Cannot copy/move file/folder with special characters to a FAT32 file system.",
+        1007 => "Cannot copy/move a file bigger than 4G to a FAT32 file system.",
+        _ => return file_station_common_error_str(code),
+    }
+    .into()
+}
+
+fn file_station_create_folder_error_str(code: i64) -> String {
+    match code {
+        1100 => "Failed to create a folder. More information in <errors> object.",
+        1101 => "The number of folders to the parent folder would exceed the system limitation.",
+        _ => return file_station_common_error_str(code),
+    }
+    .into()
+}
+
 fn file_station_common_error_str(code: i64) -> String {
     match code {
         400 => "Invalid parameter of file operation",
@@ -88,28 +157,78 @@ fn auth_error_str(code: i64) -> String {
         402 => "Permission denied",
         403 => "2-step verification code required",
         404 => "Failed to authenticate 2-step verification code",
+        406 => "2-step verification is enforced for this account",
+        407 => "Too many failed logins; the IP address is blocked",
+        408 => "The password has expired and cannot be changed",
+        409 => "The password has expired",
+        410 => "The password must be changed",
         _ => return format_common_error(code),
     }
     .into()
 }
 
-fn format_error_response(api_name: &str, resp: SynoResponse) -> anyhow::Error {
+/// An error code DSM answered a request with, and what it means for that API.
+#[derive(Debug)]
+pub struct ApiError {
+    /// The API that answered, like `SYNO.FileStation.Upload`; codes mean
+    /// different things to different APIs
+    pub api: String,
+    pub code: i64,
+    pub message: String,
+}
+
+/// Whether `e` is FileStation's 402, the NAS saying it is too busy.
+fn nas_busy(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.downcast_ref::<ApiError>()
+            .is_some_and(|e| e.code == 402 && e.api.starts_with("SYNO.FileStation."))
+    })
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} - {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Whether `e` is DSM saying the account lacks permission (code 105).
+fn permission_denied(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ApiError>().is_some_and(|e| e.code == 105)
+}
+
+fn format_error_response(api_name: &str, method: &str, resp: SynoResponse) -> anyhow::Error {
     let code = resp
         .error
-        .expect("Cannot format error if there is no error!")
-        .as_object()
-        .unwrap()
-        .get("code")
-        .expect("All error objects must have an error code")
-        .as_i64()
-        .unwrap();
+        .as_ref()
+        .and_then(|error| error.get("code"))
+        .and_then(|code| code.as_i64());
+    let Some(code) = code else {
+        let body = serde_json::json!({"success": resp.success, "error": resp.error});
+        return HttpError::malformed(api_name, method, &body).into();
+    };
     let error_str = match api_name {
         "SYNO.API.Auth" => auth_error_str(code),
-        "SYNO.FileStation.List" => file_station_common_error_str(code),
+        "SYNO.FileStation.List"
+        | "SYNO.FileStation.CheckPermission"
+        | "SYNO.FileStation.DirSize"
+        | "SYNO.FileStation.Search"
+        | "SYNO.FileStation.Download"
+        | "SYNO.FileStation.MD5" => file_station_common_error_str(code),
+        "SYNO.FileStation.CreateFolder" => file_station_create_folder_error_str(code),
         "SYNO.FileStation.Upload" => file_station_upload_error_str(code),
-        _ => panic!("Unknown API name"),
+        "SYNO.FileStation.Delete" => file_station_delete_error_str(code),
+        "SYNO.FileStation.CopyMove" => file_station_copy_move_error_str(code),
+        // APIs without codes of their own, like SYNO.Core.System, share the common ones.
+        _ => format_common_error(code),
     };
-    anyhow!("{} - {}", code, error_str)
+    ApiError {
+        api: api_name.to_string(),
+        code,
+        message: error_str,
+    }
+    .into()
 }
 
 #[derive(Debug)]
@@ -120,93 +239,248 @@ struct ApiInfo {
     name: String,
 }
 
+/// APIs every backup needs; the rest are only looked for when a feature uses them.
+const REQUIRED_APIS: &[&str] = &[
+    "SYNO.API.Auth",
+    "SYNO.FileStation.List",
+    "SYNO.FileStation.Upload",
+];
+
+/// The NAS's API `name`, which must offer `version`.
+fn find_api<'a>(apis: &'a [ApiInfo], name: &str, version: u8) -> Result<&'a ApiInfo> {
+    let api = apis
+        .iter()
+        .find(|x| x.name == name)
+        .ok_or_else(|| missing_apis(&[name]))?;
+    if !(api.min_version..=api.max_version).contains(&version) {
+        return Err(anyhow!(
+            "The NAS offers {name} versions {} to {}, but this program needs version {version}",
+            api.min_version,
+            api.max_version
+        ));
+    }
+    Ok(api)
+}
+
+/// Fails naming the required APIs the NAS doesn't offer, so a missing
+/// package shows up before anything is archived.
+fn check_required_apis(apis: &[ApiInfo]) -> Result<()> {
+    let missing = REQUIRED_APIS
+        .iter()
+        .copied()
+        .filter(|name| !apis.iter().any(|x| x.name == *name))
+        .collect::<Vec<_>>();
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(missing_apis(&missing)),
+    }
+}
+
+/// Says which APIs are missing and where they come from.
+fn missing_apis(names: &[&str]) -> anyhow::Error {
+    let they = if names.len() == 1 {
+        "It comes"
+    } else {
+        "They come"
+    };
+    let fix = if names
+        .iter()
+        .all(|name| name.starts_with("SYNO.FileStation."))
+    {
+        "with File Station: check in Package Center that it is installed and running, and in Control Panel > User that the account may use it"
+    } else {
+        "with DSM itself, so this may not be a Synology NAS, or a proxy in between keeps the web API from it"
+    };
+    anyhow!("The NAS doesn't offer {}. {they} {fix}", names.join(", "))
+}
+
+/// The version of each API this program speaks, but for `SYNO.API.Auth`,
+/// whose version follows the DSM release (see [`Dsm::detect`]).
+const API_VERSIONS: &[(&str, u8)] = &[
+    ("SYNO.API.Info", 1),
+    ("SYNO.FileStation.List", 2),
+    ("SYNO.FileStation.Upload", 2),
+    ("SYNO.FileStation.Delete", 2),
+    ("SYNO.FileStation.CopyMove", 3),
+    ("SYNO.FileStation.CheckPermission", 3),
+    ("SYNO.FileStation.DirSize", 2),
+    ("SYNO.FileStation.Search", 2),
+    ("SYNO.FileStation.CreateFolder", 2),
+    ("SYNO.FileStation.Download", 2),
+    ("SYNO.FileStation.MD5", 2),
+    ("SYNO.Core.System", 1),
+];
+
+fn api_version(name: &str) -> u8 {
+    API_VERSIONS
+        .iter()
+        .find(|(x, _)| *x == name)
+        .unwrap_or_else(|| panic!("{name} is not in API_VERSIONS"))
+        .1
+}
+
+/// The APIs this program uses, as the NAS describes them.
 fn get_api_versions(client: &Client) -> Result<Vec<ApiInfo>> {
+    let names = API_VERSIONS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(["SYNO.API.Auth", "SYNO.FileStation.Info"])
+        .collect::<Vec<_>>();
+    query_api_info(client, &names.join(","))
+}
+
+/// Asks `SYNO.API.Info` about the comma-separated APIs in `query`, or `all`.
+fn query_api_info(client: &Client, query: &str) -> Result<Vec<ApiInfo>> {
     let api_name = "SYNO.API.Info";
-    let version = 1;
+    let version = api_version(api_name);
     let method = "query";
     let api_path = "query.cgi";
 
-    let resp = client.get(api_path)
-        .query(&[("api", api_name), ("version", &version.to_string()), ("method", method), ("query", "SYNO.API.Info,SYNO.API.Auth,SYNO.FileStation.Info,SYNO.FileStation.Upload,SYNO.FileStation.List")])
-        .send()
-        .unwrap().json::<SynoResponse>().unwrap();
+    let request = client.get(api_path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("query", query),
+    ]);
+    let resp = client.send(api_name, method, request)?;
     if resp.success {
-        let data = resp
-            .data
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
+        let malformed = |body: &serde_json::Value| HttpError::malformed(api_name, method, body);
+        let data = resp.data.unwrap_or_default();
+        let apis = data.as_object().ok_or_else(|| malformed(&data))?;
+        apis.iter()
             .map(|(k, v)| {
-                let path = v.get("path").unwrap().as_str().unwrap().to_string();
-                let name = k.to_string();
-                let min_version = v.get("minVersion").unwrap().as_u64().unwrap() as u8;
-                let max_version = v.get("maxVersion").unwrap().as_u64().unwrap() as u8;
-                ApiInfo {
+                let path = v.get("path").and_then(|x| x.as_str());
+                let version = |key| {
+                    v.get(key)
+                        .and_then(|x| x.as_u64())
+                        .and_then(|x| u8::try_from(x).ok())
+                };
+                let min_version = version("minVersion");
+                let max_version = version("maxVersion");
+                let (Some(path), Some(min_version), Some(max_version)) =
+                    (path, min_version, max_version)
+                else {
+                    return Err(malformed(v).into());
+                };
+                Ok(ApiInfo {
                     min_version,
                     max_version,
-                    path,
-                    name,
-                }
+                    path: path.to_string(),
+                    name: k.to_string(),
+                })
             })
-            .collect::<Vec<ApiInfo>>();
-        Ok(data)
+            .collect()
     } else {
-        Err(format_error_response(api_name, resp))
+        Err(format_error_response(api_name, method, resp))
     }
 }
 
 fn login(client: &Client, api: &[ApiInfo], passwd: &str, account: &str) -> Result<()> {
     let api_name = "SYNO.API.Auth";
-    let version = 3;
+    let dsm = Dsm::detect(api)?;
+    let version = dsm.auth_version;
     let method = "login";
-    let api = api.iter().find(|x| x.name == api_name).unwrap();
-    assert!(api.name == api_name);
-    assert!(version <= api.max_version);
-    assert!(api.min_version <= version);
+    let api = find_api(api, api_name, version)?;
 
-    let resp = client
-        .get(&api.path)
-        .query(&[
-            ("api", api_name),
-            ("version", &version.to_string()),
-            ("method", method),
-            ("account", account),
-            ("passwd", passwd),
-            ("format", "cookie"),
-        ])
-        .send()
-        .unwrap()
-        .json::<SynoResponse>()
-        .unwrap();
+    let version = version.to_string();
+    let mut query = vec![
+        ("api", api_name),
+        ("version", &version),
+        ("method", method),
+        ("account", account),
+        ("passwd", passwd),
+    ];
+    query.extend_from_slice(dsm.login_params());
+    let request = client.get(&api.path).query(&query);
+    let resp = client.send(api_name, method, request)?;
+    if resp.success {
+        Ok(())
+    } else {
+        Err(format_error_response(api_name, method, resp))
+    }
+}
+
+/// Whether `e` is DSM refusing the login itself, as opposed to the NAS being unreachable.
+fn login_refused(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.is::<logins::Paused>()
+            || e.downcast_ref::<ApiError>()
+                .is_some_and(|e| matches!(e.code, 400 | 401 | 407))
+    })
+}
+
+/// Logs in to `nas`, unless its last logins were refused so often that another
+/// one might get this machine blocked. `force` tries anyway, for `doctor`.
+fn login_counted(client: &Client, api: &[ApiInfo], nas: &Connection, force: bool) -> Result<()> {
+    if matches!(client.mode, Mode::Replay(_)) {
+        return login(client, api, &nas.pwd, &nas.usr);
+    }
+    let key = format!("{}@{}:{}", nas.usr, nas.domain, nas.port);
+    if !force {
+        logins::check(&key)?;
+    }
+    let result = login(client, api, &nas.pwd, &nas.usr);
+    let counted = match &result {
+        Ok(()) => logins::succeeded(&key),
+        Err(e) if login_refused(e) => logins::refused(&key).map(|count| {
+            if e.downcast_ref::<ApiError>().is_some_and(|e| e.code == 407) {
+                eprintln!(
+                    "DSM's auto block has banned this machine; lift it under Control Panel > Security > Protection > Allow/Block List"
+                );
+            } else {
+                eprintln!(
+                    "The login as {} on {} was refused, {count} times in a row. DSM's auto block bans IP addresses after too many, so after {} no more are tried for {} minutes",
+                    nas.usr,
+                    nas.domain,
+                    logins::LIMIT,
+                    logins::COOL_DOWN_MINUTES
+                );
+            }
+        }),
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = counted {
+        eprintln!("Could not count the login: {e:#}");
+    }
+    result
+}
+
+/// Asks DSM to shut the NAS down, which only an administrator may.
+fn shutdown(client: &Client, api: &[ApiInfo]) -> Result<()> {
+    let api_name = "SYNO.Core.System";
+    let version = api_version(api_name);
+    let method = "shutdown";
+    let api = find_api(api, api_name, version)?;
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+    ]);
+    let resp = client.send(api_name, method, request)?;
     if resp.success {
         Ok(())
     } else {
-        Err(format_error_response(api_name, resp))
+        Err(format_error_response(api_name, method, resp))
     }
 }
 
 fn logout(client: &Client, api: &[ApiInfo]) -> Result<()> {
     let api_name = "SYNO.API.Auth";
-    let version = 3;
+    let version = Dsm::detect(api)?.auth_version;
     let method = "logout";
-    let api = api.iter().find(|x| x.name == api_name).unwrap();
-    let resp = client
-        .get(&api.path)
-        .query(&[
-            ("api", api_name),
-            ("version", &version.to_string()),
-            ("method", method),
-            ("format", "cookie"),
-        ])
-        .send()
-        .unwrap()
-        .json::<SynoResponse>()
-        .unwrap();
+    let api = find_api(api, api_name, version)?;
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("session", "FileStation"),
+        ("format", "cookie"),
+    ]);
+    let resp = client.send(api_name, method, request)?;
     if resp.success {
         Ok(())
     } else {
-        Err(format_error_response(api_name, resp))
+        Err(format_error_response(api_name, method, resp))
     }
 }
 
@@ -214,59 +488,669 @@ fn logout(client: &Client, api: &[ApiInfo]) -> Result<()> {
 struct SharedFolder {
     name: String,
     path: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct SynoResponse {
-    success: bool,
-    data: Option<serde_json::Value>,
-    error: Option<serde_json::Value>,
+    /// Free bytes on the volume holding the share, if DSM reported it
+    free_space: Option<u64>,
 }
 
 fn list_fileshares(client: &Client, api: &[ApiInfo]) -> Result<Vec<SharedFolder>> {
     let api_name = "SYNO.FileStation.List";
-    let version = 2;
+    let version = api_version(api_name);
     let method = "list_share";
-    let api = api.iter().find(|x| x.name == api_name).unwrap();
-    assert!(api.name == api_name);
-    assert!(version <= api.max_version);
-    assert!(api.min_version <= version);
+    let api = find_api(api, api_name, version)?;
+
+    let data = list_all(
+        client,
+        api,
+        version,
+        method,
+        &[("additional", r#"["volume_status"]"#)],
+        "shares",
+    )?;
+    data.iter()
+        .map(|x| {
+            let file = remote_file(api_name, method, x)?;
+            Ok(SharedFolder {
+                name: file.name,
+                path: file.path,
+                free_space: x
+                    .pointer("/additional/volume_status/freespace")
+                    .and_then(|x| x.as_u64()),
+            })
+        })
+        .collect()
+}
+
+/// Shares known by name only, where FileStation keeps them: in `/<name>`.
+fn named_shares<S: AsRef<str>>(names: &[S]) -> Vec<SharedFolder> {
+    names
+        .iter()
+        .map(|name| SharedFolder {
+            name: name.as_ref().to_string(),
+            path: format!("/{}", name.as_ref()),
+            free_space: None,
+        })
+        .collect()
+}
+
+/// A file or folder on the NAS, as returned by `SYNO.FileStation.List`.
+#[derive(Debug, Clone)]
+struct RemoteFile {
+    name: String,
+    path: String,
+    size: Option<u64>,
+    /// Modification time as a unix timestamp
+    mtime: Option<i64>,
+}
+
+/// Entries per request when paging through FileStation listings.
+const PAGE_SIZE: usize = 1000;
+
+/// Calls a paginated FileStation listing method until `total` entries have been
+/// fetched, returning the entries under `key` from all pages.
+fn list_all(
+    client: &Client,
+    api: &ApiInfo,
+    version: u8,
+    method: &str,
+    params: &[(&str, &str)],
+    key: &str,
+) -> Result<Vec<serde_json::Value>> {
+    let api_name = api.name.as_str();
+    let mut entries = Vec::new();
+    loop {
+        let request = client
+            .get(&api.path)
+            .query(&[
+                ("api", api_name),
+                ("version", &version.to_string()),
+                ("method", method),
+                ("offset", &entries.len().to_string()),
+                ("limit", &PAGE_SIZE.to_string()),
+            ])
+            .query(params);
+        let resp = client.send(api_name, method, request)?;
+        if !resp.success {
+            return Err(format_error_response(api_name, method, resp));
+        }
+        let data = resp.data.unwrap_or_default();
+        let page = data
+            .get(key)
+            .and_then(|x| x.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let total = data.get("total").and_then(|x| x.as_u64()).unwrap_or(0) as usize;
+        let done = page.is_empty();
+        entries.extend(page);
+        if done || entries.len() >= total {
+            return Ok(entries);
+        }
+    }
+}
+
+/// What [`list_folder`] should return.
+#[derive(Default)]
+struct ListQuery<'a> {
+    /// Glob the names must match, applied by DSM
+    pattern: Option<&'a str>,
+    /// Descend into subfolders
+    recursive: bool,
+}
+
+/// Lists the files in a remote folder, through as many pages as it takes,
+/// newest first by modification time.
+fn list_folder(
+    client: &Client,
+    apis: &[ApiInfo],
+    folder: &str,
+    query: &ListQuery,
+) -> Result<Vec<RemoteFile>> {
+    let api_name = "SYNO.FileStation.List";
+    let version = api_version(api_name);
+    let method = "list";
+    let api = find_api(apis, api_name, version)?;
+
+    let mut files = Vec::new();
+    let mut folders = vec![folder.to_string()];
+    while let Some(folder) = folders.pop() {
+        let mut params = vec![
+            ("folder_path", folder.as_str()),
+            ("sort_by", "mtime"),
+            ("sort_direction", "desc"),
+            ("additional", r#"["size","time"]"#),
+        ];
+        if let Some(pattern) = query.pattern {
+            params.push(("pattern", pattern));
+        }
+        if query.recursive {
+            let dirs = [&params[..4], &[("filetype", "dir")]].concat();
+            for dir in list_all(client, api, version, method, &dirs, "files")? {
+                folders.push(remote_file(api_name, method, &dir)?.path);
+            }
+        }
+        params.push(("filetype", "file"));
+        let entries = list_all(client, api, version, method, &params, "files")?;
+        for entry in &entries {
+            files.push(remote_file(api_name, method, entry)?);
+        }
+    }
+    files.sort_by_key(|f| std::cmp::Reverse(f.mtime));
+    Ok(files)
+}
+
+/// Ending of the file that pins the archive of the same stem.
+const PIN_SUFFIX: &str = ".pinned";
+
+/// One of a job's archives on the NAS.
+struct Backup {
+    file: RemoteFile,
+    /// When it was made, from the timestamp in its name
+    time: chrono::DateTime<chrono::Utc>,
+    /// The `--tag` it was made with, from its name
+    tag: Option<String>,
+    /// Protected from `prune` by a `.pinned` file next to it
+    pinned: bool,
+    /// Paths of the files that belong with it, like
+    /// `notes.txt_20240101_030000.pinned` for `notes.txt_20240101_030000.zip`
+    sidecars: Vec<String>,
+}
+
+/// The job's archives in `folder`, and in its subfolders if `recursive`, newest first.
+fn list_backups(
+    client: &Client,
+    apis: &[ApiInfo],
+    job: &Job,
+    folder: &str,
+    recursive: bool,
+) -> Result<Vec<Backup>> {
+    // Not just `*.zip`: the sidecars next to the archives matter too.
+    let pattern = format!("{}*", backup_prefix(job));
+    let query = ListQuery {
+        pattern: Some(&pattern),
+        recursive,
+    };
+    Ok(job_backups(job, list_folder(client, apis, folder, &query)?))
+}
+
+/// The files among `files` that are archives of `job`, newest first, each
+/// with the sidecars next to it.
+fn job_backups(job: &Job, files: Vec<RemoteFile>) -> Vec<Backup> {
+    let mut sidecars = std::collections::HashMap::<String, Vec<String>>::new();
+    let mut backups = Vec::new();
+    for file in files {
+        if let Some((time, tag)) = backup_stamp(job, &file.name) {
+            backups.push((file, time, tag));
+        } else if let Some(archive) = sidecar_archive(job, &file) {
+            sidecars.entry(archive).or_default().push(file.path);
+        }
+    }
+    let mut backups = backups
+        .into_iter()
+        .map(|(file, time, tag)| {
+            let sidecars = sidecars.remove(&file.path).unwrap_or_default();
+            Backup {
+                pinned: sidecars.iter().any(|s| s.ends_with(PIN_SUFFIX)),
+                file,
+                time,
+                tag,
+                sidecars,
+            }
+        })
+        .collect::<Vec<_>>();
+    backups.sort_by_key(|b| std::cmp::Reverse(b.time));
+    backups
+}
+
+/// The entry `x` of a FileStation listing `api_name` `method` returned.
+fn remote_file(api_name: &str, method: &str, x: &serde_json::Value) -> Result<RemoteFile> {
+    let name = x.get("name").and_then(|x| x.as_str());
+    let path = x.get("path").and_then(|x| x.as_str());
+    let (Some(name), Some(path)) = (name, path) else {
+        return Err(HttpError::malformed(api_name, method, x).into());
+    };
+    Ok(RemoteFile {
+        name: name.to_string(),
+        path: path.to_string(),
+        size: x.pointer("/additional/size").and_then(|x| x.as_u64()),
+        mtime: x.pointer("/additional/time/mtime").and_then(|x| x.as_i64()),
+    })
+}
+
+/// Searches `folders` recursively for files whose names match the glob `pattern`.
+fn search_files(
+    client: &Client,
+    apis: &[ApiInfo],
+    folders: &[&str],
+    pattern: &str,
+) -> Result<Vec<RemoteFile>> {
+    let api_name = "SYNO.FileStation.Search";
+    let version = api_version(api_name);
+    let method = "start";
+    let api = find_api(apis, api_name, version)?;
+
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("folder_path", &serde_json::to_string(folders)?),
+        ("recursive", "true"),
+        ("pattern", pattern),
+        ("filetype", "file"),
+    ]);
+    let resp = client.send(api_name, method, request)?;
+    if !resp.success {
+        return Err(format_error_response(api_name, method, resp));
+    }
+    let taskid = resp
+        .data
+        .as_ref()
+        .and_then(|d| d.get("taskid"))
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| anyhow!("{api_name} returned no taskid"))?
+        .to_string();
+
+    // A search reports progress through `list`, which also returns what it found so far.
+    let list = (
+        "list",
+        &[
+            ("offset", "0"),
+            ("limit", "0"),
+            ("additional", r#"["size","time"]"#),
+        ][..],
+    );
+    let result =
+        wait_for_task(client, api, api_name, &taskid, list, "The search").and_then(|data| {
+            data.get("files")
+                .and_then(|f| f.as_array())
+                .map(|files| {
+                    files
+                        .iter()
+                        .map(|file| remote_file(api_name, "list", file))
+                        .collect()
+                })
+                .unwrap_or_else(|| Ok(Vec::new()))
+        });
 
-    let resp = client
+    // DSM keeps finished searches around until they are cleaned up.
+    let method = "clean";
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("taskid", &taskid),
+    ]);
+    let _ = client.send(api_name, method, request);
+    result
+}
+
+/// Starts a FileStation background task with `params` and polls it to completion.
+/// `what` names the task in progress messages and errors.
+fn run_task(
+    client: &Client,
+    apis: &[ApiInfo],
+    api_name: &str,
+    params: &[(&str, &str)],
+    what: &str,
+) -> Result<serde_json::Value> {
+    let version = api_version(api_name);
+    let api = find_api(apis, api_name, version)?;
+
+    let method = "start";
+    let request = client
         .get(&api.path)
         .query(&[
             ("api", api_name),
             ("version", &version.to_string()),
             ("method", method),
         ])
-        .send()
-        .unwrap()
-        .json::<SynoResponse>()
-        .unwrap();
+        .query(params);
+    let resp = client.send(api_name, method, request)?;
+    if !resp.success {
+        return Err(format_error_response(api_name, method, resp));
+    }
+    let taskid = resp
+        .data
+        .as_ref()
+        .and_then(|d| d.get("taskid"))
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| anyhow!("{api_name} returned no taskid"))?
+        .to_string();
+    let status = ("status", &[][..]);
+    wait_for_task(client, api, api_name, &taskid, status, what)
+}
+
+/// Polls a background task with `poll`, the method that reports on it and
+/// its further parameters, until it reports `finished`, printing its
+/// progress every few seconds. After the client's `task_timeout` the task
+/// is stopped.
+fn wait_for_task(
+    client: &Client,
+    api: &ApiInfo,
+    api_name: &str,
+    taskid: &str,
+    poll: (&str, &[(&str, &str)]),
+    what: &str,
+) -> Result<serde_json::Value> {
+    let version = api_version(api_name);
+    let timeout = client.task_timeout;
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut interval = Duration::from_millis(200);
+    loop {
+        let (method, params) = poll;
+        let request = client
+            .get(&api.path)
+            .query(&[
+                ("api", api_name),
+                ("version", &version.to_string()),
+                ("method", method),
+                ("taskid", taskid),
+            ])
+            .query(params);
+        let resp = client.send(api_name, method, request)?;
+        if !resp.success {
+            return Err(format_error_response(api_name, method, resp));
+        }
+        let data = resp.data.unwrap_or_default();
+        if data.get("finished").and_then(|f| f.as_bool()) == Some(true) {
+            return Ok(data);
+        }
+        if started.elapsed() >= timeout {
+            let method = "stop";
+            let request = client.get(&api.path).query(&[
+                ("api", api_name),
+                ("version", &version.to_string()),
+                ("method", method),
+                ("taskid", taskid),
+            ]);
+            let _ = client.send(api_name, method, request);
+            return Err(anyhow!(
+                "{what} did not finish within {}; the task was stopped",
+                HumanDuration(timeout)
+            ));
+        }
+        if last_report.elapsed() >= Duration::from_secs(5) {
+            if let Some(progress) = data.get("progress").and_then(|p| p.as_f64()) {
+                eprintln!("{what}: {:.0}%", progress * 100.0);
+            }
+            last_report = Instant::now();
+        }
+        std::thread::sleep(interval);
+        interval = (interval * 2).min(Duration::from_secs(2));
+    }
+}
+
+/// Deletes remote files or folders, waiting for DSM's background task to finish.
+fn delete_files(client: &Client, apis: &[ApiInfo], paths: &[&str]) -> Result<()> {
+    run_task(
+        client,
+        apis,
+        "SYNO.FileStation.Delete",
+        &[
+            ("path", &serde_json::to_string(paths)?),
+            ("recursive", "true"),
+        ],
+        &format!("Deleting {}", paths.join(", ")),
+    )?;
+    Ok(())
+}
+
+/// Total size in bytes of remote files and folders, counted by DSM.
+fn dir_size(client: &Client, apis: &[ApiInfo], paths: &[&str]) -> Result<u64> {
+    let data = run_task(
+        client,
+        apis,
+        "SYNO.FileStation.DirSize",
+        &[("path", &serde_json::to_string(paths)?)],
+        "Measuring folder sizes",
+    )?;
+    data.get("total_size")
+        .and_then(|x| x.as_u64())
+        .ok_or_else(|| anyhow!("SYNO.FileStation.DirSize returned no total_size"))
+}
+
+/// The MD5 of the remote file at `path`, which DSM computes in the background.
+fn file_md5(client: &Client, apis: &[ApiInfo], path: &str) -> Result<String> {
+    let data = run_task(
+        client,
+        apis,
+        "SYNO.FileStation.MD5",
+        &[("file_path", path)],
+        &format!("Computing the MD5 of {path}"),
+    )?;
+    data.get("md5")
+        .and_then(|x| x.as_str())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("SYNO.FileStation.MD5 returned no md5"))
+}
+
+/// Copies remote files into `dest_folder`, waiting for DSM's background task
+/// to finish. Without `overwrite`, a file of the same name there fails the copy.
+fn copy_files(
+    client: &Client,
+    apis: &[ApiInfo],
+    paths: &[&str],
+    dest_folder: &str,
+    overwrite: bool,
+) -> Result<()> {
+    run_task(
+        client,
+        apis,
+        "SYNO.FileStation.CopyMove",
+        &[
+            ("path", &serde_json::to_string(paths)?),
+            ("dest_folder_path", dest_folder),
+            ("overwrite", &overwrite.to_string()),
+            ("remove_src", "false"),
+        ],
+        &format!("Copying to {dest_folder}"),
+    )?;
+    Ok(())
+}
+
+/// Asks DSM whether the account may create `filename` in `folder`.
+fn check_write_permission(
+    client: &Client,
+    apis: &[ApiInfo],
+    folder: &str,
+    filename: &str,
+) -> Result<()> {
+    let api_name = "SYNO.FileStation.CheckPermission";
+    let version = api_version(api_name);
+    let method = "write";
+    let api = find_api(apis, api_name, version)?;
+
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("path", folder),
+        ("filename", filename),
+        ("overwrite", "true"),
+        ("create_only", "false"),
+    ]);
+    let resp = client.send(api_name, method, request)?;
     if resp.success {
-        let data = resp.data.unwrap();
-        let data = data
-            .as_object()
-            .unwrap()
-            .get("shares")
-            .unwrap()
-            .as_array()
-            .unwrap();
-        let shares = data
-            .iter()
-            .map(|x| SharedFolder {
-                name: x.get("name").unwrap().as_str().unwrap().to_string(),
-                path: x.get("path").unwrap().as_str().unwrap().to_string(),
-            })
-            .collect::<Vec<SharedFolder>>();
-        Ok(shares)
+        Ok(())
+    } else {
+        Err(format_error_response(api_name, method, resp))
+    }
+}
+
+/// Asks for the file at `path`, or with `range`, like `bytes=100-`, part of it.
+fn download_request(
+    client: &Client,
+    apis: &[ApiInfo],
+    path: &str,
+    range: Option<String>,
+) -> Result<reqwest::blocking::Response> {
+    let api_name = "SYNO.FileStation.Download";
+    let version = api_version(api_name);
+    let method = "download";
+    let api = find_api(apis, api_name, version)?;
+
+    let mut request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("path", path),
+        ("mode", "download"),
+    ]);
+    if let Some(range) = range {
+        request = request.header(reqwest::header::RANGE, range);
+    }
+    client
+        .send_for_file(api_name, method, request)?
+        .map_err(|resp| format_error_response(api_name, method, resp))
+}
+
+/// Downloads the remote file `path` to `local`, or with `offset` above 0,
+/// the rest of it after the `offset` bytes `local` holds already. A NAS that
+/// ignores the range sends the whole file, which then replaces `local`.
+fn download_file(
+    client: &Client,
+    apis: &[ApiInfo],
+    path: &str,
+    local: &std::path::Path,
+    offset: u64,
+) -> Result<()> {
+    let range = (offset > 0).then(|| format!("bytes={offset}-"));
+    let mut resp = download_request(client, apis, path, range)?;
+    let mut file = if offset > 0 && resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        let mut file = std::fs::OpenOptions::new().write(true).open(local)?;
+        file.set_len(offset)?;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(offset))?;
+        file
+    } else {
+        File::create(local)?
+    };
+    resp.copy_to(&mut file)?;
+    Ok(())
+}
+
+/// `len` bytes of the file at `path` from `offset` on, through an HTTP range
+/// on the download. Fails if the NAS answers with the whole file instead.
+fn download_range(
+    client: &Client,
+    apis: &[ApiInfo],
+    path: &str,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let range = format!("bytes={offset}-{}", offset + len - 1);
+    let resp = download_request(client, apis, path, Some(range))?;
+    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        // Dropping the response stops the download of the rest.
+        return Err(anyhow!(
+            "the NAS ignores HTTP ranges on SYNO.FileStation.Download"
+        ));
+    }
+    Ok(resp.bytes()?.to_vec())
+}
+
+/// Creates `folder` in the existing folder `parent`, along with any missing
+/// folders in between. An existing folder is left as it is.
+fn create_folder(client: &Client, apis: &[ApiInfo], parent: &str, folder: &str) -> Result<()> {
+    let api_name = "SYNO.FileStation.CreateFolder";
+    let version = api_version(api_name);
+    let method = "create";
+    let api = find_api(apis, api_name, version)?;
+
+    let request = client.get(&api.path).query(&[
+        ("api", api_name),
+        ("version", &version.to_string()),
+        ("method", method),
+        ("folder_path", &serde_json::to_string(&[parent])?),
+        ("name", &serde_json::to_string(&[folder])?),
+        ("force_parent", "true"),
+    ]);
+    let resp = client.send(api_name, method, request)?;
+    if resp.success {
+        Ok(())
+    } else {
+        Err(format_error_response(api_name, method, resp))
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
     } else {
-        Err(format_error_response(api_name, resp))
+        format!("{value:.1} {}", units[unit])
     }
 }
 
-fn add_dt_to_filename(filename: &std::path::Path) -> String {
-    let dt = &chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
+/// The part of a job's uploaded file names before the timestamp, e.g. `Documents_`.
+fn backup_prefix(job: &Job) -> String {
+    let archive = std::path::PathBuf::from(job.filename.clone() + ".zip");
+    let stem = archive.file_stem().unwrap().to_string_lossy();
+    format!("{stem}_")
+}
+
+/// When a remote file was made and with which tag, if its name marks it as
+/// one of `job`'s archives as named by [`add_dt_to_filename`].
+fn backup_stamp(job: &Job, name: &str) -> Option<(chrono::DateTime<chrono::Utc>, Option<String>)> {
+    parse_stamp(
+        name.strip_prefix(&backup_prefix(job))?
+            .strip_suffix(".zip")?,
+    )
+}
+
+/// The path of the archive that `file` goes with, if its name is that of one
+/// of `job`'s archives with another ending, like `.pinned` or `.z01`. The
+/// archive itself needn't exist.
+fn sidecar_archive(job: &Job, file: &RemoteFile) -> Option<String> {
+    let prefix = backup_prefix(job);
+    // Tags have no dots, so the first one ends the stem.
+    let (stamp, ending) = file.name.strip_prefix(&prefix)?.split_once('.')?;
+    if ending == "zip" {
+        return None;
+    }
+    parse_stamp(stamp)?;
+    let folder = file.path.strip_suffix(&file.name)?;
+    Some(format!("{folder}{prefix}{stamp}.zip"))
+}
+
+/// Reads `YYYYmmdd_HHMMSS` with an optional `_tag` after it.
+fn parse_stamp(stamp: &str) -> Option<(chrono::DateTime<chrono::Utc>, Option<String>)> {
+    let dt = stamp.get(.."YYYYmmdd_HHMMSS".len())?;
+    let dt = chrono::NaiveDateTime::parse_from_str(dt, "%Y%m%d_%H%M%S").ok()?;
+    let tag = match &stamp["YYYYmmdd_HHMMSS".len()..] {
+        "" => None,
+        rest => {
+            let tag = rest.strip_prefix('_')?;
+            check_tag(tag).ok()?;
+            Some(tag.to_string())
+        }
+    };
+    Some((dt.and_utc(), tag))
+}
+
+/// Tags go into file names after the timestamp, so they are kept to letters,
+/// digits and dashes.
+fn check_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(anyhow!(
+            "Invalid tag {tag:?}; use letters, digits and dashes, like pre-upgrade"
+        ));
+    }
+    Ok(())
+}
+
+fn add_dt_to_filename(filename: &std::path::Path, tag: Option<&str>) -> String {
+    let mut dt = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
+    if let Some(tag) = tag {
+        dt = format!("{dt}_{tag}");
+    }
     let stem = filename
         .file_stem()
         .unwrap()
@@ -279,19 +1163,70 @@ fn add_dt_to_filename(filename: &std::path::Path) -> String {
     }
 }
 
-fn upload_file(client: &Client, apis: &[ApiInfo], target_path: &str, filename: &str) -> Result<()> {
+/// `name`, one of `job`'s archive names, with its timestamp a second later.
+fn later_name(job: &Job, name: &str) -> Option<String> {
+    let (time, tag) = backup_stamp(job, name)?;
+    let stamp = (time + chrono::Duration::seconds(1)).format("%Y%m%d_%H%M%S");
+    Some(match tag {
+        Some(tag) => format!("{}{stamp}_{tag}.zip", backup_prefix(job)),
+        None => format!("{}{stamp}.zip", backup_prefix(job)),
+    })
+}
+
+/// Streams exactly `len` bytes from a file, so the multipart body always matches
+/// the Content-Length computed up front. A file that shrinks mid-upload becomes
+/// an error instead of a short body that DSM rejects with code 1800.
+struct SizedReader {
+    inner: std::io::Take<File>,
+    remaining: u64,
+}
+
+impl SizedReader {
+    fn new(file: File, len: u64) -> Self {
+        SizedReader {
+            inner: file.take(len),
+            remaining: len,
+        }
+    }
+}
+
+impl Read for SizedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && self.remaining > 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "file ended {} bytes before its announced length",
+                    self.remaining
+                ),
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_file(
+    client: &Client,
+    apis: &[ApiInfo],
+    target_path: &str,
+    filename_path: &std::path::Path,
+    target_file_name: &str,
+    rate_limit: Option<ByteRate>,
+    deadline: Option<Instant>,
+    overwrite: bool,
+) -> Result<()> {
     let api_name = "SYNO.FileStation.Upload";
-    let version = 2;
+    let version = api_version(api_name);
+    let method = "upload";
 
-    let api = apis.iter().find(|x| x.name == api_name).unwrap();
-    assert!(version <= api.max_version);
-    assert!(api.min_version <= version);
+    let api = find_api(apis, api_name, version)?;
 
-    let filename_path = std::path::PathBuf::from(filename);
     if !filename_path.exists() {
         return Err(anyhow!("File to backup does not exist"));
     }
-    let target_file_name = add_dt_to_filename(&filename_path);
     eprintln!(
         "Uploading file {} to {}/{}",
         filename_path.display(),
@@ -299,107 +1234,1747 @@ fn upload_file(client: &Client, apis: &[ApiInfo], target_path: &str, filename: &
         target_file_name
     );
 
+    let file = File::open(filename_path)?;
+    let file_len = file.metadata()?.len();
+    let body = limits::limited(SizedReader::new(file, file_len), rate_limit, deadline);
     let form = Form::new()
         .text("api", api_name)
         .text("version", version.to_string())
-        .text("method", "upload")
+        .text("method", method)
         .text("path", target_path.to_string())
         .text("create_parents", "true")
-        .text("overwrite", "true")
+        .text("overwrite", overwrite.to_string())
         .part(
             "file",
-            Part::file(filename_path)
-                .unwrap()
-                .file_name(target_file_name),
+            Part::reader_with_length(body, file_len)
+                .mime_str("application/octet-stream")?
+                .file_name(target_file_name.to_string()),
         );
 
-    let resp = client
-        .post(&api.path)
-        .multipart(form)
-        .send()
-        .unwrap()
-        .json::<SynoResponse>()
-        .unwrap();
+    let request = client.post(&api.path).multipart(form);
+    let resp = client.send(api_name, method, request)?;
     if resp.success {
         Ok(())
     } else {
-        Err(format_error_response(api_name, resp))
+        Err(format_error_response(api_name, method, resp))
     }
 }
 
-/// Compresses the contents of a directory into a zip file
-/// If the input path is a file, it will be compressed into a zip file
-fn compress_iter(
-    input_path: &std::path::Path,
-    output_path: &std::path::Path,
-) -> Result<(), Box<dyn Error>> {
-    let inner = File::create(output_path)?;
-    let mut zip = ZipWriter::new(inner);
-    let options = SimpleFileOptions::default();
-
-    walkdir::WalkDir::new(input_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .for_each(|input_path| {
-            let input_path = input_path.path();
-            let mut input_file = File::open(input_path).unwrap();
-            let mut buff = Vec::new();
-            zip.start_file_from_path(input_path, options).unwrap();
-            input_file.read_to_end(&mut buff).unwrap();
-            zip.write_all(&buff).unwrap();
-        });
+/// Picks the API transport from `--record <dir>` or `--replay <dir>`.
+fn client_mode(args: &cli::Args) -> Result<Mode> {
+    if let Some(dir) = args.value("record") {
+        return Ok(Mode::Record(Recorder::new(dir.into())?));
+    }
+    if let Some(dir) = args.value("replay") {
+        return Ok(Mode::Replay(Replayer::load(dir.into())?));
+    }
+    Ok(Mode::Live)
+}
 
-    zip.finish()?;
-    Ok(())
+/// An HTTP client for `nas`, with its user agent and headers, the addresses
+/// its `resolve` gives, and trusting the keys it pins.
+fn http_client(nas: &Connection) -> reqwest::blocking::ClientBuilder {
+    let user_agent = nas
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("synology_backuper/{}", env!("CARGO_PKG_VERSION")));
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in &nas.headers {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes());
+        let mut value =
+            reqwest::header::HeaderValue::from_str(value).expect("checked when loading");
+        // They may hold credentials, so keep them out of debug output.
+        value.set_sensitive(true);
+        headers.insert(name.expect("checked when loading"), value);
+    }
+    let mut builder = reqwest::blocking::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers);
+    if !nas.pin_sha256.is_empty() {
+        builder = builder.use_preconfigured_tls(pinning::tls_config(&nas.pin_sha256));
+    }
+    for (host, ip) in &nas.resolve {
+        builder = builder.resolve(host, std::net::SocketAddr::new(*ip, nas.port));
+    }
+    builder
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    domain: String,
-    port: u16,
-    share_name: String,
-    usr: String,
-    pwd: String,
-    filename: String,
+fn build_client(nas: &Connection, mode: Mode) -> Client {
+    Client {
+        client: http_client(nas).cookie_store(true).build().unwrap(),
+        base_url: format!("{}/webapi", nas.origin()),
+        mode,
+        task_timeout: nas.task_timeout.0,
+    }
 }
 
-fn main() {
-    let config = serde_json::from_str::<Config>(
-        &std::fs::read_to_string("config.json").expect("Could not read config file"),
-    )
-    .expect("Could not parse config file");
+/// How long [`answers`] waits for a connection
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
 
-    let input_path = config.filename;
-    let output_path = input_path.clone() + ".zip";
+/// Whether `nas` is up: it takes connections and, through the web API, says
+/// which APIs it offers.
+fn answers(nas: &Connection) -> bool {
+    let reachable = nas.socket_addrs().is_ok_and(|addrs| {
+        addrs
+            .iter()
+            .any(|addr| std::net::TcpStream::connect_timeout(addr, PROBE_TIMEOUT).is_ok())
+    });
+    reachable
+        && (nas.transport != Transport::Api
+            || query_api_info(&build_client(nas, Mode::Live), "SYNO.API.Info").is_ok())
+}
 
-    compress_iter(
-        std::path::Path::new(&input_path),
-        std::path::Path::new(&output_path),
-    )
-    .expect("Failed compressing the target file");
-
-    let client = Client {
-        client: reqwest::blocking::Client::builder()
-            .cookie_store(true)
-            .build()
-            .unwrap(),
-        base_url: format!("https://{}:{}/webapi", config.domain, config.port),
-    };
-    let api_info =
-        get_api_versions(&client).expect("The API version information could not be retrieved");
-    login(&client, &api_info, &config.pwd, &config.usr).expect("Login failed");
-    let shares = list_fileshares(&client, &api_info).expect("I should be able to list shares");
-    match shares.iter().find(|x| x.name == config.share_name) {
-        Some(share) => {
-            let share_path = &share.path;
-            if let Err(e) = upload_file(&client, &api_info, share_path, &output_path) {
-                println!("Error uploading file: {}", e);
+/// A logged in connection to one target.
+struct Session {
+    client: Client,
+    api_info: Vec<ApiInfo>,
+    shares: Vec<SharedFolder>,
+    /// False when the account may not list the shares and none were configured
+    shares_listed: bool,
+}
+
+impl Session {
+    fn open(nas: &Connection, mode: Mode) -> Result<Session> {
+        let client = build_client(nas, mode);
+        let api_info = get_api_versions(&client)?;
+        check_required_apis(&api_info)?;
+        login_counted(&client, &api_info, nas, false)?;
+        let (shares, shares_listed) = if !nas.shares.is_empty() {
+            (named_shares(&nas.shares), true)
+        } else {
+            match list_fileshares(&client, &api_info) {
+                Ok(shares) => (shares, true),
+                Err(e) if permission_denied(&e) => {
+                    eprintln!(
+                        "{} may not list the shares on {}, so each share is taken to be at /<share_name>; set `shares` to say which there are",
+                        nas.usr, nas.domain
+                    );
+                    (Vec::new(), false)
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(Session {
+            client,
+            api_info,
+            shares,
+            shares_listed,
+        })
+    }
+}
+
+impl backend::StorageBackend for Session {
+    fn kind(&self) -> &'static str {
+        "the DSM web API"
+    }
+
+    fn shares(&self) -> &[SharedFolder] {
+        &self.shares
+    }
+
+    fn shares_listed(&self) -> bool {
+        self.shares_listed
+    }
+
+    fn list(&self, folder: &str, recursive: bool) -> Result<Vec<RemoteFile>> {
+        let query = ListQuery {
+            recursive,
+            ..Default::default()
+        };
+        list_folder(&self.client, &self.api_info, folder, &query)
+    }
+
+    /// Lets DSM match the names, rather than listing everything in the folder.
+    fn list_backups(&self, job: &Job, folder: &str, recursive: bool) -> Result<Vec<Backup>> {
+        list_backups(&self.client, &self.api_info, job, folder, recursive)
+    }
+
+    fn upload(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        upload_file(
+            &self.client,
+            &self.api_info,
+            folder,
+            local,
+            name,
+            rate_limit,
+            deadline,
+            true,
+        )
+    }
+
+    /// DSM answers 1805 rather than replace the file.
+    fn upload_new(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        upload_file(
+            &self.client,
+            &self.api_info,
+            folder,
+            local,
+            name,
+            rate_limit,
+            deadline,
+            false,
+        )
+    }
+
+    fn download(&self, path: &str, local: &std::path::Path) -> Result<()> {
+        download_file(&self.client, &self.api_info, path, local, 0)
+    }
+
+    fn resume_download(&self, path: &str, local: &std::path::Path, offset: u64) -> Result<()> {
+        download_file(&self.client, &self.api_info, path, local, offset)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        download_range(&self.client, &self.api_info, path, offset, len)
+    }
+
+    fn delete(&self, paths: &[&str]) -> Result<()> {
+        delete_files(&self.client, &self.api_info, paths)
+    }
+
+    fn create_folder(&self, folder: &str) -> Result<()> {
+        let (parent, name) = folder.rsplit_once('/').unwrap();
+        create_folder(&self.client, &self.api_info, parent, name)
+    }
+
+    /// DSM before 6 has no CheckPermission; the upload itself will tell.
+    fn check_write_permission(&self, folder: &str, file_name: &str) -> Result<()> {
+        if !self
+            .api_info
+            .iter()
+            .any(|x| x.name == "SYNO.FileStation.CheckPermission")
+        {
+            return Ok(());
+        }
+        check_write_permission(&self.client, &self.api_info, folder, file_name)
+    }
+
+    fn session(&self) -> Option<&Session> {
+        Some(self)
+    }
+
+    fn logout(&self) {
+        let _ = logout(&self.client, &self.api_info);
+    }
+}
+
+/// Targets are connected to the first time a job needs them, so an offsite
+/// NAS that is only a fallback costs nothing while the primary one works.
+struct Sessions<'a> {
+    targets: &'a [Target],
+    /// Taken by the primary target; `--record`/`--replay` only cover that one
+    mode: Option<Mode>,
+    live: bool,
+    open: Vec<Option<Result<Box<dyn StorageBackend>, String>>>,
+    /// Whether some target refused the login
+    refused_login: bool,
+    /// The targets that had to be woken up, by index
+    woken: Vec<bool>,
+    /// The targets connected to at some point, by index
+    connected: Vec<bool>,
+}
+
+impl<'a> Sessions<'a> {
+    fn new(targets: &'a [Target], mode: Mode) -> Self {
+        Sessions {
+            targets,
+            live: matches!(mode, Mode::Live),
+            mode: Some(mode),
+            open: targets.iter().map(|_| None).collect(),
+            refused_login: false,
+            woken: targets.iter().map(|_| false).collect(),
+            connected: targets.iter().map(|_| false).collect(),
+        }
+    }
+
+    fn get(&mut self, name: &str) -> (&'a Target, Result<&dyn StorageBackend, &str>) {
+        let i = self.targets.iter().position(|t| t.name == name).unwrap();
+        let target = &self.targets[i];
+        if self.open[i].is_none() {
+            // Once the primary target's mode is used, reconnecting is live.
+            let primary = if i == 0 { self.mode.take() } else { None };
+            let mode = if let Some(mode) = primary {
+                Ok(mode)
+            } else if self.live {
+                Ok(Mode::Live)
+            } else {
+                Err("--record and --replay only cover the primary target".to_string())
+            };
+            if self.live && !self.woken[i] {
+                match wake::wake(&target.name, &target.nas, || answers(&target.nas)) {
+                    Ok(woken) => self.woken[i] = woken,
+                    Err(e) => eprintln!("{e:#}"),
+                }
+            }
+            let session = mode.and_then(|mode| {
+                backend::open(&target.nas, mode).map_err(|e| {
+                    self.refused_login |= login_refused(&e);
+                    format!("{e:#}")
+                })
+            });
+            match &session {
+                Ok(_) => self.connected[i] = true,
+                Err(e) => eprintln!("Could not connect to target {}: {e}", target.name),
+            }
+            self.open[i] = Some(session);
+        }
+        let session = self.open[i].as_ref().unwrap();
+        (target, session.as_deref().map_err(|e| e.as_str()))
+    }
+
+    fn logout(&self) {
+        for remote in self.open.iter().flatten().flatten() {
+            remote.logout();
+        }
+    }
+
+    /// Shuts down the targets this run woke up that have `shutdown_after_wake`,
+    /// and if the run `succeeded`, those it used that have `shutdown_after_success`.
+    fn shut_down(&mut self, succeeded: bool) {
+        let targets = self.targets;
+        for (i, target) in targets.iter().enumerate() {
+            let woken = self.woken[i] && target.nas.shutdown_after_wake;
+            let done = succeeded && self.connected[i] && target.nas.shutdown_after_success;
+            if !self.live || !(woken || done) {
+                continue;
+            }
+            let result = match self.get(&target.name).1 {
+                Ok(remote) => remote
+                    .api("Shutting down")
+                    .and_then(|session| shutdown(&session.client, &session.api_info)),
+                Err(e) => Err(anyhow!("{e}")),
+            };
+            match result {
+                Ok(()) if woken => {
+                    eprintln!("Shut down target {}, which this run woke up", target.name)
+                }
+                Ok(()) => eprintln!("Shut down target {} after the run", target.name),
+                Err(e) => eprintln!("Could not shut down target {}: {e:#}", target.name),
+            }
+        }
+    }
+
+    /// Logs out of every target, so the next job connects afresh. With
+    /// `--record` and `--replay` the sessions stay, as their fixture is read
+    /// or written once.
+    fn close(&mut self) {
+        if !self.live {
+            return;
+        }
+        self.logout();
+        self.open.iter_mut().for_each(|session| *session = None);
+    }
+}
+
+/// The jobs selected by `--job`, or all of them.
+fn selected_jobs<'a>(config: &'a Config, args: &cli::Args) -> Result<Vec<&'a Job>> {
+    match args.value("job") {
+        Some(_) if args.flag("all") => Err(anyhow!("Pass either --job or --all, not both")),
+        Some(name) => {
+            let job = config
+                .jobs
+                .iter()
+                .find(|j| j.name == name)
+                .ok_or_else(|| anyhow!("There is no job named {name}"))?;
+            Ok(vec![job])
+        }
+        None => Ok(config.jobs.iter().collect()),
+    }
+}
+
+/// Exit status of a run in which some jobs succeeded and others didn't.
+const PARTIAL_FAILURE: i32 = 3;
+/// Exit status of a run in which no job failed but some were deferred, so a
+/// timer's run that left its jobs for later doesn't look like one that did them.
+const DEFERRED: i32 = 4;
+
+fn backup(config: &Config, mode: Mode, args: &cli::Args) {
+    let jobs = selected_jobs(config, args)
+        .and_then(|jobs| config::run_order(&jobs))
+        .unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        });
+    let tag = args.value("tag");
+    if let Err(e) = tag.map(check_tag).transpose() {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    }
+    let options = RunOptions {
+        tag,
+        verbose: args.flag("verbose"),
+        deterministic: args.flag("deterministic"),
+        report: args.value("report"),
+        offline: args.flag("offline"),
+        force: args.flag("force"),
+    };
+    let status = run_jobs(config, mode, &jobs, options);
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
+
+/// How `backup` was asked to run, besides which jobs.
+#[derive(Debug, Default, Clone, Copy)]
+struct RunOptions<'a> {
+    /// Goes into the archive names, from `--tag`
+    tag: Option<&'a str>,
+    /// Report more about each archive, from `--verbose`
+    verbose: bool,
+    /// Add files to archives in a fixed order, from `--deterministic`
+    deterministic: bool,
+    /// Where to list the files left out of the archives, from `--report`
+    report: Option<&'a str>,
+    /// Queue the archives instead of connecting to any target, from `--offline`
+    offline: bool,
+    /// Run the jobs on battery or a metered connection all the same, from `--force`
+    force: bool,
+}
+
+/// Runs `jobs` in the order given, logs the run, and returns the exit status it deserves.
+fn run_jobs(config: &Config, mode: Mode, jobs: &[&Job], options: RunOptions) -> i32 {
+    let start = runlog::now();
+    if let Some(path) = options.report {
+        // Each job appends its part, so start from an empty file.
+        if let Err(e) = std::fs::write(path, "") {
+            eprintln!("Could not write the report {path}: {e}");
+        }
+    }
+    let mut log = Vec::new();
+    // The sizes of the jobs' earlier archives, to hold the new ones against
+    let history = runlog::path(config)
+        .and_then(|path| runlog::read_runs(&path))
+        .map(|runs| runlog::job_successes(&runs))
+        .unwrap_or_default();
+    let mut sessions = Sessions::new(&config.targets, mode);
+    // Whatever an earlier run couldn't upload goes first, while it's still recent.
+    if !options.offline {
+        let ready = jobs
+            .iter()
+            .copied()
+            .filter(|job| options.force || conditions::unmet(job).is_none())
+            .collect::<Vec<_>>();
+        if let Err(e) = upload_queued(&mut sessions, &ready, &mut log) {
+            eprintln!("Could not read the upload queue: {e:#}");
+        }
+    }
+    // Jobs that didn't succeed, with the reason, by how they went
+    let mut timed_out = Vec::new();
+    let mut failed = Vec::new();
+    let mut skipped = Vec::new();
+    let mut not_run = Vec::new();
+    // Jobs left for later, as the machine is on battery or a metered
+    // connection, or a job they run after was, with the reason
+    let mut deferred = Vec::new();
+    // The option that left the remaining jobs alone, and what set it off
+    let mut stopped_by = None;
+    for (i, job) in jobs.iter().enumerate() {
+        let mut entry = runlog::JobRun::new(&job.name);
+        let failures = timed_out.len() + failed.len() + skipped.len();
+        if stopped_by.is_none() && config.stop_on_error && failures > 0 {
+            stopped_by = Some(("stop_on_error", "an earlier job failed"));
+        }
+        if stopped_by.is_none() && config.stop_on_auth_failure && sessions.refused_login {
+            stopped_by = Some(("stop_on_auth_failure", "a login was refused"));
+        }
+        if let Some((option, cause)) = stopped_by {
+            not_run.push(job.name.as_str());
+            entry.error = Some(format!("{cause} and {option} is set"));
+            record(&mut log, entry);
+            continue;
+        }
+        let waiting = job
+            .after
+            .iter()
+            .find(|name| deferred.iter().any(|(x, _)| x == name))
+            .map(|name| format!("job {name} was deferred"));
+        let waiting =
+            waiting.or_else(|| (!options.force).then(|| conditions::unmet(job)).flatten());
+        if let Some(reason) = waiting {
+            println!("Deferring job {}: {reason}", job.name);
+            entry.result = "deferred";
+            entry.error = Some(reason.clone());
+            record(&mut log, entry);
+            deferred.push((job.name.as_str(), reason));
+            continue;
+        }
+        let did_not_succeed = |name: &str| {
+            timed_out
+                .iter()
+                .chain(&failed)
+                .chain(&skipped)
+                .any(|(x, _)| *x == name)
+        };
+        // Prerequisites left out by --job don't hold a job back.
+        let unmet = job
+            .after
+            .iter()
+            .find(|name| jobs[..i].iter().any(|x| &x.name == *name) && did_not_succeed(name));
+        if let Some(name) = unmet {
+            println!("Skipping job {}: job {name} did not succeed", job.name);
+            let error = format!("skipped because job {name} did not succeed");
+            run_hook(job, job.on_failure.as_deref(), &[], &error, "");
+            entry.result = "skipped";
+            entry.error = Some(error);
+            record(&mut log, entry);
+            skipped.push((job.name.as_str(), format!("job {name} did not succeed")));
+            continue;
+        }
+        if let Err(e) = last_run::running(&job.name, &entry.start) {
+            eprintln!("{e:#}");
+        }
+        let outcome = backup_job(&mut sessions, job, options, &mut entry);
+        entry.end = runlog::now();
+        match outcome {
+            JobOutcome::Finished(uploaded) => {
+                entry.result = "ok";
+                // A differential's size says nothing next to the full archives'.
+                let past = history
+                    .get(&job.name)
+                    .filter(|_| job.differential.is_none());
+                if let (Some(past), Some(size)) = (past, entry.archive_bytes) {
+                    entry.warning = runlog::size_anomaly(past, size, job.size_anomaly);
+                }
+                if let Some(warning) = &entry.warning {
+                    eprintln!("Warning for job {}: {warning}", job.name);
+                }
+                let warning = entry.warning.clone().unwrap_or_default();
+                run_hook(job, job.on_success.as_deref(), &uploaded, "", &warning)
+            }
+            JobOutcome::Queued => entry.result = "queued",
+            JobOutcome::Deferred(error) => {
+                entry.result = "deferred";
+                entry.error = Some(error.clone());
+                deferred.push((job.name.as_str(), error));
+            }
+            JobOutcome::TimedOut => {
+                let error = format!("timed out after {}", job.max_duration.unwrap());
+                run_hook(job, job.on_failure.as_deref(), &[], &error, "");
+                entry.result = "timed_out";
+                entry.error = Some(error.clone());
+                timed_out.push((job.name.as_str(), error));
+            }
+            JobOutcome::Failed(error) => {
+                run_hook(job, job.on_failure.as_deref(), &[], &error, "");
+                entry.result = "failed";
+                entry.error = Some(error.clone());
+                failed.push((job.name.as_str(), error));
+            }
+        }
+        record(&mut log, entry);
+        if !config.reuse_session {
+            sessions.close();
+        }
+    }
+
+    for (heading, jobs) in [
+        ("Jobs that timed out", &timed_out),
+        ("Jobs that reached no target", &failed),
+        ("Jobs skipped because a job they run after failed", &skipped),
+        ("Jobs deferred for later", &deferred),
+    ] {
+        if jobs.is_empty() {
+            continue;
+        }
+        let names = jobs.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        eprintln!("{heading}: {}", names.join(", "));
+        for (name, reason) in jobs {
+            eprintln!("  {name}: {reason}");
+        }
+    }
+    if let Some((option, _)) = stopped_by {
+        eprintln!(
+            "Jobs not run because {option} is set: {}",
+            not_run.join(", ")
+        );
+    }
+    let unsuccessful = timed_out.len() + failed.len() + skipped.len() + not_run.len();
+    let (result, status) = match unsuccessful {
+        0 if !deferred.is_empty() => ("deferred", DEFERRED),
+        0 => ("ok", 0),
+        n if n == jobs.len() => ("failed", 1),
+        _ => ("partial", PARTIAL_FAILURE),
+    };
+    let run = runlog::Run {
+        start,
+        end: runlog::now(),
+        result,
+        jobs: log,
+    };
+    if let Err(e) = runlog::path(config).and_then(|path| runlog::append(&path, &run)) {
+        eprintln!("Could not write the run log: {e:#}");
+    }
+    fleet::publish(config, &mut sessions);
+    if !options.offline {
+        recovery::upload(config, &mut sessions);
+    }
+    sessions.shut_down(status == 0 || status == DEFERRED);
+    sessions.logout();
+    status
+}
+
+/// Adds how a job went to the run's `log`, and makes it the job's `last_run.json`.
+fn record(log: &mut Vec<runlog::JobRun>, entry: runlog::JobRun) {
+    if let Err(e) = last_run::finished(&entry) {
+        eprintln!("{e:#}");
+    }
+    log.push(entry);
+}
+
+/// Uploads the queued archives of `jobs`, adding how each went to `log`.
+/// Returns whether all of them made it.
+fn upload_queued(
+    sessions: &mut Sessions,
+    jobs: &[&Job],
+    log: &mut Vec<runlog::JobRun>,
+) -> Result<bool> {
+    let dir = queue::dir()?;
+    let mut all_uploaded = true;
+    for mut item in queue::pending(&dir)? {
+        let Some(job) = jobs.iter().find(|j| j.name == item.job) else {
+            continue;
+        };
+        let archive = item.archive(&dir);
+        eprintln!("Uploading {} from the queue", item.name);
+        let mut entry = runlog::JobRun::new(&job.name);
+        entry.queued = Some(item.queued.clone());
+        entry.files = item.files;
+        entry.bytes = item.bytes;
+        entry.archive_bytes = std::fs::metadata(&archive).ok().map(|m| m.len());
+        entry.sha256 = item.sha256.clone();
+        let checked = job.targets.iter().map(|_| None).collect();
+        let deadline = job.max_duration.map(|d| Instant::now() + d.0);
+        let outcome = upload_archive(
+            sessions, job, &archive, &item.name, checked, deadline, &mut entry,
+        );
+        entry.end = runlog::now();
+        let error = match outcome {
+            JobOutcome::Finished(_) => {
+                entry.result = "ok";
+                record(log, entry);
+                queue::remove(&dir, &item)?;
+                continue;
+            }
+            JobOutcome::Queued => unreachable!("upload_archive doesn't queue"),
+            JobOutcome::TimedOut => {
+                entry.result = "timed_out";
+                format!("timed out after {}", job.max_duration.unwrap())
+            }
+            JobOutcome::Failed(error) => {
+                entry.result = "failed";
+                error
+            }
+            JobOutcome::Deferred(error) => {
+                entry.result = "deferred";
+                error
+            }
+        };
+        eprintln!("{} stays in the queue: {error}", item.name);
+        entry.error = Some(error.clone());
+        record(log, entry);
+        item.attempts += 1;
+        item.last_error = Some(error);
+        queue::save(&dir, &item)?;
+        all_uploaded = false;
+    }
+    Ok(all_uploaded)
+}
+
+/// `queue`: lists the archives waiting for an upload, or with `--flush`, like
+/// `upload-pending`, uploads them. Returns the exit status.
+fn queue_command(config: &Config, mode: Mode, args: &cli::Args) -> Result<i32> {
+    let jobs = selected_jobs(config, args)?;
+    let dir = queue::dir()?;
+    if args.command.name == "queue" && !args.flag("flush") {
+        for item in queue::pending(&dir)? {
+            if !jobs.iter().any(|j| j.name == item.job) {
+                continue;
+            }
+            let size = std::fs::metadata(item.archive(&dir)).map_or(0, |m| m.len());
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                item.queued,
+                item.job,
+                item.name,
+                size,
+                item.attempts,
+                item.last_error.as_deref().unwrap_or_default()
+            );
+        }
+        return Ok(0);
+    }
+    Ok(if flush_queue(config, mode, &jobs)? {
+        0
+    } else {
+        1
+    })
+}
+
+/// Uploads the queued archives of `jobs` and logs it as a run. Returns
+/// whether all of them made it.
+fn flush_queue(config: &Config, mode: Mode, jobs: &[&Job]) -> Result<bool> {
+    let start = runlog::now();
+    let mut log = Vec::new();
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let uploaded = upload_queued(&mut sessions, jobs, &mut log);
+    let run = runlog::Run {
+        start,
+        end: runlog::now(),
+        result: if matches!(uploaded, Ok(true)) {
+            "ok"
+        } else {
+            "failed"
+        },
+        jobs: log,
+    };
+    if let Err(e) = runlog::path(config).and_then(|path| runlog::append(&path, &run)) {
+        eprintln!("Could not write the run log: {e:#}");
+    }
+    fleet::publish(config, &mut sessions);
+    sessions.shut_down(matches!(uploaded, Ok(true)));
+    sessions.logout();
+    uploaded
+}
+
+/// Runs one of the job's hooks, if it has it. A failing hook is reported but
+/// doesn't change how the job went.
+fn run_hook(job: &Job, command: Option<&str>, uploaded: &[String], error: &str, warning: &str) {
+    let Some(command) = command else {
+        return;
+    };
+    let archive = format!("{}.zip", job.filename);
+    let uploaded = uploaded.join("\n");
+    let env = [
+        ("SYNOLOGY_BACKUPER_JOB", job.name.as_str()),
+        ("SYNOLOGY_BACKUPER_ARCHIVE", archive.as_str()),
+        ("SYNOLOGY_BACKUPER_UPLOADED", uploaded.as_str()),
+        ("SYNOLOGY_BACKUPER_ERROR", error),
+        ("SYNOLOGY_BACKUPER_WARNING", warning),
+    ];
+    if let Err(e) = hooks::run(command, &env) {
+        eprintln!("Hook of job {}: {e:#}", job.name);
+    }
+}
+
+enum JobOutcome {
+    /// The archive reached the listed paths
+    Finished(Vec<String>),
+    /// The job ran past its `max_duration` and was stopped
+    TimedOut,
+    /// The archive was not uploaded to any target, for the given reason
+    Failed(String),
+    /// The archive went into the queue without trying to upload it, for `--offline`
+    Queued,
+    /// The NAS stayed too busy, so the archive waits in the queue for a
+    /// later try, for the given reason
+    Deferred(String),
+}
+
+/// Where `job` keeps its archives on `target`: the root of its share, or, if
+/// the NAS has no such share, a folder named after it in the target's
+/// `fallback_share`. The second value tells whether the fallback is used.
+fn job_folder(remote: &dyn StorageBackend, job: &Job, target: &Target) -> Result<(String, bool)> {
+    let share_name = job.share_name(target);
+    if !remote.shares_listed() {
+        return Ok((format!("/{share_name}"), false));
+    }
+    let shares = remote.shares();
+    if let Some(share) = shares.iter().find(|x| x.name == share_name) {
+        return Ok((share.path.clone(), false));
+    }
+    let fallback = target
+        .fallback_share
+        .as_deref()
+        .and_then(|f| shares.iter().find(|x| x.name == f));
+    if let Some(fallback) = fallback {
+        return Ok((format!("{}/{share_name}", fallback.path), true));
+    }
+    let available = shares
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match &target.fallback_share {
+        Some(f) => Err(anyhow!(
+            "neither share {share_name} nor fallback_share {f} found; shares on {}: {available}",
+            target.name
+        )),
+        None => Err(anyhow!(
+            "share {share_name} not found; shares on {}: {available}",
+            target.name
+        )),
+    }
+}
+
+/// The folder `job` uploads to on `target`, once DSM has confirmed the account
+/// may write `file_name` there. A missing fallback folder is created first.
+fn writable_share(
+    sessions: &mut Sessions,
+    job: &Job,
+    target: &str,
+    file_name: &str,
+) -> Result<String, String> {
+    let (target, remote) = sessions.get(target);
+    let remote = remote.map_err(|e| format!("unreachable ({e})"))?;
+    let (folder, fallback) = job_folder(remote, job, target).map_err(|e| {
+        println!("Share not found - could not upload file: {e}");
+        e.to_string()
+    })?;
+    if fallback {
+        remote
+            .create_folder(&folder)
+            .map_err(|e| format!("cannot create {folder}: {e}"))?;
+    }
+    remote
+        .check_write_permission(&folder, file_name)
+        .map_err(|e| format!("cannot write to {folder}: {e}"))?;
+    Ok(folder)
+}
+
+/// Deletes what a failed upload left of `name` in `folder`, since DSM may keep
+/// a truncated file. `None` when the upload left nothing behind.
+fn remove_partial_upload(
+    remote: &dyn StorageBackend,
+    target: &str,
+    folder: &str,
+    name: &str,
+) -> Option<runlog::PartialUpload> {
+    let path = format!("{folder}/{name}");
+    // Without a listing there's no telling, so try the delete anyway.
+    let bytes = match remote.list(folder, false) {
+        Ok(files) => files.into_iter().find(|f| f.name == name)?.size,
+        Err(_) => None,
+    };
+    let deleted = remote.delete(&[&path]);
+    if let Err(e) = &deleted {
+        if worm::refused(e) {
+            worm::record(target, folder, e);
+        }
+    }
+    Some(runlog::PartialUpload {
+        location: format!("{target}:{path}"),
+        bytes,
+        removed: deleted.is_ok(),
+        error: deleted.err().map(|e| format!("{e:#}")),
+    })
+}
+
+/// Reads an uploaded archive back from the target and compares it with the
+/// local one: with the SHA-256 taken while it was written, if there is one,
+/// so the local archive isn't read again.
+fn verify_upload(
+    remote: &dyn StorageBackend,
+    remote_path: &str,
+    local: &std::path::Path,
+    sha256: Option<&str>,
+) -> Result<()> {
+    let copy = local.with_extension("zip.verify");
+    let result = remote
+        .download(remote_path, &copy)
+        .and_then(|()| match sha256 {
+            Some(sha256) => Ok(audit::sha256_file(&copy)? == sha256),
+            None => same_contents(local, &copy),
+        });
+    let _ = std::fs::remove_file(&copy);
+    match result? {
+        true => Ok(()),
+        false => Err(anyhow!("the copy on the target differs from the archive")),
+    }
+}
+
+fn same_contents(a: &std::path::Path, b: &std::path::Path) -> Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Adds the files `report` names as left out or changing to the `--report`
+/// file, each line starting with the job.
+fn append_details(path: &str, job: &Job, report: &archive::ArchiveReport) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    for line in report.details() {
+        writeln!(file, "{}\t{line}", job.name)?;
+    }
+    Ok(())
+}
+
+fn backup_job(
+    sessions: &mut Sessions,
+    job: &Job,
+    options: RunOptions,
+    entry: &mut runlog::JobRun,
+) -> JobOutcome {
+    let input_path = &job.filename;
+    let output_path = input_path.clone() + ".zip";
+    let local_path = std::path::Path::new(&output_path);
+    let target_file_name = add_dt_to_filename(local_path, options.tag);
+    let deadline = job.max_duration.map(|d| Instant::now() + d.0);
+    for precondition in &job.preconditions {
+        if let Err(e) = precondition.check() {
+            eprintln!(
+                "Job {}: a precondition failed, so nothing was archived: {e:#}",
+                job.name
+            );
+            return JobOutcome::Failed(format!("precondition failed: {e:#}"));
+        }
+    }
+    if let Some(marker) = &job.require_marker_file {
+        let path = std::path::Path::new(input_path).join(marker);
+        if !path.exists() {
+            eprintln!(
+                "Job {}: {} is missing, so {input_path} is not what it should be (an unmounted disk?); nothing was archived",
+                job.name,
+                path.display()
+            );
+            return JobOutcome::Failed(format!("the marker file {} is missing", path.display()));
+        }
+    }
+    let base = job
+        .differential
+        .and_then(|full_on| chain::base(job, full_on));
+    let synthetic = base.as_ref().is_some_and(|base| base.synthetic);
+    let mut archive_options = ArchiveOptions {
+        deadline,
+        deterministic: options.deterministic,
+        base: base.map(|base| base.index),
+        volumes: synthetic.then(|| chain::volumes_folder(&target_file_name)),
+        ..job.archive_options()
+    };
+
+    // Check the targets before archiving, so a missing permission shows up in
+    // seconds rather than after a long compression. Failover targets behind
+    // the first usable one are only checked when they are needed.
+    let mut checked = job.targets.iter().map(|_| None).collect::<Vec<_>>();
+    for (i, name) in job.targets.iter().enumerate() {
+        if options.offline {
+            break;
+        }
+        let result = writable_share(sessions, job, name, &target_file_name);
+        let usable = result.is_ok();
+        checked[i] = Some(result);
+        if usable && !job.mirror {
+            break;
+        }
+    }
+    // Set when no target can be reached, which the next run may well find
+    // fixed, so the archive is made anyway and waits in the queue.
+    let mut offline = None;
+    if !options.offline && checked.iter().all(|c| matches!(c, Some(Err(_)))) {
+        let reasons = job
+            .targets
+            .iter()
+            .zip(&checked)
+            .map(|(name, c)| format!("{name}: {}", c.as_ref().unwrap().as_ref().unwrap_err()))
+            .collect::<Vec<_>>();
+        let unreachable = job.targets.iter().all(|name| sessions.get(name).1.is_err());
+        if job.queue && unreachable {
+            offline = Some(format!("no target reachable: {}", reasons.join("; ")));
+        } else {
+            eprintln!(
+                "Job {} can't upload anywhere, so nothing was archived:\n  {}",
+                job.name,
+                reasons.join("\n  ")
+            );
+            return JobOutcome::Failed(format!("can't upload anywhere: {}", reasons.join("; ")));
+        }
+    }
+
+    let containers = match &job.docker {
+        Some(docker) => match docker::prepare(docker) {
+            Ok(prepared) => Some(prepared),
+            Err(e) => {
+                eprintln!(
+                    "Job {}: could not get its containers ready, so nothing was archived: {e:#}",
+                    job.name
+                );
+                return JobOutcome::Failed(format!("docker failed: {e:#}"));
+            }
+        },
+        None => None,
+    };
+    if let Some(prepared) = &containers {
+        archive_options.also = prepared.paths.clone();
+        archive_options.docker = Some(prepared.record.clone());
+    }
+    let snapshot = match &job.snapshot {
+        Some(snapshot) => match snapshot::take(&job.name, input_path, snapshot) {
+            Ok(taken) => Some(taken),
+            Err(e) => {
+                eprintln!(
+                    "Job {}: could not take the {} snapshot, so nothing was archived: {e:#}",
+                    job.name, snapshot.kind
+                );
+                return JobOutcome::Failed(format!("snapshot failed: {e:#}"));
+            }
+        },
+        None => None,
+    };
+    archive_options.read_from = snapshot.as_ref().map(|taken| taken.path.clone());
+
+    // Archive on a thread of its own so a lowered priority ends with the job.
+    let archived = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                limits::lower_current_thread(job.priority());
+                compress_iter(
+                    std::path::Path::new(&input_path),
+                    local_path,
+                    &archive_options,
+                )
+                .map_err(|e| e.to_string())
+            })
+            .join()
+            .unwrap()
+    });
+    // Released before the upload, which may take long.
+    drop(snapshot);
+    drop(containers);
+    let report = match archived {
+        Ok(report) => report,
+        Err(_) if limits::expired(deadline) => {
+            let _ = std::fs::remove_file(&output_path);
+            eprintln!(
+                "Job {} timed out after {} while archiving; the partial archive was removed and nothing was uploaded",
+                job.name,
+                job.max_duration.unwrap()
+            );
+            return JobOutcome::TimedOut;
+        }
+        Err(e) => {
+            eprintln!("Job {} failed compressing {input_path}: {e}", job.name);
+            return JobOutcome::Failed(format!("compressing {input_path} failed: {e}"));
+        }
+    };
+    eprintln!("{}", report.summary(options.verbose));
+    if let Some(error) = too_small(job, &report) {
+        let _ = std::fs::remove_file(&output_path);
+        eprintln!(
+            "Job {} archived {error}, so the archive was removed and nothing was uploaded",
+            job.name
+        );
+        return JobOutcome::Failed(format!("archived {error}"));
+    }
+    if let Some(path) = options.report {
+        if let Err(e) = append_details(path, job, &report) {
+            eprintln!("Could not write the report {path}: {e}");
+        }
+    }
+    entry.skipped = Some(runlog::Skipped {
+        excluded: report.excluded.len(),
+        too_large: report.too_large.len(),
+        unreadable: report.unreadable.len(),
+        changed: report.unstable.len(),
+    });
+    entry.files = Some(report.files);
+    entry.bytes = Some(report.bytes);
+    entry.archive_bytes = std::fs::metadata(local_path).ok().map(|m| m.len());
+    entry.sha256 = report.sha256.clone();
+
+    if options.offline {
+        return match queue_archive(job, local_path, &target_file_name, None, entry) {
+            Ok(()) => JobOutcome::Queued,
+            Err(e) => JobOutcome::Failed(e),
+        };
+    }
+    let outcome = match offline {
+        Some(error) => JobOutcome::Failed(error),
+        None => upload_archive(
+            sessions,
+            job,
+            local_path,
+            &target_file_name,
+            checked,
+            deadline,
+            entry,
+        ),
+    };
+    let outcome = match outcome {
+        JobOutcome::Failed(error) if job.queue => {
+            match queue_archive(job, local_path, &target_file_name, Some(&error), entry) {
+                Ok(()) => JobOutcome::Failed(format!("{error}{QUEUED}")),
+                Err(e) => JobOutcome::Failed(e),
+            }
+        }
+        JobOutcome::Deferred(error) => {
+            match queue_archive(job, local_path, &target_file_name, Some(&error), entry) {
+                Ok(()) => JobOutcome::Deferred(format!("{error}{QUEUED}")),
+                Err(e) => JobOutcome::Failed(e),
+            }
+        }
+        outcome => outcome,
+    };
+    if archive_options.manifest.is_some() {
+        let name = match &outcome {
+            JobOutcome::Finished(paths) => Some(
+                paths
+                    .first()
+                    .and_then(|path| path.rsplit('/').next())
+                    .unwrap_or(&target_file_name),
+            ),
+            JobOutcome::Queued => Some(target_file_name.as_str()),
+            _ => None,
+        };
+        if let Some(name) = name {
+            let differential = archive_options.base.is_some();
+            if let Err(e) = verify_local::record(job, name, &report, differential) {
+                eprintln!(
+                    "Job {}: could not keep the manifest of {name}: {e:#}",
+                    job.name
+                );
+            }
+        }
+    }
+    if job.differential.is_some() {
+        let name = match &outcome {
+            JobOutcome::Finished(paths) => paths
+                .first()
+                .and_then(|path| path.rsplit('/').next())
+                .unwrap_or(&target_file_name),
+            JobOutcome::Queued => &target_file_name,
+            _ => return outcome,
+        };
+        let mut base = archive_options.base.as_ref().map(|base| base.full.clone());
+        let mut volumes = Vec::new();
+        if let (Some(full), Some(folder), JobOutcome::Finished(_)) =
+            (&base, &archive_options.volumes, &outcome)
+        {
+            match copy_volumes(sessions, job, &entry.uploaded, full, folder) {
+                Ok(copies) => (base, volumes) = (None, copies),
+                Err(e) => eprintln!(
+                    "Job {}: could not copy the archives {name} builds on ({e:#}); it stays a differential of {full}",
+                    job.name
+                ),
+            }
+        }
+        if let Err(e) = record_link(job, name, base, volumes, report, entry) {
+            eprintln!(
+                "Job {}: could not record {name} in the chain: {e:#}",
+                job.name
+            );
+        }
+    }
+    outcome
+}
+
+/// What falls short of the job's `min_files` or `min_size` in the archive
+/// `report` describes. A differential counts the files it left out as
+/// unchanged, which are in its full archive.
+fn too_small(job: &Job, report: &archive::ArchiveReport) -> Option<String> {
+    let files = report.files + report.unchanged as u64;
+    let bytes = report
+        .bytes
+        .max(report.index.values().map(|(size, _)| size).sum());
+    if let Some(min) = job.min_files.filter(|&min| files < min) {
+        return Some(format!("{files} files, fewer than min_files {min}"));
+    }
+    if let Some(min) = job.min_size.filter(|min| bytes < min.0) {
+        return Some(format!(
+            "{}, less than min_size {}",
+            format_bytes(bytes),
+            format_bytes(min.0)
+        ));
+    }
+    None
+}
+
+/// Copies the full archive `full` that a synthetic full archive builds on,
+/// along with the copies it builds on itself, into `folder` next to the new
+/// archive on each target it was `uploaded` to. The copies, as `target:path`.
+fn copy_volumes(
+    sessions: &mut Sessions,
+    job: &Job,
+    uploaded: &[String],
+    full: &str,
+    folder: &str,
+) -> Result<Vec<String>> {
+    let chain = chain::load()?;
+    let link = chain
+        .iter()
+        .rev()
+        .find(|l| l.job == job.name && l.name == full && l.kind == chain::Kind::Full)
+        .ok_or_else(|| anyhow!("The chain records no full archive {full}"))?;
+    let mut copies = Vec::new();
+    for upload in uploaded {
+        let Some((target, path)) = upload.split_once(':') else {
+            continue;
+        };
+        let dest = match path.rsplit_once('/') {
+            Some((parent, _)) => format!("{parent}/{folder}"),
+            None => folder.to_string(),
+        };
+        let sources = link
+            .uploaded
+            .iter()
+            .chain(&link.volumes)
+            .filter_map(|copy| copy.strip_prefix(target)?.strip_prefix(':'))
+            .collect::<Vec<_>>();
+        if !sources
+            .iter()
+            .any(|source| source.ends_with(&format!("/{full}")))
+        {
+            return Err(anyhow!("{full} was never uploaded to {target}"));
+        }
+        let remote = sessions.get(target).1.map_err(|e| anyhow!("{e}"))?;
+        remote.create_folder(&dest)?;
+        remote
+            .api("a synthetic full archive")
+            .and_then(|s| copy_files(&s.client, &s.api_info, &sources, &dest, false))?;
+        for source in sources {
+            let name = source.rsplit('/').next().unwrap_or(source);
+            copies.push(format!("{target}:{dest}/{name}"));
+        }
+    }
+    eprintln!(
+        "Job {}: copied {full} on the NAS for the synthetic full archive",
+        job.name
+    );
+    Ok(copies)
+}
+
+/// Records the archive `name` that a job with `differential` made, the
+/// differential of `base` or a full archive, with its files for the
+/// differentials after it.
+fn record_link(
+    job: &Job,
+    name: &str,
+    base: Option<String>,
+    volumes: Vec<String>,
+    report: archive::ArchiveReport,
+    entry: &runlog::JobRun,
+) -> Result<()> {
+    let link = chain::Link {
+        job: job.name.clone(),
+        name: name.to_string(),
+        kind: match base {
+            Some(_) => chain::Kind::Differential,
+            None => chain::Kind::Full,
+        },
+        base,
+        time: runlog::now(),
+        sha256: entry.sha256.clone(),
+        uploaded: entry.uploaded.clone(),
+        volumes,
+    };
+    let index = chain::Index {
+        full: name.to_string(),
+        files: report.index,
+    };
+    let full = link.kind == chain::Kind::Full;
+    chain::record(job, link, full.then_some(&index))
+}
+
+/// How the reason a job failed ends when its archive was queued
+const QUEUED: &str = "; queued for a later upload";
+
+/// Keeps the archive at `local_path` in the queue for a later upload, either
+/// because it reached no target for `error` or, with `None`, for `--offline`.
+/// Fails with the reason the job failed when the archive could not be queued.
+fn queue_archive(
+    job: &Job,
+    local_path: &std::path::Path,
+    name: &str,
+    error: Option<&str>,
+    entry: &runlog::JobRun,
+) -> Result<(), String> {
+    let item = queue::Queued {
+        job: job.name.clone(),
+        name: name.to_string(),
+        queued: runlog::now(),
+        files: entry.files,
+        bytes: entry.bytes,
+        sha256: entry.sha256.clone(),
+        attempts: u32::from(error.is_some()),
+        last_error: error.map(str::to_string),
+    };
+    let queued = queue::dir().and_then(|dir| queue::add(&dir, local_path, &item));
+    match (queued, error) {
+        (Ok(()), None) => {
+            eprintln!("Job {}: {name} was queued for upload-pending", job.name);
+            Ok(())
+        }
+        (Ok(()), Some(_)) => {
+            eprintln!(
+                "Job {}: {name} reached no target and was queued for a later upload",
+                job.name
+            );
+            Ok(())
+        }
+        (Err(e), error) => {
+            eprintln!("Could not queue {name}: {e:#}");
+            let error = error.map_or(String::new(), |error| format!("{error}; "));
+            Err(format!("{error}queueing it failed ({e:#})"))
+        }
+    }
+}
+
+/// How many later names an upload to a write-once folder tries when the
+/// archive's is taken
+const LATER_NAMES: u32 = 5;
+
+/// Uploads `local_path` into `folder` on `target` as `file_name`, returning
+/// the name it went up as. With `write_once`, nothing is replaced: a name
+/// that is taken gives way to a second later one. A folder that turns out to
+/// be write-once by refusing the upload sets `write_once` for another try.
+#[allow(clippy::too_many_arguments)]
+fn upload_unique(
+    remote: &dyn StorageBackend,
+    job: &Job,
+    target: &str,
+    folder: &str,
+    local_path: &std::path::Path,
+    file_name: &str,
+    write_once: &mut bool,
+    deadline: Option<Instant>,
+) -> (String, Result<()>) {
+    let mut file_name = file_name.to_string();
+    let mut later_names = 0;
+    loop {
+        let upload = if *write_once {
+            StorageBackend::upload_new
+        } else {
+            StorageBackend::upload
+        };
+        let result = upload(
+            remote,
+            folder,
+            local_path,
+            &file_name,
+            job.upload_rate_limit,
+            deadline,
+        );
+        match result {
+            Err(e) if !*write_once && worm::refused(&e) => {
+                worm::record(target, folder, &e);
+                *write_once = true;
+            }
+            Err(e) if *write_once && later_names < LATER_NAMES && worm::name_taken(&e) => {
+                let Some(later) = later_name(job, &file_name) else {
+                    return (file_name, Err(e));
+                };
+                eprintln!(
+                    "{folder}/{file_name} exists already and isn't replaced; uploading as {later}"
+                );
+                file_name = later;
+                later_names += 1;
+            }
+            result => return (file_name, result),
+        }
+    }
+}
+
+/// Uploads the archive at `local_path` to the job's first target that takes
+/// it, or to all of them for a mirror, and makes its copies. `checked` holds
+/// what [`writable_share`] said of the targets checked already.
+fn upload_archive(
+    sessions: &mut Sessions,
+    job: &Job,
+    local_path: &std::path::Path,
+    target_file_name: &str,
+    checked: Vec<Option<Result<String, String>>>,
+    deadline: Option<Instant>,
+    entry: &mut runlog::JobRun,
+) -> JobOutcome {
+    let mut results = Vec::new();
+    let mut uploaded = Vec::new();
+    // Whether the last target tried was too busy to take the archive
+    let mut busy = false;
+    for (name, checked) in job.targets.iter().zip(checked) {
+        let share_path = match checked
+            .unwrap_or_else(|| writable_share(sessions, job, name, target_file_name))
+        {
+            Ok(share_path) => share_path,
+            Err(e) => {
+                results.push(format!("{name}: {e}"));
+                continue;
+            }
+        };
+        let (target, Ok(remote)) = sessions.get(name) else {
+            unreachable!("writable_share succeeded, so the session is open")
+        };
+        let mut write_once = job.write_only || worm::write_once(target, &share_path);
+        let mut delay = job.busy_delay.0;
+        let mut retries = job.busy_retries;
+        let (file_name, result) = loop {
+            let (file_name, result) = upload_unique(
+                remote,
+                job,
+                name,
+                &share_path,
+                local_path,
+                target_file_name,
+                &mut write_once,
+                deadline,
+            );
+            match &result {
+                Err(e) if nas_busy(e) && retries > 0 && !limits::expired(deadline) => {
+                    eprintln!(
+                        "{name} is too busy to take the upload; trying again in {}",
+                        HumanDuration(delay)
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    retries -= 1;
+                }
+                _ => break (file_name, result),
+            }
+        };
+        busy = result.as_ref().is_err_and(nas_busy);
+        // What keeps the partial uploads and failed copies on the target
+        let keeper = if job.write_only {
+            "write_only"
+        } else {
+            "the write-once folder"
+        };
+        match result {
+            Ok(()) => {
+                let remote_path = format!("{share_path}/{file_name}");
+                if job.verify {
+                    if let Err(e) =
+                        verify_upload(remote, &remote_path, local_path, entry.sha256.as_deref())
+                    {
+                        println!("Error verifying {remote_path}: {e:#}");
+                        if !write_once {
+                            let _ = remote.delete(&[&remote_path]);
+                        }
+                        results.push(format!("{name}: verifying {remote_path} failed ({e:#})"));
+                        continue;
+                    }
+                }
+                results.push(format!(
+                    "{name}: uploaded to {remote_path}{}",
+                    if job.verify { " and verified" } else { "" }
+                ));
+                entry.uploaded.push(format!("{name}:{remote_path}"));
+                uploaded.push(remote_path.clone());
+                for folder in &job.copies {
+                    let copied = remote.api("copies").and_then(|s| {
+                        copy_files(&s.client, &s.api_info, &[&remote_path], folder, !write_once)
+                    });
+                    match copied {
+                        Ok(()) => results.push(format!("{name}: copied to {folder}")),
+                        Err(e) => {
+                            println!("Error copying file to {folder}: {e}");
+                            results.push(format!("{name}: copy to {folder} failed ({e})"));
+                        }
+                    }
+                }
+                if !job.mirror {
+                    break;
+                }
+            }
+            Err(_) if limits::expired(deadline) => {
+                // DSM may keep what it received of an aborted upload.
+                let remote_path = format!("{share_path}/{file_name}");
+                let removed = if write_once {
+                    format!("{keeper} leaves whatever arrived of {remote_path}")
+                } else {
+                    let cleanup = remove_partial_upload(remote, name, &share_path, &file_name);
+                    let removed = match &cleanup {
+                        Some(partial) if partial.removed => format!("{remote_path} was removed"),
+                        Some(partial) => format!(
+                            "{remote_path} could not be removed ({})",
+                            partial.error.as_deref().unwrap_or_default()
+                        ),
+                        None => format!("nothing of {remote_path} arrived"),
+                    };
+                    entry.partial_uploads.extend(cleanup);
+                    removed
+                };
+                eprintln!(
+                    "Job {} timed out after {} while uploading to {name}; {removed}",
+                    job.name,
+                    job.max_duration.unwrap()
+                );
+                return JobOutcome::TimedOut;
+            }
+            Err(e) if write_once => {
+                println!("Error uploading file: {e:#}");
+                results.push(format!(
+                    "{name}: {e:#}; {keeper} leaves whatever arrived of {share_path}/{file_name}"
+                ));
+            }
+            Err(e) => {
+                println!("Error uploading file: {}", e);
+                let cleanup = remove_partial_upload(remote, name, &share_path, &file_name);
+                match &cleanup {
+                    Some(partial) if partial.removed => {
+                        println!("Removed the partial upload {}", partial.location);
+                        results.push(format!("{name}: {e}; the partial upload was removed"));
+                    }
+                    Some(partial) => {
+                        let error = partial.error.as_deref().unwrap_or_default();
+                        println!(
+                            "Could not remove the partial upload {}: {error}",
+                            partial.location
+                        );
+                        results.push(format!(
+                            "{name}: {e}; the partial upload {} could not be removed ({error})",
+                            partial.location
+                        ));
+                    }
+                    None => results.push(format!("{name}: {e}")),
+                }
+                entry.partial_uploads.extend(cleanup);
+            }
+        }
+    }
+    if job.targets.len() > 1 || !job.copies.is_empty() {
+        eprintln!("Job {}:\n  {}", job.name, results.join("\n  "));
+    }
+    if !uploaded.is_empty() {
+        JobOutcome::Finished(uploaded)
+    } else if busy && job.busy_reschedule.is_some() {
+        JobOutcome::Deferred(results.join("; "))
+    } else {
+        JobOutcome::Failed(results.join("; "))
+    }
+}
+
+fn main() {
+    let args = cli::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{}", cli::help());
+        std::process::exit(2);
+    });
+    if args.flag("help") {
+        print!("{}", cli::help());
+        return;
+    }
+    match args.command.name {
+        "completions" => {
+            print!(
+                "{}",
+                completions::generate(args.positional.as_deref().unwrap())
+            );
+            return;
+        }
+        "key" => {
+            if let Err(e) = keys::run(&args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        "open-recovery" => {
+            if let Err(e) = recovery::open(&args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        "config" => {
+            // Only `schema` so far, which needs no config to read.
+            println!("{:#}", schema::schema());
+            return;
+        }
+        "__complete" => {
+            // Completion must never print errors into the user's prompt.
+            if let Ok(config) = load_config(
+                &paths::config_file(args.value("config")),
+                args.value("profile"),
+            ) {
+                for job in &config.jobs {
+                    println!("{}", job.name);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+    let mode = client_mode(&args).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    });
+    let config = load_config(
+        &paths::config_file(args.value("config")),
+        args.value("profile"),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    });
+
+    match args.command.name {
+        "backup" => backup(&config, mode, &args),
+        "find" => {
+            if let Err(e) = find::run(&config, mode, args.positional.as_deref().unwrap()) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "orphans" => {
+            if let Err(e) = backups::orphans(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "queue" | "upload-pending" => match queue_command(&config, mode, &args) {
+            Ok(0) => {}
+            Ok(status) => std::process::exit(status),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        "pin" | "unpin" => {
+            let pinned = args.command.name == "pin";
+            if let Err(e) = backups::pin(&config, mode, &args, pinned) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "restore" => {
+            if let Err(e) = restore::run(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "adopt" => {
+            if let Err(e) = catalog::adopt(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "export-recovery" => {
+            if let Err(e) = recovery::export(&config, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "list" => {
+            if let Err(e) = backups::list(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "audit" => match audit::run(&config, mode) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        "verify-local" => match verify_local::run(&config, &args) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        "daemon" => {
+            if let Err(e) = daemon::run(&config, mode) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "status" => {
+            if let Err(e) = fleet::status(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "report" => {
+            if let Err(e) = report::report(&config, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "check-chain" => match chain::check(&config, mode, &args) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(2),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(3);
+            }
+        },
+        "check" => match backups::check(&config, mode, &args) {
+            Ok(true) => {}
+            // Nagios' CRITICAL and UNKNOWN
+            Ok(false) => std::process::exit(2),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(3);
+            }
+        },
+        "prune" => {
+            if let Err(e) = backups::prune(&config, mode, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "usage" => {
+            if let Err(e) = usage::run(&config, mode) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "doctor" => {
+            if !doctor::run(&config, mode) {
+                std::process::exit(1);
+            }
+        }
+        "bench" => match bench::run(&config, mode, &args) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        "bench-compress" => {
+            if let Err(e) = bench::compress(&config, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "api-info" => {
+            if let Err(e) = api_info::run(&config, mode, args.flag("json")) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "install-systemd" => {
+            if let Err(e) = systemd::install(&config, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        "install-schedule" => {
+            if let Err(e) = install_schedule::install(&config, &args) {
+                eprintln!("{e:#}");
+                std::process::exit(1);
             }
         }
-        None => {
-            println!("Share not found - could not upload file");
-        }
+        _ => unreachable!("every command in cli::COMMANDS is dispatched"),
     }
-    logout(&client, &api_info).unwrap();
 }
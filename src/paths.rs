@@ -0,0 +1,66 @@
+//! Where the config and the program's own state live: the XDG base directories
+//! on Linux and the usual per-user folders on macOS and Windows.
+
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+const APP: &str = "synology_backuper";
+
+/// The config file to read: `--config` if given, else a `config.json` in the
+/// working directory, which is where older setups keep it, else the one in
+/// [`config_dir`].
+pub fn config_file(explicit: Option<&str>) -> PathBuf {
+    if let Some(path) = explicit {
+        return PathBuf::from(path);
+    }
+    let local = PathBuf::from("config.json");
+    if local.exists() {
+        return local;
+    }
+    config_dir().map_or(local, |dir| dir.join("config.json"))
+}
+
+pub fn config_dir() -> Result<PathBuf> {
+    if cfg!(windows) {
+        return env_dir("APPDATA").map(|dir| dir.join(APP));
+    }
+    if cfg!(target_os = "macos") {
+        return Ok(home()?.join("Library/Application Support").join(APP));
+    }
+    Ok(xdg("XDG_CONFIG_HOME", ".config")?.join(APP))
+}
+
+/// Where runs leave what later runs need. Not created until something is written.
+pub fn state_dir() -> Result<PathBuf> {
+    if cfg!(windows) {
+        return env_dir("LOCALAPPDATA").map(|dir| dir.join(APP));
+    }
+    if cfg!(target_os = "macos") {
+        return Ok(home()?.join("Library/Application Support").join(APP));
+    }
+    Ok(xdg("XDG_STATE_HOME", ".local/state")?.join(APP))
+}
+
+/// `$var`, or `fallback` under the home directory. The spec says to ignore
+/// relative paths in the variables.
+fn xdg(var: &str, fallback: &str) -> Result<PathBuf> {
+    match std::env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => Ok(dir),
+        _ => Ok(home()?.join(fallback)),
+    }
+}
+
+fn env_dir(var: &str) -> Result<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("{var} is not set"))
+}
+
+fn home() -> Result<PathBuf> {
+    env_dir("HOME")
+}
+
+/// `path` made absolute, for schedulers that start the program elsewhere.
+pub fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,148 @@
+//! Trusting a NAS by the key its certificate holds, for DSM's self-signed
+//! certificates. A pin is the base64 SHA-256 of the certificate's
+//! SubjectPublicKeyInfo, as curl's `--pinnedpubkey` takes it, so the
+//! connection is still safe from a man in the middle without any CA.
+//! Renewing the certificate keeps the pin as long as it keeps the key.
+
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::sync::Arc;
+
+/// What pins may start with, as in curl's `sha256//...`.
+const PREFIX: &str = "sha256//";
+
+/// The pin in `pin` without its prefix, if it is one.
+pub fn parse(pin: &str) -> Option<&str> {
+    let hash = pin.strip_prefix(PREFIX).unwrap_or(pin);
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(hash)
+        .ok()?;
+    (bytes.len() == 32).then_some(hash)
+}
+
+/// TLS settings that accept a server by its key being one of `pins`, whatever
+/// its certificate's name, issuer or expiry.
+pub fn tls_config(pins: &[String]) -> rustls::ClientConfig {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let verifier = PinnedKey {
+        pins: pins
+            .iter()
+            .filter_map(|p| parse(p))
+            .map(String::from)
+            .collect(),
+        provider: provider.clone(),
+    };
+    rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+#[derive(Debug)]
+struct PinnedKey {
+    pins: Vec<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let spki = spki(end_entity).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+        let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+        let pin = base64::engine::general_purpose::STANDARD.encode(hash);
+        if self.pins.contains(&pin) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        Err(rustls::Error::General(format!(
+            "the key of the server's certificate, {PREFIX}{pin}, is not in pin_sha256"
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The DER SubjectPublicKeyInfo of an X.509 certificate: the seventh field
+/// of its TBSCertificate, counting the optional version.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = contents(cert)?;
+    let (mut tbs, _) = contents(cert)?;
+    // The version is explicitly tagged [0] and may be left out.
+    if tbs.first() == Some(&0xa0) {
+        tbs = contents(tbs)?.1;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = contents(tbs)?.1;
+    }
+    let (header, len) = header(tbs)?;
+    tbs.get(..header + len)
+}
+
+/// Splits the DER element `der` starts with into its contents and what follows it.
+fn contents(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (header, len) = header(der)?;
+    let end = header.checked_add(len)?;
+    Some((der.get(header..end)?, der.get(end..)?))
+}
+
+/// The header length and contents length of the DER element `der` starts with.
+fn header(der: &[u8]) -> Option<(usize, usize)> {
+    let first = *der.get(1)?;
+    if first < 0x80 {
+        return Some((2, first as usize));
+    }
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 {
+        return None;
+    }
+    let len = der
+        .get(2..2 + count)?
+        .iter()
+        .fold(0, |len, byte| (len << 8) | *byte as usize);
+    Some((2 + count, len))
+}
@@ -0,0 +1,57 @@
+//! A job's `preconditions`: what must hold on this machine before the job
+//! archives anything, so that a disk that isn't mounted fails the job rather
+//! than going up as an empty backup of the folder it is mounted on.
+
+use crate::hooks;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precondition {
+    /// A path that must be a mount point, not just a folder
+    Mountpoint(String),
+    /// A file or folder that must exist
+    Exists(String),
+    /// A shell command that must succeed, like `findmnt /data`
+    Command(String),
+}
+
+impl Precondition {
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Precondition::Mountpoint(path) => {
+                if !is_mountpoint(Path::new(path))
+                    .with_context(|| format!("Could not look at {path}"))?
+                {
+                    return Err(anyhow!("{path} is not a mount point"));
+                }
+            }
+            Precondition::Exists(path) => {
+                if !Path::new(path).exists() {
+                    return Err(anyhow!("{path} does not exist"));
+                }
+            }
+            Precondition::Command(command) => hooks::run(command, &[])?,
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is where a filesystem is mounted: on another device than
+/// the folder it is in, or the root.
+#[cfg(unix)]
+fn is_mountpoint(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(path)?;
+    let parent = std::fs::metadata(path.join(".."))?;
+    Ok(meta.dev() != parent.dev() || meta.ino() == parent.ino())
+}
+
+/// Whether `path` is the root of a drive. Volumes mounted into a folder
+/// don't count.
+#[cfg(not(unix))]
+fn is_mountpoint(path: &Path) -> std::io::Result<bool> {
+    Ok(std::fs::canonicalize(path)?.parent().is_none())
+}
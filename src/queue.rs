@@ -0,0 +1,86 @@
+//! Archives that reached no target, kept on this machine until an upload
+//! works. Each waits in the queue folder under its archive name, with a
+//! `.json` beside it saying which job made it and how uploading it went.
+
+use crate::paths;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Queued {
+    pub job: String,
+    /// The archive name it is uploaded under, timestamp and all
+    pub name: String,
+    /// When it was queued, in RFC 3339
+    pub queued: String,
+    /// Files archived and their total size
+    pub files: Option<u64>,
+    pub bytes: Option<u64>,
+    pub sha256: Option<String>,
+    /// Uploads tried since it was queued, and why the last one failed
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl Queued {
+    /// Where the archive itself is kept.
+    pub fn archive(&self, dir: &Path) -> PathBuf {
+        dir.join(&self.name)
+    }
+
+    fn meta(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.json", self.name))
+    }
+}
+
+pub fn dir() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("queue"))
+}
+
+/// Copies the archive at `local` into the queue, to be uploaded as `item.name`.
+pub fn add(dir: &Path, local: &Path, item: &Queued) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    std::fs::copy(local, item.archive(dir))
+        .with_context(|| format!("Could not copy {} into the queue", local.display()))?;
+    save(dir, item)
+}
+
+/// Writes down how uploading `item` went.
+pub fn save(dir: &Path, item: &Queued) -> Result<()> {
+    let path = item.meta(dir);
+    std::fs::write(&path, serde_json::to_string_pretty(item)?)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Takes `item` out of the queue, archive and all.
+pub fn remove(dir: &Path, item: &Queued) -> Result<()> {
+    std::fs::remove_file(item.archive(dir))?;
+    std::fs::remove_file(item.meta(dir))?;
+    Ok(())
+}
+
+/// What is waiting in `dir`, oldest first. Descriptions whose archive is gone
+/// are left out.
+pub fn pending(dir: &Path) -> Result<Vec<Queued>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", dir.display())),
+    };
+    let mut items = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|x| x != "json") {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)?;
+        let item = serde_json::from_str::<Queued>(&text)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        if item.archive(dir).exists() {
+            items.push(item);
+        }
+    }
+    items.sort_by(|a, b| (&a.queued, &a.name).cmp(&(&b.queued, &b.name)));
+    Ok(items)
+}
@@ -0,0 +1,411 @@
+//! The `export-recovery` and `open-recovery` commands: a small bundle to keep
+//! away from this machine and the NAS, from which the backups can be found
+//! and restored once both the machine and its config are gone. It holds the
+//! config without its passwords, the run log, which records every upload
+//! and its checksum, the catalog and the rest of the state, and
+//! instructions, all sealed with the current key of the keyring, so the key
+//! export is all that is needed besides. With `self_backup`, each run
+//! uploads such a bundle too, sealed with a passphrase if one is configured,
+//! in which case it holds the keyring as well.
+
+use crate::cli::Args;
+use crate::config::{Connection, SelfBackup, Transport};
+use crate::{fleet, paths, runlog};
+use crate::{keys, Config, Sessions};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+
+const CONFIG: &str = "config.json";
+const RUN_LOG: &str = "runs.jsonl";
+const INSTRUCTIONS: &str = "RESTORE.txt";
+const KEYRING: &str = "keys.json";
+/// Folder of the bundle the state files go into
+const STATE: &str = "state";
+/// The files of the state directory that go along: the adopted archives,
+/// the differential chains and the WORM retention
+const STATE_FILES: &[&str] = &["catalog.json", "chain.json", "worm.json"];
+/// The folders of the state directory that go along: the index of what the
+/// last full archive of each job held, which its differentials are made
+/// against, and the manifests `verify-local` checks against
+const STATE_FOLDERS: &[&str] = &["index", "manifests"];
+/// Settings that may hold a password or token, left out of the bundle
+const SECRETS: &[&str] = &["pwd", "headers"];
+
+/// What a bundle is sealed with.
+enum Seal {
+    Key(keys::Keyring),
+    /// The passphrase, which lets the keyring go into the bundle
+    Passphrase(String),
+}
+
+impl Seal {
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Seal::Key(keyring) => keyring.seal(plaintext),
+            Seal::Passphrase(passphrase) => keys::seal_with_passphrase(passphrase, plaintext),
+        }
+    }
+}
+
+/// A bundle before it is sealed, and what went into it.
+struct Bundle {
+    zip: Vec<u8>,
+    /// Where secrets were taken out of the config
+    removed: Vec<String>,
+    uploads: usize,
+}
+
+pub fn export(config: &Config, args: &Args) -> Result<()> {
+    let keyring = keys::load()?.ok_or_else(|| {
+        anyhow!("export-recovery encrypts with the current key of the keyring, and there is none; `key generate` makes one")
+    })?;
+    let out = match args.value("out") {
+        Some(out) => PathBuf::from(out),
+        None => PathBuf::from(format!(
+            "synology_backuper_recovery_{}.sbr",
+            chrono::Local::now().format("%Y%m%d_%H%M%S")
+        )),
+    };
+    let current = keyring.current.clone();
+    let seal = Seal::Key(keyring);
+    let Bundle {
+        zip,
+        removed,
+        uploads,
+    } = bundle(config, &seal)?;
+    std::fs::write(&out, seal.seal(&zip)?)
+        .with_context(|| format!("Could not write {}", out.display()))?;
+    println!(
+        "Wrote {}, sealed with key {current}: the config without {}, {uploads} recorded uploads and how to restore them",
+        out.display(),
+        if removed.is_empty() {
+            "changes".to_string()
+        } else {
+            removed.join(", ")
+        },
+    );
+    println!("Keep it, and the keyring from `key export`, away from this machine and the NAS");
+    Ok(())
+}
+
+/// Uploads a bundle into `self_backup`'s folder, if the config has one, as
+/// `synology_backuper-<host>.sbr`, in place of the one of the run before.
+/// A failure is only reported, as the run itself went as it went.
+pub fn upload(config: &Config, sessions: &mut Sessions) {
+    let Some(self_backup) = &config.self_backup else {
+        return;
+    };
+    let name = format!(
+        "synology_backuper-{}.sbr",
+        fleet::machine(config.fleet.as_ref().and_then(|f| f.host.as_deref()))
+    );
+    let local = std::env::temp_dir().join(format!(
+        ".synology_backuper_self_{}_{name}",
+        std::process::id()
+    ));
+    let uploaded = seal_for(self_backup).and_then(|seal| {
+        let sealed = seal.seal(&bundle(config, &seal)?.zip)?;
+        std::fs::write(&local, sealed)?;
+        let remote = sessions
+            .get(&self_backup.target)
+            .1
+            .map_err(|e| anyhow!("{e}"))?;
+        remote.create_folder(&self_backup.folder)?;
+        remote.upload(&self_backup.folder, &local, &name, None, None)
+    });
+    let _ = std::fs::remove_file(&local);
+    if let Err(e) = uploaded {
+        eprintln!(
+            "Could not back up the config and state to {}:{}: {e:#}",
+            self_backup.target, self_backup.folder
+        );
+    }
+}
+
+fn seal_for(self_backup: &SelfBackup) -> Result<Seal> {
+    if let Some(path) = &self_backup.passphrase_file {
+        return Ok(Seal::Passphrase(read_passphrase(path)?));
+    }
+    let keyring = keys::load()?.ok_or_else(|| {
+        anyhow!("self_backup encrypts with the current key of the keyring, and there is none; `key generate` makes one, or set `passphrase_file`")
+    })?;
+    Ok(Seal::Key(keyring))
+}
+
+fn read_passphrase(path: &str) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the passphrase from {path}"))?;
+    let passphrase = text.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(anyhow!("{path} holds no passphrase"));
+    }
+    Ok(passphrase.to_string())
+}
+
+/// The config without its secrets, the run log, the state files and
+/// instructions, and with a passphrase the keyring, zipped.
+fn bundle(config: &Config, seal: &Seal) -> Result<Bundle> {
+    let text = std::fs::read_to_string(&config.path)
+        .with_context(|| format!("Could not read {}", config.path.display()))?;
+    let mut raw: Value = serde_json::from_str(&text)
+        .with_context(|| format!("Could not parse {}", config.path.display()))?;
+    let mut removed = Vec::new();
+    remove_secrets(&mut raw, "", &mut removed);
+    let run_log = runlog::path(config)?;
+    let runs = match std::fs::read(&run_log) {
+        Ok(runs) => runs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", run_log.display())),
+    };
+    let uploads = runlog::recorded_uploads(&run_log)?;
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    zip.start_file(CONFIG, options)?;
+    zip.write_all(serde_json::to_string_pretty(&raw)?.as_bytes())?;
+    zip.start_file(RUN_LOG, options)?;
+    zip.write_all(&runs)?;
+    let mut state = Vec::new();
+    let state_dir = paths::state_dir()?;
+    for file in state_files(&state_dir)? {
+        let path = state_dir.join(&file);
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                zip.start_file(format!("{STATE}/{file}"), options)?;
+                zip.write_all(&contents)?;
+                state.push(file);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        }
+    }
+    let mut keyring = false;
+    if let Seal::Passphrase(_) = seal {
+        let path = keys::path()?;
+        if path.exists() {
+            let contents = std::fs::read(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            zip.start_file(KEYRING, options)?;
+            zip.write_all(&contents)?;
+            keyring = true;
+        }
+    }
+    zip.start_file(INSTRUCTIONS, options)?;
+    let text = instructions(config, seal, keyring, &state, &removed, &uploads)?;
+    zip.write_all(text.as_bytes())?;
+    Ok(Bundle {
+        zip: zip.finish()?.into_inner(),
+        removed,
+        uploads: uploads.len(),
+    })
+}
+
+/// The [`STATE_FILES`] and the files in the [`STATE_FOLDERS`], relative to
+/// `state_dir` and with `/` between folder and file as in the bundle.
+fn state_files(state_dir: &Path) -> Result<Vec<String>> {
+    let mut files: Vec<String> = STATE_FILES.iter().map(|f| f.to_string()).collect();
+    for folder in STATE_FOLDERS {
+        let path = state_dir.join(folder);
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Could not read {}", path.display()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Not the temporary files a write in progress leaves
+            if entry.file_type()?.is_file() && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        files.extend(names.into_iter().map(|name| format!("{folder}/{name}")));
+    }
+    Ok(files)
+}
+
+/// Removes the [`SECRETS`] anywhere in `value`, noting where they were.
+fn remove_secrets(value: &mut Value, at: &str, removed: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for secret in SECRETS {
+                if map.remove(*secret).is_some() {
+                    removed.push(format!("{at}{secret}"));
+                }
+            }
+            for (key, value) in map.iter_mut() {
+                let name = value
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or(key)
+                    .to_string();
+                remove_secrets(value, &format!("{at}{name}."), removed);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                let name = item
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                remove_secrets(item, &format!("{at}{name}."), removed);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn describe(nas: &Connection) -> String {
+    let transport = match nas.transport {
+        Transport::Api => "api",
+        Transport::Webdav => "webdav",
+        Transport::Sftp => "sftp",
+        Transport::Local => "local",
+    };
+    match &nas.path {
+        Some(path) if nas.domain.is_empty() => format!("{transport} {path}"),
+        _ => format!("{transport} {}:{}, as {}", nas.domain, nas.port, nas.usr),
+    }
+}
+
+fn instructions(
+    config: &Config,
+    seal: &Seal,
+    keyring: bool,
+    state: &[String],
+    removed: &[String],
+    uploads: &[runlog::Upload],
+) -> Result<String> {
+    let mut files = vec![CONFIG.to_string(), RUN_LOG.to_string()];
+    files.extend(state.iter().map(|file| format!("{STATE}/{file}")));
+    if keyring {
+        files.push(KEYRING.to_string());
+    }
+    files.push("these instructions".to_string());
+    let mut steps = match seal {
+        Seal::Key(keyring) => vec![
+            format!("Install synology_backuper and put the keyring from `key export`, which holds key {}, where it can be read.", keyring.current),
+            format!("`synology_backuper open-recovery BUNDLE --keys KEYRING --to DIR` unpacks this bundle into DIR: {}.", files.join(", ")),
+        ],
+        Seal::Passphrase(_) => vec![
+            "Install synology_backuper and write the passphrase of `self_backup` into a file.".to_string(),
+            format!("`synology_backuper open-recovery BUNDLE --passphrase-file FILE --to DIR` unpacks this bundle into DIR: {}.", files.join(", ")),
+        ],
+    };
+    if removed.is_empty() {
+        steps.push("The config is as it was.".to_string());
+    } else {
+        steps.push(format!(
+            "These were taken out of {CONFIG} and need putting back, as `pwd` or a `pwd_file` for passwords: {}.",
+            removed.join(", ")
+        ));
+    }
+    steps.push(format!("Set `run_log` in {CONFIG} to DIR/{RUN_LOG}, so restores are checked against the checksums recorded at upload."));
+    if !state.is_empty() {
+        steps.push(format!(
+            "Copy what is in DIR/{STATE}, folders and all, into the state directory, {} here, so adopted archives, differential chains, manifests and WORM retention carry on.",
+            paths::state_dir()?.display()
+        ));
+    }
+    steps.push("Archives still queued for upload, the last run of each job and refused logins stayed on the old machine and are not in this bundle; the next run of each job archives its files anew.".to_string());
+    if keyring {
+        steps.push(format!(
+            "Copy DIR/{KEYRING} into the config directory, {} here, to restore encrypted archives.",
+            paths::config_dir()?.display()
+        ));
+    }
+    steps.push(format!("`synology_backuper --config DIR/{CONFIG} list` shows the archives on the targets, and `restore --name NAME --to FOLDER` restores one."));
+    let mut text = format!(
+        "Recovering the backups of synology_backuper\n\
+         Exported {} with synology_backuper {}, from {}.\n\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M"),
+        env!("CARGO_PKG_VERSION"),
+        config.path.display(),
+    );
+    for (i, step) in steps.iter().enumerate() {
+        text.push_str(&format!("{}. {step}\n", i + 1));
+    }
+    text.push_str("\nTargets:\n");
+    for target in &config.targets {
+        text.push_str(&format!("  {}: {}\n", target.name, describe(&target.nas)));
+    }
+    text.push_str("\nJobs:\n");
+    for job in &config.jobs {
+        text.push_str(&format!(
+            "  {}: {}, to {}\n",
+            job.name,
+            job.filename,
+            job.targets.join(", ")
+        ));
+        for target in &job.targets {
+            let newest = uploads
+                .iter()
+                .rev()
+                .find(|u| u.job == job.name && &u.target == target);
+            if let Some(newest) = newest {
+                text.push_str(&format!(
+                    "    newest recorded upload: {}\n",
+                    newest.location()
+                ));
+            }
+        }
+    }
+    Ok(text)
+}
+
+pub fn open(args: &Args) -> Result<()> {
+    let bundle = args.positional.as_deref().unwrap();
+    let sealed = std::fs::read(bundle).with_context(|| format!("Could not read {bundle}"))?;
+    let plaintext = if keys::passphrase_sealed(&sealed) {
+        let path = args.value("passphrase-file").ok_or_else(|| {
+            anyhow!("{bundle} is encrypted with a passphrase; --passphrase-file names the file holding it")
+        })?;
+        keys::open_with_passphrase(&read_passphrase(path)?, &sealed)
+    } else {
+        let keyring = match args.value("keys") {
+            Some(path) => keys::load_from(Path::new(path))?,
+            None => keys::load()?.ok_or_else(|| {
+                anyhow!("There is no keyring here; --keys names the file `key export` wrote")
+            })?,
+        };
+        keyring.open(&sealed)
+    }
+    .with_context(|| format!("Could not decrypt {bundle}"))?;
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(plaintext))
+        .with_context(|| format!("{bundle} holds no recovery bundle"))?;
+
+    let to = PathBuf::from(args.value("to").unwrap_or("."));
+    std::fs::create_dir_all(&to).with_context(|| format!("Could not create {}", to.display()))?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let Some(relative) = entry.enclosed_name() else {
+            continue;
+        };
+        let path = to.join(relative);
+        if path.exists() {
+            return Err(anyhow!(
+                "{} exists already; open the bundle into an empty folder",
+                path.display()
+            ));
+        }
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    println!(
+        "Read {} for what to do next",
+        to.join(INSTRUCTIONS).display()
+    );
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! The `report` command: a summary of the run log over a stretch of time,
+//! with how often each job succeeded, how its archive sizes went, what
+//! failed and why, and what `prune` deleted, as Markdown or as an HTML page
+//! that needs nothing else to show, for mailing or the NAS's Web Station.
+
+use crate::cli::Args;
+use crate::limits::HumanDuration;
+use crate::{format_bytes, runlog, Config};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}table{border-collapse:collapse;margin-bottom:1.5em}th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left}th{background:#eee}.failed{color:#b00}svg{display:block;margin-bottom:1em}";
+
+/// Size of the chart of each job's archive sizes
+const CHART: (f64, f64) = (480.0, 100.0);
+
+/// A heading, a table header and its rows
+type Section = (&'static str, Vec<&'static str>, Vec<Vec<String>>);
+/// Sizes of a job's archives, by when they were made
+type Sizes = Vec<(DateTime<Utc>, u64)>;
+
+/// What the report says about a job.
+#[derive(Default)]
+struct JobSummary {
+    ok: usize,
+    failed: usize,
+    sizes: Sizes,
+}
+
+/// The report: a title and its sections.
+struct Report {
+    title: String,
+    sections: Vec<Section>,
+    /// The archive sizes of each job, for charts
+    sizes: Vec<(String, Sizes)>,
+}
+
+pub fn report(config: &Config, args: &Args) -> Result<()> {
+    let since = HumanDuration::try_from(args.value("since").unwrap_or("30d").to_string())?;
+    let format = args.value("format").unwrap_or("markdown");
+    if !matches!(format, "markdown" | "html") {
+        return Err(anyhow!(
+            "Unknown report format {format:?}; it is markdown or html"
+        ));
+    }
+    let now = Utc::now();
+    let from = now - chrono::Duration::from_std(since.0)?;
+    let records = runlog::read_records(&runlog::path(config)?)?;
+    let report = gather(&records, from, now, args.value("job"));
+    let text = match format {
+        "html" => html(&report),
+        _ => markdown(&report),
+    };
+    match args.value("out") {
+        Some(out) => {
+            std::fs::write(out, text).with_context(|| format!("Could not write {out}"))?;
+            println!("Wrote the report to {out}");
+        }
+        None => print!("{text}"),
+    }
+    Ok(())
+}
+
+/// When a record of the run log was made: a run's start, an audit's or a prune's time.
+fn time(record: &Value) -> Option<DateTime<Utc>> {
+    ["start", "audit", "prune"]
+        .iter()
+        .find_map(|key| record[key].as_str())
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn gather(records: &[Value], from: DateTime<Utc>, to: DateTime<Utc>, job: Option<&str>) -> Report {
+    let records = records
+        .iter()
+        .filter(|r| time(r).is_some_and(|t| t >= from))
+        .collect::<Vec<_>>();
+    let wanted = |name: &str| job.is_none_or(|job| job == name);
+    let mut jobs = BTreeMap::<String, JobSummary>::new();
+    let mut failures = Vec::new();
+    let mut warnings = Vec::new();
+    let mut audits = Vec::new();
+    let mut prunes = Vec::new();
+    for record in &records {
+        let when = time(record).map_or_else(String::new, stamp);
+        if record.get("audit").is_some() {
+            if record["result"].as_str() != Some("ok") {
+                let problems = record["problems"].as_array().into_iter().flatten();
+                audits.push(vec![
+                    when,
+                    record["checked"].as_str().unwrap_or_default().to_string(),
+                    problems
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                ]);
+            }
+            continue;
+        }
+        if record.get("prune").is_some() {
+            let name = record["job"].as_str().unwrap_or_default();
+            if wanted(name) {
+                let deleted = record["deleted"].as_array().into_iter().flatten();
+                prunes.push(vec![
+                    when,
+                    name.to_string(),
+                    record["target"].as_str().unwrap_or_default().to_string(),
+                    record["kept"].as_u64().unwrap_or_default().to_string(),
+                    deleted
+                        .filter_map(Value::as_str)
+                        .map(|path| path.rsplit('/').next().unwrap_or(path))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ]);
+            }
+            continue;
+        }
+        for run in record["jobs"].as_array().into_iter().flatten() {
+            let Some(name) = run["job"].as_str().filter(|name| wanted(name)) else {
+                continue;
+            };
+            let summary = jobs.entry(name.to_string()).or_default();
+            match run["result"].as_str().unwrap_or_default() {
+                "ok" => {
+                    summary.ok += 1;
+                    if let Some(warning) = run["warning"].as_str() {
+                        warnings.push(vec![when.clone(), name.to_string(), warning.to_string()]);
+                    }
+                    let end = run["queued"].as_str().or(run["end"].as_str());
+                    let end = end.and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+                    if let (Some(end), Some(size)) = (end, run["archive_bytes"].as_u64()) {
+                        summary.sizes.push((end.with_timezone(&Utc), size));
+                    }
+                }
+                result @ ("failed" | "timed_out") => {
+                    summary.failed += 1;
+                    failures.push(vec![
+                        when.clone(),
+                        name.to_string(),
+                        result.replace('_', " "),
+                        run["error"].as_str().unwrap_or_default().to_string(),
+                    ]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let rates = jobs
+        .iter()
+        .map(|(name, s)| {
+            let runs = s.ok + s.failed;
+            vec![
+                name.clone(),
+                runs.to_string(),
+                s.ok.to_string(),
+                s.failed.to_string(),
+                match runs {
+                    0 => String::new(),
+                    runs => format!("{:.0}%", s.ok as f64 * 100.0 / runs as f64),
+                },
+            ]
+        })
+        .collect();
+    let mut sizes = Vec::new();
+    let mut size_rows = Vec::new();
+    for (name, summary) in &mut jobs {
+        summary.sizes.sort_by_key(|(time, _)| *time);
+        let (Some(first), Some(last)) = (summary.sizes.first(), summary.sizes.last()) else {
+            continue;
+        };
+        let values = summary.sizes.iter().map(|(_, size)| *size);
+        size_rows.push(vec![
+            name.clone(),
+            format_bytes(first.1),
+            format_bytes(last.1),
+            format_bytes(values.clone().min().unwrap_or_default()),
+            format_bytes(values.max().unwrap_or_default()),
+            match first.1 {
+                0 => String::new(),
+                first => format!("{:+.0}%", (last.1 as f64 / first as f64 - 1.0) * 100.0),
+            },
+        ]);
+        sizes.push((name.clone(), summary.sizes.clone()));
+    }
+
+    let mut sections = vec![
+        (
+            "Success rate",
+            vec!["Job", "Runs", "Succeeded", "Failed", "Rate"],
+            rates,
+        ),
+        (
+            "Archive sizes",
+            vec!["Job", "First", "Last", "Smallest", "Largest", "Change"],
+            size_rows,
+        ),
+        (
+            "Failures",
+            vec!["Time", "Job", "Result", "Reason"],
+            failures,
+        ),
+        ("Warnings", vec!["Time", "Job", "Warning"], warnings),
+        (
+            "Retention",
+            vec!["Time", "Job", "Target", "Kept", "Deleted"],
+            prunes,
+        ),
+    ];
+    if job.is_none() {
+        sections.push(("Audit alerts", vec!["Time", "Checked", "Problems"], audits));
+    }
+    Report {
+        title: format!("Backup report, {} to {}", stamp(from), stamp(to)),
+        sections,
+        sizes,
+    }
+}
+
+fn stamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn markdown(report: &Report) -> String {
+    let mut out = format!("# {}\n", report.title);
+    for (heading, header, rows) in &report.sections {
+        let _ = write!(out, "\n## {heading}\n\n");
+        if rows.is_empty() {
+            out.push_str("None.\n");
+            continue;
+        }
+        let _ = writeln!(out, "| {} |", header.join(" | "));
+        let _ = writeln!(out, "|{}", "---|".repeat(header.len()));
+        for row in rows {
+            let cells = row
+                .iter()
+                .map(|cell| cell.replace('|', "\\|").replace('\n', " "))
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "| {} |", cells.join(" | "));
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html(report: &Report) -> String {
+    let title = escape(&report.title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title><style>{STYLE}</style></head><body>\n<h1>{title}</h1>\n"
+    );
+    for (heading, header, rows) in &report.sections {
+        let _ = writeln!(out, "<h2>{heading}</h2>");
+        if *heading == "Archive sizes" {
+            for (job, sizes) in &report.sizes {
+                out.push_str(&chart(job, sizes));
+            }
+        }
+        if rows.is_empty() {
+            out.push_str("<p>None.</p>\n");
+            continue;
+        }
+        out.push_str("<table><tr>");
+        for cell in header {
+            let _ = write!(out, "<th>{cell}</th>");
+        }
+        out.push_str("</tr>\n");
+        for row in rows {
+            out.push_str(if *heading == "Failures" {
+                "<tr class=\"failed\">"
+            } else {
+                "<tr>"
+            });
+            for cell in row {
+                let _ = write!(out, "<td>{}</td>", escape(cell));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+/// A line chart of a job's archive sizes over time, as inline SVG.
+fn chart(job: &str, sizes: &[(DateTime<Utc>, u64)]) -> String {
+    let (width, height) = CHART;
+    let (Some(first), Some(last)) = (sizes.first(), sizes.last()) else {
+        return String::new();
+    };
+    let span = (last.0 - first.0).num_seconds().max(1) as f64;
+    let max = sizes
+        .iter()
+        .map(|(_, size)| *size)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+    let points = sizes
+        .iter()
+        .map(|(time, size)| {
+            let x = match sizes.len() {
+                1 => width / 2.0,
+                _ => (*time - first.0).num_seconds() as f64 / span * width,
+            };
+            format!("{x:.1},{:.1}", height - *size as f64 / max * height)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{}\" viewBox=\"0 -20 {width} {}\"><title>{}</title><text x=\"0\" y=\"-6\" font-size=\"12\">{} (up to {})</text><rect x=\"0\" y=\"0\" width=\"{width}\" height=\"{height}\" fill=\"none\" stroke=\"#ccc\"/><polyline points=\"{points}\" fill=\"none\" stroke=\"#36c\" stroke-width=\"2\"/></svg>\n",
+        height + 24.0,
+        height + 24.0,
+        escape(job),
+        escape(job),
+        format_bytes(max as u64),
+    )
+}
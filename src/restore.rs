@@ -0,0 +1,1038 @@
+//! The `restore` command: downloads one archive from whichever target of its
+//! job has it and extracts the entries asked for, with the modification
+//! times and permissions recorded when they were archived. A download that
+//! fails is kept for the next restore to carry on with. With `--list` it
+//! only reads the archive's table of contents. Where the archive has a
+//! manifest, what is restored is checked against it on the way to disk.
+//! Files stored once for several hard links are linked again, and those
+//! stored once for several files with the same contents copied again. A
+//! differential archive is restored along with the full archive it builds
+//! on, with what the differential holds or deleted left out of the full one,
+//! and a synthetic full archive along with the copies it builds on.
+//! Extended attributes and ACLs the archive keeps are set again on the
+//! files restored and the folders above them.
+
+use crate::archive::{entry_name, is_metadata, matches_any, DIFFERENTIAL, LINKS, XATTRS};
+use crate::audit::sha256_file;
+use crate::backend::{download_resuming, StorageBackend};
+use crate::catalog;
+use crate::chain::Differential;
+use crate::checksum::{self, Algorithm, Hashing};
+use crate::cli::Args;
+use crate::client::Mode;
+use crate::config::Job;
+use crate::xattrs::{self, Attributes};
+use crate::{
+    format_bytes, job_backups, job_folder, runlog, selected_jobs, zip_index, Backup, Config,
+    Sessions,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::TimeZone;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+pub fn run(config: &Config, mode: Mode, args: &Args) -> Result<()> {
+    let name = args
+        .value("name")
+        .ok_or_else(|| anyhow!("restore needs --name, the archive to restore from"))?;
+    let patterns = args.values("path").map(String::from).collect::<Vec<_>>();
+    let to = PathBuf::from(args.value("to").unwrap_or("."));
+    let overwrite = args.flag("overwrite");
+    let recursive = args.flag("recursive");
+    let jobs = selected_jobs(config, args)?;
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let result = find(&mut sessions, &jobs, name, recursive).and_then(|(job, target, backup)| {
+        let remote = sessions.get(&target).1.map_err(|e| anyhow!("{e}"))?;
+        if args.flag("list") {
+            return list(remote, job, &backup, &patterns);
+        }
+        std::fs::create_dir_all(&to)
+            .with_context(|| format!("Could not create {}", to.display()))?;
+        let mut layer = restore_archive(
+            config,
+            remote,
+            &target,
+            job,
+            &backup,
+            &patterns,
+            &to,
+            overwrite,
+            HashSet::new(),
+        )?;
+        let (mut extracted, mut skip) = (Extracted::default(), HashSet::new());
+        let (mut target, mut backup) = (target, backup);
+        // Where synthetic full archives keep the copies they build on
+        let mut volumes = Vec::new();
+        // A differential holds only what changed since its full archive, which has the rest.
+        while let Some(differential) = layer.differential.take() {
+            skip.extend(std::mem::take(&mut layer.covered));
+            skip.extend(differential.deleted);
+            extracted.merge(layer);
+            println!(
+                "{} holds what changed since the full archive {}, which the rest is restored from",
+                backup.file.name, differential.base
+            );
+            if let (Some(folder), Some((parent, _))) =
+                (differential.volumes, backup.file.path.rsplit_once('/'))
+            {
+                volumes.push((target.clone(), format!("{parent}/{folder}")));
+            }
+            (target, backup) = match find_copy(&mut sessions, job, &volumes, &differential.base) {
+                Some(found) => found,
+                None => {
+                    let (_, target, base) =
+                        find(&mut sessions, &[job], &differential.base, recursive)?;
+                    (target, base)
+                }
+            };
+            let remote = sessions.get(&target).1.map_err(|e| anyhow!("{e}"))?;
+            layer = restore_archive(
+                config,
+                remote,
+                &target,
+                job,
+                &backup,
+                &patterns,
+                &to,
+                overwrite,
+                skip.clone(),
+            )?;
+        }
+        extracted.merge(layer);
+        extracted.report(&patterns, &to)
+    });
+    sessions.logout();
+    result
+}
+
+/// The copy of the archive named `name` in one of the `volumes`, the
+/// `(target, folder)` pairs where synthetic full archives keep theirs.
+fn find_copy(
+    sessions: &mut Sessions,
+    job: &Job,
+    volumes: &[(String, String)],
+    name: &str,
+) -> Option<(String, Backup)> {
+    for (target, folder) in volumes.iter().rev() {
+        let Ok(remote) = sessions.get(target).1 else {
+            continue;
+        };
+        let files = match remote.list(folder, false) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Could not list {target}:{folder}: {e:#}");
+                continue;
+            }
+        };
+        let copy = job_backups(job, files)
+            .into_iter()
+            .find(|b| b.file.name == name);
+        if let Some(copy) = copy {
+            return Some((target.clone(), copy));
+        }
+    }
+    None
+}
+
+/// Restores what `patterns` ask for from `backup` on `target` into `to`,
+/// leaving out the entries in `skip`: picked out of the archive where it
+/// lies if only some files are asked for, and otherwise from a download.
+#[allow(clippy::too_many_arguments)]
+fn restore_archive(
+    config: &Config,
+    remote: &dyn StorageBackend,
+    target: &str,
+    job: &Job,
+    backup: &Backup,
+    patterns: &[String],
+    to: &Path,
+    overwrite: bool,
+    skip: HashSet<String>,
+) -> Result<Extracted> {
+    let size = backup
+        .file
+        .size
+        .map(|size| format!(", {}", format_bytes(size)));
+    println!(
+        "Restoring from {target}:{}{}",
+        backup.file.path,
+        size.unwrap_or_default()
+    );
+    if let (false, Some(size)) = (patterns.is_empty(), backup.file.size) {
+        let mut ranged = Ranged::new(remote, &backup.file.path, size);
+        match pick(&mut ranged, job, patterns, to, overwrite, &skip) {
+            Ok(Some(extracted)) => {
+                println!(
+                    "Read {} of the {} archive",
+                    format_bytes(ranged.fetched),
+                    format_bytes(size)
+                );
+                return Ok(extracted);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!(
+                "Could not read only the files asked for ({e:#}); downloading the whole archive"
+            ),
+        }
+    }
+    let local = to.join(format!(".synology_backuper_restore_{}", backup.file.name));
+    download(config, remote, target, backup, &local)?;
+    let restored = zip::ZipArchive::new(File::open(&local)?)
+        .context("The download is not a zip archive")
+        .and_then(|zip| extract(zip, job, patterns, to, overwrite, &skip));
+    let _ = std::fs::remove_file(&local);
+    restored
+}
+
+/// The archive named `name` on the first target of `jobs` that has it.
+fn find<'j>(
+    sessions: &mut Sessions,
+    jobs: &[&'j Job],
+    name: &str,
+    recursive: bool,
+) -> Result<(&'j Job, String, Backup)> {
+    let mut looked = Vec::new();
+    for job in jobs {
+        for target in &job.targets {
+            let (config, remote) = sessions.get(target);
+            let found = remote.map_err(|e| anyhow!("{e}")).and_then(|remote| {
+                let (folder, _) = job_folder(remote, job, config)?;
+                let backups = catalog::list_backups(remote, job, target, &folder, recursive)?;
+                Ok(backups.into_iter().find(|b| b.file.name == name))
+            });
+            match found {
+                Ok(Some(backup)) => return Ok((job, target.clone(), backup)),
+                Ok(None) => looked.push(target.as_str()),
+                Err(e) => eprintln!("Job {} on {target}: {e:#}", job.name),
+            }
+        }
+    }
+    looked.dedup();
+    Err(anyhow!(
+        "No archive named {name} on {}",
+        if looked.is_empty() {
+            "any target that could be reached".to_string()
+        } else {
+            looked.join(", ")
+        }
+    ))
+}
+
+/// Downloads `backup` from `target` to `local`, carrying on with what an
+/// earlier restore left there, and checks it against the SHA-256 the run log
+/// recorded when it was uploaded. What was downloaded stays when the
+/// download fails, for the next restore to resume.
+fn download(
+    config: &Config,
+    remote: &dyn StorageBackend,
+    target: &str,
+    backup: &Backup,
+    local: &Path,
+) -> Result<()> {
+    let path = &backup.file.path;
+    let mut have = std::fs::metadata(local).map_or(0, |m| m.len());
+    if backup.file.size.is_some_and(|size| have > size) {
+        have = 0;
+    }
+    if have > 0 {
+        println!(
+            "Carrying on with the download an earlier restore left after {}",
+            format_bytes(have)
+        );
+    }
+    if Some(have) != backup.file.size {
+        download_resuming(remote, path, local, have).with_context(|| {
+            let have = std::fs::metadata(local).map_or(0, |m| m.len());
+            format!(
+                "Could not download {path}; the {} downloaded stay in {} for the next restore to carry on from",
+                format_bytes(have),
+                local.display()
+            )
+        })?;
+    }
+
+    let uploads = runlog::recorded_uploads(&runlog::path(config)?)?;
+    let recorded = uploads
+        .iter()
+        .rev()
+        .find(|u| u.target == target && &u.path == path)
+        .and_then(|u| u.sha256.as_deref());
+    let Some(recorded) = recorded else {
+        eprintln!(
+            "The run log records no checksum for {target}:{path}, so the download is not checked"
+        );
+        return Ok(());
+    };
+    let sha256 = sha256_file(local)?;
+    if sha256 != recorded {
+        let _ = std::fs::remove_file(local);
+        return Err(anyhow!(
+            "The download of {target}:{path} has the SHA-256 {sha256}, not the {recorded} recorded when it was uploaded; it was deleted, and restoring again downloads it anew"
+        ));
+    }
+    println!("The download matches the SHA-256 recorded when it was uploaded");
+    Ok(())
+}
+
+/// Whether the entry `name` of one of `job`'s archives is asked for. A
+/// pattern is matched like an `exclude` pattern, against the path below the
+/// job's `filename` as well as the whole entry name; no patterns take all.
+fn selected(job: &Job, patterns: &[String], name: &str) -> bool {
+    if is_metadata(name) {
+        return false;
+    }
+    let root = format!("{}/", entry_name(Path::new(&job.filename)));
+    let below = name.strip_prefix(&root).unwrap_or(name);
+    patterns.is_empty()
+        || matches_any(patterns, Path::new(below))
+        || matches_any(patterns, Path::new(name))
+}
+
+/// Prints the entries of `backup` that `patterns` select. Only the end of
+/// the archive is downloaded, where its central directory is, unless the
+/// target can't download part of a file.
+fn list(
+    remote: &dyn StorageBackend,
+    job: &Job,
+    backup: &Backup,
+    patterns: &[String],
+) -> Result<()> {
+    let path = &backup.file.path;
+    let mut fetched = 0;
+    let ranged = backup.file.size.map(|size| {
+        zip_index::entries(size, |offset, len| {
+            let bytes = remote.read_range(path, offset, len)?;
+            fetched += bytes.len() as u64;
+            Ok(bytes)
+        })
+    });
+    let entries = match ranged {
+        Some(Ok(entries)) => entries,
+        failed => {
+            if let Some(Err(e)) = failed {
+                eprintln!("Could not read only the table of contents ({e:#}); downloading the whole archive");
+            }
+            let local = std::env::temp_dir().join(format!(
+                ".synology_backuper_list_{}.zip",
+                std::process::id()
+            ));
+            let entries = remote
+                .download(path, &local)
+                .with_context(|| format!("Could not download {path}"))
+                .and_then(|()| {
+                    let mut file = File::open(&local)?;
+                    let size = file.metadata()?.len();
+                    fetched = size;
+                    zip_index::entries(size, |offset, len| {
+                        let mut bytes = Vec::new();
+                        file.seek(SeekFrom::Start(offset))?;
+                        (&mut file).take(len).read_to_end(&mut bytes)?;
+                        Ok(bytes)
+                    })
+                });
+            let _ = std::fs::remove_file(&local);
+            entries?
+        }
+    };
+
+    let (mut count, mut total) = (0, 0);
+    for entry in entries.iter().filter(|e| selected(job, patterns, &e.name)) {
+        let modified = entry
+            .modified
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+        println!(
+            "{}\t{}\t{}",
+            format_bytes(entry.size),
+            modified.unwrap_or_default(),
+            entry.name
+        );
+        if !entry.is_dir() {
+            count += 1;
+            total += entry.size;
+        }
+    }
+    let read = match backup.file.size {
+        Some(size) => format!(
+            "{} of the {} archive",
+            format_bytes(fetched),
+            format_bytes(size)
+        ),
+        None => format_bytes(fetched),
+    };
+    println!("{count} files, {}; read {read}", format_bytes(total));
+    Ok(())
+}
+
+/// Extracts the entries of `zip` that [`selected`] picks, other than those
+/// in `skip`, into `to` under their entry names.
+fn extract<R: Read + Seek>(
+    mut zip: zip::ZipArchive<R>,
+    job: &Job,
+    patterns: &[String],
+    to: &Path,
+    overwrite: bool,
+    skip: &HashSet<String>,
+) -> Result<Extracted> {
+    let mut extracted = Extracted::default();
+    let manifest = zip
+        .file_names()
+        .find_map(|name| Algorithm::of_manifest(name).map(|a| (a, name.to_string())));
+    if let Some((algorithm, name)) = manifest {
+        let mut text = String::new();
+        zip.by_name(&name)?.read_to_string(&mut text)?;
+        extracted.manifest = Some((algorithm, checksum::parse_manifest(&text)));
+    }
+    let links = match zip.by_name(LINKS) {
+        Ok(file) => parse_links(file)?,
+        Err(zip::result::ZipError::FileNotFound) => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    extracted.attributes = match zip.by_name(XATTRS) {
+        Ok(file) => parse_attributes(file)?,
+        Err(zip::result::ZipError::FileNotFound) => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    extracted.differential = match zip.by_name(DIFFERENTIAL) {
+        Ok(file) => Some(parse_differential(file)?),
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let Some(relative) = entry.enclosed_name() else {
+            eprintln!("Skipped {}, which points outside the folder", entry.name());
+            continue;
+        };
+        if !selected(job, patterns, entry.name()) || skip.contains(entry.name()) {
+            continue;
+        }
+        let path = to.join(&relative);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        let modified = entry.last_modified().and_then(system_time);
+        let mode = entry.unix_mode();
+        let name = entry.name().to_string();
+        extracted
+            .write(&name, &path, &mut entry, modified, mode, overwrite)
+            .with_context(|| format!("Could not extract {name}"))?;
+    }
+    let names = zip.file_names().map(String::from).collect::<HashSet<_>>();
+    extracted.cover(&names, &links);
+    for again in extracted.unstored(job, patterns, to, &names, &links, skip) {
+        if extracted.again(&again, overwrite)? {
+            continue;
+        }
+        // What it copies wasn't restored along with it, so it is extracted here.
+        let mut entry = zip.by_name(&again.entry)?;
+        let modified = entry.last_modified().and_then(system_time);
+        let mode = entry.unix_mode();
+        extracted
+            .write(
+                &again.name,
+                &again.path,
+                &mut entry,
+                modified,
+                mode,
+                overwrite,
+            )
+            .with_context(|| {
+                format!(
+                    "Could not extract {} as {}",
+                    again.entry,
+                    again.path.display()
+                )
+            })?;
+    }
+    extracted.set_folder_attributes(to);
+    Ok(extracted)
+}
+
+/// The hard links of a [`LINKS`] entry, from each link to the entry of its file.
+fn parse_links(reader: impl Read) -> Result<BTreeMap<String, String>> {
+    serde_json::from_reader(reader).with_context(|| format!("{LINKS} is not a list of links"))
+}
+
+/// The extended attributes of an [`XATTRS`] entry, by entry name.
+fn parse_attributes(reader: impl Read) -> Result<BTreeMap<String, Attributes>> {
+    serde_json::from_reader(reader)
+        .with_context(|| format!("{XATTRS} is not a list of extended attributes"))
+}
+
+fn parse_differential(reader: impl Read) -> Result<Differential> {
+    serde_json::from_reader(reader)
+        .with_context(|| format!("{DIFFERENTIAL} doesn't name a full archive"))
+}
+
+/// Where `name`, which has no entry of its own, is restored to, if it is
+/// asked for and stays inside `to`.
+fn unstored_path(job: &Job, patterns: &[String], to: &Path, name: &str) -> Option<PathBuf> {
+    if !selected(job, patterns, name) {
+        return None;
+    }
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        eprintln!("Skipped {name}, which points outside the folder");
+        return None;
+    }
+    Some(to.join(relative))
+}
+
+/// A file restored from the contents of another entry: a hard link, or
+/// one with the same contents as a file stored once.
+struct Again {
+    /// Its name in the manifest
+    name: String,
+    path: PathBuf,
+    /// The name whose restored file it is linked to or copied from
+    from: String,
+    /// The entry holding its contents
+    entry: String,
+    hard_link: bool,
+}
+
+/// Like [`extract`], but reads the central directory and then each entry
+/// picked through `ranged`, so only those parts of the archive are
+/// downloaded. `None` if the archive holds entries this can't read on its
+/// own, which extracting the whole download handles.
+fn pick(
+    ranged: &mut Ranged,
+    job: &Job,
+    patterns: &[String],
+    to: &Path,
+    overwrite: bool,
+    skip: &HashSet<String>,
+) -> Result<Option<Extracted>> {
+    let entries = zip_index::entries(ranged.size, |offset, len| {
+        let mut bytes = Vec::new();
+        ranged.seek(SeekFrom::Start(offset))?;
+        (&mut *ranged).take(len).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    })?;
+    let mut extracted = Extracted::default();
+    let manifest = entries
+        .iter()
+        .find_map(|e| Algorithm::of_manifest(&e.name).map(|a| (a, e)));
+    if let Some((algorithm, entry)) = manifest {
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(mut file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        extracted.manifest = Some((algorithm, checksum::parse_manifest(&text)));
+    }
+    let mut links = BTreeMap::new();
+    if let Some(entry) = entries.iter().find(|e| e.name == LINKS) {
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        links = parse_links(file)?;
+    }
+    if let Some(entry) = entries.iter().find(|e| e.name == XATTRS) {
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        extracted.attributes = parse_attributes(file)?;
+    }
+    if let Some(entry) = entries.iter().find(|e| e.name == DIFFERENTIAL) {
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        extracted.differential = Some(parse_differential(file)?);
+    }
+    let wanted = entries
+        .iter()
+        .filter(|e| selected(job, patterns, &e.name) && !skip.contains(&e.name));
+    for entry in wanted {
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(mut file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        if file.name() != entry.name {
+            return Err(anyhow!(
+                "{} lies where the central directory says {} does",
+                file.name(),
+                entry.name
+            ));
+        }
+        let Some(relative) = file.enclosed_name() else {
+            eprintln!("Skipped {}, which points outside the folder", entry.name);
+            continue;
+        };
+        let path = to.join(&relative);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        let modified = entry.modified.and_then(local_time);
+        extracted
+            .write(
+                &entry.name,
+                &path,
+                &mut file,
+                modified,
+                entry.mode,
+                overwrite,
+            )
+            .with_context(|| format!("Could not extract {}", entry.name))?;
+    }
+    let names = entries
+        .iter()
+        .map(|e| e.name.clone())
+        .collect::<HashSet<_>>();
+    extracted.cover(&names, &links);
+    for again in extracted.unstored(job, patterns, to, &names, &links, skip) {
+        if extracted.again(&again, overwrite)? {
+            continue;
+        }
+        let entry = entries.iter().find(|e| e.name == again.entry).unwrap();
+        ranged.seek(SeekFrom::Start(entry.offset))?;
+        let Some(mut file) = zip::read::read_zipfile_from_stream(ranged)? else {
+            return Ok(None);
+        };
+        let modified = entry.modified.and_then(local_time);
+        extracted
+            .write(
+                &again.name,
+                &again.path,
+                &mut file,
+                modified,
+                entry.mode,
+                overwrite,
+            )
+            .with_context(|| {
+                format!(
+                    "Could not extract {} as {}",
+                    again.entry,
+                    again.path.display()
+                )
+            })?;
+    }
+    extracted.set_folder_attributes(to);
+    Ok(Some(extracted))
+}
+
+/// The files an extraction wrote and those it kept.
+#[derive(Default)]
+struct Extracted {
+    restored: usize,
+    bytes: u64,
+    existing: Vec<PathBuf>,
+    /// The archive's manifest, with its checksums by entry name
+    manifest: Option<(Algorithm, HashMap<String, String>)>,
+    /// How many restored files the manifest has a checksum for
+    checked: usize,
+    /// The restored files that don't match it
+    mismatched: Vec<PathBuf>,
+    /// Where each entry was restored to, for its hard links
+    written: HashMap<String, PathBuf>,
+    /// Hard links made to restored files
+    linked: usize,
+    /// Copies made of restored files with the same contents
+    copied: usize,
+    /// What a differential archive's [`DIFFERENTIAL`] entry holds
+    differential: Option<Differential>,
+    /// Every name the archive has a file for, stored or not, and the
+    /// folders it has extended attributes for
+    covered: HashSet<String>,
+    /// What the archive's [`XATTRS`] entry holds
+    attributes: BTreeMap<String, Attributes>,
+    /// Files and folders given their extended attributes and ACLs
+    attributed: usize,
+    /// Those where some couldn't be set, with the error
+    unattributed: Vec<(PathBuf, String)>,
+}
+
+impl Extracted {
+    /// Writes the contents `reader` holds for entry `name` to `path` and
+    /// gives it `modified` and the permissions in `mode`, unless something
+    /// is there already.
+    fn write(
+        &mut self,
+        name: &str,
+        path: &Path,
+        reader: &mut dyn Read,
+        modified: Option<std::time::SystemTime>,
+        mode: Option<u32>,
+        overwrite: bool,
+    ) -> Result<()> {
+        if path.exists() && !overwrite {
+            self.existing.push(path.to_path_buf());
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file =
+            File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+        let expected = self
+            .manifest
+            .as_ref()
+            .and_then(|(algorithm, hashes)| Some((*algorithm, hashes.get(name)?)));
+        let mut reader = Hashing::new(reader, expected.map(|(algorithm, _)| algorithm));
+        self.bytes += std::io::copy(&mut reader, &mut file)?;
+        if let (Some((_, expected)), Some(hash)) = (expected, reader.finish()) {
+            self.checked += 1;
+            if &hash != expected {
+                self.mismatched.push(path.to_path_buf());
+            }
+        }
+        if let Some(time) = modified {
+            file.set_modified(time)?;
+        }
+        // Before the permissions, which may leave the file read-only.
+        self.set_attributes(name, path);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        self.restored += 1;
+        self.written.insert(name.to_string(), path.to_path_buf());
+        Ok(())
+    }
+
+    /// Notes the entries `names` and hard `links`, and what the manifest
+    /// lists, as the files the archive has.
+    fn cover(&mut self, names: &HashSet<String>, links: &BTreeMap<String, String>) {
+        let hashes = self.manifest.iter().flat_map(|(_, hashes)| hashes.keys());
+        self.covered = names
+            .iter()
+            .chain(links.keys())
+            .chain(hashes)
+            .cloned()
+            .collect();
+    }
+
+    /// What `patterns` ask for that has no entry of its own among `names`,
+    /// other than what is in `skip`: first the files the manifest lists with
+    /// the contents of a stored one, then the hard `links`, which may lead
+    /// to those.
+    fn unstored(
+        &self,
+        job: &Job,
+        patterns: &[String],
+        to: &Path,
+        names: &HashSet<String>,
+        links: &BTreeMap<String, String>,
+        skip: &HashSet<String>,
+    ) -> Vec<Again> {
+        let hashes = self.manifest.as_ref().map(|(_, hashes)| hashes);
+        // The entries holding each of the contents
+        let stored = hashes
+            .into_iter()
+            .flatten()
+            .filter(|(name, _)| names.contains(*name))
+            .map(|(name, hash)| (hash, name))
+            .collect::<HashMap<_, _>>();
+        let entry = |name: &String| match names.contains(name) {
+            true => Some(name.clone()),
+            false => hashes
+                .and_then(|hashes| stored.get(hashes.get(name)?))
+                .map(|name| name.to_string()),
+        };
+        let mut copies = hashes
+            .into_iter()
+            .flatten()
+            .filter(|(name, _)| !names.contains(*name) && !skip.contains(*name))
+            .collect::<Vec<_>>();
+        copies.sort();
+        let mut again = Vec::new();
+        for (name, hash) in copies {
+            let (Some(from), Some(path)) =
+                (stored.get(hash), unstored_path(job, patterns, to, name))
+            else {
+                continue;
+            };
+            again.push(Again {
+                name: name.clone(),
+                path,
+                from: from.to_string(),
+                entry: from.to_string(),
+                hard_link: false,
+            });
+        }
+        for (link, name) in links.iter().filter(|(link, _)| !skip.contains(*link)) {
+            let Some(path) = unstored_path(job, patterns, to, link) else {
+                continue;
+            };
+            let Some(entry) = entry(name) else {
+                eprintln!("Skipped {link}, a hard link to {name}, which isn't in the archive");
+                continue;
+            };
+            again.push(Again {
+                name: name.clone(),
+                path,
+                from: name.clone(),
+                entry,
+                hard_link: true,
+            });
+        }
+        again
+    }
+
+    /// Links or copies `again` from where its file was restored, or keeps
+    /// what is there already; false if that file wasn't restored.
+    fn again(&mut self, again: &Again, overwrite: bool) -> Result<bool> {
+        let Some(file) = self.written.get(&again.from).cloned() else {
+            return Ok(false);
+        };
+        let path = &again.path;
+        if path.exists() {
+            if !overwrite {
+                self.existing.push(path.to_path_buf());
+                return Ok(true);
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("Could not replace {}", path.display()))?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if again.hard_link {
+            std::fs::hard_link(&file, path).with_context(|| {
+                format!("Could not link {} to {}", path.display(), file.display())
+            })?;
+            self.linked += 1;
+        } else {
+            std::fs::copy(&file, path).with_context(|| {
+                format!("Could not copy {} to {}", file.display(), path.display())
+            })?;
+            File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(std::fs::metadata(&file)?.modified()?)?;
+            self.set_attributes(&again.name, path);
+            self.copied += 1;
+            self.written.insert(again.name.clone(), path.clone());
+        }
+        Ok(true)
+    }
+
+    /// Gives the file restored for `name` at `path` the extended
+    /// attributes and ACLs the archive has for it.
+    fn set_attributes(&mut self, name: &str, path: &Path) {
+        let Some(found) = self.attributes.get(name) else {
+            return;
+        };
+        match xattrs::write(path, found) {
+            Ok(()) => self.attributed += 1,
+            Err(e) => self.unattributed.push((path.to_path_buf(), e.to_string())),
+        }
+    }
+
+    /// Gives the folders this restored files into the extended attributes
+    /// and ACLs the archive has for them.
+    fn set_folder_attributes(&mut self, to: &Path) {
+        let folders = self
+            .attributes
+            .keys()
+            .filter_map(|name| Some((name.clone(), name.strip_suffix('/')?.to_string())))
+            .collect::<Vec<_>>();
+        for (name, dir) in folders {
+            let relative = Path::new(&dir);
+            if !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                continue;
+            }
+            let path = to.join(relative);
+            if self.written.values().any(|file| file.starts_with(&path)) {
+                // So the full archive under a differential leaves it be.
+                self.covered.insert(name.clone());
+                self.set_attributes(&name, &path);
+            }
+        }
+    }
+
+    /// Adds what was restored from another archive into the same folder.
+    fn merge(&mut self, other: Extracted) {
+        self.restored += other.restored;
+        self.bytes += other.bytes;
+        self.existing.extend(other.existing);
+        self.manifest = self.manifest.take().or(other.manifest);
+        self.checked += other.checked;
+        self.mismatched.extend(other.mismatched);
+        self.linked += other.linked;
+        self.copied += other.copied;
+        self.attributed += other.attributed;
+        self.unattributed.extend(other.unattributed);
+    }
+
+    fn report(self, patterns: &[String], to: &Path) -> Result<()> {
+        for path in &self.existing {
+            eprintln!(
+                "Kept {}, which exists already; --overwrite replaces it",
+                path.display()
+            );
+        }
+        if self.restored + self.linked + self.copied == 0 && self.existing.is_empty() {
+            return Err(anyhow!("No entry matches {}", patterns.join(" or ")));
+        }
+        println!(
+            "Restored {} files, {}, to {}",
+            self.restored,
+            format_bytes(self.bytes),
+            to.display()
+        );
+        if self.copied > 0 {
+            println!(
+                "Copied {} more files from those with the same contents, which the archive stores once",
+                self.copied
+            );
+        }
+        if self.linked > 0 {
+            println!(
+                "Linked {} more names to them, which were hard links to the same files",
+                self.linked
+            );
+        }
+        if self.attributed > 0 {
+            println!(
+                "Set the extended attributes and ACLs of {} files and folders",
+                self.attributed
+            );
+        }
+        for (path, error) in &self.unattributed {
+            eprintln!(
+                "Could not set all extended attributes of {}: {error}",
+                path.display()
+            );
+        }
+        let Some((algorithm, _)) = self.manifest else {
+            return Ok(());
+        };
+        if !self.mismatched.is_empty() {
+            return Err(anyhow!(
+                "{} of the restored files differ from the {} checksums in the archive's manifest: {}",
+                self.mismatched.len(),
+                algorithm.name(),
+                self.mismatched
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        println!(
+            "All {} match the {} checksums in the archive's manifest",
+            self.checked,
+            algorithm.name()
+        );
+        Ok(())
+    }
+}
+
+/// A zip time, which is local time, as a point in time.
+fn system_time(time: zip::DateTime) -> Option<std::time::SystemTime> {
+    let local = chrono::Local
+        .with_ymd_and_hms(
+            time.year().into(),
+            time.month().into(),
+            time.day().into(),
+            time.hour().into(),
+            time.minute().into(),
+            time.second().into(),
+        )
+        .earliest()?;
+    Some(local.into())
+}
+
+fn local_time(time: chrono::NaiveDateTime) -> Option<std::time::SystemTime> {
+    Some(chrono::Local.from_local_datetime(&time).earliest()?.into())
+}
+
+/// Bytes fetched at once when reading an archive where it lies, from a
+/// multiple of this on
+const BLOCK: u64 = 256 << 10;
+/// Blocks kept, for reads that go back a little, like an entry's header
+/// after the directory
+const CACHED_BLOCKS: usize = 4;
+
+/// A file on a target read through ranged downloads, a block at a time, for
+/// [`pick`] to read a few entries out of an archive.
+struct Ranged<'a> {
+    remote: &'a dyn StorageBackend,
+    path: &'a str,
+    size: u64,
+    position: u64,
+    /// The blocks fetched last, by their number, oldest first
+    blocks: Vec<(u64, Vec<u8>)>,
+    /// Bytes downloaded so far
+    fetched: u64,
+}
+
+impl<'a> Ranged<'a> {
+    fn new(remote: &'a dyn StorageBackend, path: &'a str, size: u64) -> Self {
+        Ranged {
+            remote,
+            path,
+            size,
+            position: 0,
+            blocks: Vec::new(),
+            fetched: 0,
+        }
+    }
+
+    fn block(&mut self, number: u64) -> std::io::Result<&[u8]> {
+        if let Some(i) = self.blocks.iter().position(|(n, _)| *n == number) {
+            let block = self.blocks.remove(i);
+            self.blocks.push(block);
+        } else {
+            let start = number * BLOCK;
+            let len = BLOCK.min(self.size - start);
+            let bytes = self
+                .remote
+                .read_range(self.path, start, len)
+                .map_err(|e| std::io::Error::other(format!("{e:#}")))?;
+            if bytes.len() as u64 != len {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.fetched += len;
+            if self.blocks.len() == CACHED_BLOCKS {
+                self.blocks.remove(0);
+            }
+            self.blocks.push((number, bytes));
+        }
+        Ok(&self.blocks.last().unwrap().1)
+    }
+}
+
+impl Read for Ranged<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let offset = (self.position % BLOCK) as usize;
+        let block = self.block(self.position / BLOCK)?;
+        let n = (block.len() - offset).min(buf.len());
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Ranged<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,328 @@
+//! The run log: one JSON object per `backup` run, appended to a JSON Lines
+//! file so other tools can read the backup history without parsing the
+//! messages meant for people.
+
+use crate::Config;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize)]
+pub struct Run {
+    pub start: String,
+    pub end: String,
+    /// `"ok"`, `"deferred"`, `"partial"` or `"failed"`, like the exit status
+    pub result: &'static str,
+    pub jobs: Vec<JobRun>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobRun {
+    pub job: String,
+    /// `"ok"`, `"failed"`, `"timed_out"`, `"skipped"`, `"not_run"`, `"queued"` or `"deferred"`
+    pub result: &'static str,
+    pub start: String,
+    pub end: String,
+    /// When the archive was made, for one uploaded from the queue
+    pub queued: Option<String>,
+    /// Files archived and their total size, once archiving finished
+    pub files: Option<u64>,
+    pub bytes: Option<u64>,
+    /// Size of the zip
+    pub archive_bytes: Option<u64>,
+    /// Files left out of the archive or archived while changing, by why
+    pub skipped: Option<Skipped>,
+    /// Where the archive was uploaded to, as `target:path`
+    pub uploaded: Vec<String>,
+    /// What failed uploads left on the targets, and whether it was removed
+    pub partial_uploads: Vec<PartialUpload>,
+    /// SHA-256 of the zip, for audits to compare the uploads against
+    pub sha256: Option<String>,
+    pub error: Option<String>,
+    /// What looked wrong about an archive that was uploaded anyway
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+/// Counts of the files an archive is missing or may have wrong, as in
+/// [`crate::archive::ArchiveReport`].
+#[derive(Debug, Serialize)]
+pub struct Skipped {
+    pub excluded: usize,
+    pub too_large: usize,
+    pub unreadable: usize,
+    pub changed: usize,
+}
+
+/// A truncated file a failed upload left behind.
+#[derive(Debug, Serialize)]
+pub struct PartialUpload {
+    /// As `target:path`
+    pub location: String,
+    /// Its size, if the target listed it
+    pub bytes: Option<u64>,
+    pub removed: bool,
+    pub error: Option<String>,
+}
+
+/// An integrity audit, logged alongside the runs.
+#[derive(Debug, Serialize)]
+pub struct Audit {
+    /// When it ran; also what tells audits from runs
+    pub audit: String,
+    /// `"ok"` or `"alert"`
+    pub result: &'static str,
+    /// The upload that was downloaded and compared, as `target:path`
+    pub checked: Option<String>,
+    pub problems: Vec<String>,
+}
+
+/// Archives `prune` deleted, logged alongside the runs.
+#[derive(Debug, Serialize)]
+pub struct Prune {
+    /// When it ran; also what tells prunes from runs
+    pub prune: String,
+    pub job: String,
+    pub target: String,
+    /// How many archives it kept
+    pub kept: usize,
+    /// The archives it deleted, as paths
+    pub deleted: Vec<String>,
+}
+
+/// An archive a run uploaded, as the log recorded it.
+#[derive(Debug)]
+pub struct Upload {
+    pub job: String,
+    pub target: String,
+    pub path: String,
+    pub sha256: Option<String>,
+}
+
+impl Upload {
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.target, self.path)
+    }
+}
+
+impl JobRun {
+    pub fn new(job: &str) -> JobRun {
+        let now = now();
+        JobRun {
+            job: job.to_string(),
+            result: "not_run",
+            start: now.clone(),
+            end: now,
+            files: None,
+            bytes: None,
+            queued: None,
+            archive_bytes: None,
+            skipped: None,
+            uploaded: Vec::new(),
+            partial_uploads: Vec::new(),
+            sha256: None,
+            error: None,
+            warning: None,
+        }
+    }
+}
+
+/// The configured `run_log`, or `runs.jsonl` in the state directory.
+pub fn path(config: &Config) -> Result<PathBuf> {
+    match &config.run_log {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(crate::paths::state_dir()?.join("runs.jsonl")),
+    }
+}
+
+/// The current time in RFC 3339, as the log records it.
+pub fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Appends `run` as one line to `path`, creating the file and its folder if needed.
+pub fn append(path: &Path, run: &impl Serialize) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_string(run)?;
+    line.push('\n');
+    // One write per line, so concurrent runs can't interleave inside one.
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Could not append to {}", path.display()))
+}
+
+/// The runs in the log at `path`, oldest first. A missing log has none.
+pub fn read_runs(path: &Path) -> Result<Vec<Value>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(parse_runs(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Could not read {}", path.display())),
+    }
+}
+
+/// Every line in the log at `path` that parses, runs, audits and prunes,
+/// oldest first.
+pub fn read_records(path: &Path) -> Result<Vec<Value>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text
+            .lines()
+            .filter_map(|l| serde_json::from_str::<Value>(l).ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Could not read {}", path.display())),
+    }
+}
+
+/// The runs in the lines of a log, skipping audits and lines that don't parse.
+pub fn parse_runs(text: &str) -> Vec<Value> {
+    text.lines()
+        .filter_map(|l| serde_json::from_str::<Value>(l).ok())
+        .filter(|x| x.get("jobs").is_some())
+        .collect()
+}
+
+/// Every upload of a successful job in the log at `path`, oldest first.
+pub fn recorded_uploads(path: &Path) -> Result<Vec<Upload>> {
+    Ok(uploads(&read_runs(path)?))
+}
+
+/// Every upload of a successful job in `runs`.
+pub fn uploads(runs: &[Value]) -> Vec<Upload> {
+    let mut uploads = Vec::new();
+    for run in runs {
+        for job in run["jobs"].as_array().into_iter().flatten() {
+            let (Some(name), Some("ok")) = (job["job"].as_str(), job["result"].as_str()) else {
+                continue;
+            };
+            for location in job["uploaded"].as_array().into_iter().flatten() {
+                let Some((target, path)) = location.as_str().and_then(|x| x.split_once(':')) else {
+                    continue;
+                };
+                uploads.push(Upload {
+                    job: name.to_string(),
+                    target: target.to_string(),
+                    path: path.to_string(),
+                    sha256: job["sha256"].as_str().map(String::from),
+                });
+            }
+        }
+    }
+    uploads
+}
+
+/// When each job last succeeded, by job name, from the log at `path`.
+pub fn newest_successes(path: &Path) -> Result<HashMap<String, DateTime<Utc>>> {
+    Ok(successes(&read_runs(path)?))
+}
+
+/// When each job in `runs` last succeeded, by job name.
+pub fn successes(runs: &[Value]) -> HashMap<String, DateTime<Utc>> {
+    let mut newest = HashMap::new();
+    for run in runs {
+        for job in run["jobs"].as_array().into_iter().flatten() {
+            let (Some(name), Some("ok"), Some(end)) = (
+                job["job"].as_str(),
+                job["result"].as_str(),
+                job["end"].as_str(),
+            ) else {
+                continue;
+            };
+            // A queued archive is as old as it was when it was made.
+            let end = job["queued"].as_str().unwrap_or(end);
+            let Ok(end) = DateTime::parse_from_rfc3339(end) else {
+                continue;
+            };
+            let end = end.with_timezone(&Utc);
+            let time = newest.entry(name.to_string()).or_insert(end);
+            *time = (*time).max(end);
+        }
+    }
+    newest
+}
+
+/// When a job succeeded, and how big its archive was
+pub type Success = (DateTime<Utc>, Option<u64>);
+
+/// How many of a job's last archives an archive's size is held against
+const RECENT: usize = 5;
+
+/// A warning if `size` is more than `percent` percent away from the average
+/// size of the last archives in `successes`, of which there must be a few.
+pub fn size_anomaly(successes: &[Success], size: u64, percent: u32) -> Option<String> {
+    let recent = successes
+        .iter()
+        .rev()
+        .filter_map(|(_, size)| *size)
+        .take(RECENT)
+        .collect::<Vec<_>>();
+    if percent == 0 || recent.len() < 3 {
+        return None;
+    }
+    let average = recent.iter().sum::<u64>() as f64 / recent.len() as f64;
+    if average == 0.0 {
+        return None;
+    }
+    let change = (size as f64 / average - 1.0) * 100.0;
+    if change.abs() <= percent as f64 {
+        return None;
+    }
+    let (way, hint) = if change < 0.0 {
+        (
+            "smaller",
+            "is the source path right and everything mounted?",
+        )
+    } else {
+        ("larger", "is something like a log growing without bounds?")
+    };
+    Some(format!(
+        "the archive is {:.0}% {way} than the average of the last {} ({}); {hint}",
+        change.abs(),
+        recent.len(),
+        crate::format_bytes(average as u64)
+    ))
+}
+
+/// Every successful run of each job in `runs`, oldest first, by job name.
+pub fn job_successes(runs: &[Value]) -> BTreeMap<String, Vec<Success>> {
+    let mut successes = BTreeMap::<_, Vec<_>>::new();
+    for run in runs {
+        for job in run["jobs"].as_array().into_iter().flatten() {
+            let (Some(name), Some("ok"), Some(end)) = (
+                job["job"].as_str(),
+                job["result"].as_str(),
+                job["end"].as_str(),
+            ) else {
+                continue;
+            };
+            let end = job["queued"].as_str().unwrap_or(end);
+            let Ok(end) = DateTime::parse_from_rfc3339(end) else {
+                continue;
+            };
+            successes
+                .entry(name.to_string())
+                .or_default()
+                .push((end.with_timezone(&Utc), job["archive_bytes"].as_u64()));
+        }
+    }
+    for runs in successes.values_mut() {
+        runs.sort_by_key(|(time, _)| *time);
+    }
+    successes
+}
+
+/// The name of every job in `runs`, however it went.
+pub fn job_names(runs: &[Value]) -> BTreeSet<String> {
+    runs.iter()
+        .flat_map(|run| run["jobs"].as_array().into_iter().flatten())
+        .filter_map(|job| job["job"].as_str().map(String::from))
+        .collect()
+}
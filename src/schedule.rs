@@ -0,0 +1,305 @@
+//! Job schedules, written in config as `"hourly"`, `"hourly :15"`,
+//! `"daily 03:00"` or `"weekly sun 03:00"`, and the windows jobs may or may
+//! not run in, like `"01:00-06:00"`.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Timelike};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    Hourly {
+        minute: u8,
+    },
+    Daily {
+        hour: u8,
+        minute: u8,
+    },
+    Weekly {
+        weekday: Weekday,
+        hour: u8,
+        minute: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+const WEEKDAYS: [(Weekday, &str); 7] = [
+    (Weekday::Mon, "mon"),
+    (Weekday::Tue, "tue"),
+    (Weekday::Wed, "wed"),
+    (Weekday::Thu, "thu"),
+    (Weekday::Fri, "fri"),
+    (Weekday::Sat, "sat"),
+    (Weekday::Sun, "sun"),
+];
+
+impl Weekday {
+    pub fn short_name(self) -> &'static str {
+        WEEKDAYS.iter().find(|(d, _)| *d == self).unwrap().1
+    }
+
+    pub fn long_name(self) -> &'static str {
+        match self {
+            Weekday::Mon => "Monday",
+            Weekday::Tue => "Tuesday",
+            Weekday::Wed => "Wednesday",
+            Weekday::Thu => "Thursday",
+            Weekday::Fri => "Friday",
+            Weekday::Sat => "Saturday",
+            Weekday::Sun => "Sunday",
+        }
+    }
+
+    /// The day of the week `t` falls on.
+    pub fn of(t: impl Datelike) -> Weekday {
+        WEEKDAYS[t.weekday().num_days_from_monday() as usize].0
+    }
+
+    /// 0 for Sunday through 6 for Saturday, as launchd and cron count.
+    pub fn days_from_sunday(self) -> u8 {
+        let from_monday = WEEKDAYS.iter().position(|(d, _)| *d == self).unwrap() as u8;
+        (from_monday + 1) % 7
+    }
+}
+
+fn parse_time(s: &str) -> Result<(u8, u8)> {
+    let (h, m) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected a time like 03:00, got {s}"))?;
+    let hour = if h.is_empty() { 0 } else { h.parse::<u8>()? };
+    let minute = m.parse::<u8>()?;
+    if hour > 23 || minute > 59 {
+        return Err(anyhow!("{s} is not a valid time of day"));
+    }
+    Ok((hour, minute))
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Schedule> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let schedule = match words[..] {
+            ["hourly"] => Schedule::Hourly { minute: 0 },
+            ["hourly", at] => Schedule::Hourly {
+                minute: parse_time(at)?.1,
+            },
+            ["daily", at] => {
+                let (hour, minute) = parse_time(at)?;
+                Schedule::Daily { hour, minute }
+            }
+            ["weekly", day, at] => {
+                let weekday = day.parse()?;
+                let (hour, minute) = parse_time(at)?;
+                Schedule::Weekly {
+                    weekday,
+                    hour,
+                    minute,
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                "invalid schedule {s:?}; use \"hourly\", \"daily HH:MM\" or \"weekly DAY HH:MM\""
+            ))
+            }
+        };
+        Ok(schedule)
+    }
+}
+
+impl std::str::FromStr for Weekday {
+    type Err = anyhow::Error;
+
+    /// `"sun"`, `"Sunday"` and the like.
+    fn from_str(day: &str) -> Result<Weekday> {
+        Ok(WEEKDAYS
+            .iter()
+            .find(|(_, name)| day.to_ascii_lowercase().starts_with(name))
+            .ok_or_else(|| anyhow!("{day} is not a weekday"))?
+            .0)
+    }
+}
+
+impl TryFrom<String> for Weekday {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Weekday> {
+        s.parse()
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Schedule> {
+        s.parse()
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule fires at the start of minute `t`.
+    fn matches(&self, t: NaiveDateTime) -> bool {
+        let minute_matches =
+            |hour: u8, minute: u8| t.hour() == u32::from(hour) && t.minute() == u32::from(minute);
+        match *self {
+            Schedule::Hourly { minute } => t.minute() == u32::from(minute),
+            Schedule::Daily { hour, minute } => minute_matches(hour, minute),
+            Schedule::Weekly {
+                weekday,
+                hour,
+                minute,
+            } => {
+                t.weekday().num_days_from_sunday() == u32::from(weekday.days_from_sunday())
+                    && minute_matches(hour, minute)
+            }
+        }
+    }
+
+    /// The first time after `t` the schedule fires, in local time like the
+    /// system schedulers use. Times a DST change skips are left out.
+    pub fn next_after(&self, t: DateTime<Local>) -> DateTime<Local> {
+        let mut candidate = t
+            .naive_local()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        // A week and a bit covers every schedule, whatever DST does.
+        for _ in 0..8 * 24 * 60 {
+            candidate += chrono::Duration::minutes(1);
+            if self.matches(candidate) {
+                if let Some(local) = Local.from_local_datetime(&candidate).earliest() {
+                    return local;
+                }
+            }
+        }
+        unreachable!("every schedule fires at least once a week")
+    }
+
+    /// The schedule as a systemd `OnCalendar=` expression.
+    pub fn on_calendar(&self) -> String {
+        match *self {
+            Schedule::Hourly { minute } => format!("*-*-* *:{minute:02}:00"),
+            Schedule::Daily { hour, minute } => format!("*-*-* {hour:02}:{minute:02}:00"),
+            Schedule::Weekly {
+                weekday,
+                hour,
+                minute,
+            } => {
+                let mut day = weekday.short_name().to_string();
+                day[..1].make_ascii_uppercase();
+                format!("{day} *-*-* {hour:02}:{minute:02}:00")
+            }
+        }
+    }
+}
+
+/// A stretch of every day, or of one weekday, during which jobs may run or,
+/// as a blackout, may not: `"01:00-06:00"`, `"22:00-06:00"` across midnight,
+/// or `"sat 08:00-20:00"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    weekday: Option<Weekday>,
+    /// Minutes into the day it starts and ends; ending no later than it
+    /// starts means at that time on the next day
+    start: u16,
+    end: u16,
+}
+
+impl std::str::FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Window> {
+        let invalid =
+            || anyhow!("invalid window {s:?}; use \"HH:MM-HH:MM\" or \"DAY HH:MM-HH:MM\"");
+        let (weekday, times) = match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [times] => (None, times),
+            [day, times] => (Some(day.parse()?), times),
+            _ => return Err(invalid()),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let minutes = |at: &str| parse_time(at).map(|(h, m)| u16::from(h) * 60 + u16::from(m));
+        Ok(Window {
+            weekday,
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Window> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(weekday) = self.weekday {
+            write!(f, "{} ", weekday.short_name())?;
+        }
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl Window {
+    /// Whether the window holds the minute `t`.
+    pub fn contains(&self, t: NaiveDateTime) -> bool {
+        let minute = (t.hour() * 60 + t.minute()) as u16;
+        let on = |day: NaiveDateTime| self.weekday.is_none_or(|w| w == Weekday::of(day));
+        if self.start < self.end {
+            on(t) && (self.start..self.end).contains(&minute)
+        } else {
+            (on(t) && minute >= self.start)
+                || (on(t - chrono::Duration::days(1)) && minute < self.end)
+        }
+    }
+}
+
+/// Whether a job may run at `t`: inside one of its `windows`, if it has
+/// any, and in none of its `blackout` windows.
+pub fn allowed(windows: &[Window], blackout: &[Window], t: NaiveDateTime) -> bool {
+    (windows.is_empty() || windows.iter().any(|w| w.contains(t)))
+        && !blackout.iter().any(|w| w.contains(t))
+}
+
+/// The first minute from `t` on at which a job may run, within a week and a
+/// bit, if there is one.
+pub fn next_allowed(
+    windows: &[Window],
+    blackout: &[Window],
+    t: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let mut candidate = t.naive_local().with_second(0)?.with_nanosecond(0)?;
+    for _ in 0..8 * 24 * 60 {
+        if allowed(windows, blackout, candidate) {
+            if let Some(local) = Local.from_local_datetime(&candidate).earliest() {
+                return Some(local.max(t));
+            }
+        }
+        candidate += chrono::Duration::minutes(1);
+    }
+    None
+}
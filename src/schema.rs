@@ -0,0 +1,355 @@
+//! A JSON Schema of the config file, for `config schema` and editors, and the
+//! check that catches misspelt settings with it.
+//!
+//! serde's `deny_unknown_fields` can't be combined with the flattened
+//! connection settings, so unknown keys are looked up in the schema instead,
+//! which keeps the two from drifting apart.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+
+/// Settings of one NAS, shared by the top level and `targets`.
+fn connection() -> Map<String, Value> {
+    let transport = json!({
+        "description": "How archives reach this NAS",
+        "enum": ["api", "webdav", "sftp", "local"],
+        "default": "api",
+    });
+    let Value::Object(mut map) = json!({
+        "domain": {"type": "string", "description": "Host name of the NAS; not needed for the local transport"},
+        "port": {"type": "integer", "minimum": 1, "maximum": 65535},
+        "usr": {"type": "string", "description": "Account to log in as"},
+        "pwd": {"type": "string", "description": "Password; may be left out in favour of pwd_file or a systemd credential"},
+        "pwd_file": {"type": "string", "description": "File holding the password"},
+        "https": {"type": "boolean", "default": true, "description": "false to talk plain HTTP"},
+        "transport": transport,
+        "type": transport,
+        "identity_file": {"type": "string", "description": "SSH key for the sftp transport"},
+        "adaptive_chunks": {"type": "boolean", "default": true, "description": "Size the sftp transport's requests by the measured throughput; false leaves it to sftp"},
+        "path": {"type": "string", "description": "Folder of the local transport, whose subfolders are its shares"},
+        "shares": {"type": "array", "items": {"type": "string"}, "description": "Shares to use without listing them, for accounts that may not"},
+        "pin_sha256": {"type": "array", "items": {"type": "string"}, "description": "Base64 SHA-256 hashes of the certificate keys to trust, for self-signed certificates"},
+        "user_agent": {"type": "string", "description": "Sent instead of synology_backuper/<version>"},
+        "headers": {"type": "object", "additionalProperties": {"type": "string"}, "description": "Further headers sent with every request, e.g. for a proxy"},
+        "resolve": {"type": "object", "additionalProperties": {"type": "string"}, "description": "IP addresses to use for host names instead of DNS, e.g. {\"nas.example.com\": \"192.168.1.10\"}"},
+    }) else {
+        unreachable!()
+    };
+    // Apart, as json! can't take more at once.
+    map.insert("wake_mac".into(), json!({"type": "string", "description": "MAC address to send a Wake-on-LAN packet to when the NAS doesn't answer, like \"00:11:32:aa:bb:cc\""}));
+    map.insert("wake_address".into(), json!({"type": "string", "default": "255.255.255.255:9", "description": "Where the Wake-on-LAN packet goes, like \"192.168.1.255:9\""}));
+    map.insert("wake_timeout".into(), json!({"type": "string", "default": "5m", "description": "How long the NAS may take to answer once woken"}));
+    map.insert("task_timeout".into(), json!({"type": "string", "default": "1h", "description": "How long a FileStation background task, like a copy or a delete, may run before it is stopped and taken for failed"}));
+    map.insert("shutdown_after_success".into(), json!({"type": "boolean", "default": false, "description": "Shut the NAS down after every run that used it and succeeded; the account must be an administrator"}));
+    map.insert("shutdown_after_wake".into(), json!({"type": "boolean", "default": false, "description": "Shut the NAS down again after a run that had to wake it; the account must be an administrator"}));
+    map
+}
+
+fn object(properties: Map<String, Value>, required: &[&str]) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+pub fn schema() -> Value {
+    let duration = json!({"type": "string", "description": "Such as \"90m\" or \"1h30m\""});
+    let mut job = json!({
+        "name": {"type": "string"},
+        "filename": {"type": "string", "description": "File or directory to archive"},
+        "share_name": {"type": "string"},
+        "schedule": {"type": "string", "description": "\"hourly\", \"hourly :15\", \"daily 03:00\" or \"weekly sun 03:00\""},
+        "unicode_names": {"enum": ["raw", "nfc"], "default": "raw"},
+        "changed_file_retries": {"type": "integer", "minimum": 0, "default": 0},
+        "max_depth": {"type": "integer", "minimum": 0},
+        "parallelism": {"type": "integer", "minimum": 1, "default": 1},
+        "max_open_files": {"type": "integer", "minimum": 1},
+        "one_file_system": {"type": "boolean", "default": false},
+        "exclude": {"type": "array", "items": {"type": "string"}, "description": "Globs of files and directories to leave out, like \"*.tmp\" or \"cache/**\""},
+        "max_file_size": {"type": "string", "description": "Leave out files larger than this, e.g. \"4GiB\""},
+        "compression": {"type": "string", "pattern": "^(stored|deflate(:[0-9])?|bzip2(:[1-9])?|zstd(:[0-9]+)?)$", "default": "deflate", "description": "How files are compressed, like deflate:9 or zstd:3; only deflate opens in every unzip"},
+        "store_extensions": {"type": "array", "items": {"type": "string"}, "description": "Extensions of files stored without compression; replaces the built-in list of compressed formats"},
+        "nice": {"type": "integer", "minimum": 0, "maximum": 19},
+        "ionice": {"type": "string", "description": "\"idle\" or \"best-effort 0\" to \"best-effort 7\""},
+        "upload_rate_limit": {"type": "string", "description": "Bytes per second, such as \"2MiB\""},
+        "max_duration": duration,
+        "targets": {"type": "array", "items": {"type": "string"}},
+        "usr": {"type": "string", "description": "Account to log in to the targets as instead of theirs; the job gets its own session on each, as target@usr"},
+        "pwd": {"type": "string", "description": "Password of the job's usr"},
+        "pwd_file": {"type": "string", "description": "File holding the password of the job's usr"},
+        "mirror": {"type": "boolean", "default": false},
+        "copies": {"type": "array", "items": {"type": "string"}},
+        "keep_last": {"type": "integer", "minimum": 1},
+        "keep_within": duration,
+        "keep_daily": {"type": "integer", "minimum": 0},
+        "keep_weekly": {"type": "integer", "minimum": 0},
+        "keep_monthly": {"type": "integer", "minimum": 0},
+        "keep_yearly": {"type": "integer", "minimum": 0},
+        "max_total_size": {"type": "string", "description": "prune deletes the oldest archives until the rest fit, e.g. \"500GB\""},
+        "keep_tagged": {"type": "boolean", "default": false, "description": "prune keeps every archive made with --tag"},
+        "verify": {"type": "boolean", "default": false},
+        "write_only": {"type": "boolean", "default": false, "description": "Only upload, never list, delete or replace anything on the targets"},
+        "queue": {"type": "boolean", "default": true, "description": "Keep archives that reached no target for a later upload"},
+        "size_anomaly": {"type": "integer", "minimum": 0, "default": 40, "description": "Warn when an archive is this many percent smaller or larger than the average of the job's recent ones; 0 never warns"},
+        "after": {"type": "array", "items": {"type": "string"}, "description": "Jobs that must succeed before this one runs"},
+        "on_success": {"type": "string", "description": "Shell command run after the job uploaded its archive"},
+        "on_failure": {"type": "string", "description": "Shell command run after the job failed, timed out or was skipped"},
+    });
+    // Apart, as json! can't take more at once.
+    job["manifest"] = json!({"type": "boolean", "default": false, "description": "End the archive with a manifest of the checksums of its files"});
+    job["checksum"] = json!({"enum": ["blake3", "sha256"], "default": "blake3", "description": "The algorithm of the manifest"});
+    job["min_files"] = json!({"type": "integer", "minimum": 0, "description": "Fail rather than upload an archive of fewer files than this"});
+    job["min_size"] = json!({"type": "string", "description": "Fail rather than upload an archive of files smaller than this together, e.g. \"1MiB\""});
+    job["require_marker_file"] = json!({"type": "string", "description": "A file below filename that must be there, or the job fails before archiving, e.g. a sentinel on a disk that must be mounted"});
+    job["preconditions"] = json!({"type": "array", "description": "What must hold before the job archives, or it fails", "items": {"type": "object", "properties": {
+        "mountpoint": {"type": "string", "description": "A path that must be a mount point"},
+        "exists": {"type": "string", "description": "A file or folder that must exist"},
+        "command": {"type": "string", "description": "A shell command that must succeed, like \"findmnt /data\""},
+    }}});
+    job["snapshot"] = json!({"type": "object", "description": "Archive from a read-only snapshot of the filesystem, taken before and released after, so the files are as they were at one moment", "properties": {
+        "type": {"enum": ["btrfs", "zfs", "lvm", "vss"]},
+        "mountpoint": {"type": "string", "description": "Where the filesystem is mounted, with filename below it: the btrfs subvolume, the ZFS dataset's mount point, or the drive for VSS, like \"C:\\\""},
+        "volume": {"type": "string", "description": "The ZFS dataset, found from mountpoint if left out, or the LVM logical volume, like \"/dev/vg0/home\""},
+        "size": {"type": "string", "default": "1G", "description": "Room for the changes made while the LVM snapshot exists, as lvcreate's --size"},
+    }, "required": ["type", "mountpoint"], "additionalProperties": false});
+    job["docker"] = json!({"type": "object", "description": "Docker containers and volumes to archive along with filename, recording the containers' images", "properties": {
+        "containers": {"type": "array", "items": {"type": "string"}, "description": "Containers whose volumes to archive"},
+        "volumes": {"type": "array", "items": {"type": "string"}, "description": "Further volumes, by name"},
+        "stop": {"enum": ["none", "pause", "stop"], "default": "none", "description": "Pause or stop the running containers while archiving, and bring them back after"},
+    }, "additionalProperties": false});
+    job["min_battery"] = json!({"type": "integer", "minimum": 0, "maximum": 100, "description": "Wait while the machine runs on its battery with less charge than this, in percent"});
+    job["skip_metered"] = json!({"type": "boolean", "default": false, "description": "Wait while the machine is online through a metered connection"});
+    let window = json!({"type": "string", "pattern": "^((?i)(mon|tue|wed|thu|fri|sat|sun)[a-z]* )?[0-9]{1,2}:[0-9]{2}-[0-9]{1,2}:[0-9]{2}$"});
+    job["windows"] = json!({"type": "array", "items": window, "description": "When the job may run, like [\"01:00-06:00\"] or [\"sat 08:00-20:00\"]; outside them it is deferred"});
+    job["blackout"] = json!({"type": "array", "items": window, "description": "When the job may not run, in the same form as windows"});
+    job["jitter"] = json!({"type": "string", "description": "Start up to this much later than the schedule says, at random, like \"15m\""});
+    job["busy_retries"] = json!({"type": "integer", "minimum": 0, "default": 3, "description": "How often an upload is tried again when the NAS says it is too busy (error 402)"});
+    job["busy_delay"] = json!({"type": "string", "default": "1m", "description": "How long to wait before the first of those, doubled for each next one"});
+    job["busy_reschedule"] = json!({"type": "string", "description": "When the NAS stays too busy, keep the archive in the queue instead of failing, for the daemon to upload this much later, like \"2h\""});
+    job["differential"] = json!({"type": "string", "pattern": "^(?i)(mon|tue|wed|thu|fri|sat|sun)", "description": "The weekday of the full archive, like \"sun\"; on the other days only the files changed since it are archived, and restore layers them on it"});
+    job["synthetic_full"] = json!({"type": "integer", "minimum": 0, "description": "With differential, make a new full archive on the NAS after this many differentials, and on differential's weekday: the last full archive is copied there and only what changed since is uploaded"});
+    job["dedup_contents"] = json!({"type": "boolean", "default": false, "description": "Store files with the same contents once and list the others in the manifest, which this turns on; restore copies them again"});
+    job["xattrs"] = json!({"type": "boolean", "default": false, "description": "Keep extended attributes and ACLs of the files and folders in the archive, and have restore set them again; on Windows the read-only, hidden and system attributes"});
+    job["ads_warnings"] = json!({"type": "boolean", "default": false, "description": "On Windows, list the files with alternate data streams, which the archive can't keep, in the run summary"});
+    job["dedup_hardlinks"] = json!({"type": "boolean", "default": false, "description": "Store a file with several hard links in the tree once, and have restore link the others to it"});
+    let worm = json!({"type": "boolean", "default": false, "description": "The shares keep their files write-once, so nothing there is replaced or deleted"});
+    let mut target = connection();
+    target.insert("name".into(), json!({"type": "string"}));
+    target.insert("share_name".into(), json!({"type": "string"}));
+    target.insert("fallback_share".into(), json!({"type": "string"}));
+    target.insert("worm".into(), worm.clone());
+
+    let mut top = connection();
+    top.extend(
+        json!({
+            "share_name": {"type": "string", "description": "Default share for jobs that don't name their own"},
+            "fallback_share": {"type": "string"},
+            "worm": worm,
+            "filename": {"type": "string", "description": "Shorthand for a single job named default"},
+            "targets": {"type": "array", "items": {"$ref": "#/$defs/target"}},
+            "jobs": {"type": "array", "items": {"$ref": "#/$defs/job"}},
+            "audit_interval": {"type": "string", "description": "How often the daemon audits the uploads, e.g. \"24h\""},
+            "run_log": {"type": "string", "description": "JSON Lines file each run is appended to"},
+            "fleet": {
+                "type": "object",
+                "description": "A folder on a target where the machines backing up to it share their run logs, for check --fleet and list --fleet",
+                "properties": {
+                    "folder": {"type": "string", "description": "Like \"/backup/synology_backuper\""},
+                    "target": {"type": "string", "default": "primary"},
+                    "host": {"type": "string", "description": "This machine's name there; the host name by default"},
+                },
+                "required": ["folder"],
+                "additionalProperties": false,
+            },
+            "stop_on_error": {"type": "boolean", "default": false, "description": "Leave the remaining jobs of a run alone once one has failed"},
+            "stop_on_auth_failure": {"type": "boolean", "default": false, "description": "Leave the remaining jobs of a run alone once a target has refused the login"},
+            "reuse_session": {"type": "boolean", "default": true, "description": "Log in to each target once for all the jobs of a run, rather than once per job"},
+            "profiles": {
+                "type": "object",
+                "description": "Overrides laid over the rest of the config by --profile",
+                "additionalProperties": {"type": "object"},
+            },
+        })
+        .as_object()
+        .unwrap()
+        .clone(),
+    );
+    top.insert(
+        "self_backup".into(),
+        json!({
+            "type": "object",
+            "description": "A folder on a target each run uploads an encrypted copy of the config, run log and catalog to, for open-recovery",
+            "properties": {
+                "folder": {"type": "string", "description": "Like \"/backup/synology_backuper\""},
+                "target": {"type": "string", "default": "primary"},
+                "passphrase_file": {"type": "string", "description": "File holding a passphrase to encrypt with instead of the current key, so the keyring can go along"},
+            },
+            "required": ["folder"],
+            "additionalProperties": false,
+        }),
+    );
+    let mut schema = object(top, &[]);
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["title"] = json!("synology_backuper config");
+    schema["$defs"] = json!({
+        "target": object(target, &["name"]),
+        "job": object(job.as_object().unwrap().clone(), &["name", "filename"]),
+    });
+    schema
+}
+
+/// Fails on the first key the schema doesn't know, suggesting the closest one
+/// it does. Profiles are checked like the top level, as that is what they override.
+pub fn check_fields(config: &Value) -> Result<()> {
+    let schema = schema();
+    check(&schema, &schema, config, "")?;
+    if let Some(profiles) = config.get("profiles").and_then(Value::as_object) {
+        for (name, profile) in profiles {
+            check(&schema, &schema, profile, &format!("profiles.{name}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn check(root: &Value, schema: &Value, value: &Value, at: &str) -> Result<()> {
+    let schema = match schema.get("$ref").and_then(Value::as_str) {
+        Some(pointer) => root
+            .pointer(pointer.trim_start_matches('#'))
+            .expect("the schema only refers to itself"),
+        None => schema,
+    };
+    match value {
+        Value::Object(map) => {
+            let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+                return Ok(());
+            };
+            for (key, value) in map {
+                let path = match at {
+                    "" => key.clone(),
+                    at => format!("{at}.{key}"),
+                };
+                match properties.get(key) {
+                    Some(schema) => check(root, schema, value, &path)?,
+                    None => {
+                        let place = match at {
+                            "" => "at the top level".to_string(),
+                            at => format!("in {at}"),
+                        };
+                        let hint = closest(key, properties.keys())
+                            .map(|x| format!("; did you mean `{x}`?"))
+                            .unwrap_or_default();
+                        return Err(anyhow!("Unknown setting `{key}` {place}{hint}"));
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(root, schema, item, &format!("{at}[{i}]"))?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The known key a typo most likely meant, if any is close enough.
+fn closest<'a>(key: &str, known: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    known
+        .map(|x| (edit_distance(key, x), x))
+        .filter(|(d, x)| *d <= 2.max(x.len() / 4))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, x)| x.as_str())
+}
+
+/// Levenshtein distance, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Connection, Job};
+    use serde::de::{self, Deserialize, Visitor};
+
+    /// Fails any deserialization of a struct with the names of its fields,
+    /// aliases included, as serde passes them to `deserialize_struct`.
+    struct Fields;
+
+    impl<'de> de::Deserializer<'de> for Fields {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom(""))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom(fields.join(" ")))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    fn fields<'de, T: Deserialize<'de>>() -> Vec<String> {
+        let Err(e) = T::deserialize(Fields) else {
+            unreachable!()
+        };
+        e.to_string().split(' ').map(str::to_string).collect()
+    }
+
+    fn missing(fields: &[String], schema: &Value) -> Vec<String> {
+        let properties = schema["properties"].as_object().unwrap();
+        fields
+            .iter()
+            .filter(|x| !properties.contains_key(*x))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn schema_has_every_field_the_config_reads() {
+        let schema = schema();
+        let connection = fields::<Connection>();
+        assert!(connection.contains(&"domain".to_string()));
+        assert_eq!(missing(&connection, &schema), Vec::<String>::new());
+        assert_eq!(
+            missing(&connection, &schema["$defs"]["target"]),
+            Vec::<String>::new()
+        );
+        // `Target` flattens `Connection`, which hides its own fields from
+        // serde, so those are named here.
+        let target = ["name", "share_name", "fallback_share", "worm"].map(String::from);
+        assert_eq!(
+            missing(&target, &schema["$defs"]["target"]),
+            Vec::<String>::new()
+        );
+        let job = fields::<Job>();
+        assert!(job.contains(&"filename".to_string()));
+        assert_eq!(missing(&job, &schema["$defs"]["job"]), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,303 @@
+//! Uploading over SFTP, for NAS where only SSH is open.
+//!
+//! This drives the system's OpenSSH `sftp` in batch mode, so it logs in with
+//! the user's SSH keys and agent and honours `~/.ssh/config`. DSM shows an SFTP
+//! user its shares as top-level folders, just like FileStation does.
+//!
+//! sftp sends a file as a pipeline of fixed-size requests. Unless the target
+//! turns off `adaptive_chunks`, their size starts small and follows the
+//! throughput of each upload, so a fast LAN gets large requests and a slow or
+//! flaky uplink small ones.
+
+use crate::backend::StorageBackend;
+use crate::config::Connection;
+use crate::limits::{self, ByteRate};
+use crate::{RemoteFile, SharedFolder};
+use anyhow::{anyhow, Context, Result};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Bounds of the request size passed to `sftp -B`; OpenSSH takes at most 256 KiB.
+const MIN_CHUNK: u64 = 16 * 1024;
+const MAX_CHUNK: u64 = 256 * 1024;
+/// How long one request should take to send at the measured throughput.
+const CHUNK_TIME: Duration = Duration::from_millis(20);
+/// Transfers smaller than this take too little time to measure.
+const MIN_SAMPLE: u64 = 1024 * 1024;
+
+pub struct Sftp {
+    destination: String,
+    port: u16,
+    identity_file: Option<String>,
+    /// The host, and the address `resolve` gives it
+    host: String,
+    address: Option<std::net::IpAddr>,
+    shares: Vec<SharedFolder>,
+    /// The request size the next session uses, unless sftp picks its own
+    chunk: Option<Cell<u64>>,
+}
+
+/// One line of `ls -l`.
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: Option<u64>,
+}
+
+impl Sftp {
+    /// Connects once to list the shares, which also checks the key is accepted.
+    pub fn open(nas: &Connection) -> Result<Sftp> {
+        let mut sftp = Sftp {
+            destination: format!("{}@{}", nas.usr, nas.url_host()),
+            port: nas.port,
+            identity_file: nas.identity_file.clone(),
+            host: nas.host().to_string(),
+            address: nas.resolve.get(nas.host()).copied(),
+            shares: Vec::new(),
+            chunk: nas
+                .adaptive_chunks
+                .unwrap_or(true)
+                .then(|| Cell::new(MIN_CHUNK)),
+        };
+        sftp.shares = sftp
+            .ls("/")?
+            .into_iter()
+            .filter(|e| e.is_dir)
+            .map(|e| SharedFolder {
+                path: format!("/{}", e.name),
+                name: e.name,
+                free_space: None,
+            })
+            .collect();
+        Ok(sftp)
+    }
+
+    /// Runs `commands` in one `sftp` session and returns what it printed.
+    /// The session is killed when `deadline` passes.
+    fn run(
+        &self,
+        commands: &[String],
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<String> {
+        let mut command = Command::new("sftp");
+        command.args(["-b", "-", "-q", "-o", "BatchMode=yes"]);
+        if let Some(chunk) = &self.chunk {
+            command.args(["-B", &chunk.get().to_string()]);
+        }
+        command.args(["-P", &self.port.to_string()]);
+        if let Some(ip) = self.address {
+            // Keep checking the host key under the name it was first seen by.
+            command.args(["-o", &format!("HostName={ip}")]);
+            command.args(["-o", &format!("HostKeyAlias={}", self.host)]);
+        }
+        if let Some(identity_file) = &self.identity_file {
+            command.args(["-i", identity_file]);
+        }
+        if let Some(rate) = rate_limit {
+            // sftp takes its limit in Kbit/s.
+            command.args(["-l", &(rate.0 * 8 / 1000).max(1).to_string()]);
+        }
+        let mut child = command
+            .arg(&self.destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Could not run sftp")?;
+        let mut stdin = child.stdin.take().unwrap();
+        for line in commands {
+            writeln!(stdin, "{line}")?;
+        }
+        drop(stdin);
+        // Drain the pipes while waiting, so a long listing can't fill them up.
+        let drain = |mut pipe: Box<dyn Read + Send>| {
+            std::thread::spawn(move || {
+                let mut out = String::new();
+                let _ = pipe.read_to_string(&mut out);
+                out
+            })
+        };
+        let stdout = drain(Box::new(child.stdout.take().unwrap()));
+        let stderr = drain(Box::new(child.stderr.take().unwrap()));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if limits::expired(deadline) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(limits::timed_out().into());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        };
+        let stdout = stdout.join().unwrap();
+        if !status.success() {
+            let stderr = stderr.join().unwrap();
+            return Err(anyhow!(
+                "sftp to {} failed: {}",
+                self.destination,
+                stderr.trim()
+            ));
+        }
+        Ok(stdout)
+    }
+
+    /// Runs an upload of `len` bytes and sizes the next session's requests
+    /// by how it went: for the throughput it reached, or at half the size
+    /// after a failure.
+    fn transfer(
+        &self,
+        command: String,
+        len: u64,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let result = self.run(&[command], rate_limit, deadline);
+        let Some(size) = &self.chunk else {
+            return result.map(|_| ());
+        };
+        let chunk = match &result {
+            Ok(_) if len < MIN_SAMPLE => return Ok(()),
+            Ok(_) => {
+                let rate = len as f64 / start.elapsed().as_secs_f64().max(1e-3);
+                (rate * CHUNK_TIME.as_secs_f64()) as u64
+            }
+            Err(_) => size.get() / 2,
+        };
+        size.set(chunk.clamp(MIN_CHUNK, MAX_CHUNK));
+        result.map(|_| ())
+    }
+
+    fn ls(&self, folder: &str) -> Result<Vec<Entry>> {
+        let out = self.run(&[format!("ls -l {}", quote(folder))], None, None)?;
+        Ok(out
+            .lines()
+            .filter(|l| !l.starts_with("sftp>"))
+            .filter_map(entry)
+            .filter(|e| e.name != "." && e.name != "..")
+            .collect())
+    }
+}
+
+impl StorageBackend for Sftp {
+    fn kind(&self) -> &'static str {
+        "SFTP"
+    }
+
+    fn shares(&self) -> &[SharedFolder] {
+        &self.shares
+    }
+
+    fn list(&self, folder: &str, recursive: bool) -> Result<Vec<RemoteFile>> {
+        let mut files = Vec::new();
+        let mut folders = vec![folder.trim_end_matches('/').to_string()];
+        while let Some(folder) = folders.pop() {
+            for entry in self.ls(&folder)? {
+                let path = format!("{folder}/{}", entry.name);
+                if entry.is_dir {
+                    if recursive {
+                        folders.push(path);
+                    }
+                    continue;
+                }
+                files.push(RemoteFile {
+                    name: entry.name,
+                    path,
+                    size: entry.size,
+                    mtime: None,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn upload(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let path = format!("{folder}/{name}");
+        eprintln!("Uploading file {} to {path} over SFTP", local.display());
+        let len = std::fs::metadata(local)?.len();
+        let local = local.to_str().ok_or_else(|| anyhow!("non-UTF-8 path"))?;
+        self.transfer(
+            format!("put {} {}", quote(local), quote(&path)),
+            len,
+            rate_limit,
+            deadline,
+        )
+    }
+
+    fn download(&self, path: &str, local: &std::path::Path) -> Result<()> {
+        let local = local.to_str().ok_or_else(|| anyhow!("non-UTF-8 path"))?;
+        self.run(
+            &[format!("get {} {}", quote(path), quote(local))],
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// sftp's `reget` carries on from the size of the local file.
+    fn resume_download(&self, path: &str, local: &std::path::Path, offset: u64) -> Result<()> {
+        if offset == 0 {
+            return self.download(path, local);
+        }
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(local)?
+            .set_len(offset)?;
+        let local = local.to_str().ok_or_else(|| anyhow!("non-UTF-8 path"))?;
+        self.run(
+            &[format!("reget {} {}", quote(path), quote(local))],
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn create_folder(&self, folder: &str) -> Result<()> {
+        // A leading `-` tells sftp to carry on if the command fails.
+        self.run(&[format!("-mkdir {}", quote(folder))], None, None)?;
+        Ok(())
+    }
+
+    fn delete(&self, paths: &[&str]) -> Result<()> {
+        let commands = paths
+            .iter()
+            .map(|p| format!("rm {}", quote(p)))
+            .collect::<Vec<_>>();
+        self.run(&commands, None, None)?;
+        Ok(())
+    }
+}
+
+/// Parses `drwxr-xr-x 2 user users 4096 Jan  1 03:00 name`. Some servers print
+/// the whole path in the last column, so only its final part is kept.
+fn entry(line: &str) -> Option<Entry> {
+    let mut rest = line.trim_start();
+    let mut fields = Vec::new();
+    for _ in 0..8 {
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    // What is left is the name, spaces and all.
+    Some(Entry {
+        name: rest.rsplit('/').next()?.to_string(),
+        is_dir: fields[0].starts_with('d'),
+        size: fields[4].parse().ok(),
+    })
+}
+
+/// Quotes a path for an sftp batch file.
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,261 @@
+//! A job's `snapshot`: a read-only snapshot of the filesystem `filename` is
+//! on, taken right before archiving and released right after, so the archive
+//! holds every file as it was at one moment, like a database's files that
+//! would otherwise be read at different points of its writing. The files are
+//! read from the snapshot but named in the archive as where they live, so
+//! restores, differentials and the catalog don't see a difference.
+//!
+//! btrfs snapshots the subvolume into a folder next to it, ZFS the dataset,
+//! read through its `.zfs/snapshot` folder, and LVM the logical volume,
+//! mounted read-only into a folder of its own; Windows takes a VSS shadow
+//! copy of the volume. The snapshot of a run that didn't get to release it
+//! is taken for a leftover and released before the next, except with VSS,
+//! whose shadow copies have no names to know them by.
+
+use crate::config::safe_name;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Btrfs,
+    Zfs,
+    Lvm,
+    Vss,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Kind::Btrfs => "btrfs",
+            Kind::Zfs => "ZFS",
+            Kind::Lvm => "LVM",
+            Kind::Vss => "VSS",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snapshot {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    /// Where the filesystem to snapshot is mounted, with `filename` below it:
+    /// the btrfs subvolume, the ZFS dataset's mount point or the drive, like `C:\`
+    pub mountpoint: String,
+    /// The ZFS dataset, found from `mountpoint` if left out, or the LVM
+    /// logical volume, like `/dev/vg0/home`
+    pub volume: Option<String>,
+    /// Room for the changes made while an LVM snapshot exists, `lvcreate`'s `--size`
+    #[serde(default = "default_size")]
+    pub size: String,
+}
+
+fn default_size() -> String {
+    "1G".to_string()
+}
+
+impl Snapshot {
+    /// Checks that the snapshot can hold `filename`, and that its kind is
+    /// one this system has.
+    pub fn check(&self, filename: &str) -> Result<()> {
+        if !Path::new(filename).starts_with(&self.mountpoint) {
+            return Err(anyhow!(
+                "{filename} is not below the snapshot's mountpoint {}",
+                self.mountpoint
+            ));
+        }
+        let here = match self.kind {
+            Kind::Btrfs | Kind::Lvm => cfg!(target_os = "linux"),
+            Kind::Zfs => cfg!(unix),
+            Kind::Vss => cfg!(windows),
+        };
+        if !here {
+            return Err(anyhow!(
+                "{} snapshots can't be taken on {}",
+                self.kind,
+                std::env::consts::OS
+            ));
+        }
+        if self.kind == Kind::Lvm && self.volume.is_none() {
+            return Err(anyhow!(
+                "An LVM snapshot needs the logical volume as `volume`, like /dev/vg0/home"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot taken for a run, released when this is dropped.
+pub struct Taken {
+    kind: Kind,
+    /// Where `filename` is found in the snapshot
+    pub path: PathBuf,
+    /// What releases it, in order
+    release: Vec<Vec<String>>,
+    /// The folder an LVM snapshot is mounted into, removed once it is unmounted
+    mounted: Option<PathBuf>,
+}
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        for command in &self.release {
+            if let Err(e) = run(command) {
+                eprintln!("Could not release the {} snapshot: {e:#}", self.kind);
+                return;
+            }
+        }
+        if let Some(dir) = &self.mounted {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+/// `program` with `args`, as a command line for [`run`].
+fn command(program: &str, args: &[&str]) -> Vec<String> {
+    std::iter::once(program)
+        .chain(args.iter().copied())
+        .map(String::from)
+        .collect()
+}
+
+/// Runs `command` and returns what it printed, or fails with what it printed
+/// as an error.
+fn run(command: &[String]) -> Result<String> {
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .output()
+        .with_context(|| format!("Could not run {}", command[0]))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            command.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The job's name as it can go into the names of snapshots and volumes.
+fn snapshot_name(job: &str) -> String {
+    format!("synology_backuper_{}", safe_name(job))
+}
+
+/// Takes the snapshot of `job`'s files.
+pub fn take(job: &str, filename: &str, snapshot: &Snapshot) -> Result<Taken> {
+    let relative = Path::new(filename)
+        .strip_prefix(&snapshot.mountpoint)
+        .expect("checked when the config is loaded");
+    let name = snapshot_name(job);
+    let mountpoint = snapshot.mountpoint.as_str();
+    let mut taken = Taken {
+        kind: snapshot.kind,
+        path: PathBuf::new(),
+        release: Vec::new(),
+        mounted: None,
+    };
+    match snapshot.kind {
+        Kind::Btrfs => {
+            let dir = Path::new(mountpoint).join(format!(".{name}"));
+            let dir_str = dir.to_string_lossy().into_owned();
+            let delete = command("btrfs", &["subvolume", "delete", &dir_str]);
+            if dir.exists() {
+                run(&delete).context("Could not delete the snapshot of an earlier run")?;
+            }
+            run(&command(
+                "btrfs",
+                &["subvolume", "snapshot", "-r", mountpoint, &dir_str],
+            ))?;
+            taken.release.push(delete);
+            taken.path = dir.join(relative);
+        }
+        Kind::Zfs => {
+            let dataset = match &snapshot.volume {
+                Some(dataset) => dataset.clone(),
+                None => run(&command("zfs", &["list", "-H", "-o", "name", mountpoint]))?
+                    .trim()
+                    .to_string(),
+            };
+            let full = format!("{dataset}@{name}");
+            let destroy = command("zfs", &["destroy", &full]);
+            if run(&command("zfs", &["list", "-H", "-t", "snapshot", &full])).is_ok() {
+                run(&destroy).context("Could not destroy the snapshot of an earlier run")?;
+            }
+            run(&command("zfs", &["snapshot", &full]))?;
+            taken.release.push(destroy);
+            taken.path = Path::new(mountpoint)
+                .join(".zfs/snapshot")
+                .join(&name)
+                .join(relative);
+        }
+        Kind::Lvm => {
+            let volume = snapshot.volume.as_deref().unwrap();
+            let group = Path::new(volume)
+                .parent()
+                .ok_or_else(|| anyhow!("{volume} is not a logical volume like /dev/vg0/home"))?;
+            let device = group.join(&name).to_string_lossy().into_owned();
+            let dir = std::env::temp_dir().join(&name);
+            let dir_str = dir.to_string_lossy().into_owned();
+            let unmount = command("umount", &[&dir_str]);
+            let remove = command("lvremove", &["-f", &device]);
+            if run(&command("lvs", &[&device])).is_ok() {
+                let _ = run(&unmount);
+                run(&remove).context("Could not remove the snapshot of an earlier run")?;
+            }
+            run(&command(
+                "lvcreate",
+                &[
+                    "--snapshot",
+                    "--name",
+                    &name,
+                    "--size",
+                    &snapshot.size,
+                    volume,
+                ],
+            ))?;
+            taken.release.push(remove.clone());
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+            taken.mounted = Some(dir.clone());
+            // XFS refuses to mount a second filesystem with the same UUID.
+            let fstype =
+                run(&command("findmnt", &["-n", "-o", "FSTYPE", mountpoint])).unwrap_or_default();
+            let options = match fstype.trim() {
+                "xfs" => "ro,nouuid",
+                _ => "ro",
+            };
+            run(&command("mount", &["-o", options, &device, &dir_str]))?;
+            taken.release = vec![unmount, remove];
+            taken.path = dir.join(relative);
+        }
+        Kind::Vss => {
+            let script = format!(
+                "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create -Arguments @{{Volume='{}'; Context='ClientAccessible'}}; \
+                 if ($r.ReturnValue -ne 0) {{ Write-Error \"Win32_ShadowCopy.Create returned $($r.ReturnValue)\"; exit 1 }}; \
+                 $s = Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+                 Write-Output $r.ShadowID; Write-Output $s.DeviceObject",
+                mountpoint.replace('\'', "''")
+            );
+            let output = run(&command("powershell", &["-NoProfile", "-Command", &script]))?;
+            let mut lines = output.lines().map(str::trim);
+            let (Some(id), Some(device)) = (lines.next(), lines.next()) else {
+                return Err(anyhow!("Creating the shadow copy printed {output:?}"));
+            };
+            taken.release.push(command(
+                "powershell",
+                &[
+                    "-NoProfile",
+                    "-Command",
+                    &format!(
+                        "Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{id}' }} | Remove-CimInstance"
+                    ),
+                ],
+            ));
+            taken.path = Path::new(&format!("{device}\\")).join(relative);
+        }
+    }
+    Ok(taken)
+}
@@ -0,0 +1,107 @@
+//! Sparse file handling. Zip has no notion of holes, so they are archived as
+//! zeros, but on Linux the zeros are produced without reading them from disk.
+
+use std::fs::{File, Metadata};
+use std::io::Read;
+
+/// Bytes actually allocated on disk for a file, where the platform says.
+pub fn allocated_size(meta: &Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(meta.blocks() * 512)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        None
+    }
+}
+
+/// A file is worth reporting as sparse when less than half of it, and at least
+/// a MiB less than its apparent size, is allocated.
+pub fn is_sparse(meta: &Metadata) -> bool {
+    allocated_size(meta)
+        .is_some_and(|allocated| allocated * 2 < meta.len() && meta.len() - allocated >= 1 << 20)
+}
+
+/// A reader over `file` that skips holes where the OS can find them.
+pub fn reader(file: File, meta: &Metadata) -> Box<dyn Read> {
+    #[cfg(target_os = "linux")]
+    if is_sparse(meta) {
+        return Box::new(linux::HoleSkippingReader::new(file, meta.len()));
+    }
+    let _ = meta;
+    Box::new(file)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    /// Reads data regions normally and fills holes with zeros, using `SEEK_DATA`/`SEEK_HOLE`.
+    pub struct HoleSkippingReader {
+        file: File,
+        pos: u64,
+        len: u64,
+        /// End of the data region the file offset is currently in
+        data_end: u64,
+    }
+
+    impl HoleSkippingReader {
+        pub fn new(file: File, len: u64) -> Self {
+            HoleSkippingReader {
+                file,
+                pos: 0,
+                len,
+                data_end: 0,
+            }
+        }
+
+        fn seek(&self, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+            let offset = unsafe { libc::lseek(self.file.as_raw_fd(), self.pos as i64, whence) };
+            if offset >= 0 {
+                return Ok(Some(offset as u64));
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                // No more data after pos: the rest of the file is a hole.
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        }
+    }
+
+    impl Read for HoleSkippingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos >= self.len || buf.is_empty() {
+                return Ok(0);
+            }
+            if self.pos >= self.data_end {
+                let data_start = self
+                    .seek(libc::SEEK_DATA)?
+                    .unwrap_or(self.len)
+                    .min(self.len);
+                if data_start > self.pos {
+                    let n = buf.len().min((data_start - self.pos) as usize);
+                    buf[..n].fill(0);
+                    self.pos += n as u64;
+                    return Ok(n);
+                }
+                self.data_end = self.seek(libc::SEEK_HOLE)?.unwrap_or(self.len);
+                self.file.seek(SeekFrom::Start(self.pos))?;
+            }
+            let max = buf.len().min((self.data_end - self.pos) as usize);
+            let n = self.file.read(&mut buf[..max])?;
+            self.pos += n as u64;
+            if n == 0 {
+                // The file was truncated under us; report EOF like a plain read would.
+                self.len = self.pos;
+            }
+            Ok(n)
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! `install-systemd`: a oneshot template service, `synology_backuper@.service`,
+//! that backs up the job it is an instance of, plus a timer per scheduled job
+//! that starts its instance at the job's schedule. Jobs that need more than
+//! the service gives them, like a snapshot past the sandbox of the system
+//! service, get a drop-in for their instance.
+
+use crate::cli::Args;
+use crate::config::{Config, Job, PASSWORD_CREDENTIAL};
+use crate::snapshot::Kind;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+const UNIT: &str = "synology_backuper";
+
+struct Units {
+    service: String,
+    /// File name and contents of each job's timer
+    timers: Vec<(String, String)>,
+    /// Folder and contents of the drop-ins for the instances of jobs that
+    /// need more than the service allows
+    drop_ins: Vec<(String, String)>,
+}
+
+/// `name` as the instance part of a unit name, as `systemd-escape` does it;
+/// the service gets it back with `%I`.
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for (i, byte) in name.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    escaped
+}
+
+/// The timer of the job with the escaped `instance` name.
+fn timer_name(instance: &str) -> String {
+    format!("{UNIT}@{instance}.timer")
+}
+
+/// The drop-in folder of the service instance with the escaped `instance` name.
+fn drop_in_dir(instance: &str) -> String {
+    format!("{UNIT}@{instance}.service.d")
+}
+
+/// File name of the drop-ins in a [`drop_in_dir`].
+const DROP_IN: &str = "synology_backuper.conf";
+
+/// Sandboxing for the system service. Everything stays readable; only the
+/// directories the archives are written to and the state directory are
+/// writable. systemd creates the state directory under `%S`, and pointing
+/// `XDG_STATE_HOME` there makes it the one runs keep their state in.
+const SYSTEM_HARDENING: &str = "\
+StateDirectory=synology_backuper
+Environment=XDG_STATE_HOME=%S
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictSUIDSGID=yes
+RestrictRealtime=yes
+RestrictNamespaces=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+RestrictAddressFamilies=AF_INET AF_INET6 AF_UNIX
+CapabilityBoundingSet=CAP_DAC_READ_SEARCH
+UMask=0077
+";
+
+/// What the service of `job` needs beyond [`SYSTEM_HARDENING`] or
+/// [`USER_HARDENING`], if anything.
+fn relaxed(job: &Job, system: bool) -> Option<String> {
+    let mut relaxed = String::new();
+    if let Some(snapshot) = job.snapshot.as_ref().filter(|_| system) {
+        relaxed.push_str(&match snapshot.kind {
+            // The snapshot is a folder in the subvolume, and deleting a
+            // leftover one takes CAP_SYS_ADMIN.
+            Kind::Btrfs => format!(
+                "CapabilityBoundingSet=CAP_SYS_ADMIN\nReadWritePaths=\"{}\"\n",
+                snapshot.mountpoint
+            ),
+            Kind::Zfs => "\
+CapabilityBoundingSet=CAP_SYS_ADMIN
+PrivateDevices=no
+DevicePolicy=closed
+DeviceAllow=/dev/zfs rw
+"
+            .to_string(),
+            // lvcreate writes the volume group's metadata to its physical
+            // volumes, whatever disks they are, besides the device-mapper
+            // devices of the volume and its snapshot, which is mounted into
+            // the service's private /tmp.
+            Kind::Lvm => "\
+CapabilityBoundingSet=CAP_SYS_ADMIN CAP_IPC_LOCK
+PrivateDevices=no
+DevicePolicy=closed
+DeviceAllow=/dev/mapper/control rw
+DeviceAllow=block-* rw
+ReadWritePaths=-/etc/lvm -/run/lock/lvm -/run/lvm
+RestrictAddressFamilies=AF_NETLINK
+"
+            .to_string(),
+            Kind::Vss => String::new(),
+        });
+    }
+    if job.docker.is_some() {
+        // Services don't get the environment `docker` is set up with here.
+        for name in DOCKER_ENVIRONMENT {
+            if let Ok(value) = std::env::var(name) {
+                relaxed.push_str(&format!("Environment=\"{name}={value}\"\n"));
+            }
+        }
+        // Root is let past the permissions of the files it reads, but a
+        // socket needs writing to, and the one of rootless Docker belongs to
+        // its user.
+        let socket = std::env::var("DOCKER_HOST")
+            .ok()
+            .and_then(|host| host.strip_prefix("unix://").map(str::to_string))
+            .filter(|socket| !ROOT_DOCKER_SOCKETS.contains(&socket.as_str()));
+        if system && socket.is_some() {
+            relaxed.push_str("CapabilityBoundingSet=CAP_DAC_OVERRIDE\n");
+        }
+    }
+    (!relaxed.is_empty()).then_some(relaxed)
+}
+
+/// The variables that choose the Docker daemon `docker` talks to.
+const DOCKER_ENVIRONMENT: &[&str] = &["DOCKER_HOST", "DOCKER_CONTEXT", "DOCKER_CONFIG"];
+
+/// Where the socket of a Docker daemon run by root is.
+const ROOT_DOCKER_SOCKETS: &[&str] = &["/var/run/docker.sock", "/run/docker.sock"];
+
+/// User managers can't set up most namespaces, so user units get the subset that works unprivileged.
+const USER_HARDENING: &str = "\
+NoNewPrivileges=yes
+RestrictSUIDSGID=yes
+RestrictRealtime=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+UMask=0077
+";
+
+fn render(config: &Config, system: bool, workdir: &Path, credential: &Path) -> Result<Units> {
+    let exe = std::env::current_exe()?;
+    let timers = config
+        .jobs
+        .iter()
+        .filter_map(|j| j.schedule.map(|s| (j, s)))
+        .map(|(j, s)| {
+            let jitter = j
+                .jitter
+                .map(|d| d.0.as_secs())
+                .filter(|&secs| secs > 0)
+                .map(|secs| format!("RandomizedDelaySec={secs}\n"))
+                .unwrap_or_default();
+            let timer = format!(
+                "[Unit]
+Description=Scheduled Synology backup of job {name}
+
+[Timer]
+OnCalendar={calendar}
+{jitter}Persistent=true
+
+[Install]
+WantedBy=timers.target
+",
+                name = j.name,
+                calendar = s.on_calendar(),
+            );
+            (timer_name(&escape(&j.name)), timer)
+        })
+        .collect::<Vec<_>>();
+    if timers.is_empty() {
+        return Err(anyhow!(
+            "No job has a `schedule`, so there is nothing to put in a timer"
+        ));
+    }
+
+    let mut writable = config
+        .jobs
+        .iter()
+        .map(|j| {
+            let source =
+                std::fs::canonicalize(&j.filename).unwrap_or_else(|_| PathBuf::from(&j.filename));
+            source.parent().unwrap_or(&source).display().to_string()
+        })
+        .collect::<Vec<_>>();
+    writable.sort();
+    writable.dedup();
+
+    let hardening = if system {
+        format!(
+            "{SYSTEM_HARDENING}ReadWritePaths={}\n",
+            writable
+                .iter()
+                .map(|p| format!("\"{p}\""))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    } else {
+        USER_HARDENING.to_string()
+    };
+    let drop_ins = config
+        .jobs
+        .iter()
+        .filter_map(|j| {
+            let drop_in = format!("[Service]\n{}", relaxed(j, system)?);
+            Some((drop_in_dir(&escape(&j.name)), drop_in))
+        })
+        .collect();
+    let service = format!(
+        "[Unit]
+Description=Back up job %I to a Synology NAS
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+WorkingDirectory={workdir}
+ExecStart=\"{exe}\" --config \"{config}\" backup --job \"%I\"
+LoadCredential={PASSWORD_CREDENTIAL}:{credential}
+{hardening}",
+        workdir = workdir.display(),
+        exe = exe.display(),
+        config = config.path.display(),
+        credential = credential.display(),
+    );
+    Ok(Units {
+        service,
+        timers,
+        drop_ins,
+    })
+}
+
+fn unit_dir(system: bool) -> Result<PathBuf> {
+    if system {
+        return Ok(PathBuf::from("/etc/systemd/system"));
+    }
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?)
+            .join(".config"),
+    };
+    Ok(config_home.join("systemd/user"))
+}
+
+fn credential_path(system: bool) -> Result<PathBuf> {
+    if system {
+        return Ok(PathBuf::from("/etc/credstore").join(PASSWORD_CREDENTIAL));
+    }
+    Ok(unit_dir(false)?
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join(UNIT)
+        .join(PASSWORD_CREDENTIAL))
+}
+
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents.as_bytes())?;
+    Ok(())
+}
+
+fn systemctl(system: bool, args: &[&str]) -> Result<()> {
+    let mut command = std::process::Command::new("systemctl");
+    if !system {
+        command.arg("--user");
+    }
+    let status = command
+        .args(args)
+        .status()
+        .context("Could not run systemctl")?;
+    if !status.success() {
+        return Err(anyhow!("systemctl {} failed with {status}", args.join(" ")));
+    }
+    Ok(())
+}
+
+pub fn install(config: &Config, args: &Args) -> Result<()> {
+    let system = match (args.flag("system"), args.flag("user")) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => return Err(anyhow!("Pass exactly one of --user or --system")),
+    };
+    let workdir = std::env::current_dir()?;
+    let credential = credential_path(system)?;
+    let units = render(config, system, &workdir, &credential)?;
+
+    if args.flag("print") {
+        print!("# {UNIT}@.service\n{}", units.service);
+        for (name, timer) in &units.timers {
+            print!("\n# {name}\n{timer}");
+        }
+        for (folder, drop_in) in &units.drop_ins {
+            print!("\n# {folder}/{DROP_IN}\n{drop_in}");
+        }
+        return Ok(());
+    }
+
+    let dir = unit_dir(system)?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    // The timers of jobs that lost their schedule, and the single timer that
+    // started every job before there was one per job.
+    let mut stale = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&format!("{UNIT}@")) && name.ends_with(".timer"))
+        .filter(|name| !units.timers.iter().any(|(timer, _)| timer == name))
+        .collect::<Vec<_>>();
+    if dir.join(format!("{UNIT}.timer")).exists() {
+        stale.push(format!("{UNIT}.timer"));
+    }
+    for name in &stale {
+        let _ = systemctl(system, &["disable", "--now", name]);
+        std::fs::remove_file(dir.join(name))?;
+    }
+    let _ = std::fs::remove_file(dir.join(format!("{UNIT}.service")));
+    // And the drop-ins of jobs that no longer need one.
+    let stale = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&format!("{UNIT}@")) && name.ends_with(".service.d"))
+        .filter(|name| !units.drop_ins.iter().any(|(folder, _)| folder == name));
+    for name in stale {
+        let _ = std::fs::remove_file(dir.join(&name).join(DROP_IN));
+        let _ = std::fs::remove_dir(dir.join(&name));
+    }
+
+    std::fs::write(dir.join(format!("{UNIT}@.service")), units.service)?;
+    for (name, timer) in &units.timers {
+        std::fs::write(dir.join(name), timer)?;
+    }
+    for (folder, drop_in) in &units.drop_ins {
+        std::fs::create_dir_all(dir.join(folder))?;
+        std::fs::write(dir.join(folder).join(DROP_IN), drop_in)?;
+    }
+    write_private(&credential, &config.nas.pwd)
+        .with_context(|| format!("Could not write {}", credential.display()))?;
+    eprintln!(
+        "Wrote {UNIT}@.service and {} to {}, and the password credential to {}",
+        units
+            .timers
+            .iter()
+            .chain(&units.drop_ins)
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        dir.display(),
+        credential.display()
+    );
+    eprintln!("You can now remove `pwd` from the config file");
+
+    systemctl(system, &["daemon-reload"])?;
+    for (name, _) in &units.timers {
+        systemctl(system, &["enable", "--now", name])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! The `usage` command: how much space each job's backups take on its targets.
+
+use crate::client::Mode;
+use crate::{catalog, dir_size, format_bytes, job_folder, Config, Sessions};
+use anyhow::{anyhow, Result};
+
+pub fn run(config: &Config, mode: Mode) -> Result<()> {
+    let mut sessions = Sessions::new(&config.targets, mode);
+    let mut shares = Vec::new();
+    let mut failed = false;
+
+    println!(
+        "{:<20}{:<12}{:<16}{:>8}  SIZE",
+        "JOB", "TARGET", "SHARE", "BACKUPS"
+    );
+    for job in &config.jobs {
+        for name in &job.targets {
+            let (target, remote) = sessions.get(name);
+            let share_name = job.share_name(target);
+            let row = remote.map_err(|e| anyhow!("{e}")).and_then(|remote| {
+                let (folder, _) = job_folder(remote, job, target)?;
+                let backups = catalog::list_backups(remote, job, name, &folder, false)?;
+                if !shares.contains(&(name, share_name, folder.clone())) {
+                    shares.push((name, share_name, folder));
+                }
+                let paths = backups
+                    .iter()
+                    .map(|b| b.file.path.as_str())
+                    .collect::<Vec<_>>();
+                // Without the web API, the listed sizes are all there is to go by.
+                let size = match remote.api("DirSize") {
+                    Ok(_) if paths.is_empty() => 0,
+                    Ok(s) => dir_size(&s.client, &s.api_info, &paths)?,
+                    Err(_) => backups.iter().filter_map(|b| b.file.size).sum(),
+                };
+                Ok(format!("{:>8}  {}", backups.len(), format_bytes(size)))
+            });
+            let row = row.unwrap_or_else(|e| {
+                failed = true;
+                format!("{:>8}  {e:#}", "-")
+            });
+            println!("{:<20}{:<12}{:<16}{row}", job.name, name, share_name);
+        }
+    }
+
+    println!();
+    println!("{:<12}{:<16}  TOTAL", "TARGET", "SHARE");
+    for (name, share_name, folder) in shares {
+        let (_, remote) = sessions.get(name);
+        let remote = remote.expect("the share was found through this target");
+        let total = remote
+            .api("the share total")
+            .and_then(|s| dir_size(&s.client, &s.api_info, &[&folder]))
+            .map(format_bytes)
+            .unwrap_or_else(|e| {
+                failed = true;
+                format!("{e:#}")
+            });
+        println!("{:<12}{:<16}  {total}", name, share_name);
+    }
+
+    sessions.logout();
+    if failed {
+        return Err(anyhow!("Some sizes could not be determined"));
+    }
+    Ok(())
+}
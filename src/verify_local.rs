@@ -0,0 +1,193 @@
+//! `verify-local`: checking the source tree against the manifest of the
+//! job's last archive, to catch files that rotted on this machine's disk
+//! before the next backups carry the damage along. A file whose size and
+//! modification time are as they were when it was archived should still
+//! have the checksum the manifest lists; one that doesn't changed without
+//! anything writing it. Files changed or deleted since are only counted.
+//!
+//! For that, each archive made with a manifest leaves a copy of it in
+//! `manifests/<job>.json` in the state directory, with the size,
+//! modification time and path each file had; a differential's is laid over
+//! the one of the full archive it builds on.
+
+use crate::archive::{self, ArchiveReport};
+use crate::checksum::Algorithm;
+use crate::cli::Args;
+use crate::config::Job;
+use crate::{paths, selected_jobs, Config};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// The archive it was last updated by
+    archive: String,
+    algorithm: Algorithm,
+    /// By entry name
+    files: BTreeMap<String, Recorded>,
+}
+
+/// A file as it was archived.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Recorded {
+    path: PathBuf,
+    hash: String,
+    size: u64,
+    /// In nanoseconds since 1970
+    modified: i64,
+}
+
+/// How a file compares with its record.
+enum Finding {
+    Intact,
+    Changed,
+    Gone,
+    Corrupted,
+    Unreadable(String),
+}
+
+fn path(job: &Job) -> Result<PathBuf> {
+    Ok(paths::state_dir()?
+        .join("manifests")
+        .join(format!("{}.json", job.name)))
+}
+
+fn load(path: &Path) -> Result<Option<Manifest>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Keeps the manifest of the archive `name` that `report` describes. Of a
+/// `differential`, the files it left out as unchanged keep what the
+/// manifest before it says of them.
+pub fn record(job: &Job, name: &str, report: &ArchiveReport, differential: bool) -> Result<()> {
+    let Some(algorithm) = job.archive_options().manifest else {
+        return Ok(());
+    };
+    let path = path(job)?;
+    let mut files = match load(&path)? {
+        Some(before) if differential && before.algorithm == algorithm => before.files,
+        _ => BTreeMap::new(),
+    };
+    files.retain(|entry, _| report.index.contains_key(entry));
+    for (entry, hash) in &report.hashes {
+        let (Some(&(size, modified)), Some(source)) =
+            (report.index.get(entry), report.sources.get(entry))
+        else {
+            continue;
+        };
+        files.insert(
+            entry.clone(),
+            Recorded {
+                path: source.clone(),
+                hash: hash.clone(),
+                size,
+                modified,
+            },
+        );
+    }
+    let manifest = Manifest {
+        archive: name.to_string(),
+        algorithm,
+        files,
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, serde_json::to_string(&manifest)?)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+fn check(file: &Recorded, algorithm: Algorithm) -> Finding {
+    let meta = match std::fs::metadata(&file.path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Finding::Gone,
+        Err(e) => return Finding::Unreadable(e.to_string()),
+    };
+    if meta.len() != file.size || archive::modified_nanos(&file.path) != file.modified {
+        return Finding::Changed;
+    }
+    match archive::hash_file(&file.path, algorithm) {
+        Ok(hash) if hash == file.hash => Finding::Intact,
+        Ok(_) => Finding::Corrupted,
+        Err(e) => Finding::Unreadable(e.to_string()),
+    }
+}
+
+/// Checks the files of every selected job on `--parallelism` threads, the
+/// processor's count by default, printing what it found. Returns whether no
+/// file was corrupted or unreadable.
+pub fn run(config: &Config, args: &Args) -> Result<bool> {
+    let threads = match args.value("parallelism") {
+        Some(n) => n
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow!("--parallelism takes a number of threads, got {n:?}"))?,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let mut sound = true;
+    for job in selected_jobs(config, args)? {
+        let Some(manifest) = load(&path(job)?)? else {
+            println!(
+                "Job {}: no manifest to check against; it needs `manifest` and a backup since",
+                job.name
+            );
+            continue;
+        };
+        let files = manifest.files.values().collect::<Vec<_>>();
+        let chunk = files.len().div_ceil(threads).max(1);
+        let findings = std::thread::scope(|scope| {
+            let workers = files
+                .chunks(chunk)
+                .map(|part| {
+                    scope.spawn(move || {
+                        part.iter()
+                            .map(|file| (*file, check(file, manifest.algorithm)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let (mut intact, mut changed, mut gone) = (0, 0, 0);
+        let mut problems = Vec::new();
+        for (file, finding) in findings {
+            match finding {
+                Finding::Intact => intact += 1,
+                Finding::Changed => changed += 1,
+                Finding::Gone => gone += 1,
+                Finding::Corrupted => problems.push(format!(
+                    "{}: its contents differ from when it was archived, though its size and modification time don't",
+                    file.path.display()
+                )),
+                Finding::Unreadable(e) => {
+                    problems.push(format!("{}: could not be read: {e}", file.path.display()))
+                }
+            }
+        }
+        println!(
+            "Job {}: {} files against the manifest of {}: {intact} intact, {changed} changed since, {gone} gone, {} damaged",
+            job.name,
+            files.len(),
+            manifest.archive,
+            problems.len()
+        );
+        for problem in &problems {
+            println!("  {problem}");
+        }
+        sound &= problems.is_empty();
+    }
+    Ok(sound)
+}
@@ -0,0 +1,82 @@
+//! Wake-on-LAN for a NAS that sleeps when it isn't needed: the magic packet,
+//! six `0xff` bytes and the MAC address sixteen times, sent as a UDP
+//! broadcast, and then the wait until the NAS answers.
+
+use crate::config::Connection;
+use crate::limits::HumanDuration;
+use anyhow::{anyhow, Context, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Where the magic packet goes unless `wake_address` says otherwise
+pub const BROADCAST: &str = "255.255.255.255:9";
+
+/// How long to wait between asking whether the NAS is up yet
+const POLL: Duration = Duration::from_secs(5);
+
+/// The six bytes of a MAC address written like `00:11:32:aa:bb:cc` or `00-11-32-AA-BB-CC`.
+pub fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let parts = mac
+        .split([':', '-'])
+        .map(|part| match part.len() {
+            2 => u8::from_str_radix(part, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    parts.try_into().ok()
+}
+
+pub fn magic_packet(mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    packet
+}
+
+fn send(mac: [u8; 6], to: SocketAddr) -> Result<()> {
+    let bind = match to {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&magic_packet(mac), to)?;
+    Ok(())
+}
+
+/// Wakes `name` up if `answers` says it is asleep, and waits until it
+/// answers, for up to the connection's `wake_timeout`. Returns whether it
+/// had to be woken.
+pub fn wake(name: &str, nas: &Connection, answers: impl Fn() -> bool) -> Result<bool> {
+    let Some(mac) = &nas.wake_mac else {
+        return Ok(false);
+    };
+    if answers() {
+        return Ok(false);
+    }
+    let mac = parse_mac(mac).ok_or_else(|| anyhow!("{mac} is not a MAC address"))?;
+    let to = nas
+        .wake_address
+        .unwrap_or_else(|| BROADCAST.parse().unwrap());
+    eprintln!("Target {name} doesn't answer; waking it up");
+    send(mac, to).with_context(|| format!("Could not send the wake-up packet to {to}"))?;
+    let HumanDuration(timeout) = nas.wake_timeout;
+    let deadline = Instant::now() + timeout;
+    loop {
+        std::thread::sleep(POLL.min(deadline.saturating_duration_since(Instant::now())));
+        if answers() {
+            eprintln!("Target {name} is awake");
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Target {name} did not answer within {} of the wake-up packet",
+                nas.wake_timeout
+            ));
+        }
+        // Sent again, in case the first went astray while the NAS was
+        // still falling asleep.
+        let _ = send(mac, to);
+    }
+}
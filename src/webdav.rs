@@ -0,0 +1,328 @@
+//! Uploading over WebDAV, for NAS that expose DSM's WebDAV server but not the web API.
+//!
+//! Shares are the top-level collections of the server, so a share `backup`
+//! lives at `/backup` just like it does in FileStation.
+
+use crate::backend::StorageBackend;
+use crate::config::Connection;
+use crate::limits::{self, ByteRate};
+use crate::{RemoteFile, SharedFolder};
+use anyhow::{anyhow, Result};
+use reqwest::blocking::{Body, RequestBuilder, Response};
+use reqwest::header::RANGE;
+use reqwest::{Method, StatusCode};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::time::Instant;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:"><D:prop>
+<D:resourcetype/><D:getcontentlength/><D:getlastmodified/><D:quota-available-bytes/>
+</D:prop></D:propfind>"#;
+
+pub struct WebDav {
+    client: reqwest::blocking::Client,
+    base_url: String,
+    usr: String,
+    pwd: String,
+    shares: Vec<SharedFolder>,
+}
+
+/// One `<response>` of a PROPFIND.
+struct Entry {
+    path: String,
+    is_dir: bool,
+    size: Option<u64>,
+    mtime: Option<i64>,
+    free_space: Option<u64>,
+}
+
+impl WebDav {
+    /// Connects and lists the shares, which also checks the credentials.
+    pub fn open(nas: &Connection) -> Result<WebDav> {
+        let mut dav = WebDav {
+            client: crate::http_client(nas).build()?,
+            base_url: nas.origin(),
+            usr: nas.usr.clone(),
+            pwd: nas.pwd.clone(),
+            shares: Vec::new(),
+        };
+        dav.shares = dav
+            .propfind("/")?
+            .into_iter()
+            .filter(|e| e.is_dir && e.path != "/")
+            .map(|e| SharedFolder {
+                name: e.path.trim_start_matches('/').to_string(),
+                path: e.path,
+                free_space: e.free_space,
+            })
+            .collect();
+        Ok(dav)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base_url, encode_path(path)))
+            .basic_auth(&self.usr, Some(&self.pwd))
+    }
+
+    fn send(&self, what: &str, path: &str, request: RequestBuilder) -> Result<Response> {
+        let resp = request.send()?;
+        match resp.status().as_u16() {
+            200..=299 => Ok(resp),
+            401 => Err(anyhow!("WebDAV login as {} failed (HTTP 401)", self.usr)),
+            status => Err(anyhow!("WebDAV {what} {path} failed with HTTP {status}")),
+        }
+    }
+
+    /// Uploads `local`, with `new` only if nothing has `name` in `folder` yet.
+    fn put(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+        new: bool,
+    ) -> Result<()> {
+        let path = format!("{folder}/{name}");
+        eprintln!("Uploading file {} to {path} over WebDAV", local.display());
+        let file = File::open(local)?;
+        let len = file.metadata()?.len();
+        let body = Body::sized(limits::limited(file, rate_limit, deadline), len);
+        let mut request = self.request(Method::PUT, &path).body(body);
+        if new {
+            request = request.header("If-None-Match", "*");
+        }
+        self.send("PUT", &path, request)?;
+        Ok(())
+    }
+
+    /// The folder itself and its direct children.
+    fn propfind(&self, folder: &str) -> Result<Vec<Entry>> {
+        let request = self
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), folder)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY);
+        let xml = self.send("PROPFIND", folder, request)?.text()?;
+        Ok(elements(&xml, "response").into_iter().map(entry).collect())
+    }
+}
+
+impl StorageBackend for WebDav {
+    fn kind(&self) -> &'static str {
+        "WebDAV"
+    }
+
+    fn shares(&self) -> &[SharedFolder] {
+        &self.shares
+    }
+
+    fn list(&self, folder: &str, recursive: bool) -> Result<Vec<RemoteFile>> {
+        let mut files = Vec::new();
+        let mut folders = vec![folder.trim_end_matches('/').to_string()];
+        while let Some(folder) = folders.pop() {
+            for entry in self.propfind(&folder)? {
+                if entry.path == folder {
+                    continue;
+                }
+                if entry.is_dir {
+                    if recursive {
+                        folders.push(entry.path);
+                    }
+                    continue;
+                }
+                files.push(RemoteFile {
+                    name: entry.path.rsplit('/').next().unwrap().to_string(),
+                    path: entry.path,
+                    size: entry.size,
+                    mtime: entry.mtime,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn upload(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.put(folder, local, name, rate_limit, deadline, false)
+    }
+
+    /// The server answers 412 rather than replace the file.
+    fn upload_new(
+        &self,
+        folder: &str,
+        local: &std::path::Path,
+        name: &str,
+        rate_limit: Option<ByteRate>,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.put(folder, local, name, rate_limit, deadline, true)
+    }
+
+    fn download(&self, path: &str, local: &std::path::Path) -> Result<()> {
+        let mut resp = self.send("GET", path, self.request(Method::GET, path))?;
+        resp.copy_to(&mut File::create(local)?)?;
+        Ok(())
+    }
+
+    /// A server that ignores the range sends the whole file, which then
+    /// replaces `local`.
+    fn resume_download(&self, path: &str, local: &std::path::Path, offset: u64) -> Result<()> {
+        let request = self
+            .request(Method::GET, path)
+            .header(RANGE, format!("bytes={offset}-"));
+        let mut resp = self.send("GET", path, request)?;
+        let mut file = if offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT {
+            let mut file = std::fs::OpenOptions::new().write(true).open(local)?;
+            file.set_len(offset)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file
+        } else {
+            File::create(local)?
+        };
+        resp.copy_to(&mut file)?;
+        Ok(())
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let request = self
+            .request(Method::GET, path)
+            .header(RANGE, format!("bytes={offset}-{}", offset + len - 1));
+        let resp = self.send("GET", path, request)?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(anyhow!("the WebDAV server ignores HTTP ranges"));
+        }
+        Ok(resp.bytes()?.to_vec())
+    }
+
+    fn create_folder(&self, folder: &str) -> Result<()> {
+        let request = self.request(Method::from_bytes(b"MKCOL").unwrap(), folder);
+        let resp = request.send()?;
+        match resp.status().as_u16() {
+            // 405: the folder is already there
+            200..=299 | 405 => Ok(()),
+            status => Err(anyhow!("WebDAV MKCOL {folder} failed with HTTP {status}")),
+        }
+    }
+
+    fn delete(&self, paths: &[&str]) -> Result<()> {
+        for path in paths {
+            let resp = self.request(Method::DELETE, path).send()?;
+            let status = resp.status().as_u16();
+            if !(200..=299).contains(&status) && status != 404 {
+                return Err(anyhow!("WebDAV DELETE {path} failed with HTTP {status}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entry(response: &str) -> Entry {
+    let prop = |name| {
+        elements(response, name)
+            .first()
+            .map(|x| x.trim().to_string())
+    };
+    let href = prop("href").unwrap_or_default();
+    // Servers may answer with full URLs or with absolute paths.
+    let href = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]).to_string(),
+        None => href,
+    };
+    let path = percent_decode(&href);
+    let path = match path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        path => path.to_string(),
+    };
+    Entry {
+        path,
+        is_dir: !elements(response, "collection").is_empty(),
+        size: prop("getcontentlength").and_then(|x| x.parse().ok()),
+        mtime: prop("getlastmodified")
+            .and_then(|x| chrono::DateTime::parse_from_rfc2822(&x).ok())
+            .map(|x| x.timestamp()),
+        free_space: prop("quota-available-bytes").and_then(|x| x.parse().ok()),
+    }
+}
+
+/// The contents of every element whose local name is `local`, whatever its
+/// namespace prefix. Enough XML for a multistatus, where such elements don't nest.
+fn elements<'a>(xml: &'a str, local: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let name = &rest[..end];
+        if name.rsplit(':').next() != Some(local) {
+            continue;
+        }
+        let Some(close) = rest.find('>') else {
+            break;
+        };
+        if rest[..close].ends_with('/') {
+            found.push("");
+            continue;
+        }
+        rest = &rest[close + 1..];
+        let end_tag = format!("</{name}>");
+        match rest.find(&end_tag) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + end_tag.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+/// Percent-encodes everything in `path` but unreserved characters and `/`.
+fn encode_path(path: &str) -> String {
+    let mut out = String::new();
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,99 @@
+//! Write-once folders, like a share with DSM's WriteOnce (WORM) locking, where
+//! files can be added but, while locked, neither changed nor deleted. A folder
+//! is taken to be one when its target's config says `worm`, or once DSM
+//! refused to change a file in it the way such a folder does, which is
+//! remembered in the state directory, in `worm.json`. Uploads there never
+//! replace a file, moving on to the next second's name where one is taken,
+//! and nothing there is deleted: the NAS frees the files itself once their
+//! locks expire.
+
+use crate::config::Target;
+use crate::{paths, ApiError};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A folder found to be write-once.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Detected {
+    pub target: String,
+    pub folder: String,
+    pub detected: String,
+    /// What DSM answered
+    pub error: String,
+}
+
+pub fn path() -> Result<PathBuf> {
+    Ok(paths::state_dir()?.join("worm.json"))
+}
+
+fn load() -> Result<Vec<Detected>> {
+    let path = path()?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    serde_json::from_str(&text).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Whether `folder` on `target`, or a folder it is in, keeps its files write-once.
+pub fn write_once(target: &Target, folder: &str) -> bool {
+    target.worm
+        || load().unwrap_or_default().iter().any(|d| {
+            d.target == target.name
+                && folder
+                    .strip_prefix(&d.folder)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+}
+
+/// Whether `e` is DSM refusing to change or delete a file the way it does
+/// in a write-once folder.
+pub fn refused(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.downcast_ref::<ApiError>()
+            .is_some_and(|e| matches!(e.code, 407 | 411))
+    })
+}
+
+/// Whether `e` is an upload failing because the name is taken.
+pub fn name_taken(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.downcast_ref::<ApiError>()
+            .is_some_and(|e| matches!(e.code, 414 | 1805))
+            || e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::AlreadyExists)
+    })
+}
+
+/// Remembers that `folder` on `target` is write-once, since DSM answered
+/// a change to it with `e`, and says what follows from that.
+pub fn record(target: &str, folder: &str, e: &anyhow::Error) {
+    eprintln!(
+        "{target}:{folder} refused to change a file ({e:#}) as a write-once (WORM) folder does. From now on uploads there never replace a file and nothing there is deleted; the NAS frees the files once their locks expire"
+    );
+    let recorded = load().and_then(|mut detected| {
+        detected.retain(|d| !(d.target == target && d.folder == folder));
+        detected.push(Detected {
+            target: target.to_string(),
+            folder: folder.to_string(),
+            detected: crate::runlog::now(),
+            error: format!("{e:#}"),
+        });
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(&detected)?)
+            .with_context(|| format!("Could not write {}", path.display()))
+    });
+    match recorded {
+        Ok(()) => {
+            if let Ok(path) = path() {
+                eprintln!("{} remembers this; deleting it forgets it", path.display());
+            }
+        }
+        Err(e) => eprintln!("Could not remember it: {e:#}"),
+    }
+}
@@ -0,0 +1,449 @@
+//! Extended attributes and ACLs, for jobs with `xattrs`: read from each file
+//! and folder when it is archived and set again when it is restored. On
+//! Linux, POSIX ACLs are extended attributes themselves
+//! (`system.posix_acl_access` and, on folders, `system.posix_acl_default`),
+//! so they come along with the rest; macOS keeps its ACLs apart, and they are
+//! carried as the text `chmod +a` and `ls -le` use. On Windows what is kept
+//! are the file attributes read-only, hidden and system; NTFS compression,
+//! EFS encryption and alternate data streams, which a zip has no place for,
+//! are only reported. Elsewhere there is nothing to read.
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// What a file or folder carries besides its contents, mode and time.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Attributes {
+    /// Extended attributes by name, their values in base64
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// The extended ACL on macOS, as text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acl: Option<String>,
+    /// Of the Windows file attributes, those [`windows::KEPT`] names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub windows: Option<u32>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        self.xattrs.is_empty() && self.acl.is_none() && self.windows.is_none()
+    }
+}
+
+/// The attributes of `path`, a symlink's own rather than its target's.
+pub fn read(path: &Path) -> io::Result<Attributes> {
+    let mut attributes = Attributes::default();
+    for name in sys::list(path)? {
+        // Gone since it was listed
+        let Some(value) = sys::get(path, &name)? else {
+            continue;
+        };
+        let value = base64::engine::general_purpose::STANDARD.encode(value);
+        attributes.xattrs.insert(name, value);
+    }
+    attributes.acl = sys::get_acl(path)?;
+    #[cfg(windows)]
+    {
+        attributes.windows = windows::kept(path)?;
+    }
+    Ok(attributes)
+}
+
+/// What of `path` the archive can't keep, so a restore won't bring it back:
+/// on Windows, with `attributes`, NTFS compression and EFS encryption, and
+/// with `streams`, the alternate data streams, by name.
+#[cfg(windows)]
+pub fn unkept(path: &Path, attributes: bool, streams: bool) -> io::Result<Vec<String>> {
+    let mut unkept = Vec::new();
+    if attributes {
+        unkept.extend(windows::unkept(path)?.into_iter().map(String::from));
+    }
+    if streams {
+        for stream in windows::streams(path)? {
+            unkept.push(format!("the alternate data stream {stream}"));
+        }
+    }
+    Ok(unkept)
+}
+
+#[cfg(not(windows))]
+pub fn unkept(_path: &Path, _attributes: bool, _streams: bool) -> io::Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+/// Gives `path` the `attributes` read from a file before. Where some can't
+/// be set, like `security.*` ones without root, or on a filesystem without
+/// them, the others are set all the same and the first error returned.
+pub fn write(path: &Path, attributes: &Attributes) -> io::Result<()> {
+    let mut first = None;
+    for (name, value) in &attributes.xattrs {
+        let set = base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|value| sys::set(path, name, &value))
+            .map_err(|e| io::Error::new(e.kind(), format!("{name}: {e}")));
+        if let Err(e) = set {
+            first.get_or_insert(e);
+        }
+    }
+    if let Some(acl) = &attributes.acl {
+        if let Err(e) = sys::set_acl(path, acl) {
+            first.get_or_insert(io::Error::new(e.kind(), format!("ACL: {e}")));
+        }
+    }
+    // Elsewhere hidden and system mean nothing, and the mode has read-only.
+    #[cfg(windows)]
+    if let Some(bits) = attributes.windows {
+        if let Err(e) = windows::set(path, bits) {
+            first.get_or_insert(io::Error::new(e.kind(), format!("file attributes: {e}")));
+        }
+    }
+    first.map_or(Ok(()), Err)
+}
+
+#[cfg(windows)]
+pub mod windows {
+    use std::ffi::c_void;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use std::path::Path;
+
+    const READONLY: u32 = 0x1;
+    const HIDDEN: u32 = 0x2;
+    const SYSTEM: u32 = 0x4;
+    const NORMAL: u32 = 0x80;
+    const COMPRESSED: u32 = 0x800;
+    const ENCRYPTED: u32 = 0x4000;
+    /// The file attributes a restore sets again
+    pub const KEPT: u32 = READONLY | HIDDEN | SYSTEM;
+
+    const INVALID_FILE_ATTRIBUTES: u32 = u32::MAX;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    /// From `FindFirstStreamW`, for a file with no streams at all, like a folder
+    const ERROR_HANDLE_EOF: i32 = 38;
+    /// From `FindFirstStreamW`, on filesystems without streams, like FAT
+    const ERROR_INVALID_PARAMETER: i32 = 87;
+
+    /// `WIN32_FIND_STREAM_DATA`
+    #[repr(C)]
+    struct StreamData {
+        size: i64,
+        /// `MAX_PATH + 36` characters
+        name: [u16; 296],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileAttributesW(path: *const u16) -> u32;
+        fn SetFileAttributesW(path: *const u16, attributes: u32) -> i32;
+        fn FindFirstStreamW(
+            path: *const u16,
+            level: i32,
+            data: *mut c_void,
+            flags: u32,
+        ) -> *mut c_void;
+        fn FindNextStreamW(handle: *mut c_void, data: *mut c_void) -> i32;
+        fn FindClose(handle: *mut c_void) -> i32;
+    }
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    /// The attributes of `path` among [`KEPT`], if it has any.
+    pub fn kept(path: &Path) -> io::Result<Option<u32>> {
+        let bits = std::fs::symlink_metadata(path)?.file_attributes() & KEPT;
+        Ok((bits != 0).then_some(bits))
+    }
+
+    /// Sets the attributes of [`KEPT`] that `bits` has on `path`, leaving its others.
+    pub fn set(path: &Path, bits: u32) -> io::Result<()> {
+        let path = wide(path);
+        let now = unsafe { GetFileAttributesW(path.as_ptr()) };
+        if now == INVALID_FILE_ATTRIBUTES {
+            return Err(io::Error::last_os_error());
+        }
+        let bits = match (now & !KEPT) | (bits & KEPT) {
+            0 => NORMAL,
+            bits => bits,
+        };
+        match unsafe { SetFileAttributesW(path.as_ptr(), bits) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn unkept(path: &Path) -> io::Result<Vec<&'static str>> {
+        let bits = std::fs::symlink_metadata(path)?.file_attributes();
+        let mut unkept = Vec::new();
+        if bits & COMPRESSED != 0 {
+            unkept.push("NTFS compression");
+        }
+        if bits & ENCRYPTED != 0 {
+            unkept.push("EFS encryption");
+        }
+        Ok(unkept)
+    }
+
+    /// The names of the alternate data streams of `path`, like `Zone.Identifier`.
+    pub fn streams(path: &Path) -> io::Result<Vec<String>> {
+        let path = wide(path);
+        let mut data = StreamData {
+            size: 0,
+            name: [0; 296],
+        };
+        let data_ptr = (&mut data as *mut StreamData).cast::<c_void>();
+        let handle = unsafe { FindFirstStreamW(path.as_ptr(), 0, data_ptr, 0) };
+        if handle as isize == INVALID_HANDLE_VALUE {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(ERROR_HANDLE_EOF | ERROR_INVALID_PARAMETER) => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        let mut streams = Vec::new();
+        loop {
+            let len = data.name.iter().position(|c| *c == 0).unwrap_or(296);
+            // Like `:Zone.Identifier:$DATA`, and `::$DATA` for the contents
+            let name = String::from_utf16_lossy(&data.name[..len]);
+            let name = name.trim_start_matches(':').trim_end_matches(":$DATA");
+            if !name.is_empty() {
+                streams.push(name.to_string());
+            }
+            if unsafe { FindNextStreamW(handle, data_ptr) } == 0 {
+                break;
+            }
+        }
+        unsafe { FindClose(handle) };
+        Ok(streams)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    #[cfg(target_os = "macos")]
+    use std::ffi::CStr;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Whether `e` says the filesystem has no extended attributes at all.
+    fn unsupported(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::ENOTSUP)
+    }
+
+    /// Calls `f` with a buffer grown until what it reads fits: first with
+    /// none, for the size, then with that much, again if it grew meanwhile.
+    fn sized(f: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let len = f(std::ptr::null_mut(), 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; len as usize];
+            if buf.is_empty() {
+                return Ok(buf);
+            }
+            let len = f(buf.as_mut_ptr().cast(), buf.len());
+            if len >= 0 {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    pub fn list(path: &Path) -> io::Result<Vec<String>> {
+        let path = c_path(path)?;
+        let names = sized(|buf, len| unsafe {
+            #[cfg(target_os = "linux")]
+            let n = libc::llistxattr(path.as_ptr(), buf.cast(), len);
+            #[cfg(target_os = "macos")]
+            let n = libc::listxattr(path.as_ptr(), buf.cast(), len, libc::XATTR_NOFOLLOW);
+            n
+        });
+        let names = match names {
+            Err(e) if unsupported(&e) => return Ok(Vec::new()),
+            names => names?,
+        };
+        Ok(names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect())
+    }
+
+    /// The value of `name`, or `None` if `path` doesn't have it.
+    pub fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let (path, name) = (c_path(path)?, CString::new(name)?);
+        let value = sized(|buf, len| unsafe {
+            #[cfg(target_os = "linux")]
+            let n = libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len);
+            #[cfg(target_os = "macos")]
+            let n = libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf,
+                len,
+                0,
+                libc::XATTR_NOFOLLOW,
+            );
+            n
+        });
+        match value {
+            #[cfg(target_os = "linux")]
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+            #[cfg(target_os = "macos")]
+            Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => Ok(None),
+            value => value.map(Some),
+        }
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let (path, name) = (c_path(path)?, CString::new(name)?);
+        let status = unsafe {
+            #[cfg(target_os = "linux")]
+            let status = libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            );
+            #[cfg(target_os = "macos")]
+            let status = libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            );
+            status
+        };
+        match status {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// On Linux the ACLs are among the extended attributes.
+    #[cfg(target_os = "linux")]
+    pub fn get_acl(_path: &Path) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn set_acl(_path: &Path, _acl: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "macOS ACLs can't be set on Linux",
+        ))
+    }
+
+    #[cfg(target_os = "macos")]
+    extern "C" {
+        fn acl_get_link_np(path: *const libc::c_char, kind: libc::c_int) -> *mut libc::c_void;
+        fn acl_set_link_np(
+            path: *const libc::c_char,
+            kind: libc::c_int,
+            acl: *mut libc::c_void,
+        ) -> libc::c_int;
+        fn acl_to_text(acl: *mut libc::c_void, len: *mut libc::ssize_t) -> *mut libc::c_char;
+        fn acl_from_text(text: *const libc::c_char) -> *mut libc::c_void;
+        fn acl_free(object: *mut libc::c_void) -> libc::c_int;
+    }
+
+    /// `ACL_TYPE_EXTENDED` in `<sys/acl.h>`, the only kind macOS has
+    #[cfg(target_os = "macos")]
+    const ACL_TYPE_EXTENDED: libc::c_int = 0x100;
+
+    /// The extended ACL of `path`, if it has one.
+    #[cfg(target_os = "macos")]
+    pub fn get_acl(path: &Path) -> io::Result<Option<String>> {
+        let path = c_path(path)?;
+        let acl = unsafe { acl_get_link_np(path.as_ptr(), ACL_TYPE_EXTENDED) };
+        if acl.is_null() {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOENT) | Some(libc::ENOTSUP) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let text = unsafe { acl_to_text(acl, std::ptr::null_mut()) };
+        let result = match text.is_null() {
+            true => Err(io::Error::last_os_error()),
+            false => {
+                let owned = unsafe { CStr::from_ptr(text) }
+                    .to_string_lossy()
+                    .into_owned();
+                unsafe { acl_free(text.cast()) };
+                Ok(Some(owned))
+            }
+        };
+        unsafe { acl_free(acl) };
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_acl(path: &Path, acl: &str) -> io::Result<()> {
+        let (path, text) = (c_path(path)?, CString::new(acl)?);
+        let acl = unsafe { acl_from_text(text.as_ptr()) };
+        if acl.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let status = unsafe { acl_set_link_np(path.as_ptr(), ACL_TYPE_EXTENDED, acl) };
+        let result = match status {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        unsafe { acl_free(acl) };
+        result
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn list(_path: &Path) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    pub fn get(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are only restored on Linux and macOS",
+        ))
+    }
+
+    pub fn get_acl(_path: &Path) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub fn set_acl(_path: &Path, _acl: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ACLs are only restored on Linux and macOS",
+        ))
+    }
+}
@@ -0,0 +1,175 @@
+//! Reading the table of contents of a zip without having the zip: the
+//! central directory at its end says what every entry is, so a few ranged
+//! reads of a remote archive list it, and say where to read each entry
+//! from. Only what listing and picking entries out need is parsed.
+
+use anyhow::{anyhow, Result};
+use chrono::NaiveDateTime;
+
+const END: u32 = 0x0605_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+/// The "version made by" system whose attributes hold a unix mode
+const UNIX: u16 = 3;
+/// The end of central directory record without its comment
+const END_LEN: u64 = 22;
+/// How much of the end is read first: the longest comment and the
+/// end record, which usually takes in the whole directory of a small archive.
+const TAIL_LEN: u64 = 0xffff + END_LEN;
+
+pub struct Entry {
+    pub name: String,
+    pub size: u64,
+    /// In local time, as zip keeps it
+    pub modified: Option<NaiveDateTime>,
+    /// The permissions, if the entry was archived on unix
+    pub mode: Option<u32>,
+    /// Where the entry's local header is, which its data follows
+    pub offset: u64,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// The entries of the zip that is `size` bytes long, reading it with `read`,
+/// which returns the `len` bytes from `offset` on.
+pub fn entries(size: u64, mut read: impl FnMut(u64, u64) -> Result<Vec<u8>>) -> Result<Vec<Entry>> {
+    let tail_start = size.saturating_sub(TAIL_LEN);
+    let tail = read(tail_start, size - tail_start)?;
+    let end = (0..tail.len().saturating_sub(END_LEN as usize - 1))
+        .rev()
+        .find(|&i| u32_at(&tail, i) == Some(END))
+        .ok_or_else(|| anyhow!("not a zip archive: it has no end of central directory"))?;
+    let field = |at: usize| u32_at(&tail, end + at).map(u64::from);
+    let mut count = u16_at(&tail, end + 10).map(u64::from);
+    let mut length = field(12);
+    let mut offset = field(16);
+
+    // Zip64 archives put 0xffff... in the end record and the real values in
+    // another one, whose position a locator right before it holds.
+    let truncated =
+        count == Some(0xffff) || length == Some(0xffff_ffff) || offset == Some(0xffff_ffff);
+    if truncated && end >= 20 && u32_at(&tail, end - 20) == Some(ZIP64_LOCATOR) {
+        let at = u64_at(&tail, end - 12).ok_or_else(|| anyhow!("truncated zip64 locator"))?;
+        let record = at
+            .checked_sub(tail_start)
+            .and_then(|i| tail.get(i as usize..i as usize + 56).map(<[u8]>::to_vec));
+        let record = match record {
+            Some(record) => record,
+            None => read(at, 56)?,
+        };
+        if u32_at(&record, 0) != Some(ZIP64_END) {
+            return Err(anyhow!(
+                "not a zip archive: its zip64 end record is missing"
+            ));
+        }
+        count = u64_at(&record, 32);
+        length = u64_at(&record, 40);
+        offset = u64_at(&record, 48);
+    }
+    let (Some(count), Some(length), Some(offset)) = (count, length, offset) else {
+        return Err(anyhow!(
+            "not a zip archive: its end of central directory is cut off"
+        ));
+    };
+    if offset.checked_add(length).is_none_or(|e| e > size) {
+        return Err(anyhow!(
+            "not a zip archive: its central directory lies outside it"
+        ));
+    }
+
+    let directory = match offset.checked_sub(tail_start) {
+        Some(i) => tail[i as usize..(i + length) as usize].to_vec(),
+        None => read(offset, length)?,
+    };
+    parse(&directory, count)
+}
+
+fn parse(directory: &[u8], count: u64) -> Result<Vec<Entry>> {
+    let broken = || anyhow!("the central directory is broken");
+    let mut entries = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        if u32_at(directory, at) != Some(DIRECTORY_ENTRY) {
+            return Err(broken());
+        }
+        let field16 = |i: usize| u16_at(directory, at + i).ok_or_else(broken);
+        let field32 = |i: usize| u32_at(directory, at + i).ok_or_else(broken);
+        let made_by = field16(4)?;
+        let (time, date) = (field16(12)?, field16(14)?);
+        let mut compressed_size = u64::from(field32(20)?);
+        let mut size = u64::from(field32(24)?);
+        let name_len = field16(28)? as usize;
+        let extra_len = field16(30)? as usize;
+        let comment_len = field16(32)? as usize;
+        let attributes = field32(38)?;
+        let mut offset = u64::from(field32(42)?);
+        let name = directory
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(broken)?;
+        let extra = directory
+            .get(at + 46 + name_len..at + 46 + name_len + extra_len)
+            .ok_or_else(broken)?;
+        // The zip64 extra field holds, in order, the values that didn't fit,
+        // so each one counts to find those after it.
+        if let Some(mut zip64) = extra_field(extra, 0x0001) {
+            for value in [&mut size, &mut compressed_size, &mut offset] {
+                if *value == 0xffff_ffff {
+                    *value = u64_at(zip64, 0).ok_or_else(broken)?;
+                    zip64 = &zip64[8..];
+                }
+            }
+        }
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            size,
+            modified: msdos_time(date, time),
+            mode: (made_by >> 8 == UNIX).then_some(attributes >> 16),
+            offset,
+        });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// The data of the extra field with the header `id`.
+fn extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let len = u16_at(extra, 2)? as usize;
+        let data = extra.get(4..4 + len)?;
+        if u16_at(extra, 0) == Some(id) {
+            return Some(data);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn msdos_time(date: u16, time: u16) -> Option<NaiveDateTime> {
+    chrono::NaiveDate::from_ymd_opt(
+        1980 + i32::from(date >> 9),
+        u32::from((date >> 5) & 0xf),
+        u32::from(date & 0x1f),
+    )?
+    .and_hms_opt(
+        u32::from(time >> 11),
+        u32::from((time >> 5) & 0x3f),
+        u32::from(time & 0x1f) * 2,
+    )
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
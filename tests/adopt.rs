@@ -0,0 +1,104 @@
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+
+#[test]
+fn adopted_archives_count_for_list_and_prune() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.FileStation.MD5"] = json!({"path": "entry.cgi", "minVersion": 1, "maxVersion": 2});
+    mock.set_api_info(info);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let filename = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "default", "filename": filename, "keep_last": 1}]);
+    let file = |name: &str, size: u64, mtime: i64| {
+        json!({
+            "name": name,
+            "path": format!("/backup/laptop/{name}"),
+            "isdir": false,
+            "additional": {"size": size, "time": {"mtime": mtime}},
+        })
+    };
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 4, "files": [
+            file("laptop-2024-01-05_03-00-00.zip", 2048, 1_800_000_000),
+            file("photos 20230301.zip", 4096, 1_800_000_000),
+            file("manual.zip", 1024, 1_700_000_000),
+            file("readme.txt", 10, 1_700_000_000),
+        ]})),
+    );
+
+    let args = ["adopt", "--share", "backup", "--dir", "laptop"];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains("Adopted /backup/laptop/laptop-2024-01-05_03-00-00.zip, 2.0 KiB, made 2024-01-05 03:00:00, MD5 6cc7e7c6a8b65d0a4f2bcd1e465e48e7"),
+        "{out}"
+    );
+    assert!(
+        out.contains("/backup/laptop/manual.zip has no timestamp in its name"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Skipped 1 files that are not zip archives"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Adopted 3 archives into job default on primary"),
+        "{out}"
+    );
+    let md5s = mock.calls("SYNO.FileStation.MD5", "start");
+    assert_eq!(
+        md5s[0].params["file_path"],
+        "/backup/laptop/laptop-2024-01-05_03-00-00.zip"
+    );
+    let catalog: Value = serde_json::from_str(
+        &std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/catalog.json"))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(catalog.as_array().unwrap().len(), 3);
+    assert_eq!(catalog[2]["time"], "2023-03-01T00:00:00Z");
+
+    // Adopting again updates the entries rather than doubling them.
+    assert!(run(&dir, &config, &args).status.success());
+    let output = run(&dir, &config, &["list"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3, "{out}");
+    assert!(
+        lines[0].contains(
+            "primary:/backup/laptop/laptop-2024-01-05_03-00-00.zip\t2.0 KiB\t2024-01-05 03:00:00"
+        ),
+        "{out}"
+    );
+    assert!(lines[2].contains("photos 20230301.zip"), "{out}");
+
+    let output = run(&dir, &config, &["prune", "--dry-run"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("keeping 1, would delete 2"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!out.contains("laptop-2024"), "{out}");
+
+    let output = run(
+        &dir,
+        &config,
+        &[&args[..], &["--target", "offsite"]].concat(),
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Job default doesn't go to target offsite; it goes to primary"),
+        "{}",
+        stderr(&output)
+    );
+}
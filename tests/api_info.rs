@@ -0,0 +1,78 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn lists_every_api_with_the_version_used() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.DownloadStation.Task"] =
+        json!({"path": "entry.cgi", "minVersion": 1, "maxVersion": 3});
+    info["SYNO.FileStation.CopyMove"]["maxVersion"] = json!(2);
+    info.as_object_mut()
+        .unwrap()
+        .remove("SYNO.FileStation.Search");
+    mock.set_api_info(info);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["api-info"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let row = |name: &str| {
+        out.lines()
+            .find(|l| l.starts_with(&format!("{name} ")))
+            .unwrap_or_else(|| panic!("no {name} in {out}"))
+            .split_whitespace()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        row("SYNO.API.Auth"),
+        ["SYNO.API.Auth", "1", "7", "7", "auth.cgi"]
+    );
+    assert_eq!(row("SYNO.DownloadStation.Task")[3], "-");
+    assert_eq!(row("SYNO.FileStation.CopyMove")[3], "3!");
+    assert!(out.contains("12 APIs, DSM 7"), "{out}");
+    assert!(
+        out.contains("Not offered but used by this program: SYNO.FileStation.Search"),
+        "{out}"
+    );
+    let query = &mock.calls("SYNO.API.Info", "query")[0];
+    assert_eq!(query.params["query"], "all");
+    assert!(mock.calls("SYNO.API.Auth", "login").is_empty());
+
+    let output = run(&dir, &config, &["api-info", "--json"]);
+    let info = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(info["dsm"], "DSM 7");
+    let upload = info["apis"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|a| a["name"] == "SYNO.FileStation.Upload")
+        .unwrap();
+    assert_eq!(
+        *upload,
+        json!({"name": "SYNO.FileStation.Upload", "path": "entry.cgi", "min_version": 1, "max_version": 3, "used_version": 2})
+    );
+}
+
+#[test]
+fn refuses_a_version_too_large_to_keep() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.FileStation.CopyMove"]["maxVersion"] = json!(258);
+    mock.set_api_info(info);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["api-info"]);
+    let err = stderr(&output);
+    assert!(!output.status.success(), "{err}");
+    assert!(!err.contains("panicked"), "{err}");
+    assert!(
+        err.contains(r#"SYNO.API.Info query: the answer isn't DSM's JSON: {"#),
+        "{err}"
+    );
+    assert!(err.contains(r#""maxVersion":258"#), "{err}");
+}
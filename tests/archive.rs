@@ -0,0 +1,480 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn entry_names_use_forward_slashes() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/sub/deeper.txt", "deeper\n");
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let entries = zip_entries(&mock.calls("SYNO.FileStation.Upload", "upload")[0]);
+    let mut names = entries.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names.len(), 2);
+    for name in &names {
+        assert!(!name.contains('\\'), "{name}");
+        assert!(!name.starts_with('/'), "{name}");
+    }
+    assert!(names[0].ends_with("data/notes.txt"), "{names:?}");
+    assert!(names[1].ends_with("data/sub/deeper.txt"), "{names:?}");
+}
+
+#[cfg(windows)]
+#[test]
+fn archives_paths_longer_than_max_path() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let deep = (0..12)
+        .map(|i| format!("directory_level_{i:02}_padding"))
+        .collect::<Vec<_>>();
+    let relative = format!("data/{}/leaf.txt", deep.join("/"));
+    let leaf = dir.path().join(&relative);
+    assert!(leaf.as_os_str().len() > 260);
+    let verbatim = format!(r"\\?\{}", leaf.display());
+    std::fs::create_dir_all(std::path::Path::new(&verbatim).parent().unwrap()).unwrap();
+    std::fs::write(&verbatim, "far down\n").unwrap();
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let entries = zip_entries(&mock.calls("SYNO.FileStation.Upload", "upload")[0]);
+    let (name, contents) = entries
+        .iter()
+        .find(|(n, _)| n.ends_with("leaf.txt"))
+        .unwrap();
+    assert!(name.ends_with(&relative), "{name}");
+    assert!(!name.contains('\\'), "{name}");
+    assert_eq!(contents, b"far down\n");
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_entry_names_drop_the_drive() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let entries = zip_entries(&mock.calls("SYNO.FileStation.Upload", "upload")[0]);
+    assert!(!entries[0].0.contains(':'), "{}", entries[0].0);
+    assert!(!entries[0].0.contains('\\'), "{}", entries[0].0);
+}
+
+#[test]
+fn normalizes_entry_names_to_nfc_on_request() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/cafe\u{301}.txt", "coffee\n");
+    let data = dir.path().join("data");
+    config["filename"] = serde_json::Value::Null;
+    config["jobs"] = serde_json::json!([
+        {"name": "nfc", "filename": data.to_str().unwrap(), "unicode_names": "nfc"},
+        {"name": "raw", "filename": data.to_str().unwrap()},
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    let has = |upload, name: &str| {
+        zip_entries(upload)
+            .iter()
+            .any(|(n, _)| n.ends_with(&format!("data/{name}")))
+    };
+    assert!(has(&uploads[0], "caf\u{e9}.txt"));
+    assert!(has(&uploads[1], "cafe\u{301}.txt"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn names_that_are_not_utf8_are_reported_instead_of_renamed() {
+    use std::os::unix::ffi::OsStrExt;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+    // Both would read as `bad\u{fffd}.txt`.
+    for name in [&b"bad\xff.txt"[..], b"bad\xfe.txt"] {
+        let path = dir
+            .path()
+            .join("data")
+            .join(std::ffi::OsStr::from_bytes(name));
+        std::fs::write(path, "latin-1\n").unwrap();
+    }
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    let entries = zip_entries(&mock.calls("SYNO.FileStation.Upload", "upload")[0]);
+    let names = entries.iter().map(|e| e.0.as_str()).collect::<Vec<_>>();
+    assert!(names.iter().any(|n| n.ends_with("notes.txt")), "{names:?}");
+    assert!(!names.iter().any(|n| n.contains("bad")), "{names:?}");
+    assert_eq!(
+        err.matches("its name is not valid UTF-8").count(),
+        2,
+        "{err}"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sparse_files_are_archived_and_reported() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+    let image = std::fs::File::create(dir.path().join("data/disk.img")).unwrap();
+    image.set_len(64 << 20).unwrap();
+    drop(image);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(err.contains("1 sparse files"), "{err}");
+    assert!(err.contains("disk.img"), "{err}");
+    let entries = zip_entries(&mock.calls("SYNO.FileStation.Upload", "upload")[0]);
+    let (_, contents) = entries
+        .iter()
+        .find(|(n, _)| n.ends_with("disk.img"))
+        .unwrap();
+    assert_eq!(contents.len(), 64 << 20);
+    assert!(contents.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn parallel_archive_keeps_walk_order_and_depth_limit() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    for i in 0..40 {
+        dir.write(&format!("data/d{}/f{i:02}.txt", i % 4), &format!("file {i}\n"));
+    }
+    dir.write("data/d0/deeper/hidden.txt", "too deep\n");
+    let config = serde_json::json!({
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "share_name": "backup",
+        "usr": "tester",
+        "pwd": "secret",
+        "jobs": [
+            {"name": "serial", "filename": dir.path().join("data").to_str().unwrap(), "max_depth": 2},
+            {"name": "parallel", "filename": dir.path().join("data").to_str().unwrap(), "max_depth": 2,
+             "parallelism": 4, "max_open_files": 3},
+        ],
+    });
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    let serial = zip_entries(&uploads[0]);
+    let parallel = zip_entries(&uploads[1]);
+    assert_eq!(serial.len(), 40);
+    assert_eq!(serial, parallel);
+    assert!(!serial.iter().any(|(n, _)| n.ends_with("hidden.txt")));
+}
+
+/// Runs the backup in a mount namespace of its own with a tmpfs mounted at
+/// `data/mnt`, or `None` where such namespaces can't be made.
+#[cfg(target_os = "linux")]
+fn run_with_tmpfs(dir: &TempDir, config: &serde_json::Value) -> Option<std::process::Output> {
+    std::fs::write(dir.path().join("config.json"), config.to_string()).unwrap();
+    let script = format!(
+        "mount -t tmpfs none data/mnt && echo other filesystem > data/mnt/elsewhere.txt && exec \"{}\"",
+        env!("CARGO_BIN_EXE_synology_backuper")
+    );
+    let output = std::process::Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c", &script])
+        .env("XDG_STATE_HOME", dir.path().join("xdg/state"))
+        .current_dir(dir.path())
+        .output()
+        .ok()?;
+    let err = stderr(&output);
+    if err.contains("unshare") || err.contains("mount:") {
+        eprintln!("Skipped, as no tmpfs could be mounted: {err}");
+        return None;
+    }
+    Some(output)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn one_file_system_stops_at_a_mount_point() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    dir.write("data/here.txt", "this filesystem\n");
+    std::fs::create_dir_all(dir.path().join("data/mnt")).unwrap();
+    let data = dir.path().join("data");
+    let config = serde_json::json!({
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "share_name": "backup",
+        "usr": "tester",
+        "pwd": "secret",
+        "jobs": [
+            {"name": "all", "filename": data},
+            {"name": "serial", "filename": data, "one_file_system": true},
+            {"name": "parallel", "filename": data, "one_file_system": true, "parallelism": 4},
+        ],
+    });
+
+    let Some(output) = run_with_tmpfs(&dir, &config) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    let names = |i: usize| {
+        let mut names = zip_entries(&uploads[i])
+            .into_iter()
+            .map(|(name, _)| name.rsplit('/').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(names(0), ["elsewhere.txt", "here.txt"]);
+    assert_eq!(names(1), ["here.txt"]);
+    assert_eq!(names(2), ["here.txt"]);
+}
+
+#[test]
+fn parallel_entries_too_large_to_wait_in_memory_wait_in_a_file() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    for i in 0..6 {
+        dir.write(&format!("data/f{i}.txt"), &format!("{i}").repeat(i * 200));
+    }
+    let data = dir.path().join("data");
+    let config = serde_json::json!({
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "share_name": "backup",
+        "usr": "tester",
+        "pwd": "secret",
+        "jobs": [
+            {"name": "serial", "filename": data.to_str().unwrap()},
+            // So many open files leave each a few hundred bytes of memory.
+            {"name": "parallel", "filename": data.to_str().unwrap(),
+             "parallelism": 3, "max_open_files": 1048576},
+        ],
+    });
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    let serial = zip_entries(&uploads[0]);
+    assert_eq!(serial.len(), 6);
+    assert_eq!(serial, zip_entries(&uploads[1]));
+    let left = walkdir::WalkDir::new(dir.path())
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_name().to_string_lossy().ends_with(".spool"))
+        .count();
+    assert_eq!(left, 0);
+}
+
+#[test]
+fn deterministic_archives_list_their_files_sorted_by_path() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    for name in ["b/z.txt", "a/y.txt", "a0.txt", "a/sub/x.txt", "c.txt"] {
+        dir.write(&format!("data/{name}"), name);
+    }
+    let data = dir.path().join("data");
+    let config = serde_json::json!({
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "share_name": "backup",
+        "usr": "tester",
+        "pwd": "secret",
+        "jobs": [
+            {"name": "serial", "filename": data.to_str().unwrap()},
+            {"name": "parallel", "filename": data.to_str().unwrap(), "parallelism": 3},
+        ],
+    });
+
+    let output = run(&dir, &config, &["backup", "--deterministic"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    for upload in &uploads {
+        let names = zip_entries(upload)
+            .into_iter()
+            .map(|(n, _)| n.split("data/").nth(1).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["a/sub/x.txt", "a/y.txt", "a0.txt", "b/z.txt", "c.txt"]
+        );
+    }
+}
+
+#[test]
+fn summary_breaks_the_archive_down_by_file_type() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/a.txt", &"compressible ".repeat(1000));
+    dir.write("data/b.TXT", &"compressible ".repeat(1000));
+    dir.write("data/c.csv", "x\n");
+    dir.write("data/README", "no extension at all\n");
+    for ext in ["json", "xml", "md", "toml"] {
+        dir.write(&format!("data/d.{ext}"), "a few more bytes\n");
+    }
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let err = stderr(&output);
+    assert!(err.contains("By file type:\n  .txt: 3 files, "), "{err}");
+    assert!(err.contains("\n  (none): 1 files, "), "{err}");
+    assert!(err.contains("\n  2 other types: 2 files, "), "{err}");
+    assert!(!err.contains(".csv"), "{err}");
+
+    let output = run(&dir, &config, &["backup", "--verbose"]);
+    assert!(
+        stderr(&output).contains("\n  .csv: 1 files, "),
+        "{}",
+        stderr(&output)
+    );
+}
+
+fn compression_methods(upload: &Recorded) -> Vec<(String, zip::CompressionMethod)> {
+    let data = upload.files[0].2.clone();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+    let mut methods = (0..archive.len())
+        .map(|i| {
+            let entry = archive.by_index_raw(i).unwrap();
+            let name = entry.name().rsplit('/').next().unwrap().to_string();
+            (name, entry.compression())
+        })
+        .collect::<Vec<_>>();
+    methods.sort_by(|a, b| a.0.cmp(&b.0));
+    methods
+}
+
+#[test]
+fn stores_already_compressed_formats_without_deflating_them() {
+    use zip::CompressionMethod::{Deflated, Stored};
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/photo.JPG", "not really a jpeg\n");
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        compression_methods(upload),
+        [("notes.txt".into(), Deflated), ("photo.JPG".into(), Stored)]
+    );
+
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = serde_json::json!([{
+        "name": "data",
+        "filename": source,
+        "store_extensions": ["txt"],
+        "parallelism": 2,
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[1];
+    assert_eq!(
+        compression_methods(upload),
+        [("notes.txt".into(), Stored), ("photo.JPG".into(), Deflated)]
+    );
+}
+
+#[test]
+fn compresses_with_the_codec_the_job_names() {
+    use zip::CompressionMethod::{Stored, Zstd};
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/photo.jpg", "not really a jpeg\n");
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = serde_json::json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "compression": "zstd:3",
+    }]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        compression_methods(upload),
+        [("notes.txt".into(), Zstd), ("photo.jpg".into(), Stored)]
+    );
+    let entries = zip_entries(upload);
+    assert!(entries
+        .iter()
+        .any(|(name, contents)| name.ends_with("notes.txt")
+            && contents == b"hello from the backuper tests\n"));
+
+    config["jobs"][0]["compression"] = "deflate:12".into();
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("deflate"), "{}", stderr(&output));
+}
+
+#[test]
+fn skipped_files_are_counted_and_listed_in_the_report() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/scratch.tmp", "temporary\n");
+    dir.write("data/cache/blob", "cached\n");
+    dir.write("data/big.bin", &"x".repeat(2000));
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = serde_json::json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "exclude": ["*.tmp", "cache"],
+        "max_file_size": "1KB",
+    }]);
+    let report = dir.path().join("skipped.tsv");
+
+    let output = run(
+        &dir,
+        &config,
+        &["backup", "--report", report.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Skipped 2 excluded by `exclude`, 1 larger than max_file_size"),
+        "{}",
+        stderr(&output)
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let names = zip_entries(upload)
+        .into_iter()
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
+    assert!(names.iter().all(|n| n.ends_with("notes.txt")), "{names:?}");
+
+    let report = std::fs::read_to_string(report).unwrap();
+    let rows = report
+        .lines()
+        .map(|l| {
+            let fields = l.split('\t').collect::<Vec<_>>();
+            let name = fields[2].rsplit(['/', '\\']).next().unwrap();
+            format!("{} {} {name} {}", fields[0], fields[1], fields[3])
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            "data excluded cache ",
+            "data excluded scratch.tmp ",
+            "data too_large big.bin 2000"
+        ]
+    );
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+fn local_backup(dir: &TempDir) -> (serde_json::Value, PathBuf) {
+    let mock = MockDsm::start();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let mut config = base_config(&mock, dir);
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+    let output = run(dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let archive = std::fs::read_dir(root.join("backup"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    (config, archive)
+}
+
+fn log(dir: &TempDir) -> Vec<serde_json::Value> {
+    let path = dir.path().join("xdg/state/synology_backuper/runs.jsonl");
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+fn name(path: &Path) -> String {
+    path.file_name().unwrap().to_str().unwrap().to_string()
+}
+
+#[test]
+fn audit_catches_a_changed_and_a_deleted_archive() {
+    let dir = TempDir::new();
+    let (config, archive) = local_backup(&dir);
+
+    let output = run(&dir, &config, &["audit"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains(&format!("primary:/backup/{} matches", name(&archive))),
+        "{}",
+        stdout(&output)
+    );
+
+    std::fs::write(&archive, b"PK\x03\x04 bit rot").unwrap();
+    let output = run(&dir, &config, &["audit"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("differs from what was uploaded"));
+
+    std::fs::remove_file(&archive).unwrap();
+    let output = run(&dir, &config, &["audit"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains(&format!("primary:/backup/{} is gone", name(&archive))),
+        "{}",
+        stderr(&output)
+    );
+
+    let audits = log(&dir)
+        .into_iter()
+        .filter(|x| x.get("audit").is_some())
+        .collect::<Vec<_>>();
+    let results = audits
+        .iter()
+        .map(|x| x["result"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(results, ["ok", "alert", "alert"]);
+}
+
+#[test]
+fn daemon_audits_every_audit_interval() {
+    let dir = TempDir::new();
+    let (mut config, _) = local_backup(&dir);
+    config["audit_interval"] = json!("1s");
+    std::fs::write(dir.path().join("config.json"), config.to_string()).unwrap();
+
+    let mut daemon = std::process::Command::new(env!("CARGO_BIN_EXE_synology_backuper"))
+        .arg("daemon")
+        .env("XDG_STATE_HOME", dir.path().join("xdg/state"))
+        .current_dir(dir.path())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let audited = loop {
+        if log(&dir)
+            .iter()
+            .any(|x| x["result"] == "ok" && x.get("audit").is_some())
+        {
+            break true;
+        }
+        if Instant::now() > deadline {
+            break false;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    };
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    assert!(audited);
+}
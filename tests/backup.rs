@@ -0,0 +1,1549 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn uploads_archive_to_configured_share() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let logins = mock.calls("SYNO.API.Auth", "login");
+    assert_eq!(logins.len(), 1);
+    assert_eq!(logins[0].params["account"], "tester");
+    assert_eq!(logins[0].params["passwd"], "secret");
+
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 1);
+    let upload = &uploads[0];
+    assert_eq!(upload.path, "webapi/entry.cgi");
+    assert_eq!(upload.params["path"], "/backup");
+    assert_eq!(upload.params["overwrite"], "true");
+    let (field, file_name, contents) = &upload.files[0];
+    assert_eq!(field, "file");
+    assert!(file_name.starts_with("notes.txt_"), "{file_name}");
+    assert!(file_name.ends_with(".zip"), "{file_name}");
+    assert_eq!(&contents[..4], b"PK\x03\x04");
+
+    assert_eq!(mock.calls("SYNO.API.Auth", "logout").len(), 1);
+}
+
+#[test]
+fn upload_announces_content_length() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        upload.headers.get("content-length"),
+        Some(&upload.body_len.to_string())
+    );
+    assert!(!upload.headers.contains_key("transfer-encoding"));
+}
+
+#[test]
+fn uses_api_paths_from_info_query() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.API.Auth"]["path"] = json!("custom_auth.cgi");
+    mock.set_api_info(info);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        mock.calls("SYNO.API.Auth", "login")[0].path,
+        "webapi/custom_auth.cgi"
+    );
+}
+
+#[test]
+fn refuses_auth_api_without_a_supported_version() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.API.Auth"]["maxVersion"] = json!(2);
+    mock.set_api_info(info);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("needs one of 3 to 7"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.calls("SYNO.API.Auth", "login").is_empty());
+}
+
+#[test]
+fn adapts_the_login_to_the_dsm_release() {
+    for (max_version, device_token) in [(6, None), (7, Some("no"))] {
+        let mock = MockDsm::start();
+        let mut info = default_api_info();
+        info["SYNO.API.Auth"]["maxVersion"] = json!(max_version);
+        mock.set_api_info(info);
+        let dir = TempDir::new();
+        let config = base_config(&mock, &dir);
+
+        let output = run(&dir, &config, &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let login = &mock.calls("SYNO.API.Auth", "login")[0];
+        assert_eq!(login.params["version"], max_version.to_string());
+        assert_eq!(login.params["session"], "FileStation");
+        assert_eq!(
+            login.params.get("enable_device_token").map(String::as_str),
+            device_token
+        );
+        let logout = &mock.calls("SYNO.API.Auth", "logout")[0];
+        assert_eq!(logout.params["version"], max_version.to_string());
+    }
+}
+
+#[test]
+fn maps_login_errors_to_messages() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.API.Auth", "login", err(400));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("400 - No such account or incorrect password"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+}
+
+#[test]
+fn maps_upload_errors_to_messages() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.FileStation.Upload", "upload", err(1805));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let out = stdout(&output);
+    assert!(
+        out.contains("1805 - Can't overwrite or skip the existed file"),
+        "{out}"
+    );
+    assert_eq!(mock.calls("SYNO.API.Auth", "logout").len(), 1);
+}
+
+#[test]
+fn removes_what_a_failed_upload_left_behind() {
+    let mock = MockDsm::start();
+    mock.once(
+        "SYNO.FileStation.Upload",
+        "upload",
+        Reply::Raw(502, "Bad Gateway".into()),
+    );
+    mock.once("SYNO.FileStation.List", "list", Reply::Truncated(100));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    let out = stdout(&output);
+    assert!(
+        out.contains("Removed the partial upload primary:/backup/notes.txt_"),
+        "{out}"
+    );
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        delete.params["path"],
+        format!("[\"/backup/{}\"]", upload.files[0].1)
+    );
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let logged = serde_json::from_str::<serde_json::Value>(&log).unwrap();
+    let partial = &logged["jobs"][0]["partial_uploads"][0];
+    assert_eq!(partial["bytes"], 100);
+    assert_eq!(partial["removed"], true);
+
+    // Nothing was left this time, so there is nothing to delete.
+    mock.once("SYNO.FileStation.Upload", "upload", err(1800));
+    run(&dir, &config, &[]);
+    assert_eq!(mock.calls("SYNO.FileStation.Delete", "start").len(), 1);
+}
+
+#[test]
+fn uploads_for_accounts_that_may_not_list_shares() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.FileStation.List", "list_share", err(105));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("tester may not list the shares on 127.0.0.1"),
+        "{}",
+        stderr(&output)
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.params["path"], "/backup");
+
+    config["shares"] = json!(["backup"]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        !stderr(&output).contains("may not list"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(mock.calls("SYNO.FileStation.List", "list_share").len(), 1);
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 2);
+}
+
+#[test]
+fn reports_missing_share() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["share_name"] = json!("nonexistent");
+
+    let output = run(&dir, &config, &[]);
+    let out = stdout(&output);
+    assert!(out.contains("Share not found"), "{out}");
+    assert!(out.contains("shares on primary: backup, photo"), "{out}");
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+}
+
+#[test]
+fn uploads_into_the_fallback_share_when_the_share_is_missing() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["share_name"] = json!("nonexistent");
+    config["fallback_share"] = json!("backup");
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let create = &mock.calls("SYNO.FileStation.CreateFolder", "create")[0];
+    assert_eq!(create.params["folder_path"], r#"["/backup"]"#);
+    assert_eq!(create.params["name"], r#"["nonexistent"]"#);
+    assert_eq!(create.params["force_parent"], "true");
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.params["path"], "/backup/nonexistent");
+}
+
+#[test]
+fn upload_rate_limit_slows_the_upload() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    // Incompressible data, so the archive stays about as large as the file.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let noise = (0..300_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    let source = dir.path().join("data/noise.bin");
+    std::fs::write(&source, noise).unwrap();
+    let job = serde_json::json!({
+        "name": "slow",
+        "filename": source.to_str().unwrap(),
+        "upload_rate_limit": "200KiB/s",
+        "nice": 10,
+    });
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = serde_json::json!([job]);
+
+    let started = std::time::Instant::now();
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(1200));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn uploads_say_how_long_they_are_up_front() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // DSM doesn't take chunked uploads, so the body has its length announced.
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        upload.headers.get("content-length"),
+        Some(&upload.body_len.to_string()),
+        "{:?}",
+        upload.headers
+    );
+    assert!(!upload.headers.contains_key("transfer-encoding"));
+}
+
+#[test]
+fn rejects_an_invalid_ionice_class() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = serde_json::json!([
+        {"name": "x", "filename": "data", "ionice": "best-effort 9"}
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("0 to 7"), "{}", stderr(&output));
+}
+
+#[test]
+fn max_duration_aborts_a_slow_upload_and_cleans_up() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let noise = (0..400_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    let source = dir.path().join("data/noise.bin");
+    std::fs::write(&source, noise).unwrap();
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = serde_json::json!([{
+        "name": "slow",
+        "filename": source.to_str().unwrap(),
+        "upload_rate_limit": "100KiB",
+        "max_duration": "1s",
+    }]);
+    // Without a listing there's no telling what arrived, so the delete is tried.
+    mock.on("SYNO.FileStation.List", "list", err(408));
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(
+        err.contains("Job slow timed out after 1s while uploading"),
+        "{err}"
+    );
+    assert!(err.contains("was removed"), "{err}");
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert!(
+        delete.params["path"].starts_with(r#"["/backup/noise.bin_"#),
+        "{:?}",
+        delete.params
+    );
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let logged = serde_json::from_str::<serde_json::Value>(&log).unwrap();
+    let partial = &logged["jobs"][0]["partial_uploads"][0];
+    assert_eq!(partial["removed"], true);
+
+    // A delete that fails is reported as such.
+    mock.on("SYNO.FileStation.Delete", "start", common::err(900));
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(err.contains("could not be removed"), "{err}");
+    assert!(!err.contains("was removed"), "{err}");
+}
+
+#[test]
+fn checks_write_permission_before_archiving() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.FileStation.CheckPermission", "write", err(105));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(err.contains("nothing was archived"), "{err}");
+    assert!(err.contains("cannot write to /backup: 105"), "{err}");
+    assert!(!dir.path().join("data/notes.txt.zip").exists());
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+    let check = &mock.calls("SYNO.FileStation.CheckPermission", "write")[0];
+    assert_eq!(check.params["path"], "/backup");
+    assert!(check.params["filename"].starts_with("notes.txt_"));
+}
+
+#[test]
+fn skips_the_permission_check_on_old_dsm() {
+    let mock = MockDsm::start();
+    mock.remove_api("SYNO.FileStation.CheckPermission");
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn verify_catches_a_corrupted_upload() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.Download",
+        "download",
+        Reply::File(b"PK\x03\x04 not what was sent".to_vec()),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "verify": true}]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("differs from the archive"),
+        "{}",
+        stdout(&output)
+    );
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert!(delete.params["path"].starts_with(r#"["/backup/notes.txt_"#));
+}
+
+#[test]
+fn verify_compares_with_the_hash_taken_while_writing() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    dir.write("data/a.txt", "before\n");
+    // Larger than what is held back, so its header is rewritten after it was hashed.
+    let mut state = 1u64;
+    let photo = (0..24 << 20)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 56) as u8
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(dir.path().join("data/b.jpg"), &photo).unwrap();
+    dir.write("data/c.txt", "after\n");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "parallelism": 1,
+        "verify": true,
+    }]);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert_eq!(mock.calls("SYNO.FileStation.Download", "download").len(), 1);
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let mut names = zip_entries(upload)
+        .into_iter()
+        .map(|(name, _)| name.rsplit('/').next().unwrap().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a.txt", "b.jpg", "c.txt", "notes.txt"]);
+}
+
+#[test]
+fn backs_up_only_the_job_named_with_job() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    let photos = dir.write("data/photo.jpg", "not really a photo\n");
+    config["jobs"] = json!([
+        {"name": "notes", "filename": notes},
+        {"name": "photos", "filename": photos},
+    ]);
+
+    let output = run(&dir, &config, &["backup", "--job", "photos"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 1);
+    assert!(uploads[0].files[0].1.starts_with("photo.jpg_"));
+
+    let output = run(&dir, &config, &["backup", "--all"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 3);
+
+    let output = run(&dir, &config, &["backup", "--job", "music"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("There is no job named music"));
+}
+
+#[test]
+fn runs_jobs_after_their_prerequisites_and_skips_them_on_failure() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    let dump = dir.write("data/db.sql", "create table t;\n");
+    config["jobs"] = json!([
+        {"name": "notes", "filename": notes, "after": ["dump"]},
+        {"name": "dump", "filename": dump},
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert!(uploads[0].files[0].1.starts_with("db.sql_"));
+    assert!(uploads[1].files[0].1.starts_with("notes.txt_"));
+
+    config["jobs"][1]["share_name"] = json!("nonexistent");
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stdout(&output).contains("Skipping job notes: job dump did not succeed"),
+        "{}",
+        stdout(&output)
+    );
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 2);
+
+    config["jobs"][1]["after"] = json!(["notes"]);
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("in a cycle"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_the_hooks_with_the_outcome_in_the_environment() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {
+            "name": "notes",
+            "filename": notes,
+            "on_success": r#"echo "$SYNOLOGY_BACKUPER_JOB $SYNOLOGY_BACKUPER_UPLOADED" > success.txt"#,
+        },
+        {
+            "name": "lost",
+            "filename": notes,
+            "share_name": "nonexistent",
+            "on_failure": r#"echo "$SYNOLOGY_BACKUPER_JOB: $SYNOLOGY_BACKUPER_ERROR" > failure.txt"#,
+        },
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    let success = std::fs::read_to_string(dir.path().join("success.txt")).unwrap();
+    assert!(success.starts_with("notes /backup/notes.txt_"), "{success}");
+    let failure = std::fs::read_to_string(dir.path().join("failure.txt")).unwrap();
+    assert!(
+        failure.starts_with("lost: can't upload anywhere: primary: share nonexistent not found"),
+        "{failure}"
+    );
+}
+
+#[test]
+fn warns_when_an_archive_is_much_smaller_than_the_recent_ones() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": notes}]);
+    let state = dir.path().join("xdg/state/synology_backuper");
+    std::fs::create_dir_all(&state).unwrap();
+    let earlier = (0..3)
+        .map(|_| {
+            let now = chrono::Utc::now().to_rfc3339();
+            json!({"start": now, "end": now, "result": "ok", "jobs": [{
+                "job": "notes", "result": "ok", "start": now, "end": now,
+                "archive_bytes": 1_000_000, "uploaded": [],
+            }]})
+            .to_string()
+                + "\n"
+        })
+        .collect::<String>();
+    std::fs::write(state.join("runs.jsonl"), earlier).unwrap();
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let err = stderr(&output);
+    assert!(
+        err.contains("Warning for job notes: the archive is 100% smaller than the average of the last 3 (976.6 KiB); is the source path right"),
+        "{err}"
+    );
+    let log = std::fs::read_to_string(state.join("runs.jsonl")).unwrap();
+    let last = serde_json::from_str::<serde_json::Value>(log.lines().last().unwrap()).unwrap();
+    assert!(last["jobs"][0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("smaller"));
+
+    // 0 turns the warning off.
+    config["jobs"][0]["size_anomaly"] = json!(0);
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        !stderr(&output).contains("Warning for job"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn fails_instead_of_uploading_a_near_empty_archive() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write("data/a.txt", "a");
+    config.as_object_mut().unwrap().remove("filename");
+    let data = dir.path().join("data");
+    config["jobs"] = json!([{"name": "data", "filename": data, "min_files": 5}]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("archived 2 files, fewer than min_files 5"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+    assert!(!dir.path().join("data.zip").exists());
+
+    config["jobs"][0]["min_files"] = json!(2);
+    config["jobs"][0]["min_size"] = json!("1MB");
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("less than min_size"),
+        "{}",
+        stderr(&output)
+    );
+
+    config["jobs"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("min_size");
+    config["jobs"][0]["require_marker_file"] = json!(".mounted");
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains(".mounted is missing"),
+        "{}",
+        stderr(&output)
+    );
+
+    dir.write("data/.mounted", "");
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn preconditions_fail_the_job_before_it_archives() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    let data = dir.path().join("data");
+    config["jobs"] = json!([{"name": "notes", "filename": notes}]);
+
+    for (precondition, error) in [
+        (json!({"mountpoint": data}), "is not a mount point"),
+        (
+            json!({"exists": data.join("missing")}),
+            "missing does not exist",
+        ),
+        (json!({"command": "exit 3"}), "exit 3 failed"),
+    ] {
+        config["jobs"][0]["preconditions"] = json!([{"command": "true"}, precondition]);
+        let output = run(&dir, &config, &[]);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+        assert!(
+            stderr(&output).contains("a precondition failed, so nothing was archived"),
+            "{}",
+            stderr(&output)
+        );
+        assert!(stderr(&output).contains(error), "{}", stderr(&output));
+        assert!(!dir.path().join("data/notes.txt.zip").exists());
+    }
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+
+    config["jobs"][0]["preconditions"] = json!([{"mountpoint": "/"}, {"exists": data}]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn defers_jobs_on_a_metered_connection() {
+    use std::os::unix::fs::PermissionsExt;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "notes", "filename": notes, "skip_metered": true},
+        {"name": "after_notes", "filename": notes, "after": ["notes"]},
+    ]);
+    // NetworkManager, as busctl asks it, says the connection is metered.
+    dir.write("bin/busctl", "#!/bin/sh\necho 'u 1'\n");
+    let busctl = dir.path().join("bin/busctl");
+    std::fs::set_permissions(&busctl, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        dir.path().join("bin").display(),
+        std::env::var("PATH").unwrap()
+    );
+
+    let output = run_env(&dir, &config, &[], &[("PATH", &path)]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.contains("Deferring job notes: on a metered connection and skip_metered is set"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Deferring job after_notes: job notes was deferred"),
+        "{out}"
+    );
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let logged = serde_json::from_str::<serde_json::Value>(&log).unwrap();
+    assert_eq!(logged["jobs"][0]["result"], "deferred");
+    // Its dependent waits along with it, which the run's status shows too.
+    assert_eq!(logged["jobs"][1]["result"], "deferred");
+    assert_eq!(logged["jobs"][1]["error"], "job notes was deferred");
+    assert_eq!(logged["result"], "deferred");
+    assert!(
+        stderr(&output).contains("Jobs deferred for later: notes, after_notes\n  notes: on a metered connection and skip_metered is set\n  after_notes: job notes was deferred"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = run_env(&dir, &config, &["backup", "--force"], &[("PATH", &path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 2);
+}
+
+#[test]
+fn defers_jobs_outside_their_windows_or_in_a_blackout() {
+    use chrono::Timelike;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    let hour = (chrono::Local::now().hour() + 2) % 24;
+    let window = format!("{hour:02}:00-{:02}:00", (hour + 1) % 24);
+    config["jobs"] = json!([
+        {"name": "notes", "filename": notes, "windows": [window]},
+        {"name": "never", "filename": notes, "blackout": ["00:00-00:00"]},
+    ]);
+
+    // Nothing failed, but nothing was backed up either.
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.contains(&format!(
+            "Deferring job notes: outside its windows {window}"
+        )),
+        "{out}"
+    );
+    assert!(
+        out.contains("Deferring job never: in its blackout 00:00-00:00"),
+        "{out}"
+    );
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let logged = serde_json::from_str::<serde_json::Value>(&log).unwrap();
+    assert_eq!(logged["result"], "deferred");
+
+    let output = run(&dir, &config, &["backup", "--job", "notes", "--force"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn keeps_going_after_a_failed_job_and_names_it_in_the_summary() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "lost", "filename": notes, "share_name": "nonexistent"},
+        {"name": "notes", "filename": notes},
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+    assert!(
+        stderr(&output).contains(
+            "Jobs that reached no target: lost\n  lost: can't upload anywhere: primary: share nonexistent not found"
+        ),
+        "{}",
+        stderr(&output)
+    );
+
+    config["stop_on_error"] = json!(true);
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+    assert!(stderr(&output).contains("Jobs not run because stop_on_error is set: notes"));
+}
+
+#[test]
+fn appends_each_run_to_the_run_log() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "notes", "filename": notes},
+        {"name": "lost", "filename": notes, "share_name": "nonexistent"},
+    ]);
+
+    run(&dir, &config, &["backup", "--job", "notes"]);
+    run(&dir, &config, &[]);
+
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let runs = log
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0]["result"], "ok");
+    let job = &runs[0]["jobs"][0];
+    assert_eq!(job["job"], "notes");
+    assert_eq!(job["files"], 1);
+    assert!(job["archive_bytes"].as_u64().unwrap() > 0);
+    assert!(job["uploaded"][0]
+        .as_str()
+        .unwrap()
+        .starts_with("primary:/backup/notes.txt_"));
+
+    assert_eq!(runs[1]["result"], "partial");
+    assert_eq!(runs[1]["jobs"][1]["result"], "failed");
+    assert!(runs[1]["jobs"][1]["error"]
+        .as_str()
+        .unwrap()
+        .contains("share nonexistent not found"));
+}
+
+#[test]
+fn names_the_package_behind_a_missing_api() {
+    let mock = MockDsm::start();
+    mock.remove_api("SYNO.FileStation.Upload");
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(!err.contains("panicked"), "{err}");
+    assert!(
+        err.contains("The NAS doesn't offer SYNO.FileStation.Upload. It comes with File Station"),
+        "{err}"
+    );
+    assert!(mock.calls("SYNO.API.Auth", "login").is_empty());
+}
+
+#[test]
+fn tries_again_after_a_gateway_error() {
+    let mock = MockDsm::start();
+    mock.once(
+        "SYNO.API.Auth",
+        "login",
+        Reply::Raw(502, "<html><title>502 Bad Gateway</title></html>".into()),
+    );
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("SYNO.API.Auth login: HTTP 502: <html><title>502 Bad Gateway</title></html>; trying again"),
+        "{err}"
+    );
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 2);
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn reports_http_errors_with_the_start_of_the_body() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list_share",
+        Reply::Raw(500, format!("Internal error {}", "x".repeat(300))),
+    );
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(!err.contains("panicked"), "{err}");
+    assert!(
+        err.contains("SYNO.FileStation.List list_share: HTTP 500: Internal error xxx"),
+        "{err}"
+    );
+    assert!(!err.contains(&"x".repeat(250)), "{err}");
+    assert!(!err.contains("trying again"), "{err}");
+    assert_eq!(mock.calls("SYNO.FileStation.List", "list_share").len(), 1);
+}
+
+#[test]
+fn logs_in_once_for_all_jobs_unless_told_otherwise() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "first", "filename": notes},
+        {"name": "second", "filename": notes},
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 2);
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 1);
+    assert_eq!(mock.calls("SYNO.API.Auth", "logout").len(), 1);
+
+    config["reuse_session"] = json!(false);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 4);
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 3);
+    assert_eq!(mock.calls("SYNO.API.Auth", "logout").len(), 3);
+}
+
+#[test]
+fn stops_logging_in_after_repeated_refusals() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.API.Auth", "login", err(400));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    for count in 1..=3 {
+        let output = run(&dir, &config, &[]);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+        assert!(
+            stderr(&output).contains(&format!("was refused, {count} times in a row")),
+            "{}",
+            stderr(&output)
+        );
+    }
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 3);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("the last 3 logins were refused, so none is tried until"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 3);
+}
+
+#[test]
+fn stop_on_auth_failure_leaves_the_remaining_jobs_alone() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.API.Auth", "login", err(400));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "first", "filename": notes},
+        {"name": "second", "filename": notes},
+    ]);
+    config["stop_on_auth_failure"] = json!(true);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Jobs not run because stop_on_auth_failure is set: second"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(mock.calls("SYNO.API.Auth", "login").len(), 1);
+}
+
+#[test]
+fn reaches_a_nas_by_its_ipv6_address() {
+    let mock = MockDsm::start_on("[::1]:0");
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["domain"] = json!("::1");
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+
+    config["domain"] = json!("[::1]");
+    let output = run(&dir, &config, &["doctor"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}");
+    let row = out.lines().find(|l| l.starts_with("TCP connect")).unwrap();
+    assert!(
+        row.contains(&format!("connected to [::1]:{}", mock.port())),
+        "{row}"
+    );
+}
+
+#[test]
+fn resolves_host_names_from_the_config() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["domain"] = json!("nas.example.invalid");
+    config["resolve"] = json!({"nas.example.invalid": "127.0.0.1"});
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+
+    let output = run(&dir, &config, &["doctor"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}");
+    let row = out
+        .lines()
+        .find(|l| l.starts_with("DNS resolution"))
+        .unwrap();
+    assert!(
+        row.contains("nas.example.invalid -> 127.0.0.1 (from resolve)"),
+        "{row}"
+    );
+}
+
+#[test]
+fn trusts_a_self_signed_certificate_by_its_pinned_key() {
+    let mock = MockDsm::start_tls();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["https"] = json!(true);
+    config["pin_sha256"] = json!([format!("sha256//{MOCK_PIN}")]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+
+    config["pin_sha256"] = json!(["AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]);
+    let output = run(&dir, &config, &["doctor"]);
+    let out = stdout(&output);
+    assert!(!output.status.success(), "{out}");
+    let row = out
+        .lines()
+        .find(|l| l.starts_with("TLS handshake"))
+        .unwrap();
+    assert!(
+        row.contains(&format!("sha256//{MOCK_PIN}, is not in pin_sha256")),
+        "{row}"
+    );
+    assert_eq!(mock.calls("SYNO.API.Info", "query").len(), 1);
+}
+
+#[test]
+fn identifies_itself_in_every_request() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+
+    run(&dir, &config, &[]);
+    let agent = &mock.requests()[0].headers["user-agent"];
+    assert!(agent.starts_with("synology_backuper/"), "{agent}");
+
+    config["user_agent"] = json!("nightly-backup");
+    config["headers"] = json!({"X-Proxy-Token": "abc123"});
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = mock
+        .calls("SYNO.FileStation.Upload", "upload")
+        .pop()
+        .unwrap();
+    assert_eq!(upload.headers["user-agent"], "nightly-backup");
+    assert_eq!(upload.headers["x-proxy-token"], "abc123");
+
+    config["headers"] = json!({"Bad Name": "x"});
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("has a header \"Bad Name\", which is not a valid name"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn a_write_only_job_never_lists_deletes_or_replaces() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let filename = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{
+        "name": "default",
+        "filename": filename,
+        "write_only": true,
+        "keep_last": 3,
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("Job default is write_only, so its archives are never pruned"),
+        "{}",
+        stderr(&output)
+    );
+
+    config["jobs"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("keep_last");
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.params["overwrite"], "false");
+
+    mock.once(
+        "SYNO.FileStation.Upload",
+        "upload",
+        Reply::Raw(502, "Bad Gateway".into()),
+    );
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("write_only leaves whatever arrived of /backup/notes.txt_"),
+        "{}",
+        stderr(&output)
+    );
+
+    for command in ["list", "prune"] {
+        let output = run(&dir, &config, &[command]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(
+            stderr(&output)
+                .contains("Job default is write_only, so its archives are never listed or deleted"),
+            "{}",
+            stderr(&output)
+        );
+    }
+    let output = run(&dir, &config, &["restore", "--name", &upload.files[0].1]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("job default is write_only"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.calls("SYNO.FileStation.List", "list").is_empty());
+    assert!(mock.calls("SYNO.FileStation.Delete", "start").is_empty());
+}
+
+#[test]
+fn a_write_once_share_gets_new_names_instead_of_replaced_files() {
+    let mock = MockDsm::start();
+    mock.once("SYNO.FileStation.Upload", "upload", err(407));
+    mock.once("SYNO.FileStation.Upload", "upload", err(414));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 3);
+    assert_eq!(uploads[0].params["overwrite"], "true");
+    assert_eq!(uploads[1].params["overwrite"], "false");
+    assert_eq!(uploads[1].files[0].1, uploads[0].files[0].1);
+    assert_ne!(uploads[2].files[0].1, uploads[1].files[0].1);
+    assert!(
+        stderr(&output).contains(&format!(
+            "/backup/{} exists already and isn't replaced; uploading as {}",
+            uploads[1].files[0].1, uploads[2].files[0].1
+        )),
+        "{}",
+        stderr(&output)
+    );
+    assert!(dir
+        .path()
+        .join("xdg/state/synology_backuper/worm.json")
+        .exists());
+
+    // Later runs know and don't try replacing.
+    assert!(run(&dir, &config, &[]).status.success());
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads[3].params["overwrite"], "false");
+}
+
+#[test]
+fn a_synthetic_full_copies_the_last_full_on_the_nas() {
+    use chrono::Datelike;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    dir.write("data/kept.txt", "the same all week");
+    dir.write("data/changed.txt", "as on sunday");
+    // Today, which would make a full archive without synthetic_full.
+    let full_on = chrono::Local::now().weekday().to_string();
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "differential": full_on,
+        "synthetic_full": 6,
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let full = mock.calls("SYNO.FileStation.Upload", "upload")[0].files[0]
+        .1
+        .clone();
+
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    dir.write("data/changed.txt", "changed on monday");
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains(&format!(
+            "making a synthetic full archive, of a copy of {full}"
+        )),
+        "{err}"
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[1];
+    let synthetic = upload.files[0].1.clone();
+    let mut entries = zip_entries(upload)
+        .into_iter()
+        .map(|(name, contents)| {
+            let name = name.rsplit('/').next().unwrap().to_string();
+            (name, String::from_utf8(contents).unwrap())
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(entries.len(), 2, "{entries:?}");
+    assert_eq!(
+        entries[1],
+        ("changed.txt".into(), "changed on monday".into())
+    );
+    let volumes = format!("{}.volumes", synthetic.strip_suffix(".zip").unwrap());
+    assert!(entries[0].1.contains(&volumes), "{}", entries[0].1);
+
+    let copy = &mock.calls("SYNO.FileStation.CopyMove", "start")[0];
+    assert_eq!(
+        copy.params["path"],
+        json!([format!("/backup/{full}")]).to_string()
+    );
+    assert_eq!(
+        copy.params["dest_folder_path"],
+        format!("/backup/{volumes}")
+    );
+    let chain =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/chain.json")).unwrap();
+    let chain: serde_json::Value = serde_json::from_str(&chain).unwrap();
+    assert_eq!(chain[1]["kind"], "full");
+    assert_eq!(chain[1]["base"], serde_json::Value::Null);
+    assert_eq!(
+        chain[1]["volumes"],
+        json!([format!("primary:/backup/{volumes}/{full}")])
+    );
+    // Written next to itself and renamed into place, with nothing left over.
+    let state = std::fs::read_dir(dir.path().join("xdg/state/synology_backuper"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert!(
+        !state.iter().any(|name| name.starts_with(".chain.json")),
+        "{state:?}"
+    );
+}
+
+#[test]
+fn writes_each_jobs_last_run_for_other_tools_to_poll() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+    let jobs = dir.path().join("xdg/state/synology_backuper/jobs/default");
+    let read = || {
+        let text = std::fs::read_to_string(jobs.join("last_run.json")).unwrap();
+        serde_json::from_str::<serde_json::Value>(&text).unwrap()
+    };
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let last = read();
+    assert_eq!(last["job"], "default");
+    assert_eq!(last["status"], "ok");
+    assert_eq!(last["last_success"], last["end"]);
+    assert!(last["error"].is_null());
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(
+        last["uploaded"][0],
+        format!("primary:/backup/{}", upload.files[0].1)
+    );
+
+    mock.once("SYNO.API.Auth", "login", err(400));
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    let failed = read();
+    assert_ne!(failed["status"], "ok");
+    assert!(failed["error"].as_str().is_some(), "{failed}");
+    assert_eq!(failed["last_success"], last["end"]);
+    // Nothing is left of the file it was written to before the rename.
+    assert_eq!(std::fs::read_dir(&jobs).unwrap().count(), 1);
+}
+
+#[test]
+fn verify_local_finds_files_that_changed_without_being_written() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let rotted = dir.write("data/rotted.txt", "the original contents");
+    let edited = dir.write("data/edited.txt", "a first draft");
+    let deleted = dir.write("data/deleted.txt", "soon gone");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "manifest": true,
+    }]);
+    config.as_object_mut().unwrap().remove("filename");
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = run(&dir, &config, &["verify-local"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("4 intact, 0 changed since, 0 gone, 0 damaged"),
+        "{}",
+        stdout(&output)
+    );
+
+    // Same size and modification time, other contents: what a flipped bit looks like.
+    let modified = std::fs::metadata(&rotted).unwrap().modified().unwrap();
+    std::fs::write(&rotted, "the original centents").unwrap();
+    let file = std::fs::File::options().write(true).open(&rotted).unwrap();
+    file.set_modified(modified).unwrap();
+    drop(file);
+    std::fs::write(&edited, "a second, longer draft").unwrap();
+    std::fs::remove_file(&deleted).unwrap();
+    let output = run(&dir, &config, &["verify-local", "--parallelism", "2"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.contains("1 intact, 1 changed since, 1 gone, 1 damaged"),
+        "{out}"
+    );
+    assert!(
+        out.contains(&format!("{}: its contents differ", rotted.display())),
+        "{out}"
+    );
+    assert!(!out.contains(&format!("{}:", edited.display())), "{out}");
+
+    let output = run(&dir, &config, &["verify-local", "--parallelism", "0"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("--parallelism takes a number"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn archives_from_a_snapshot_under_the_files_own_names() {
+    use std::os::unix::fs::PermissionsExt;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let data = dir.path().join("data");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": data.to_str().unwrap(),
+        "snapshot": {"type": "btrfs", "mountpoint": dir.path().to_str().unwrap()},
+    }]);
+    // A btrfs that copies the folder, and marks the copy to tell it apart.
+    dir.write(
+        "bin/btrfs",
+        r#"#!/bin/sh
+echo "$@" >> "$BTRFS_LOG"
+[ -n "$BTRFS_FAIL" ] && { echo "not a btrfs subvolume" >&2; exit 1; }
+case "$2" in
+  snapshot) mkdir "$5" && cp -a "$4/data" "$5/data" && echo "from the snapshot" > "$5/data/notes.txt" ;;
+  delete) rm -rf "$3" ;;
+esac
+"#,
+    );
+    let btrfs = dir.path().join("bin/btrfs");
+    std::fs::set_permissions(&btrfs, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        dir.path().join("bin").display(),
+        std::env::var("PATH").unwrap()
+    );
+    let log = dir.path().join("btrfs.log");
+    let env = [
+        ("PATH", path.as_str()),
+        ("BTRFS_LOG", log.to_str().unwrap()),
+    ];
+
+    let output = run_env(&dir, &config, &[], &env);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entries = zip_entries(upload);
+    assert_eq!(
+        entries.len(),
+        1,
+        "{:?}",
+        entries.iter().map(|e| &e.0).collect::<Vec<_>>()
+    );
+    let (name, contents) = &entries[0];
+    let notes = data.join("notes.txt");
+    assert_eq!(name, notes.to_str().unwrap().trim_start_matches('/'));
+    assert_eq!(contents, b"from the snapshot\n");
+    let snapshot = dir.path().join(".synology_backuper_data");
+    assert!(!snapshot.exists());
+    let calls = std::fs::read_to_string(&log).unwrap();
+    let snapshot = snapshot.to_str().unwrap();
+    assert_eq!(
+        calls,
+        format!(
+            "subvolume snapshot -r {} {snapshot}\nsubvolume delete {snapshot}\n",
+            dir.path().display()
+        )
+    );
+
+    let env = [env[0], env[1], ("BTRFS_FAIL", "1")];
+    let output = run_env(&dir, &config, &[], &env);
+    assert!(!output.status.success());
+    let err = stderr(&output);
+    assert!(
+        err.contains("could not take the btrfs snapshot, so nothing was archived"),
+        "{err}"
+    );
+    assert!(err.contains("not a btrfs subvolume"), "{err}");
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn docker_jobs_archive_the_volumes_of_stopped_containers_and_record_their_images() {
+    use std::os::unix::fs::PermissionsExt;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "cloud",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "docker": {"containers": ["cloud"], "stop": "stop"},
+    }]);
+    let volume = dir.path().join("volumes/cloud_data/_data");
+    let stored = dir.write("volumes/cloud_data/_data/db.sqlite", "rows");
+    dir.write(
+        "bin/docker",
+        &format!(
+            r#"#!/bin/sh
+echo "$@" >> "$DOCKER_LOG"
+case "$1" in
+  inspect) cat <<'JSON'
+[{{"Name": "/cloud", "Image": "sha256:abc", "Config": {{"Image": "nextcloud:27.1"}},
+  "State": {{"Status": "running", "Running": true, "Paused": false}},
+  "Mounts": [{{"Type": "volume", "Name": "cloud_data", "Source": "{}", "Destination": "/var/www"}},
+             {{"Type": "bind", "Source": "/etc/hosts", "Destination": "/etc/hosts"}}]}}]
+JSON
+  ;;
+esac
+"#,
+            volume.display()
+        ),
+    );
+    let docker = dir.path().join("bin/docker");
+    std::fs::set_permissions(&docker, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        dir.path().join("bin").display(),
+        std::env::var("PATH").unwrap()
+    );
+    let log = dir.path().join("docker.log");
+    let env = [
+        ("PATH", path.as_str()),
+        ("DOCKER_LOG", log.to_str().unwrap()),
+    ];
+
+    let output = run_env(&dir, &config, &[], &env);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entries = zip_entries(upload);
+    let names = entries.iter().map(|e| e.0.as_str()).collect::<Vec<_>>();
+    assert!(
+        names.contains(&stored.to_str().unwrap().trim_start_matches('/')),
+        "{names:?}"
+    );
+    assert!(names.iter().any(|n| n.ends_with("notes.txt")), "{names:?}");
+    assert!(!names.contains(&"etc/hosts"), "{names:?}");
+    let (_, record) = entries
+        .iter()
+        .find(|e| e.0 == ".synology_backuper_docker.json")
+        .expect("the containers are recorded");
+    let record: serde_json::Value = serde_json::from_slice(record).unwrap();
+    assert_eq!(record[0]["name"], "cloud");
+    assert_eq!(record[0]["image"], "nextcloud:27.1");
+    assert_eq!(record[0]["image_id"], "sha256:abc");
+    assert_eq!(record[0]["volumes"][0], json!(["cloud_data", "/var/www"]));
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "inspect cloud\nstop cloud\nstart cloud\n"
+    );
+}
+
+#[test]
+fn reports_dsm_answers_missing_what_they_should_hold() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.API.Auth",
+        "login",
+        Reply::Json(json!({"success": false})),
+    );
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(!err.contains("panicked"), "{err}");
+    assert!(
+        err.contains(
+            r#"SYNO.API.Auth login: the answer isn't DSM's JSON: {"error":null,"success":false}"#
+        ),
+        "{err}"
+    );
+
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list_share",
+        ok(json!({"offset": 0, "total": 1, "shares": [{"name": "backup"}]})),
+    );
+    let config = base_config(&mock, &dir);
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(!err.contains("panicked"), "{err}");
+    assert!(
+        err.contains(
+            r#"SYNO.FileStation.List list_share: the answer isn't DSM's JSON: {"name":"backup"}"#
+        ),
+        "{err}"
+    );
+}
@@ -0,0 +1,462 @@
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+
+fn backup_entry(dt: &str) -> Value {
+    json!({
+        "name": format!("notes.txt_{dt}.zip"),
+        "path": format!("/backup/notes.txt_{dt}.zip"),
+        "isdir": false,
+        "additional": {"size": 1024},
+    })
+}
+
+/// Serves three archives, two on the first page and one on the second.
+fn serve_three_backups(mock: &MockDsm) {
+    mock.once(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 3, "files": [
+            backup_entry("20240103_030000"),
+            backup_entry("20240101_030000"),
+        ]})),
+    );
+    mock.once(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 2, "total": 3, "files": [
+            backup_entry("20240102_030000"),
+            {"name": "notes.txt_draft.zip", "path": "/backup/notes.txt_draft.zip", "isdir": false},
+        ]})),
+    );
+}
+
+#[test]
+fn lists_all_pages_newest_first() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["list"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let paths = out
+        .lines()
+        .map(|l| l.split('\t').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        [
+            "primary:/backup/notes.txt_20240103_030000.zip",
+            "primary:/backup/notes.txt_20240102_030000.zip",
+            "primary:/backup/notes.txt_20240101_030000.zip",
+        ]
+    );
+    let pages = mock.calls("SYNO.FileStation.List", "list");
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[0].params["offset"], "0");
+    assert_eq!(pages[1].params["offset"], "2");
+    assert_eq!(pages[0].params["sort_by"], "mtime");
+}
+
+#[test]
+fn prune_keeps_the_newest_archives() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 1}]);
+
+    let output = run(&dir, &config, &["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("keeping 1, deleting 2"));
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert_eq!(
+        delete.params["path"],
+        r#"["/backup/notes.txt_20240102_030000.zip","/backup/notes.txt_20240101_030000.zip"]"#
+    );
+}
+
+#[test]
+fn prune_dry_run_deletes_nothing() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_within": "1d"}]);
+
+    let output = run(&dir, &config, &["prune", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("keeping 0, would delete 3"));
+    assert_eq!(stdout(&output).lines().count(), 3);
+    assert!(mock.calls("SYNO.FileStation.Delete", "start").is_empty());
+}
+
+#[test]
+fn prune_stops_deleting_where_the_folder_turns_out_write_once() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    serve_three_backups(&mock);
+    mock.once("SYNO.FileStation.Delete", "start", err(407));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 1}]);
+
+    let output = run(&dir, &config, &["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains(
+            "primary:/backup refused to change a file (407 - Operation not permitted) as a write-once (WORM) folder does"
+        ),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = run(&dir, &config, &["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output)
+            .contains("primary:/backup is write-once (WORM), so nothing there is deleted"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(mock.calls("SYNO.FileStation.Delete", "start").len(), 1);
+}
+
+#[test]
+fn check_passes_after_a_fresh_backup_from_the_run_log() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["check", "--max-age", "26h"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stdout(&output));
+    assert!(stdout(&output).starts_with("CRITICAL - default has no backup"));
+
+    assert!(run(&dir, &config, &[]).status.success());
+    let lists = mock.calls("SYNO.FileStation.List", "list").len();
+    let output = run(&dir, &config, &["check", "--max-age", "26h"]);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).starts_with("OK - 1 jobs backed up within 26h"));
+    assert_eq!(mock.calls("SYNO.FileStation.List", "list").len(), lists);
+}
+
+#[test]
+fn check_lists_the_target_for_jobs_missing_from_the_run_log() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["check", "--max-age", "26h"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stdout(&output));
+    let out = stdout(&output);
+    assert!(
+        out.starts_with("CRITICAL - default last backed up "),
+        "{out}"
+    );
+    assert!(
+        out.contains("default: last backup 2024-01-03 03:00:00"),
+        "{out}"
+    );
+}
+
+fn serve_tagged_backups(mock: &MockDsm) {
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 3, "files": [
+            backup_entry("20240103_030000"),
+            backup_entry("20240102_030000_pre-upgrade"),
+            backup_entry("20240101_030000"),
+        ]})),
+    );
+}
+
+#[test]
+fn tags_name_the_archive_and_filter_list() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["backup", "--tag", "pre-upgrade"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let name = &mock.calls("SYNO.FileStation.Upload", "upload")[0].files[0].1;
+    assert!(name.ends_with("_pre-upgrade.zip"), "{name}");
+
+    let output = run(&dir, &config, &["backup", "--tag", "not_ok"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    serve_tagged_backups(&mock);
+    let output = run(&dir, &config, &["list", "--tag", "pre-upgrade"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lines = stdout(&output)
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    assert!(
+        lines[0].ends_with("\t2024-01-02 03:00:00\tpre-upgrade\t"),
+        "{lines:?}"
+    );
+}
+
+#[test]
+fn prune_spares_tagged_archives_with_keep_tagged() {
+    let mock = MockDsm::start();
+    serve_tagged_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] =
+        json!([{"name": "notes", "filename": source, "keep_last": 1, "keep_tagged": true}]);
+
+    let output = run(&dir, &config, &["prune", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output).trim(),
+        "/backup/notes.txt_20240101_030000.zip"
+    );
+}
+
+#[test]
+fn pinned_archives_survive_prune() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+    serve_tagged_backups(&mock);
+
+    let output = run(&dir, &config, &["pin", "notes.txt_20240101_030000.zip"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.files[0].1, "notes.txt_20240101_030000.pinned");
+
+    let output = run(&dir, &config, &["pin", "notes.txt_19990101_030000.zip"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 4, "files": [
+            backup_entry("20240103_030000"),
+            backup_entry("20240102_030000"),
+            backup_entry("20240101_030000"),
+            {"name": "notes.txt_20240101_030000.pinned",
+             "path": "/backup/notes.txt_20240101_030000.pinned", "isdir": false},
+        ]})),
+    );
+    let mut config = config;
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 1}]);
+    let output = run(&dir, &config, &["prune", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output).trim(),
+        "/backup/notes.txt_20240102_030000.zip"
+    );
+    let output = run(&dir, &config, &["list"]);
+    assert!(
+        stdout(&output).contains("\tpinned\n"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn gfs_retention_keeps_the_newest_of_each_period_and_explains_why() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 5, "files": [
+            backup_entry("20240131_030000"),
+            backup_entry("20240130_030000"),
+            backup_entry("20240130_010000"),
+            backup_entry("20240115_030000"),
+            backup_entry("20231231_030000"),
+        ]})),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "notes", "filename": source, "keep_daily": 2, "keep_monthly": 2}
+    ]);
+
+    let output = run_env(
+        &dir,
+        &config,
+        &["prune", "--dry-run", "--explain"],
+        &[("TZ", "UTC")],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "/backup/notes.txt_20240131_030000.zip\tkeep_daily 2024-01-31, keep_monthly 2024-01\n\
+         /backup/notes.txt_20240130_030000.zip\tkeep_daily 2024-01-30\n\
+         /backup/notes.txt_20240130_010000.zip\tdelete\n\
+         /backup/notes.txt_20240115_030000.zip\tdelete\n\
+         /backup/notes.txt_20231231_030000.zip\tkeep_monthly 2023-12\n"
+    );
+    assert!(mock.calls("SYNO.FileStation.Delete", "start").is_empty());
+}
+
+#[test]
+fn max_total_size_prunes_the_oldest_archives_past_the_budget() {
+    let mock = MockDsm::start();
+    serve_tagged_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "max_total_size": "2.5KiB"}]);
+
+    let output = run(&dir, &config, &["prune", "--explain"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "/backup/notes.txt_20240103_030000.zip\tmax_total_size\n\
+         /backup/notes.txt_20240102_030000_pre-upgrade.zip\tmax_total_size\n\
+         /backup/notes.txt_20240101_030000.zip\tdelete, over max_total_size\n"
+    );
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert_eq!(
+        delete.params["path"],
+        r#"["/backup/notes.txt_20240101_030000.zip"]"#
+    );
+}
+
+fn sidecar_entry(name: &str) -> Value {
+    json!({"name": name, "path": format!("/backup/{name}"), "isdir": false})
+}
+
+#[test]
+fn prune_deletes_sidecars_with_their_archive_and_orphans_cleans_up_the_rest() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 5, "files": [
+            backup_entry("20240103_030000"),
+            backup_entry("20240102_030000"),
+            sidecar_entry("notes.txt_20240102_030000.meta.json"),
+            sidecar_entry("notes.txt_20240102_030000.z01"),
+            sidecar_entry("notes.txt_20231201_030000.pinned"),
+        ]})),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 1}]);
+
+    let output = run(&dir, &config, &["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert_eq!(
+        delete.params["path"],
+        r#"["/backup/notes.txt_20240102_030000.zip","/backup/notes.txt_20240102_030000.meta.json","/backup/notes.txt_20240102_030000.z01"]"#
+    );
+
+    let output = run(&dir, &config, &["orphans", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "/backup/notes.txt_20231201_030000.pinned\n"
+    );
+}
+
+#[test]
+fn report_sums_up_the_runs_failures_and_prunes() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 1}]);
+    let state = dir.path().join("xdg/state/synology_backuper");
+    std::fs::create_dir_all(&state).unwrap();
+    let now = chrono::Utc::now();
+    let at = |days: i64| (now - chrono::Duration::days(days)).to_rfc3339();
+    let job = |days: i64, result: &str, bytes: u64, error: Option<&str>| {
+        json!({"start": at(days), "end": at(days), "result": result, "jobs": [{
+            "job": "notes", "result": result, "start": at(days), "end": at(days),
+            "archive_bytes": bytes, "uploaded": [], "error": error,
+        }]})
+    };
+    let runs = [
+        job(40, "ok", 500, None),
+        job(3, "ok", 1000, None),
+        job(2, "failed", 0, Some("Could not <log in>")),
+        job(1, "ok", 1500, None),
+    ];
+    let lines = runs.iter().map(|r| format!("{r}\n")).collect::<String>();
+    std::fs::write(state.join("runs.jsonl"), lines).unwrap();
+
+    let output = run(&dir, &config, &["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = run(&dir, &config, &["report", "--since", "30d"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(out.contains("| notes | 3 | 2 | 1 | 67% |"), "{out}");
+    assert!(
+        out.contains("| notes | 1000 B | 1.5 KiB | 1000 B | 1.5 KiB | +50% |"),
+        "{out}"
+    );
+    assert!(
+        out.contains("| notes | failed | Could not <log in> |"),
+        "{out}"
+    );
+    assert!(
+        out.contains("| notes | primary | 1 | notes.txt_20240102_030000.zip, notes.txt_20240101_030000.zip |"),
+        "{out}"
+    );
+
+    let html = dir.path().join("report.html");
+    let output = run(
+        &dir,
+        &config,
+        &[
+            "report",
+            "--format",
+            "html",
+            "--out",
+            html.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let page = std::fs::read_to_string(&html).unwrap();
+    assert!(page.starts_with("<!DOCTYPE html>"));
+    assert!(page.contains("<polyline points="));
+    assert!(page.contains("Could not &lt;log in&gt;"));
+    assert!(!page.contains("<script") && !page.contains("src="));
+}
+
+#[test]
+fn prune_keeps_what_a_differential_the_chain_doesnt_know_may_build_on() {
+    let mock = MockDsm::start();
+    serve_three_backups(&mock);
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{
+        "name": "notes",
+        "filename": source,
+        "keep_last": 1,
+        "differential": "sun",
+    }]);
+
+    let output = run(&dir, &config, &["prune"]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("chain.json doesn't record what notes.txt_20240103_030000.zip builds on, so the 2 archives older than it are kept"),
+        "{err}"
+    );
+    assert!(err.contains("keeping 3, deleting 0"), "{err}");
+    assert!(mock.calls("SYNO.FileStation.Delete", "start").is_empty());
+}
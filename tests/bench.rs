@@ -0,0 +1,72 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn times_uploads_and_deletes_what_it_uploaded() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["bench", "--size", "64KiB", "--count", "2"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains("64.0 KiB to /backup over the DSM web API, 2 times"),
+        "{out}"
+    );
+    assert!(out.contains("Upload 2: "), "{out}");
+    assert!(out.contains("Throughput: min "), "{out}");
+    assert!(out.contains("Latency of 10 folder listings: p50 "), "{out}");
+
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 2);
+    let (_, name, contents) = &uploads[0].files[0];
+    assert!(name.starts_with(".synology_backuper_bench_"), "{name}");
+    assert_eq!(contents.len(), 64 * 1024);
+    let deletes = mock.calls("SYNO.FileStation.Delete", "start");
+    assert_eq!(deletes.len(), 2);
+    assert_eq!(deletes[0].params["path"], format!(r#"["/backup/{name}"]"#));
+}
+
+#[test]
+fn compares_compression_settings_on_a_sample_of_the_files() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    dir.write(
+        "data/log.txt",
+        &"the same line again and again\n".repeat(2000),
+    );
+    dir.write("data/sub/more.txt", &"and another one\n".repeat(500));
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = serde_json::json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "compression": "deflate:6",
+    }]);
+
+    let output = run(&dir, &config, &["bench-compress", "--sample", "1MB"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains("Job data: 66.4 KiB of 66.4 KiB in 3 files"),
+        "{out}"
+    );
+    for setting in ["stored", "deflate:1", "bzip2:9", "zstd:19"] {
+        assert!(
+            out.lines().any(|l| l.trim_start().starts_with(setting)),
+            "{out}"
+        );
+    }
+    assert!(
+        out.contains("deflate:6") && out.contains("(current)"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Recommended: \"compression\": ")
+            || out.contains("The current setting is the fastest"),
+        "{out}"
+    );
+    assert!(mock.requests().is_empty());
+}
@@ -0,0 +1,666 @@
+//! A tiny in-process stand-in for the DSM web API.
+//!
+//! The mock speaks just enough HTTP/1.1 for reqwest's blocking client, routes
+//! on the `api`/`method` parameters like DSM does, and records every request so
+//! tests can assert on what the backuper actually sent.
+#![allow(dead_code)]
+
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The pin of the key in [`MockDsm::start_tls`]'s certificate.
+pub const MOCK_PIN: &str = "swAtVQsfakWGq6LCvsI6DTgErWxFnzE9csL9uygKdHA=";
+
+/// A request as seen by the mock server.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    /// Query string and form fields merged; multipart text fields included.
+    pub params: HashMap<String, String>,
+    /// Uploaded multipart files as (field name, file name, contents).
+    pub files: Vec<(String, String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub body_len: usize,
+}
+
+impl Recorded {
+    /// Requests outside `webapi/` are WebDAV ones, whose api is reported as
+    /// `WEBDAV` and whose method is the HTTP method.
+    fn is_webdav(&self) -> bool {
+        !self.path.starts_with("webapi/")
+    }
+
+    pub fn api(&self) -> &str {
+        match self.params.get("api") {
+            Some(api) => api,
+            None if self.is_webdav() => "WEBDAV",
+            None => "",
+        }
+    }
+
+    pub fn api_method(&self) -> &str {
+        match self.params.get("method") {
+            Some(method) => method,
+            None if self.is_webdav() => &self.method,
+            None => "",
+        }
+    }
+
+    pub fn is(&self, api: &str, method: &str) -> bool {
+        self.api() == api && self.api_method() == method
+    }
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<Recorded>,
+    /// Scripted responses, consumed front to back before falling back to the defaults.
+    scripted: HashMap<(String, String), VecDeque<Reply>>,
+    /// Responses replacing the defaults for every call.
+    overrides: HashMap<(String, String), Reply>,
+    api_info: Value,
+}
+
+#[derive(Clone)]
+pub enum Reply {
+    Json(Value),
+    Raw(u16, String),
+    File(Vec<u8>),
+    /// A `SYNO.FileStation.List` page holding the last uploaded file, as if
+    /// DSM had kept this many bytes of it.
+    Truncated(u64),
+    /// A file whose download breaks off after this many bytes.
+    CutOff(Vec<u8>, usize),
+}
+
+pub struct MockDsm {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+pub fn ok(data: Value) -> Reply {
+    Reply::Json(json!({"success": true, "data": data}))
+}
+
+pub fn err(code: i64) -> Reply {
+    Reply::Json(json!({"success": false, "error": {"code": code}}))
+}
+
+pub fn default_api_info() -> Value {
+    let mut apis = serde_json::Map::new();
+    for (name, path, min, max) in [
+        ("SYNO.API.Info", "query.cgi", 1, 1),
+        ("SYNO.API.Auth", "auth.cgi", 1, 7),
+        ("SYNO.FileStation.Info", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.List", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.Upload", "entry.cgi", 1, 3),
+        ("SYNO.FileStation.Delete", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.CopyMove", "entry.cgi", 1, 3),
+        ("SYNO.FileStation.CheckPermission", "entry.cgi", 1, 3),
+        ("SYNO.FileStation.DirSize", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.Search", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.CreateFolder", "entry.cgi", 1, 2),
+        ("SYNO.FileStation.Download", "entry.cgi", 1, 2),
+    ] {
+        apis.insert(
+            name.to_string(),
+            json!({"path": path, "minVersion": min, "maxVersion": max}),
+        );
+    }
+    Value::Object(apis)
+}
+
+impl MockDsm {
+    pub fn start() -> MockDsm {
+        MockDsm::start_on("127.0.0.1:0")
+    }
+
+    /// Listens on `addr` instead, such as `[::1]:0` for IPv6.
+    pub fn start_on(addr: &str) -> MockDsm {
+        MockDsm::serve(addr, None)
+    }
+
+    /// Speaks HTTPS with the self-signed certificate in `tls/`, whose key
+    /// [`MOCK_PIN`] pins.
+    pub fn start_tls() -> MockDsm {
+        let cert = CertificateDer::from(include_bytes!("tls/cert.der").to_vec());
+        let key = PrivatePkcs8KeyDer::from(include_bytes!("tls/key.der").to_vec());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key.into())
+            .unwrap();
+        MockDsm::serve("127.0.0.1:0", Some(Arc::new(config)))
+    }
+
+    fn serve(addr: &str, tls: Option<Arc<rustls::ServerConfig>>) -> MockDsm {
+        let listener = TcpListener::bind(addr).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State {
+            api_info: default_api_info(),
+            ..Default::default()
+        }));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = thread_state.clone();
+                let tls = tls.clone();
+                std::thread::spawn(move || match tls {
+                    Some(config) => {
+                        let Ok(connection) = rustls::ServerConnection::new(config) else {
+                            return;
+                        };
+                        serve_connection(rustls::StreamOwned::new(connection, stream), state)
+                    }
+                    None => serve_connection(stream, state),
+                });
+            }
+        });
+        MockDsm { port, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Replaces the API catalog returned by `SYNO.API.Info`.
+    pub fn set_api_info(&self, info: Value) {
+        self.state.lock().unwrap().api_info = info;
+    }
+
+    /// Removes one API from the catalog, as if its DSM package was not installed.
+    pub fn remove_api(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.api_info.as_object_mut().unwrap().remove(name);
+    }
+
+    /// Answers every `api`/`method` call with `reply`.
+    pub fn on(&self, api: &str, method: &str, reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .overrides
+            .insert((api.to_string(), method.to_string()), reply);
+    }
+
+    /// Answers the next `api`/`method` call with `reply`, then falls back.
+    pub fn once(&self, api: &str, method: &str, reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .entry((api.to_string(), method.to_string()))
+            .or_default()
+            .push_back(reply);
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn calls(&self, api: &str, method: &str) -> Vec<Recorded> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.is(api, method))
+            .collect()
+    }
+}
+
+fn default_reply(state: &State, api: &str, method: &str) -> Reply {
+    match (api, method) {
+        ("SYNO.API.Info", "query") => ok(state.api_info.clone()),
+        ("SYNO.API.Auth", "login") => ok(json!({"sid": "mock-sid"})),
+        ("SYNO.API.Auth", "logout") => ok(Value::Null),
+        ("SYNO.FileStation.List", "list_share") => ok(json!({
+            "offset": 0,
+            "total": 2,
+            "shares": [
+                {
+                    "name": "backup",
+                    "path": "/backup",
+                    "isdir": true,
+                    "additional": {"volume_status": {"freespace": 5_000_000_000u64, "totalspace": 8_000_000_000u64}},
+                },
+                {"name": "photo", "path": "/photo", "isdir": true},
+            ]
+        })),
+        ("SYNO.FileStation.List", "list") => ok(json!({"offset": 0, "total": 0, "files": []})),
+        ("SYNO.FileStation.Upload", "upload") => ok(Value::Null),
+        ("SYNO.FileStation.CheckPermission", "write") => ok(Value::Null),
+        ("SYNO.FileStation.CreateFolder", "create") => ok(json!({"folders": []})),
+        // Hands back the last uploaded file, as if it had been stored intact.
+        ("SYNO.FileStation.Download", "download") => {
+            let upload = state
+                .requests
+                .iter()
+                .rev()
+                .find(|r| r.is("SYNO.FileStation.Upload", "upload"));
+            match upload {
+                Some(upload) => Reply::File(upload.files[0].2.clone()),
+                None => err(408),
+            }
+        }
+        ("SYNO.FileStation.DirSize", "start") => {
+            ok(json!({"taskid": "FileStation_51CBB59C68EFE6A3"}))
+        }
+        ("SYNO.FileStation.Search", "start") => ok(json!({"taskid": "51CE617CF57B24E5"})),
+        ("SYNO.FileStation.Search", "list") => {
+            ok(json!({"finished": true, "offset": 0, "total": 0, "files": []}))
+        }
+        ("SYNO.FileStation.Search", "clean") => ok(Value::Null),
+        ("SYNO.FileStation.MD5", "start") => ok(json!({"taskid": "FileStation_51CEC9C979340E5B"})),
+        ("SYNO.FileStation.MD5", "status") => {
+            ok(json!({"finished": true, "md5": "6cc7e7c6a8b65d0a4f2bcd1e465e48e7"}))
+        }
+        ("SYNO.FileStation.DirSize", "status") => {
+            ok(json!({"finished": true, "num_dir": 0, "num_file": 0, "total_size": 1024}))
+        }
+        ("SYNO.FileStation.Delete", "start") => {
+            ok(json!({"taskid": "FileStation_51CEC9C979340E5A"}))
+        }
+        ("SYNO.FileStation.Delete", "status") => ok(json!({"finished": true, "progress": 1})),
+        ("SYNO.FileStation.CopyMove", "start") => {
+            ok(json!({"taskid": "FileStation_51D00B7912CDE0B0"}))
+        }
+        ("SYNO.FileStation.CopyMove", "status") => ok(json!({"finished": true, "progress": 1})),
+        _ => err(102),
+    }
+}
+
+/// A PROPFIND multistatus listing `entries` as (path, size); a `None` size marks a folder.
+pub fn multistatus(entries: &[(&str, Option<u64>)]) -> Reply {
+    let responses = entries
+        .iter()
+        .map(|(path, size)| match size {
+            None => format!(
+                "<d:response><d:href>{path}</d:href><d:propstat><d:prop>\
+                 <d:resourcetype><d:collection/></d:resourcetype>\
+                 <d:quota-available-bytes>5000000000</d:quota-available-bytes>\
+                 </d:prop></d:propstat></d:response>"
+            ),
+            Some(size) => format!(
+                "<d:response><d:href>{path}</d:href><d:propstat><d:prop>\
+                 <d:resourcetype/><d:getcontentlength>{size}</d:getcontentlength>\
+                 <d:getlastmodified>Mon, 01 Jan 2024 03:00:00 GMT</d:getlastmodified>\
+                 </d:prop></d:propstat></d:response>"
+            ),
+        })
+        .collect::<String>();
+    Reply::Raw(
+        207,
+        format!(
+            r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">{responses}</d:multistatus>"#
+        ),
+    )
+}
+
+fn truncated_listing(state: &State, size: u64) -> Reply {
+    let upload = state
+        .requests
+        .iter()
+        .rev()
+        .find(|r| r.is("SYNO.FileStation.Upload", "upload"))
+        .expect("nothing was uploaded");
+    let name = &upload.files[0].1;
+    ok(json!({"offset": 0, "total": 1, "files": [{
+        "name": name,
+        "path": format!("{}/{name}", upload.params["path"]),
+        "isdir": false,
+        "additional": {"size": size},
+    }]}))
+}
+
+/// The root lists the `backup` and `photo` shares; every other folder is empty.
+fn default_webdav_reply(method: &str, path: &str) -> Reply {
+    match method {
+        "PROPFIND" if path.is_empty() => {
+            multistatus(&[("/", None), ("/backup/", None), ("/photo/", None)])
+        }
+        "PROPFIND" => multistatus(&[(&format!("/{path}/"), None)]),
+        "PUT" | "MKCOL" => Reply::Raw(201, String::new()),
+        "DELETE" => Reply::Raw(204, String::new()),
+        _ => Reply::Raw(405, String::new()),
+    }
+}
+
+fn serve_connection(stream: impl Read + Write, state: Arc<Mutex<State>>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let Some(request) = read_request(&mut reader) else {
+            return;
+        };
+        let (reply, range) = {
+            let mut state = state.lock().unwrap();
+            let key = (request.api().to_string(), request.api_method().to_string());
+            let path = request.path.clone();
+            let range = request.headers.get("range").and_then(|r| parse_range(r));
+            state.requests.push(request);
+            let reply = match state.scripted.get_mut(&key).and_then(|q| q.pop_front()) {
+                Some(reply) => reply,
+                None => match state.overrides.get(&key) {
+                    Some(reply) => reply.clone(),
+                    None if key.0 == "WEBDAV" => default_webdav_reply(&key.1, &path),
+                    None => default_reply(&state, &key.0, &key.1),
+                },
+            };
+            let reply = match reply {
+                Reply::Truncated(size) => truncated_listing(&state, size),
+                reply => reply,
+            };
+            (reply, range)
+        };
+        let mut content_range = String::new();
+        let (status, content_type, body) = match reply {
+            Reply::Json(v) => (200, "application/json", v.to_string().into_bytes()),
+            Reply::Raw(status, body) => (status, "text/html", body.into_bytes()),
+            // Files are served in part when asked, as DSM does.
+            Reply::File(body) => match range {
+                Some((start, end)) if start < body.len() as u64 => {
+                    let end = end.unwrap_or(u64::MAX).min(body.len() as u64 - 1);
+                    content_range =
+                        format!("Content-Range: bytes {start}-{end}/{}\r\n", body.len());
+                    let part = body[start as usize..=end as usize].to_vec();
+                    (206, "application/octet-stream", part)
+                }
+                _ => (200, "application/octet-stream", body),
+            },
+            Reply::CutOff(body, sent) => {
+                let head = format!(
+                    "HTTP/1.1 200 Mock\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                );
+                let writer = reader.get_mut();
+                let _ = writer.write_all(head.as_bytes());
+                let _ = writer.write_all(&body[..sent]);
+                return;
+            }
+            Reply::Truncated(_) => unreachable!("resolved above"),
+        };
+        let head = format!(
+            "HTTP/1.1 {status} Mock\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n{content_range}Set-Cookie: id=mock-sid; path=/\r\n\r\n",
+            body.len()
+        );
+        let writer = reader.get_mut();
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(&body).is_err() {
+            return;
+        }
+    }
+}
+
+/// The first and last byte of a `Range: bytes=first-[last]` header.
+fn parse_range(header: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+    Some((start.parse().ok()?, end))
+}
+
+fn read_request(reader: &mut BufReader<impl Read>) -> Option<Recorded> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+        }
+    }
+    let body = if let Some(len) = headers.get("content-length") {
+        let mut body = vec![0; len.parse().ok()?];
+        reader.read_exact(&mut body).ok()?;
+        body
+    } else if headers
+        .get("transfer-encoding")
+        .is_some_and(|x| x.contains("chunked"))
+    {
+        read_chunked(reader)?
+    } else {
+        Vec::new()
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut params = parse_urlencoded(query);
+    let mut files = Vec::new();
+    let content_type = headers.get("content-type").cloned().unwrap_or_default();
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        params.extend(parse_urlencoded(&String::from_utf8_lossy(&body)));
+    } else if let Some(boundary) = content_type.split("boundary=").nth(1) {
+        for (name, file_name, data) in parse_multipart(&body, boundary) {
+            match file_name {
+                Some(file_name) => files.push((name, file_name, data)),
+                None => {
+                    params.insert(name, String::from_utf8_lossy(&data).into_owned());
+                }
+            }
+        }
+    }
+    Some(Recorded {
+        method,
+        path: path.trim_start_matches('/').to_string(),
+        headers,
+        params,
+        files,
+        body_len: body.len(),
+        body,
+    })
+}
+
+fn read_chunked(reader: &mut BufReader<impl Read>) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut size = String::new();
+        reader.read_line(&mut size).ok()?;
+        let size = usize::from_str_radix(size.trim(), 16).ok()?;
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
+
+pub fn parse_urlencoded(s: &str) -> HashMap<String, String> {
+    s.split('&')
+        .filter(|x| !x.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_multipart(body: &[u8], boundary: &str) -> Vec<(String, Option<String>, Vec<u8>)> {
+    let delimiter = format!("--{}", boundary.trim_matches('"'));
+    let mut parts = Vec::new();
+    for raw in split_bytes(body, delimiter.as_bytes()).into_iter().skip(1) {
+        if raw.starts_with(b"--") {
+            break;
+        }
+        let raw = raw.strip_prefix(b"\r\n").unwrap_or(raw);
+        let Some(split) = find(raw, b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&raw[..split]);
+        let data = &raw[split + 4..];
+        let data = data.strip_suffix(b"\r\n").unwrap_or(data);
+        let disposition = head
+            .lines()
+            .find(|l| l.to_ascii_lowercase().starts_with("content-disposition"))
+            .unwrap_or("");
+        let field = |key: &str| {
+            disposition
+                .split(';')
+                .map(|x| x.trim())
+                .find_map(|x| x.strip_prefix(&format!("{key}=")))
+                .map(|x| x.trim_matches('"').to_string())
+        };
+        parts.push((
+            field("name").unwrap_or_default(),
+            field("filename"),
+            data.to_vec(),
+        ));
+    }
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn split_bytes<'a>(mut haystack: &'a [u8], needle: &[u8]) -> Vec<&'a [u8]> {
+    let mut out = Vec::new();
+    while let Some(i) = find(haystack, needle) {
+        out.push(&haystack[..i]);
+        haystack = &haystack[i + needle.len()..];
+    }
+    out.push(haystack);
+    out
+}
+
+/// A scratch directory removed again when the test ends.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "synology_backuper_test_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The config the tests start from: one small file, backed up to the `backup` share.
+pub fn base_config(mock: &MockDsm, dir: &TempDir) -> Value {
+    let source = dir.write("data/notes.txt", "hello from the backuper tests\n");
+    json!({
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "share_name": "backup",
+        "usr": "tester",
+        "pwd": "secret",
+        "filename": source.to_str().unwrap(),
+    })
+}
+
+/// Runs the backuper binary in `dir` with `config` written to `config.json`.
+pub fn run(dir: &TempDir, config: &Value, args: &[&str]) -> Output {
+    run_env(dir, config, args, &[])
+}
+
+/// Like [`run`], with extra environment variables for the child process.
+pub fn run_env(dir: &TempDir, config: &Value, args: &[&str], env: &[(&str, &str)]) -> Output {
+    std::fs::write(
+        dir.path().join("config.json"),
+        serde_json::to_string_pretty(config).unwrap(),
+    )
+    .unwrap();
+    run_bare(dir, args, env)
+}
+
+/// Runs the binary in `dir` without writing a config there first. The XDG
+/// directories point into `dir`, at `xdg/config` and `xdg/state`.
+pub fn run_bare(dir: &TempDir, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_synology_backuper"))
+        .args(args)
+        .env("XDG_CONFIG_HOME", dir.path().join("xdg/config"))
+        .env("XDG_STATE_HOME", dir.path().join("xdg/state"))
+        .envs(env.iter().copied())
+        .current_dir(dir.path())
+        .output()
+        .unwrap()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Entry names and contents of the first zip file uploaded in `upload`.
+pub fn zip_entries(upload: &Recorded) -> Vec<(String, Vec<u8>)> {
+    let data = upload.files[0].2.clone();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+    (0..archive.len())
+        .map(|i| {
+            let mut entry = archive.by_index(i).unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            (entry.name().to_string(), contents)
+        })
+        .collect()
+}
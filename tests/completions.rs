@@ -0,0 +1,39 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn bash_completions_are_valid_shell() {
+    let dir = TempDir::new();
+    let output = run(&dir, &json!({}), &["completions", "bash"]);
+    assert!(output.status.success());
+    let script = dir.write("completions.bash", &stdout(&output));
+    let check = std::process::Command::new("bash")
+        .arg("-n")
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(check.success());
+}
+
+#[test]
+fn rejects_unknown_shell() {
+    let dir = TempDir::new();
+    let output = run(&dir, &json!({}), &["completions", "tcsh"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn completes_configured_job_names() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "photos", "filename": "photos"},
+        {"name": "documents", "filename": "documents", "share_name": "photo"},
+    ]);
+
+    let output = run(&dir, &config, &["__complete", "jobs"]);
+    assert_eq!(stdout(&output), "default\nphotos\ndocuments\n");
+}
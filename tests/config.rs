@@ -0,0 +1,111 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn rejects_a_misspelt_job_setting() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([{"name": "notes", "filename": "notes.txt", "keep_lats": 3}]);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output)
+            .contains("Unknown setting `keep_lats` in jobs[0]; did you mean `keep_last`?"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.requests().is_empty());
+}
+
+#[test]
+fn checks_profiles_like_the_top_level() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["profiles"] = json!({"work": {"shares_name": "work"}});
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output)
+            .contains("Unknown setting `shares_name` in profiles.work; did you mean `share_name`?"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn prints_the_schema_without_a_config() {
+    let dir = TempDir::new();
+    let output = run_bare(&dir, &["config", "schema"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(schema["additionalProperties"], false);
+    assert_eq!(
+        schema["$defs"]["job"]["required"],
+        json!(["name", "filename"])
+    );
+    assert_eq!(
+        schema["$defs"]["target"]["properties"]["transport"]["enum"],
+        json!(["api", "webdav", "sftp", "local"])
+    );
+}
+
+#[test]
+fn refuses_pruned_jobs_whose_archives_are_named_alike_in_one_share() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let home = dir.write("home/a/docs/a.txt", "a");
+    let srv = dir.write("srv/docs/b.txt", "b");
+    config["jobs"] = json!([
+        {"name": "home", "filename": home.parent().unwrap(), "keep_last": 3},
+        {"name": "srv", "filename": srv.parent().unwrap()},
+    ]);
+
+    let output = run(&dir, &config, &["prune"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains(
+            "Jobs home and srv both name their archives docs_* in share backup on target primary"
+        ),
+        "{}",
+        stderr(&output)
+    );
+
+    config["jobs"][1]["share_name"] = json!("photo");
+    let output = run(&dir, &config, &["prune", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn refuses_job_names_that_schedules_and_snapshots_cant_tell_apart() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let data = dir.path().join("data");
+    config["jobs"] = json!([
+        {"name": "home_docs", "filename": data},
+        {"name": "home docs", "filename": data, "share_name": "photo"},
+    ]);
+
+    let output = run(&dir, &config, &["list"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains(
+            "Jobs home_docs and home docs both come out as home_docs in the names of scheduled tasks and snapshots"
+        ),
+        "{}",
+        stderr(&output)
+    );
+
+    config["jobs"][1]["name"] = json!("home-docs");
+    let output = run(&dir, &config, &["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
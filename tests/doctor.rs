@@ -0,0 +1,51 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn doctor_passes_against_healthy_nas() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["doctor"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}");
+    for check in [
+        "State directory",
+        "DNS resolution",
+        "TCP connect",
+        "API info",
+        "Login",
+        "Share listing",
+        "Share backup",
+        "Write permission backup",
+    ] {
+        let row = out.lines().find(|l| l.starts_with(check)).unwrap();
+        assert!(row.contains("PASS"), "{row}");
+    }
+    assert!(out.contains("4.7 GiB free"), "{out}");
+
+    let probe = &mock.calls("SYNO.FileStation.Upload", "upload")[0].files[0].1;
+    assert!(probe.starts_with(".synology_backuper_probe_"));
+    let delete = &mock.calls("SYNO.FileStation.Delete", "start")[0];
+    assert_eq!(delete.params["path"], format!(r#"["/backup/{probe}"]"#));
+}
+
+#[test]
+fn doctor_reports_missing_write_permission() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.FileStation.Upload", "upload", err(105));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["doctor"]);
+    let out = stdout(&output);
+    assert_eq!(output.status.code(), Some(1), "{out}");
+    let row = out
+        .lines()
+        .find(|l| l.starts_with("Write permission backup"))
+        .unwrap();
+    assert!(row.contains("FAIL") && row.contains("105"), "{row}");
+    assert!(mock.calls("SYNO.FileStation.Delete", "start").is_empty());
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn finds_backups_across_shares() {
+    let mock = MockDsm::start();
+    mock.once(
+        "SYNO.FileStation.Search",
+        "list",
+        ok(json!({"finished": false, "offset": 0, "total": 0, "files": []})),
+    );
+    mock.on(
+        "SYNO.FileStation.Search",
+        "list",
+        ok(json!({"finished": true, "offset": 0, "total": 2, "files": [
+            {"name": "notes.txt_20240102_030000.zip", "path": "/photo/old/notes.txt_20240102_030000.zip", "isdir": false,
+             "additional": {"size": 2048, "time": {"mtime": 1704164400}}},
+            {"name": "notes.txt_20240101_030000.zip", "path": "/backup/notes.txt_20240101_030000.zip", "isdir": false,
+             "additional": {"size": 1024, "time": {"mtime": 1704078000}}},
+        ]})),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([
+        {"name": "a", "filename": source},
+        {"name": "b", "filename": source, "share_name": "photo"},
+    ]);
+
+    let output = run(&dir, &config, &["find", "*_202401*"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2, "{out}");
+    assert!(
+        lines[0].starts_with("primary:/backup/notes.txt_20240101_030000.zip\t1.0 KiB\t2024-01-01")
+    );
+    assert!(lines[1].starts_with("primary:/photo/old/"), "{out}");
+    assert!(stderr(&output).contains("2 files match *_202401*"));
+
+    let start = &mock.calls("SYNO.FileStation.Search", "start")[0];
+    assert_eq!(start.params["folder_path"], r#"["/backup","/photo"]"#);
+    assert_eq!(start.params["pattern"], "*_202401*");
+    assert_eq!(mock.calls("SYNO.FileStation.Search", "list").len(), 2);
+    assert_eq!(mock.calls("SYNO.FileStation.Search", "clean").len(), 1);
+}
+
+#[test]
+fn reports_a_failing_search() {
+    let mock = MockDsm::start();
+    mock.on("SYNO.FileStation.Search", "list", err(401));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["find", "*.zip"]);
+    assert!(!output.status.success());
+    let err = stderr(&output);
+    assert!(
+        err.contains("401 - Unknown error of file operation"),
+        "{err}"
+    );
+    assert!(!err.contains("panicked"), "{err}");
+    assert_eq!(mock.calls("SYNO.FileStation.Search", "clean").len(), 1);
+}
@@ -0,0 +1,392 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn systemd_units_follow_job_schedules() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "photos", "filename": "photos", "schedule": "weekly sun 04:30", "jitter": "15m"},
+    ]);
+    config["filename"] = json!(null);
+    config["jobs"][0]["filename"] = json!(dir.path().join("data").to_str().unwrap());
+
+    let output = run(&dir, &config, &["install-systemd", "--system", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("OnCalendar=Sun *-*-* 04:30:00\n"), "{out}");
+    assert!(out.contains("RandomizedDelaySec=900\n"), "{out}");
+    assert!(
+        out.contains("LoadCredential=synology_backuper_pwd:/etc/credstore/synology_backuper_pwd"),
+        "{out}"
+    );
+    assert!(out.contains("ProtectSystem=strict"), "{out}");
+    assert!(out.contains("ReadWritePaths="), "{out}");
+}
+
+#[test]
+fn systemd_system_service_can_write_its_state() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "photos", "filename": dir.path().join("data"), "schedule": "daily 01:15"},
+    ]);
+
+    let output = run(&dir, &config, &["install-systemd", "--system", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // ProtectSystem=strict leaves only these writable, and `state_dir()`
+    // resolves to $XDG_STATE_HOME/synology_backuper.
+    assert!(out.contains("ProtectSystem=strict\n"), "{out}");
+    assert!(out.contains("StateDirectory=synology_backuper\n"), "{out}");
+    assert!(out.contains("Environment=XDG_STATE_HOME=%S\n"), "{out}");
+
+    let output = run(&dir, &config, &["install-systemd", "--user", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!out.contains("StateDirectory="), "{out}");
+    assert!(!out.contains("XDG_STATE_HOME"), "{out}");
+}
+
+#[test]
+fn systemd_relaxes_the_sandbox_for_jobs_with_a_snapshot() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let data = dir.path().join("data");
+    let mountpoint = dir.path().to_str().unwrap();
+    config["jobs"] = json!([
+        {"name": "plain", "filename": data, "schedule": "daily 01:15"},
+        {
+            "name": "home-lv",
+            "filename": data,
+            "schedule": "daily 02:15",
+            "snapshot": {"type": "lvm", "mountpoint": mountpoint, "volume": "/dev/vg0/home"},
+        },
+        {
+            "name": "subvolume",
+            "filename": data,
+            "schedule": "daily 03:15",
+            "snapshot": {"type": "btrfs", "mountpoint": mountpoint},
+        },
+    ]);
+
+    let output = run(&dir, &config, &["install-systemd", "--system", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The shared service keeps its sandbox; only the instances that take
+    // snapshots get more.
+    let service = out.split("\n# ").next().unwrap();
+    assert!(service.contains("PrivateDevices=yes\n"), "{out}");
+    assert!(
+        service.contains("CapabilityBoundingSet=CAP_DAC_READ_SEARCH\n"),
+        "{out}"
+    );
+    let drop_ins = out
+        .split("\n# ")
+        .filter(|unit| unit.contains(".service.d/"))
+        .collect::<Vec<_>>();
+    assert_eq!(drop_ins.len(), 2, "{out}");
+    let lvm = drop_ins[0];
+    assert!(
+        lvm.starts_with("synology_backuper@home\\x2dlv.service.d/synology_backuper.conf\n"),
+        "{out}"
+    );
+    assert!(
+        lvm.contains("CapabilityBoundingSet=CAP_SYS_ADMIN CAP_IPC_LOCK\n"),
+        "{out}"
+    );
+    assert!(lvm.contains("PrivateDevices=no\n"), "{out}");
+    assert!(
+        lvm.contains("DeviceAllow=/dev/mapper/control rw\n"),
+        "{out}"
+    );
+    let btrfs = drop_ins[1];
+    assert!(
+        btrfs.starts_with("synology_backuper@subvolume.service.d/synology_backuper.conf\n"),
+        "{out}"
+    );
+    assert!(
+        btrfs.contains("CapabilityBoundingSet=CAP_SYS_ADMIN\n"),
+        "{out}"
+    );
+    assert!(
+        btrfs.contains(&format!("ReadWritePaths=\"{mountpoint}\"\n")),
+        "{out}"
+    );
+
+    // A user service can't be given capabilities.
+    let output = run(&dir, &config, &["install-systemd", "--user", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!out.contains(".service.d/"), "{out}");
+}
+
+#[test]
+fn systemd_gives_docker_jobs_the_daemon_they_were_installed_with() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let data = dir.path().join("data");
+    config["jobs"] = json!([
+        {"name": "plain", "filename": data, "schedule": "daily 01:15"},
+        {
+            "name": "nextcloud",
+            "filename": data,
+            "schedule": "daily 02:15",
+            "docker": {"containers": ["nextcloud"]},
+        },
+    ]);
+    let rootless = [("DOCKER_HOST", "unix:///run/user/1000/docker.sock")];
+
+    let output = run_env(
+        &dir,
+        &config,
+        &["install-systemd", "--system", "--print"],
+        &rootless,
+    );
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let drop_ins = out
+        .split("\n# ")
+        .filter(|unit| unit.contains(".service.d/"))
+        .collect::<Vec<_>>();
+    assert_eq!(drop_ins.len(), 1, "{out}");
+    assert!(
+        drop_ins[0].starts_with("synology_backuper@nextcloud.service.d/synology_backuper.conf\n"),
+        "{out}"
+    );
+    assert!(
+        drop_ins[0].contains("Environment=\"DOCKER_HOST=unix:///run/user/1000/docker.sock\"\n"),
+        "{out}"
+    );
+    // The socket of rootless Docker isn't root's to write to.
+    assert!(
+        drop_ins[0].contains("CapabilityBoundingSet=CAP_DAC_OVERRIDE\n"),
+        "{out}"
+    );
+
+    // Root's own daemon needs nothing more.
+    let rootful = [("DOCKER_HOST", "unix:///var/run/docker.sock")];
+    let args = ["install-systemd", "--system", "--print"];
+    let output = run_env(&dir, &config, &args, &rootful);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!out.contains("CAP_DAC_OVERRIDE"), "{out}");
+
+    // A user service doesn't get the environment either.
+    let args = ["install-systemd", "--user", "--print"];
+    let output = run_env(&dir, &config, &args, &rootless);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains("Environment=\"DOCKER_HOST=unix:///run/user/1000/docker.sock\"\n"),
+        "{out}"
+    );
+    assert!(!out.contains("CAP_DAC_OVERRIDE"), "{out}");
+}
+
+#[test]
+fn systemd_timers_start_only_their_own_job() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let data = dir.path().join("data");
+    config["jobs"] = json!([
+        {"name": "photos", "filename": data, "schedule": "weekly sun 04:30", "jitter": "15m"},
+        {"name": "my-docs", "filename": data, "schedule": "daily 01:15"},
+        {"name": "manual", "filename": data},
+    ]);
+
+    let output = run(&dir, &config, &["install-systemd", "--user", "--print"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("# synology_backuper@.service\n"), "{out}");
+    assert!(out.contains(" backup --job \"%I\"\n"), "{out}");
+    let timers = out.split("\n# ").skip(1).collect::<Vec<_>>();
+    assert_eq!(timers.len(), 2, "{out}");
+    assert!(
+        timers[0].starts_with("synology_backuper@photos.timer\n"),
+        "{out}"
+    );
+    assert!(
+        timers[0].contains("OnCalendar=Sun *-*-* 04:30:00\n"),
+        "{out}"
+    );
+    assert!(timers[0].contains("RandomizedDelaySec=900\n"), "{out}");
+    assert!(
+        timers[1].starts_with("synology_backuper@my\\x2ddocs.timer\n"),
+        "{out}"
+    );
+    assert!(timers[1].contains("OnCalendar=*-*-* 01:15:00\n"), "{out}");
+    assert!(!timers[1].contains("RandomizedDelaySec"), "{out}");
+    assert!(!out.contains("manual"), "{out}");
+}
+
+#[test]
+fn systemd_install_needs_a_schedule() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["install-systemd", "--user", "--print"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("schedule"));
+}
+
+#[test]
+fn reads_password_from_systemd_credential() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("pwd");
+    dir.write("creds/synology_backuper_pwd", "from-credential\n");
+
+    let creds = dir.path().join("creds");
+    let output = run_env(
+        &dir,
+        &config,
+        &[],
+        &[("CREDENTIALS_DIRECTORY", creds.to_str().unwrap())],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        mock.calls("SYNO.API.Auth", "login")[0].params["passwd"],
+        "from-credential"
+    );
+}
+
+#[test]
+fn task_scheduler_xml_has_one_trigger_per_job() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "photos", "filename": "photos", "schedule": "weekly sun 04:30"},
+        {"name": "docs", "filename": "docs", "schedule": "daily 01:15", "jitter": "15m"},
+    ]);
+
+    let output = run(
+        &dir,
+        &config,
+        &["install-schedule", "--platform", "windows", "--print"],
+    );
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(out.matches("<CalendarTrigger>").count(), 2, "{out}");
+    let tasks = out.split("<!-- task ").skip(1).collect::<Vec<_>>();
+    assert_eq!(tasks.len(), 2, "{out}");
+    assert!(
+        tasks[0].starts_with("synology_backuper_photos -->"),
+        "{out}"
+    );
+    assert!(tasks[1].starts_with("synology_backuper_docs -->"), "{out}");
+    assert!(
+        tasks[1].contains("backup --job &quot;docs&quot;</Arguments>"),
+        "{out}"
+    );
+    assert!(!tasks[0].contains("<RandomDelay>"), "{out}");
+    assert!(out.contains("<DaysOfWeek><Sunday /></DaysOfWeek>"), "{out}");
+    assert!(out.contains("T01:15:00</StartBoundary>"), "{out}");
+    assert_eq!(
+        out.matches("<RandomDelay>PT900S</RandomDelay>").count(),
+        1,
+        "{out}"
+    );
+    let config_path = dir.path().join("config.json").canonicalize().unwrap();
+    assert!(
+        out.contains(&format!(
+            "<Arguments>--config &quot;{}&quot; backup --job &quot;photos&quot;</Arguments>",
+            config_path.display()
+        )),
+        "{out}"
+    );
+}
+
+#[test]
+fn task_scheduler_puts_the_random_delay_where_the_schema_has_it() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "hourly", "filename": "a", "schedule": "hourly :15", "jitter": "10m"},
+        {"name": "daily", "filename": "b", "schedule": "daily 01:15", "jitter": "1h"},
+    ]);
+
+    let output = run(
+        &dir,
+        &config,
+        &["install-schedule", "--platform", "windows", "--print"],
+    );
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // After the trigger's own settings and before what it repeats by.
+    assert!(
+        out.contains(
+            "    <TimeTrigger>
+      <StartBoundary>2024-01-01T00:15:00</StartBoundary>
+      <Repetition><Interval>PT1H</Interval></Repetition>
+      <RandomDelay>PT600S</RandomDelay>
+    </TimeTrigger>
+"
+        ),
+        "{out}"
+    );
+    assert!(
+        out.contains(
+            "    <CalendarTrigger>
+      <StartBoundary>2024-01-01T01:15:00</StartBoundary>
+      <RandomDelay>PT3600S</RandomDelay>
+      <ScheduleByDay><DaysInterval>1</DaysInterval></ScheduleByDay>
+    </CalendarTrigger>
+"
+        ),
+        "{out}"
+    );
+}
+
+#[test]
+fn launchd_plist_uses_calendar_intervals() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["jobs"] = json!([
+        {"name": "photos", "filename": "photos", "schedule": "weekly sun 04:30", "jitter": "5m"},
+        {"name": "manual", "filename": "photos"},
+    ]);
+
+    let output = run(
+        &dir,
+        &config,
+        &["install-schedule", "--platform", "macos", "--print"],
+    );
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(out.matches("<key>Label</key>").count(), 1, "{out}");
+    assert!(
+        out.contains("<string>com.github.el-hult.synology_backuper.photos</string>"),
+        "{out}"
+    );
+    assert!(
+        out.contains("<string>--job</string>\n    <string>photos</string>"),
+        "{out}"
+    );
+    assert!(
+        stderr(&output).contains("job photos's `jitter` is left out"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(
+        out.contains("<key>Weekday</key><integer>0</integer>"),
+        "{out}"
+    );
+    assert!(out.contains("<key>Hour</key><integer>4</integer>"), "{out}");
+    assert!(
+        out.contains("<key>Minute</key><integer>30</integer>"),
+        "{out}"
+    );
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::*;
+use serde_json::Value;
+
+#[test]
+fn rotating_keeps_the_older_keys_and_export_holds_them_all() {
+    let dir = TempDir::new();
+    let keyring = dir.path().join("xdg/config/synology_backuper/keys.json");
+
+    let output = run_bare(&dir, &["key", "export"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("`key generate` makes one"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = run_bare(&dir, &["key", "generate"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains(keyring.to_str().unwrap()),
+        "{}",
+        stdout(&output)
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&keyring).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let first: Value = serde_json::from_str(&std::fs::read_to_string(&keyring).unwrap()).unwrap();
+
+    let output = run_bare(&dir, &["key", "generate"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("`key rotate`"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = run_bare(&dir, &["key", "rotate"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let out = dir.path().join("export.json");
+    let output = run_bare(
+        &dir,
+        &["key", "export", "--out", out.to_str().unwrap()],
+        &[],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let export: Value = serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+    let keys = export["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0], first["keys"][0]);
+    assert_ne!(export["current"], first["current"]);
+    assert_eq!(export["current"], keys[1]["id"]);
+    assert_ne!(keys[0]["key"], keys[1]["key"]);
+}
+
+#[test]
+fn rotating_can_seal_recovery_bundles_again_with_the_new_key() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+    let keyring = dir.path().join("xdg/config/synology_backuper/keys.json");
+    assert!(run_bare(&dir, &["key", "generate"], &[]).status.success());
+    let bundle = dir.path().join("recovery.sbr");
+    let output = run(
+        &dir,
+        &config,
+        &["export-recovery", "--out", bundle.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let before = std::fs::read(&bundle).unwrap();
+
+    let output = run_bare(
+        &dir,
+        &["key", "rotate", "--reseal", bundle.to_str().unwrap()],
+        &[],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut keys: Value =
+        serde_json::from_str(&std::fs::read_to_string(&keyring).unwrap()).unwrap();
+    let current = keys["current"].as_str().unwrap().to_string();
+    assert!(
+        stdout(&output).contains(&format!("again with key {current}")),
+        "{}",
+        stdout(&output)
+    );
+    let after = std::fs::read(&bundle).unwrap();
+    assert_ne!(before, after);
+    assert_eq!(&after[4..12], current.as_bytes());
+    let left = std::fs::read_dir(keyring.parent().unwrap())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(left, ["keys.json"]);
+
+    // The new key alone opens it.
+    keys["keys"].as_array_mut().unwrap().remove(0);
+    let only_new = dir.path().join("only_new.json");
+    std::fs::write(&only_new, keys.to_string()).unwrap();
+    let to = dir.path().join("opened");
+    let output = run_bare(
+        &dir,
+        &[
+            "open-recovery",
+            bundle.to_str().unwrap(),
+            "--keys",
+            only_new.to_str().unwrap(),
+            "--to",
+            to.to_str().unwrap(),
+        ],
+        &[],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(to.join("RESTORE.txt").exists());
+}
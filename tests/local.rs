@@ -0,0 +1,251 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn backs_up_into_a_local_folder() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let mut config = base_config(&mock, &dir);
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(mock.requests().is_empty());
+    let names = std::fs::read_dir(root.join("backup"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), 1, "{names:?}");
+    assert!(names[0].starts_with("notes.txt_"), "{names:?}");
+
+    let output = run(&dir, &config, &["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains(&format!("primary:/backup/{}", names[0])),
+        "{}",
+        stdout(&output)
+    );
+}
+
+fn usb_config(mock: &MockDsm, dir: &TempDir, root: &std::path::Path) -> serde_json::Value {
+    let mut config = base_config(mock, dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["targets"] = json!([{
+        "name": "usb",
+        "type": "local",
+        "path": root.to_str().unwrap(),
+    }]);
+    config["jobs"] = json!([{
+        "name": "docs",
+        "filename": source,
+        "targets": ["primary", "usb"],
+        "mirror": true,
+        "verify": true,
+    }]);
+    config
+}
+
+#[test]
+fn mirrors_to_a_usb_drive_and_verifies_both_copies() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let config = usb_config(&mock, &dir, &root);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(err.contains("primary: uploaded to /backup/"), "{err}");
+    assert!(err.contains("usb: uploaded to /backup/"), "{err}");
+    assert_eq!(err.matches(" and verified").count(), 2, "{err}");
+    assert_eq!(mock.calls("SYNO.FileStation.Download", "download").len(), 1);
+    assert_eq!(std::fs::read_dir(root.join("backup")).unwrap().count(), 1);
+}
+
+#[test]
+fn refuses_a_drive_that_is_not_mounted() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    // An empty mount point: the share folder only exists on the drive itself.
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(&root).unwrap();
+    let config = usb_config(&mock, &dir, &root);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(0), "{err}");
+    assert!(err.contains("usb: share backup not found"), "{err}");
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+}
+
+#[test]
+fn check_chain_fails_once_a_full_archive_goes() {
+    use chrono::Datelike;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+    let full_on = chrono::Local::now().weekday().succ().to_string();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "differential": full_on}]);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    dir.write("data/notes.txt", "changed since");
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run(&dir, &config, &["check-chain"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}");
+    assert!(
+        out.starts_with("OK - the chains of 1 jobs are whole"),
+        "{out}"
+    );
+    assert!(out.contains("2 archives of the chain there"), "{out}");
+
+    let mut names = std::fs::read_dir(root.join("backup"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    std::fs::remove_file(root.join("backup").join(&names[0])).unwrap();
+    let output = run(&dir, &config, &["check-chain"]);
+    let out = stdout(&output);
+    assert_eq!(output.status.code(), Some(2), "{out}");
+    assert!(out.starts_with("CRITICAL - "), "{out}");
+    assert!(
+        out.contains(&format!(
+            "{} can't be restored, as the full archive {} it builds on is gone",
+            names[1], names[0]
+        )),
+        "{out}"
+    );
+}
+
+#[test]
+fn machines_share_their_run_logs_for_a_fleet_wide_check() {
+    let mock = MockDsm::start();
+    let shared = TempDir::new();
+    let root = shared.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let machine = |host: &str, job: &str| {
+        let dir = TempDir::new();
+        let mut config = base_config(&mock, &dir);
+        config.as_object_mut().unwrap().remove("filename");
+        let source = dir.write(&format!("data/{job}.txt"), job);
+        config["transport"] = json!("local");
+        config["path"] = json!(root.to_str().unwrap());
+        config["fleet"] = json!({"folder": "/backup/fleet", "host": host});
+        config["jobs"] = json!([{"name": job, "filename": source.to_str().unwrap()}]);
+        let output = run(&dir, &config, &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        (dir, config)
+    };
+    let (alpha, config) = machine("alpha", "notes");
+    let _beta = machine("beta", "photos");
+    let mut logs = std::fs::read_dir(root.join("backup/fleet"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    logs.sort();
+    assert_eq!(logs, ["runs-alpha.jsonl", "runs-beta.jsonl"]);
+
+    let output = run(&alpha, &config, &["check", "--max-age", "1h", "--fleet"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}");
+    assert!(
+        out.starts_with("OK - 2 jobs on 2 machines backed up within 1h"),
+        "{out}"
+    );
+    assert!(out.contains("alpha/notes: last backup"), "{out}");
+    assert!(out.contains("beta/photos: last backup"), "{out}");
+
+    let output = run(&alpha, &config, &["list", "--fleet"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.lines().all(|line| line.ends_with("\talpha")), "{out}");
+
+    let output = run(&alpha, &config, &["status", "--fleet"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rows = out.lines().collect::<Vec<_>>();
+    assert_eq!(rows.len(), 3, "{out}");
+    assert!(rows[0].starts_with("MACHINE"), "{out}");
+    assert!(rows[1].starts_with("alpha           notes"), "{out}");
+    assert!(rows[2].starts_with("beta            photos"), "{out}");
+    assert!(rows[2].ends_with('▁'), "{out}");
+}
+
+#[test]
+fn status_lists_the_jobs_of_every_machine_in_the_fleet() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    let folder = root.join("backup/fleet");
+    std::fs::create_dir_all(&folder).unwrap();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+    config["fleet"] = json!({"folder": "/backup/fleet", "host": "alpha"});
+    config["jobs"] = json!([{"name": "notes", "filename": source}]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // beta last backed up its photos ten days ago, and never its mail.
+    let ago = |days: i64| (chrono::Utc::now() - chrono::Duration::days(days)).to_rfc3339();
+    let beta = [
+        (12, "ok", 1000),
+        (11, "ok", 1500),
+        (10, "ok", 2000),
+        (1, "failed", 0),
+    ]
+    .map(|(days, result, size)| {
+        json!({"start": ago(days), "end": ago(days), "result": result, "jobs": [
+            {"job": "photos", "result": result, "end": ago(days), "archive_bytes": size},
+            {"job": "mail", "result": "failed", "end": ago(days)},
+        ]})
+        .to_string()
+    })
+    .join("\n");
+    std::fs::write(folder.join("runs-beta.jsonl"), beta).unwrap();
+    // gamma shares a log without a run in it yet, and delta's can't be read.
+    std::fs::write(folder.join("runs-gamma.jsonl"), "").unwrap();
+    std::fs::write(folder.join("runs-delta.jsonl"), [0xff, 0xfe]).unwrap();
+
+    let output = run(&dir, &config, &["status", "--fleet"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Could not read the run log of delta"),
+        "{}",
+        stderr(&output)
+    );
+    let rows = out.lines().collect::<Vec<_>>();
+    assert_eq!(rows.len(), 5, "{out}");
+    assert!(rows[0].starts_with("MACHINE         JOB"), "{out}");
+    assert!(rows[1].starts_with("alpha           notes"), "{out}");
+    assert!(rows[1].contains(" 0s "), "{out}");
+    assert!(
+        rows[2].starts_with("beta            mail                never"),
+        "{out}"
+    );
+    assert!(rows[3].starts_with("beta            photos"), "{out}");
+    assert!(rows[3].contains(" 240h "), "{out}");
+    assert!(rows[3].contains(" 2.0 KiB "), "{out}");
+    assert!(rows[3].ends_with("▁▄█ +100%"), "{out}");
+    assert!(
+        rows[4].starts_with("gamma                               never"),
+        "{out}"
+    );
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+fn profile_config(home: &MockDsm, offsite: &MockDsm, dir: &TempDir) -> serde_json::Value {
+    let mut config = base_config(home, dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{"name": "notes", "filename": source, "keep_last": 10}]);
+    config["profiles"] = json!({
+        "offsite": {
+            "port": offsite.port(),
+            "usr": "remote",
+            "share_name": "photo",
+            "jobs": [{"name": "notes", "keep_last": 1}],
+        },
+    });
+    config
+}
+
+#[test]
+fn profile_overrides_connection_and_retention() {
+    let home = MockDsm::start();
+    let offsite = MockDsm::start();
+    offsite.once(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 2, "files": [
+            {"name": "notes.txt_20240102_030000.zip", "path": "/photo/notes.txt_20240102_030000.zip", "isdir": false},
+            {"name": "notes.txt_20240101_030000.zip", "path": "/photo/notes.txt_20240101_030000.zip", "isdir": false},
+        ]})),
+    );
+    let dir = TempDir::new();
+    let config = profile_config(&home, &offsite, &dir);
+
+    let output = run(
+        &dir,
+        &config,
+        &["--profile", "offsite", "prune", "--dry-run"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(home.requests().is_empty());
+    assert_eq!(
+        offsite.calls("SYNO.API.Auth", "login")[0].params["account"],
+        "remote"
+    );
+    assert_eq!(
+        stdout(&output).trim(),
+        "/photo/notes.txt_20240101_030000.zip"
+    );
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(home.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn names_the_profiles_when_one_is_missing() {
+    let home = MockDsm::start();
+    let dir = TempDir::new();
+    let config = profile_config(&home, &home, &dir);
+
+    let output = run(&dir, &config, &["--profile", "work"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("no profile work; it has offsite"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn finds_the_config_in_the_xdg_config_dir() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = serde_json::to_string(&base_config(&mock, &dir)).unwrap();
+    let config_dir = dir.path().join("xdg/config/synology_backuper");
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(config_dir.join("config.json"), &config).unwrap();
+
+    let output = run_bare(&dir, &[], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+
+    let output = run_bare(&dir, &["--config", "elsewhere.json"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("Could not read config file elsewhere.json"),
+        "{}",
+        stderr(&output)
+    );
+}
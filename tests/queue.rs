@@ -0,0 +1,176 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+fn queue_lines(dir: &TempDir, config: &serde_json::Value) -> Vec<String> {
+    let output = run(dir, config, &["queue"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output).lines().map(String::from).collect()
+}
+
+#[test]
+fn queues_archives_while_the_nas_is_unreachable_and_flushes_them_later() {
+    let mock = MockDsm::start();
+    mock.once("SYNO.API.Auth", "login", err(400));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("was queued for a later upload"),
+        "{}",
+        stderr(&output)
+    );
+    let lines = queue_lines(&dir, &config);
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    let fields = lines[0].split('\t').collect::<Vec<_>>();
+    assert_eq!(fields[1], "default");
+    assert!(fields[2].starts_with("notes.txt_"), "{fields:?}");
+    assert_eq!(fields[4], "1");
+    assert!(fields[5].contains("no target reachable"), "{fields:?}");
+    let name = fields[2].to_string();
+
+    let output = run(&dir, &config, &["queue", "--flush"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.files[0].1, name);
+    assert!(queue_lines(&dir, &config).is_empty());
+    let log =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let flush = serde_json::from_str::<serde_json::Value>(log.lines().last().unwrap()).unwrap();
+    assert_eq!(flush["jobs"][0]["result"], "ok");
+    assert!(flush["jobs"][0]["queued"].is_string());
+}
+
+#[test]
+fn a_backup_uploads_the_queue_before_its_own_archives() {
+    let mock = MockDsm::start();
+    mock.once("SYNO.FileStation.Upload", "upload", err(1800));
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    run(&dir, &config, &[]);
+    assert_eq!(queue_lines(&dir, &config).len(), 1);
+
+    // Archive names only have seconds, so make sure the next one differs.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 3);
+    assert_eq!(uploads[1].files[0].1, uploads[0].files[0].1);
+    assert_ne!(uploads[2].files[0].1, uploads[0].files[0].1);
+    assert!(queue_lines(&dir, &config).is_empty());
+}
+
+#[test]
+fn offline_backups_only_archive_until_upload_pending() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["backup", "--offline"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(mock.requests().is_empty());
+    let lines = queue_lines(&dir, &config);
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    let fields = lines[0].split('\t').collect::<Vec<_>>();
+    assert_eq!(fields[4..], ["0", ""]);
+
+    let output = run(&dir, &config, &["upload-pending"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.files[0].1, fields[2]);
+    assert_eq!(zip_entries(upload)[0].1, b"hello from the backuper tests\n");
+    assert!(queue_lines(&dir, &config).is_empty());
+}
+
+#[test]
+fn a_busy_nas_is_given_time_and_then_the_archive_waits_in_the_queue() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let notes = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] =
+        json!([{"name": "notes", "filename": notes, "busy_retries": 1, "busy_delay": "1s"}]);
+
+    mock.once("SYNO.FileStation.Upload", "upload", err(402));
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("primary is too busy to take the upload; trying again in 1s"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 2);
+
+    config["jobs"][0]["busy_retries"] = json!(0);
+    config["jobs"][0]["busy_reschedule"] = json!("2h");
+    mock.once("SYNO.FileStation.Upload", "upload", err(402));
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Jobs deferred for later: notes"),
+        "{}",
+        stderr(&output)
+    );
+    let output = run(&dir, &config, &["queue"]);
+    let out = stdout(&output);
+    assert_eq!(out.lines().count(), 1, "{out}");
+    assert!(out.contains("System is too busy"), "{out}");
+}
+
+#[test]
+fn an_archive_that_shrinks_while_it_uploads_fails_instead_of_arriving_cut_short() {
+    let mock = MockDsm::start();
+    mock.once("SYNO.API.Auth", "login", err(400));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    // Incompressible data, so the upload takes seconds at the rate limit.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let noise = (0..400_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    let source = dir.path().join("data/noise.bin");
+    std::fs::write(&source, noise).unwrap();
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "slow",
+        "filename": source.to_str().unwrap(),
+        "upload_rate_limit": "100KiB",
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let name = queue_lines(&dir, &config)[0]
+        .split('\t')
+        .nth(2)
+        .unwrap()
+        .to_string();
+    let queued = dir
+        .path()
+        .join("xdg/state/synology_backuper/queue")
+        .join(&name);
+
+    let output = std::thread::scope(|scope| {
+        let flush = scope.spawn(|| run(&dir, &config, &["queue", "--flush"]));
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&queued)
+            .unwrap();
+        file.set_len(1000).unwrap();
+        flush.join().unwrap()
+    });
+    let err = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{err}");
+    assert!(err.contains(&format!("{name} stays in the queue")), "{err}");
+    assert!(err.contains("before its announced length"), "{err}");
+    assert!(mock.calls("SYNO.FileStation.Upload", "upload").is_empty());
+}
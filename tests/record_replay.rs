@@ -0,0 +1,63 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn replays_a_recorded_session_without_the_nas() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let fixtures = dir.path().join("fixtures");
+
+    let output = run(&dir, &config, &["--record", fixtures.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let mut recorded = std::fs::read_dir(&fixtures)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    recorded.sort();
+    assert_eq!(
+        recorded,
+        [
+            "0000_SYNO.API.Info_query.json",
+            "0001_SYNO.API.Auth_login.json",
+            "0002_SYNO.FileStation.List_list_share.json",
+            "0003_SYNO.FileStation.CheckPermission_write.json",
+            "0004_SYNO.FileStation.Upload_upload.json",
+            "0005_SYNO.API.Auth_logout.json",
+        ]
+    );
+    let login = std::fs::read_to_string(fixtures.join(&recorded[1])).unwrap();
+    assert!(!login.contains("secret"), "{login}");
+    assert!(!login.contains("tester"), "{login}");
+    assert!(!login.contains("mock-sid"), "{login}");
+
+    let requests_before = mock.requests().len();
+    config["port"] = json!(1);
+    let output = run(&dir, &config, &["--replay", fixtures.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.requests().len(), requests_before);
+}
+
+#[test]
+fn a_missing_replay_directory_is_an_error_not_a_panic() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+    let missing = dir.path().join("no-such-fixtures");
+
+    let output = run(
+        &dir,
+        &config,
+        &["--replay", missing.to_str().unwrap(), "list"],
+    );
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Could not read fixture directory"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+}
@@ -0,0 +1,249 @@
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+
+#[test]
+fn a_bundle_opens_elsewhere_with_the_exported_keys_and_holds_no_passwords() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["targets"] = json!([{
+        "name": "offsite",
+        "domain": "127.0.0.1",
+        "port": mock.port(),
+        "https": false,
+        "usr": "tester",
+        "pwd": "other secret",
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = run(&dir, &config, &["export-recovery"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("`key generate` makes one"),
+        "{}",
+        stderr(&output)
+    );
+
+    assert!(run_bare(&dir, &["key", "generate"], &[]).status.success());
+    let bundle = dir.path().join("recovery.sbr");
+    let output = run(
+        &dir,
+        &config,
+        &["export-recovery", "--out", bundle.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("without pwd, targets.offsite.pwd, 1 recorded uploads"),
+        "{}",
+        stdout(&output)
+    );
+    let sealed = std::fs::read(&bundle).unwrap();
+    assert!(!sealed.windows(6).any(|w| w == b"secret"));
+
+    // After a rotation the export still holds the key that sealed it.
+    assert!(run_bare(&dir, &["key", "rotate"], &[]).status.success());
+    let keys = dir.path().join("keys-export.json");
+    let args = ["key", "export", "--out", keys.to_str().unwrap()];
+    assert!(run_bare(&dir, &args, &[]).status.success());
+
+    let elsewhere = TempDir::new();
+    let to = elsewhere.path().join("recovered");
+    let open = [
+        "open-recovery",
+        bundle.to_str().unwrap(),
+        "--keys",
+        keys.to_str().unwrap(),
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run_bare(&elsewhere, &open, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let recovered: Value =
+        serde_json::from_str(&std::fs::read_to_string(to.join("config.json")).unwrap()).unwrap();
+    let mut expected = config.clone();
+    expected.as_object_mut().unwrap().remove("pwd");
+    expected["targets"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("pwd");
+    assert_eq!(recovered, expected);
+    let runs = dir.path().join("xdg/state/synology_backuper/runs.jsonl");
+    assert_eq!(
+        std::fs::read(to.join("runs.jsonl")).unwrap(),
+        std::fs::read(runs).unwrap()
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let instructions = std::fs::read_to_string(to.join("RESTORE.txt")).unwrap();
+    assert!(
+        instructions.contains(&format!(
+            "newest recorded upload: primary:/backup/{}",
+            upload.files[0].1
+        )),
+        "{instructions}"
+    );
+    assert!(
+        instructions.contains("offsite: api 127.0.0.1"),
+        "{instructions}"
+    );
+
+    // Opening again doesn't write over what the first one unpacked.
+    let output = run_bare(&elsewhere, &open, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("exists already"),
+        "{}",
+        stderr(&output)
+    );
+
+    let mut damaged = sealed;
+    let last = damaged.len() - 1;
+    damaged[last] ^= 1;
+    std::fs::write(&bundle, damaged).unwrap();
+    let output = run_bare(&elsewhere, &open, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("changed or damaged"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn each_run_uploads_the_config_state_and_keyring_sealed_with_a_passphrase() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let passphrase = dir.write("passphrase.txt", "correct horse battery staple\n");
+    let catalog = dir.write("xdg/state/synology_backuper/catalog.json", "[]");
+    let mut config = base_config(&mock, &dir);
+    config["self_backup"] = json!({
+        "folder": "/backup/synology_backuper",
+        "passphrase_file": passphrase.to_str().unwrap(),
+    });
+    assert!(run_bare(&dir, &["key", "generate"], &[]).status.success());
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    assert_eq!(uploads.len(), 2);
+    let upload = &uploads[1];
+    assert_eq!(upload.params["path"], "/backup/synology_backuper");
+    assert!(upload.files[0].1.starts_with("synology_backuper-"));
+    assert!(upload.files[0].1.ends_with(".sbr"));
+    let bundle = dir.path().join("self.sbr");
+    std::fs::write(&bundle, &upload.files[0].2).unwrap();
+
+    // It opens without the keyring, which it brings along.
+    let elsewhere = TempDir::new();
+    let to = elsewhere.path().join("recovered");
+    let mut open = vec![
+        "open-recovery",
+        bundle.to_str().unwrap(),
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run_bare(&elsewhere, &open, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("encrypted with a passphrase"),
+        "{}",
+        stderr(&output)
+    );
+    open.extend(["--passphrase-file", passphrase.to_str().unwrap()]);
+    let output = run_bare(&elsewhere, &open, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        std::fs::read(to.join("keys.json")).unwrap(),
+        std::fs::read(dir.path().join("xdg/config/synology_backuper/keys.json")).unwrap()
+    );
+    assert_eq!(
+        std::fs::read(to.join("state/catalog.json")).unwrap(),
+        std::fs::read(catalog).unwrap()
+    );
+    let instructions = std::fs::read_to_string(to.join("RESTORE.txt")).unwrap();
+    assert!(
+        instructions.contains("--passphrase-file FILE"),
+        "{instructions}"
+    );
+}
+
+#[test]
+fn a_differential_carries_on_from_the_state_a_bundle_brings_back() {
+    use chrono::Datelike;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+    // Never today, so only a missing index makes an archive full.
+    let full_on = chrono::Local::now().weekday().succ().to_string();
+    config["jobs"] = json!([{
+        "name": "notes",
+        "filename": source,
+        "differential": full_on,
+        "manifest": true,
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    assert!(run_bare(&dir, &["key", "generate"], &[]).status.success());
+    let bundle = dir.path().join("recovery.sbr");
+    let output = run(
+        &dir,
+        &config,
+        &["export-recovery", "--out", bundle.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // The state directory is lost, and comes back from the bundle.
+    let state = dir.path().join("xdg/state/synology_backuper");
+    std::fs::remove_dir_all(&state).unwrap();
+    let to = dir.path().join("recovered");
+    let open = [
+        "open-recovery",
+        bundle.to_str().unwrap(),
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run_bare(&dir, &open, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(to.join("state/index/notes.json").exists());
+    assert!(to.join("state/manifests/notes.json").exists());
+    let instructions = std::fs::read_to_string(to.join("RESTORE.txt")).unwrap();
+    assert!(
+        instructions.contains("state/index/notes.json, state/manifests/notes.json"),
+        "{instructions}"
+    );
+    assert!(
+        instructions.contains("Copy what is in DIR/state, folders and all"),
+        "{instructions}"
+    );
+    assert!(
+        instructions.contains("Archives still queued for upload"),
+        "{instructions}"
+    );
+    for entry in walkdir::WalkDir::new(to.join("state")) {
+        let entry = entry.unwrap();
+        let relative = entry.path().strip_prefix(to.join("state")).unwrap();
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(state.join(relative)).unwrap();
+        } else {
+            std::fs::copy(entry.path(), state.join(relative)).unwrap();
+        }
+    }
+
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    dir.write("data/notes.txt", "changed since");
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("archiving what changed since the full archive"),
+        "{err}"
+    );
+}
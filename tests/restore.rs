@@ -0,0 +1,852 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::time::{Duration, SystemTime};
+
+/// Lists the last uploaded archive on the share, and returns its name and contents.
+fn serve_uploaded_archive(mock: &MockDsm) -> (String, Vec<u8>) {
+    let uploads = mock.calls("SYNO.FileStation.Upload", "upload");
+    let (_, name, contents) = uploads.last().unwrap().files[0].clone();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 1, "files": [{
+            "name": name,
+            "path": format!("/backup/{name}"),
+            "isdir": false,
+            "additional": {"size": contents.len()},
+        }]})),
+    );
+    (name, contents)
+}
+
+/// Bytes that don't compress.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 1u64;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn extracts_only_the_matching_files_with_their_times_and_permissions() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let invoice = dir.write("data/docs/invoices/2024-01.txt", "invoice\n");
+    dir.write("data/docs/letter.txt", "letter\n");
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&invoice)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&invoice, std::fs::Permissions::from_mode(0o640)).unwrap();
+    }
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (name, _) = serve_uploaded_archive(&mock);
+
+    let to = dir.path().join("restored");
+    let args = [
+        "restore",
+        "--name",
+        &name,
+        "--path",
+        "docs/invoices/**",
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run(&dir, &config, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("Restored 1 files"),
+        "{}",
+        stdout(&output)
+    );
+    let restored = to.join(invoice.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read_to_string(&restored).unwrap(), "invoice\n");
+    assert!(!restored.with_file_name("letter.txt").exists());
+    assert!(!restored
+        .parent()
+        .unwrap()
+        .with_file_name("letter.txt")
+        .exists());
+    let meta = std::fs::metadata(&restored).unwrap();
+    let drift = meta
+        .modified()
+        .unwrap()
+        .duration_since(modified)
+        .unwrap_or_default();
+    assert!(drift < Duration::from_secs(2), "{drift:?}");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+    }
+    // Nothing of the download is left behind.
+    assert_eq!(std::fs::read_dir(&to).unwrap().count(), 1);
+
+    // Restoring again keeps what is there.
+    std::fs::write(&restored, "edited\n").unwrap();
+    let output = run(&dir, &config, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("--overwrite replaces it"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(std::fs::read_to_string(&restored).unwrap(), "edited\n");
+
+    let output = run(
+        &dir,
+        &config,
+        &["restore", "--name", "data_19990101_000000.zip"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("No archive named data_19990101_000000.zip on primary"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn lists_an_archive_from_its_end_alone() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    // Noise doesn't compress, so the archive is larger than its end.
+    std::fs::write(dir.path().join("data/noise.bin"), noise(300_000)).unwrap();
+    dir.write("data/docs/letter.txt", "letter\n");
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+    }]);
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (name, archive) = serve_uploaded_archive(&mock);
+    let size = archive.len();
+
+    let output = run(&dir, &config, &["restore", "--name", &name, "--list"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lines = out.lines().collect::<Vec<_>>();
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("293.0 KiB\t") && l.ends_with("data/noise.bin")),
+        "{out}"
+    );
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("7 B\t") && l.ends_with("data/docs/letter.txt")),
+        "{out}"
+    );
+    assert!(
+        out.contains("3 files, 293.0 KiB; read 64.0 KiB of the"),
+        "{out}"
+    );
+    let downloads = mock.calls("SYNO.FileStation.Download", "download");
+    assert_eq!(downloads.len(), 1);
+    assert_eq!(
+        downloads[0].headers["range"],
+        format!("bytes={}-{}", size - 65557, size - 1)
+    );
+
+    let output = run(
+        &dir,
+        &config,
+        &["restore", "--name", &name, "--list", "--path", "docs/**"],
+    );
+    let out = stdout(&output);
+    assert!(
+        out.contains("letter.txt") && !out.contains("noise.bin"),
+        "{out}"
+    );
+    assert!(out.contains("1 files, 7 B"), "{out}");
+}
+
+#[test]
+fn picks_a_few_files_out_of_the_archive_where_it_lies() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    std::fs::write(dir.path().join("data/noise.bin"), noise(3_000_000)).unwrap();
+    dir.write("data/docs/letter.txt", "letter\n");
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+    }]);
+    // Sorted, the letter comes first and the noise after it.
+    let output = run(&dir, &config, &["backup", "--deterministic"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (name, archive) = serve_uploaded_archive(&mock);
+
+    let to = dir.path().join("restored");
+    let args = [
+        "restore",
+        "--name",
+        &name,
+        "--path",
+        "letter.txt",
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("Restored 1 files, 7 B"), "{out}");
+    let downloads = mock.calls("SYNO.FileStation.Download", "download");
+    assert!(downloads.iter().all(|d| d.headers.contains_key("range")));
+    let fetched = downloads
+        .iter()
+        .map(|d| {
+            let (start, end) = d.headers["range"][6..].split_once('-').unwrap();
+            end.parse::<usize>().unwrap() + 1 - start.parse::<usize>().unwrap()
+        })
+        .sum::<usize>();
+    assert!(fetched < archive.len(), "{fetched} of {}", archive.len());
+    assert!(out.contains(" of the 2.9 MiB archive"), "{out}");
+    let letter = dir.path().join("data/docs/letter.txt");
+    let restored = to.join(letter.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read_to_string(restored).unwrap(), "letter\n");
+}
+
+#[test]
+fn carries_on_a_download_that_broke_off() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    std::fs::write(dir.path().join("data/noise.bin"), noise(100_000)).unwrap();
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (name, archive) = serve_uploaded_archive(&mock);
+    mock.once(
+        "SYNO.FileStation.Download",
+        "download",
+        Reply::CutOff(archive, 40_000),
+    );
+
+    let to = dir.path().join("restored");
+    let output = run(
+        &dir,
+        &config,
+        &["restore", "--name", &name, "--to", to.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("broke off after 39.1 KiB"),
+        "{}",
+        stderr(&output)
+    );
+    let downloads = mock.calls("SYNO.FileStation.Download", "download");
+    assert_eq!(downloads.len(), 2);
+    assert_eq!(downloads[1].headers["range"], "bytes=40000-");
+    let noise_file = dir.path().join("data/noise.bin");
+    let restored = to.join(noise_file.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(restored).unwrap(), noise(100_000));
+}
+
+#[test]
+fn resumes_a_failed_restore_and_checks_the_download() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    std::fs::write(dir.path().join("data/noise.bin"), noise(100_000)).unwrap();
+    config["filename"] = dir.path().join("data").to_str().unwrap().into();
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (name, archive) = serve_uploaded_archive(&mock);
+    // The connection breaks, and stays down for every resume.
+    mock.once(
+        "SYNO.FileStation.Download",
+        "download",
+        Reply::CutOff(archive.clone(), 40_000),
+    );
+    for _ in 0..3 {
+        mock.once("SYNO.FileStation.Download", "download", err(408));
+    }
+
+    let to = dir.path().join("restored");
+    let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+    let output = run(&dir, &config, &args);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("the 39.1 KiB downloaded stay in"),
+        "{}",
+        stderr(&output)
+    );
+    let partial = to.join(format!(".synology_backuper_restore_{name}"));
+    assert_eq!(std::fs::metadata(&partial).unwrap().len(), 40_000);
+
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("earlier restore left after 39.1 KiB"), "{out}");
+    assert!(out.contains("matches the SHA-256 recorded"), "{out}");
+    let downloads = mock.calls("SYNO.FileStation.Download", "download");
+    assert_eq!(downloads.last().unwrap().headers["range"], "bytes=40000-");
+    assert!(!partial.exists());
+    let noise_file = dir.path().join("data/noise.bin");
+    let restored = to.join(noise_file.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(&restored).unwrap(), noise(100_000));
+
+    // What an earlier restore left doesn't belong to this archive.
+    std::fs::write(&partial, vec![0; 40_000]).unwrap();
+    let output = run(&dir, &config, &[&args[..], &["--overwrite"]].concat());
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("recorded when it was uploaded; it was deleted"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!partial.exists());
+}
+
+#[test]
+fn the_manifest_lists_the_checksums_and_restore_checks_them() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let abc = dir.write("data/abc.txt", "abc");
+    let empty = dir.write("data/empty.txt", "");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "manifest": true,
+    }]);
+    config.as_object_mut().unwrap().remove("filename");
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entries = zip_entries(upload);
+    let (name, manifest) = entries.last().unwrap();
+    assert_eq!(name, ".synology_backuper_manifest.b3");
+    let manifest = String::from_utf8(manifest.clone()).unwrap();
+    let entry = |path: &std::path::Path| path.to_str().unwrap().trim_start_matches('/').to_string();
+    assert!(
+        manifest.contains(&format!(
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85  {}\n",
+            entry(&abc)
+        )),
+        "{manifest}"
+    );
+    assert!(
+        manifest.contains(&format!(
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262  {}\n",
+            entry(&empty)
+        )),
+        "{manifest}"
+    );
+
+    let (name, _) = serve_uploaded_archive(&mock);
+    let to = dir.path().join("restored");
+    let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+    let output = run(&dir, &config, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("All 3 match the BLAKE3 checksums in the archive's manifest"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(!to.join(".synology_backuper_manifest.b3").exists());
+
+    // An archive changed since leaves the changed file restored but fails.
+    // Without the run log, whose checksum of the whole archive would catch it first.
+    std::fs::remove_file(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    let mut changed = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (entry_name, contents) in &entries {
+        changed
+            .start_file(
+                entry_name.as_str(),
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+        let contents = if *entry_name == entry(&abc) {
+            b"abd".to_vec()
+        } else {
+            contents.clone()
+        };
+        std::io::Write::write_all(&mut changed, &contents).unwrap();
+    }
+    let changed = changed.finish().unwrap().into_inner();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 1, "files": [{
+            "name": name,
+            "path": format!("/backup/{name}"),
+            "isdir": false,
+            "additional": {"size": changed.len()},
+        }]})),
+    );
+    mock.on(
+        "SYNO.FileStation.Download",
+        "download",
+        Reply::File(changed),
+    );
+    let output = run(&dir, &config, &[&args[..], &["--overwrite"]].concat());
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains(&format!(
+            "1 of the restored files differ from the BLAKE3 checksums in the archive's manifest: {}",
+            to.join(entry(&abc)).display()
+        )),
+        "{}",
+        stderr(&output)
+    );
+
+    config["jobs"][0]["checksum"] = json!("sha256");
+    assert!(run(&dir, &config, &[]).status.success());
+    let upload = mock
+        .calls("SYNO.FileStation.Upload", "upload")
+        .pop()
+        .unwrap();
+    let entries = zip_entries(&upload);
+    let (name, manifest) = entries.last().unwrap();
+    assert_eq!(name, ".synology_backuper_manifest.sha256");
+    assert!(String::from_utf8_lossy(manifest)
+        .contains("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  "));
+}
+
+#[cfg(unix)]
+#[test]
+fn hard_links_are_stored_once_and_linked_again() {
+    use std::os::unix::fs::MetadataExt;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let original = dir.write("data/a/mail.eml", "the same message\n");
+    std::fs::create_dir_all(dir.path().join("data/b")).unwrap();
+    std::fs::hard_link(&original, dir.path().join("data/b/mail.eml")).unwrap();
+    dir.write("data/b/other.eml", "another message\n");
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "dedup_hardlinks": true,
+        "manifest": true,
+    }]);
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("1 hard links to files archived under another name were stored once"),
+        "{err}"
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entries = zip_entries(upload);
+    let mails = entries
+        .iter()
+        .filter(|(name, _)| name.ends_with("mail.eml"))
+        .count();
+    assert_eq!(mails, 1);
+    let (_, links) = entries
+        .iter()
+        .find(|(name, _)| name == ".synology_backuper_links.json")
+        .unwrap();
+    let links: serde_json::Value = serde_json::from_slice(links).unwrap();
+    assert_eq!(links.as_object().unwrap().len(), 1);
+
+    let (name, _) = serve_uploaded_archive(&mock);
+    let to = dir.path().join("restored");
+    let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("Restored 3 files"), "{out}");
+    assert!(out.contains("Linked 1 more names to them"), "{out}");
+    let restored = to.join(dir.path().strip_prefix("/").unwrap()).join("data");
+    let (a, b) = (restored.join("a/mail.eml"), restored.join("b/mail.eml"));
+    assert_eq!(std::fs::read_to_string(&b).unwrap(), "the same message\n");
+    assert_eq!(
+        std::fs::metadata(&a).unwrap().ino(),
+        std::fs::metadata(&b).unwrap().ino()
+    );
+    assert!(!to.join(".synology_backuper_links.json").exists());
+
+    // The link alone is extracted from the file it links to.
+    let alone = dir.path().join("alone");
+    let args = [
+        "restore",
+        "--name",
+        &name,
+        "--path",
+        "b/mail.eml",
+        "--to",
+        alone.to_str().unwrap(),
+    ];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(out.contains("Restored 1 files"), "{out}");
+    assert!(out.contains("All 1 match"), "{out}");
+    let restored = alone
+        .join(dir.path().strip_prefix("/").unwrap())
+        .join("data");
+    assert_eq!(
+        std::fs::read_to_string(restored.join("b/mail.eml")).unwrap(),
+        "the same message\n"
+    );
+    assert!(!restored.join("a/mail.eml").exists());
+}
+
+#[test]
+fn same_contents_are_stored_once_and_copied_again() {
+    for parallelism in [1, 3] {
+        let mock = MockDsm::start();
+        let dir = TempDir::new();
+        let mut config = base_config(&mock, &dir);
+        config.as_object_mut().unwrap().remove("filename");
+        dir.write("data/2024/beach.jpg", "waves and sand");
+        dir.write("data/copy of beach.jpg", "waves and sand");
+        dir.write("data/2024/hills.jpg", "grass and rock");
+        config["jobs"] = json!([{
+            "name": "data",
+            "filename": dir.path().join("data").to_str().unwrap(),
+            "dedup_contents": true,
+            "parallelism": parallelism,
+        }]);
+        let output = run(&dir, &config, &[]);
+        let err = stderr(&output);
+        assert!(output.status.success(), "{err}");
+        assert!(
+            err.contains("1 files with the same contents as another were stored once, saving 14 B"),
+            "{err}"
+        );
+        let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+        let entries = zip_entries(upload);
+        let beaches = entries
+            .iter()
+            .filter(|(name, _)| name.ends_with("beach.jpg"))
+            .count();
+        assert_eq!(beaches, 1, "{parallelism}");
+        assert!(entries.iter().any(|(name, _)| name.ends_with("hills.jpg")));
+        let (_, manifest) = entries.last().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(manifest)
+                .matches("beach.jpg")
+                .count(),
+            2
+        );
+
+        let (name, _) = serve_uploaded_archive(&mock);
+        let to = dir.path().join("restored");
+        let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+        let output = run(&dir, &config, &args);
+        let out = stdout(&output);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(out.contains("Restored 3 files"), "{out}");
+        assert!(
+            out.contains("Copied 1 more files from those with the same contents"),
+            "{out}"
+        );
+        let restored = to.join(dir.path().strip_prefix("/").unwrap()).join("data");
+        for path in ["2024/beach.jpg", "copy of beach.jpg"] {
+            assert_eq!(
+                std::fs::read_to_string(restored.join(path)).unwrap(),
+                "waves and sand"
+            );
+        }
+
+        // The copy alone is extracted from the file with its contents.
+        let alone = dir.path().join("alone");
+        let args = [
+            "restore",
+            "--name",
+            &name,
+            "--path",
+            "copy of beach.jpg",
+            "--to",
+            alone.to_str().unwrap(),
+        ];
+        let output = run(&dir, &config, &args);
+        let out = stdout(&output);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(out.contains("Restored 1 files"), "{out}");
+        let restored = alone
+            .join(dir.path().strip_prefix("/").unwrap())
+            .join("data");
+        assert!(restored.join("copy of beach.jpg").exists());
+        assert!(!restored.join("2024/beach.jpg").exists());
+    }
+}
+
+#[test]
+fn a_differential_holds_the_changes_and_restores_with_its_full_archive() {
+    use chrono::Datelike;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let root = dir.path().join("usb");
+    std::fs::create_dir_all(root.join("backup")).unwrap();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    config["transport"] = json!("local");
+    config["path"] = json!(root.to_str().unwrap());
+    dir.write("data/kept.txt", "the same all week");
+    dir.write("data/changed.txt", "as on sunday");
+    dir.write("data/deleted.txt", "gone by monday");
+    // Never today, so only the missing index makes the first archive full.
+    let full_on = chrono::Local::now().weekday().succ().to_string();
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "differential": full_on,
+    }]);
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("making a full archive, as there is none"),
+        "{err}"
+    );
+    let full = std::fs::read_dir(root.join("backup"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .file_name()
+        .into_string()
+        .unwrap();
+
+    std::thread::sleep(Duration::from_millis(1100));
+    dir.write("data/changed.txt", "changed on monday");
+    std::fs::remove_file(dir.path().join("data/deleted.txt")).unwrap();
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains(&format!(
+            "archiving what changed since the full archive {full}"
+        )),
+        "{err}"
+    );
+    assert!(
+        err.contains("2 files unchanged since the full archive were left out"),
+        "{err}"
+    );
+    let mut names = std::fs::read_dir(root.join("backup"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names.len(), 2, "{names:?}");
+    let differential = names.iter().find(|n| **n != full).unwrap().clone();
+    let zip = std::fs::File::open(root.join("backup").join(&differential)).unwrap();
+    let zip = zip::ZipArchive::new(zip).unwrap();
+    let mut entries = zip
+        .file_names()
+        .filter_map(|n| n.rsplit('/').next())
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [".synology_backuper_differential.json", "changed.txt"]
+    );
+
+    let to = dir.path().join("restored");
+    let args = [
+        "restore",
+        "--name",
+        &differential,
+        "--to",
+        to.to_str().unwrap(),
+    ];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains(&format!("holds what changed since the full archive {full}")),
+        "{out}"
+    );
+    assert!(out.contains("Restored 3 files"), "{out}");
+    let restored = to.join(dir.path().strip_prefix("/").unwrap()).join("data");
+    assert_eq!(
+        std::fs::read_to_string(restored.join("changed.txt")).unwrap(),
+        "changed on monday"
+    );
+    assert!(restored.join("kept.txt").exists());
+    assert!(restored.join("notes.txt").exists());
+    assert!(!restored.join("deleted.txt").exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn xattrs_and_acls_are_archived_and_restored() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    let c = |path: &std::path::Path| CString::new(path.as_os_str().as_bytes()).unwrap();
+    let set = |path: &std::path::Path, name: &str, value: &[u8]| {
+        let name = CString::new(name).unwrap();
+        let status = unsafe {
+            libc::lsetxattr(
+                c(path).as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        assert_eq!(status, 0, "{}", std::io::Error::last_os_error());
+    };
+    let get = |path: &std::path::Path, name: &str| {
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0u8; 256];
+        let len = unsafe {
+            libc::lgetxattr(
+                c(path).as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        (len >= 0).then(|| buf[..len as usize].to_vec())
+    };
+    // user::rw-, user:12345:r--, group::r--, mask::r--, other::---, as the
+    // kernel stores them: a version, then tag, permissions and id for each.
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [
+        (0x01u16, 6u16, u32::MAX),
+        (0x02, 4, 12345),
+        (0x04, 4, u32::MAX),
+        (0x10, 4, u32::MAX),
+        (0x20, 0, u32::MAX),
+    ] {
+        acl.extend(tag.to_le_bytes());
+        acl.extend(perm.to_le_bytes());
+        acl.extend(id.to_le_bytes());
+    }
+
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let tagged = dir.write("data/docs/tagged.txt", "with attributes\n");
+    dir.write("data/docs/plain.txt", "without\n");
+    set(&tagged, "user.origin", b"scanner");
+    set(&tagged, "system.posix_acl_access", &acl);
+    set(&dir.path().join("data/docs"), "user.project", b"taxes");
+    std::fs::set_permissions(&tagged, std::fs::Permissions::from_mode(0o440)).unwrap();
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "xattrs": true,
+    }]);
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("2 files and folders had extended attributes or ACLs, which were kept"),
+        "{err}"
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entries = zip_entries(upload);
+    let (_, kept) = entries
+        .iter()
+        .find(|(name, _)| name == ".synology_backuper_xattrs.json")
+        .unwrap();
+    let kept: serde_json::Value = serde_json::from_slice(kept).unwrap();
+    let docs = dir.path().join("data/docs");
+    let folder = format!("{}/", docs.to_str().unwrap().trim_start_matches('/'));
+    assert_eq!(
+        kept[&folder]["xattrs"]["user.project"], "dGF4ZXM=",
+        "{kept}"
+    );
+
+    let (name, _) = serve_uploaded_archive(&mock);
+    let to = dir.path().join("restored");
+    let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+    let output = run(&dir, &config, &args);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        out.contains("Set the extended attributes and ACLs of 2 files and folders"),
+        "{out}"
+    );
+    let restored = to.join(docs.strip_prefix("/").unwrap());
+    let file = restored.join("tagged.txt");
+    assert_eq!(get(&file, "user.origin").as_deref(), Some(&b"scanner"[..]));
+    // As chmod left it, with the owner's write permission taken away
+    let acl = get(&tagged, "system.posix_acl_access");
+    assert!(acl.as_ref().is_some_and(|acl| acl.len() == 4 + 5 * 8));
+    assert_eq!(get(&file, "system.posix_acl_access"), acl);
+    assert_eq!(
+        std::fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+        0o440
+    );
+    assert_eq!(
+        get(&restored, "user.project").as_deref(),
+        Some(&b"taxes"[..])
+    );
+    assert_eq!(get(&restored.join("plain.txt"), "user.origin"), None);
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_file_attributes_are_kept_and_streams_reported() {
+    use std::os::windows::fs::MetadataExt;
+    const HIDDEN: u32 = 0x2;
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let hidden = dir.write("data/hidden.txt", "out of sight\n");
+    let downloaded = dir.write("data/downloaded.txt", "from the internet\n");
+    let status = std::process::Command::new("attrib")
+        .arg("+h")
+        .arg(&hidden)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let stream = format!("{}:Zone.Identifier", downloaded.display());
+    std::fs::write(stream, "[ZoneTransfer]\r\nZoneId=3\r\n").unwrap();
+    config["jobs"] = json!([{
+        "name": "data",
+        "filename": dir.path().join("data").to_str().unwrap(),
+        "xattrs": true,
+        "ads_warnings": true,
+    }]);
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains(&format!(
+            "{}: the alternate data stream Zone.Identifier",
+            downloaded.display()
+        )),
+        "{err}"
+    );
+    let upload = &mock.calls("SYNO.FileStation.Upload", "upload")[0];
+    let entry = zip_entries(upload)
+        .into_iter()
+        .map(|(name, _)| name)
+        .find(|name| name.ends_with("hidden.txt"))
+        .unwrap();
+
+    let (name, _) = serve_uploaded_archive(&mock);
+    let to = dir.path().join("restored");
+    let args = ["restore", "--name", &name, "--to", to.to_str().unwrap()];
+    let output = run(&dir, &config, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let restored = std::fs::metadata(to.join(entry)).unwrap();
+    assert_ne!(restored.file_attributes() & HIDDEN, 0);
+}
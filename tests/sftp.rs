@@ -0,0 +1,162 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use serde_json::json;
+
+/// Installs a stand-in `sftp` that runs the batch commands against the local
+/// folder `remote` and logs its arguments and commands to `sftp.log`.
+fn fake_sftp(dir: &TempDir) -> (String, std::path::PathBuf) {
+    let bin = dir.path().join("bin");
+    let remote = dir.path().join("remote");
+    std::fs::create_dir_all(remote.join("backup")).unwrap();
+    std::fs::create_dir_all(remote.join("photo")).unwrap();
+    let script = dir.write(
+        "bin/sftp",
+        &format!(
+            r#"#!/bin/sh
+root="{root}"
+echo "args $*" >> "{log}"
+while read -r line; do
+  echo "batch $line" >> "{log}"
+  echo "sftp> $line"
+  set -- $(echo "$line" | tr -d '"')
+  case "${{1#-}}" in
+    ls) ls -ln "$root$3" | tail -n +2 ;;
+    put) cp "$2" "$root$3" ;;
+    get) cp "$root$2" "$3" ;;
+    rm) rm "$root$2" ;;
+    mkdir) mkdir -p "$root$2" ;;
+  esac || exit 1
+done
+"#,
+            root = remote.display(),
+            log = dir.path().join("sftp.log").display(),
+        ),
+    );
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    (path, remote)
+}
+
+fn sftp_config(mock: &MockDsm, dir: &TempDir) -> serde_json::Value {
+    let mut config = base_config(mock, dir);
+    config["transport"] = json!("sftp");
+    config["port"] = json!(2222);
+    config["identity_file"] = json!("/keys/backup");
+    config.as_object_mut().unwrap().remove("pwd");
+    config
+}
+
+#[test]
+fn uploads_with_the_system_sftp() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let (path, remote) = fake_sftp(&dir);
+    let config = sftp_config(&mock, &dir);
+
+    let output = run_env(&dir, &config, &[], &[("PATH", &path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(mock.requests().is_empty());
+    let uploaded = std::fs::read_dir(remote.join("backup"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(uploaded.len(), 1);
+    assert!(uploaded[0].starts_with("notes.txt_"), "{uploaded:?}");
+    let log = std::fs::read_to_string(dir.path().join("sftp.log")).unwrap();
+    assert!(
+        log.contains("-P 2222 -i /keys/backup tester@127.0.0.1"),
+        "{log}"
+    );
+}
+
+#[test]
+fn prunes_over_sftp() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let (path, remote) = fake_sftp(&dir);
+    for dt in ["20240101_030000", "20240102_030000", "20240103_030000"] {
+        std::fs::write(remote.join(format!("backup/notes.txt_{dt}.zip")), "zip").unwrap();
+    }
+    let mut config = sftp_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    config["jobs"] = json!([{
+        "name": "notes",
+        "filename": dir.path().join("data/notes.txt").to_str().unwrap(),
+        "keep_last": 2,
+    }]);
+
+    let output = run_env(&dir, &config, &["prune"], &[("PATH", &path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output).trim(),
+        "/backup/notes.txt_20240101_030000.zip"
+    );
+    assert!(!remote.join("backup/notes.txt_20240101_030000.zip").exists());
+    assert!(remote.join("backup/notes.txt_20240102_030000.zip").exists());
+}
+
+#[test]
+fn sizes_sftp_requests_by_the_throughput_of_the_last_upload() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let (path, _) = fake_sftp(&dir);
+    // Already compressed, so the archive stays large enough to measure.
+    dir.write("data/photo.jpg", &"x".repeat(2 * 1024 * 1024));
+    let mut config = sftp_config(&mock, &dir);
+    config.as_object_mut().unwrap().remove("filename");
+    let photo = dir.path().join("data/photo.jpg");
+    config["jobs"] = json!([
+        {"name": "first", "filename": photo.to_str().unwrap()},
+        {"name": "second", "filename": photo.to_str().unwrap()},
+    ]);
+
+    let output = run_env(&dir, &config, &[], &[("PATH", &path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = std::fs::read_to_string(dir.path().join("sftp.log")).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    let puts = lines
+        .windows(2)
+        .filter(|w| w[1].starts_with("batch put"))
+        .map(|w| w[0])
+        .collect::<Vec<_>>();
+    assert_eq!(puts.len(), 2, "{log}");
+    assert!(puts[0].contains("-B 16384 "), "{}", puts[0]);
+    // Copying a local file is quick, so the requests grow to the limit.
+    assert!(puts[1].contains("-B 262144 "), "{}", puts[1]);
+}
+
+#[test]
+fn leaves_the_request_size_to_sftp_without_adaptive_chunks() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let (path, _) = fake_sftp(&dir);
+    let mut config = sftp_config(&mock, &dir);
+    config["adaptive_chunks"] = json!(false);
+
+    let output = run_env(&dir, &config, &[], &[("PATH", &path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = std::fs::read_to_string(dir.path().join("sftp.log")).unwrap();
+    assert!(log.contains("batch put"), "{log}");
+    assert!(!log.contains("-B "), "{log}");
+}
+
+#[test]
+fn refuses_adaptive_chunks_on_the_web_api() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["adaptive_chunks"] = json!(true);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("`adaptive_chunks`"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(mock.calls("SYNO.API.Auth", "login").is_empty());
+}
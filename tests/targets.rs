@@ -0,0 +1,298 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+fn two_target_config(primary: &MockDsm, offsite: &MockDsm, dir: &TempDir) -> serde_json::Value {
+    let mut config = base_config(primary, dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["targets"] = json!([{
+        "name": "offsite",
+        "domain": "127.0.0.1",
+        "port": offsite.port(),
+        "https": false,
+        "usr": "remote",
+        "pwd": "elsewhere",
+        "share_name": "photo",
+    }]);
+    config["jobs"] = json!([{
+        "name": "docs",
+        "filename": source,
+        "targets": ["primary", "offsite"],
+    }]);
+    config
+}
+
+#[test]
+fn fails_over_to_the_next_target() {
+    let primary = MockDsm::start();
+    primary.on("SYNO.API.Auth", "login", err(400));
+    let offsite = MockDsm::start();
+    let dir = TempDir::new();
+    let config = two_target_config(&primary, &offsite, &dir);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(err.contains("primary: unreachable"), "{err}");
+    assert!(err.contains("offsite: uploaded to /photo/"), "{err}");
+    assert!(primary
+        .calls("SYNO.FileStation.Upload", "upload")
+        .is_empty());
+    let upload = &offsite.calls("SYNO.FileStation.Upload", "upload")[0];
+    assert_eq!(upload.params["path"], "/photo");
+    assert_eq!(
+        offsite.calls("SYNO.API.Auth", "login")[0].params["account"],
+        "remote"
+    );
+}
+
+#[test]
+fn mirror_uploads_to_every_target() {
+    let primary = MockDsm::start();
+    let offsite = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = two_target_config(&primary, &offsite, &dir);
+    config["jobs"][0]["mirror"] = json!(true);
+
+    let output = run(&dir, &config, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(primary.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+    assert_eq!(offsite.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
+
+#[test]
+fn fails_when_no_target_is_reachable() {
+    let primary = MockDsm::start();
+    primary.on("SYNO.API.Auth", "login", err(400));
+    let offsite = MockDsm::start();
+    offsite.on("SYNO.FileStation.Upload", "upload", err(1805));
+    let dir = TempDir::new();
+    let config = two_target_config(&primary, &offsite, &dir);
+
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Jobs that reached no target: docs"));
+}
+
+#[test]
+fn copies_the_upload_into_extra_folders() {
+    let mock = MockDsm::start();
+    mock.once(
+        "SYNO.FileStation.CopyMove",
+        "status",
+        ok(json!({"finished": false, "progress": 0.5})),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["jobs"] = json!([{
+        "name": "docs",
+        "filename": source,
+        "copies": ["/photo/second_copy"],
+    }]);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("primary: copied to /photo/second_copy"),
+        "{err}"
+    );
+    let uploaded = &mock.calls("SYNO.FileStation.Upload", "upload")[0].files[0].1;
+    let start = &mock.calls("SYNO.FileStation.CopyMove", "start")[0];
+    assert_eq!(start.params["path"], format!(r#"["/backup/{uploaded}"]"#));
+    assert_eq!(start.params["dest_folder_path"], "/photo/second_copy");
+    assert_eq!(start.params["remove_src"], "false");
+    let status = mock.calls("SYNO.FileStation.CopyMove", "status");
+    assert_eq!(status.len(), 2);
+    assert_eq!(status[1].params["taskid"], "FileStation_51D00B7912CDE0B0");
+}
+
+#[test]
+fn a_copy_that_never_finishes_is_stopped_after_task_timeout() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.CopyMove",
+        "status",
+        ok(json!({"finished": false, "progress": 0.42})),
+    );
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    config["task_timeout"] = json!("6s");
+    config["jobs"] = json!([{
+        "name": "docs",
+        "filename": source,
+        "copies": ["/photo/second_copy"],
+    }]);
+
+    let started = std::time::Instant::now();
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(30),
+        "{err}"
+    );
+    assert!(output.status.success(), "{err}");
+    // Progress every few seconds while it runs, then the timeout.
+    assert!(
+        err.contains("Copying to /photo/second_copy: 42%\n"),
+        "{err}"
+    );
+    assert!(
+        err.contains(
+            "Copying to /photo/second_copy did not finish within 6s; the task was stopped"
+        ),
+        "{err}"
+    );
+    let stop = &mock.calls("SYNO.FileStation.CopyMove", "stop")[0];
+    assert_eq!(stop.params["taskid"], "FileStation_51D00B7912CDE0B0");
+}
+
+#[test]
+fn a_job_with_its_own_account_logs_in_on_its_own() {
+    let mock = MockDsm::start();
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    let source = config.as_object_mut().unwrap().remove("filename").unwrap();
+    let pwd_file = dir.write("laptop.pwd", "laptop secret\n");
+    config["jobs"] = json!([
+        {"name": "docs", "filename": source},
+        {"name": "laptop", "filename": source, "usr": "laptop", "pwd_file": pwd_file},
+    ]);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    let logins = mock.calls("SYNO.API.Auth", "login");
+    let accounts = logins
+        .iter()
+        .map(|l| (l.params["account"].as_str(), l.params["passwd"].as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        accounts,
+        [("tester", "secret"), ("laptop", "laptop secret")]
+    );
+    let runs =
+        std::fs::read_to_string(dir.path().join("xdg/state/synology_backuper/runs.jsonl")).unwrap();
+    assert!(runs.contains("\"primary@laptop:/backup/"), "{runs}");
+
+    config["jobs"][1]
+        .as_object_mut()
+        .unwrap()
+        .remove("pwd_file");
+    let output = run(&dir, &config, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("Job laptop has `usr` but neither `pwd` nor `pwd_file`"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn wakes_a_sleeping_nas_and_shuts_it_down_again() {
+    let dir = TempDir::new();
+    // Nothing listens on the NAS's port until the wake-up packet arrives.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let wol = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let wol_address = wol.local_addr().unwrap().to_string();
+    let nas = std::thread::spawn(move || {
+        let mut packet = [0; 200];
+        let (len, _) = wol.recv_from(&mut packet).unwrap();
+        let mock = MockDsm::start_on(&format!("127.0.0.1:{port}"));
+        let mut info = default_api_info();
+        info["SYNO.Core.System"] = json!({"path": "entry.cgi", "minVersion": 1, "maxVersion": 3});
+        mock.set_api_info(info);
+        mock.on("SYNO.Core.System", "shutdown", ok(serde_json::Value::Null));
+        (packet[..len].to_vec(), mock)
+    });
+    let placeholder = MockDsm::start();
+    let mut config = base_config(&placeholder, &dir);
+    config["port"] = json!(port);
+    config["wake_mac"] = json!("00:11:32:AA:bb:0c");
+    config["wake_address"] = json!(wol_address);
+    config["wake_timeout"] = json!("1m");
+    config["shutdown_after_wake"] = json!(true);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("Target primary doesn't answer; waking it up"),
+        "{err}"
+    );
+    let (packet, nas) = nas.join().unwrap();
+    let mut magic = vec![0xff; 6];
+    for _ in 0..16 {
+        magic.extend_from_slice(&[0x00, 0x11, 0x32, 0xaa, 0xbb, 0x0c]);
+    }
+    assert_eq!(packet, magic);
+    assert_eq!(nas.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+    assert_eq!(nas.calls("SYNO.Core.System", "shutdown").len(), 1);
+    assert!(err.contains("Shut down target primary"), "{err}");
+
+    // A NAS that is up already is left running.
+    config["port"] = json!(placeholder.port());
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(!err.contains("waking it up"), "{err}");
+    assert!(placeholder.calls("SYNO.Core.System", "shutdown").is_empty());
+}
+
+#[test]
+fn shuts_down_a_nas_after_a_successful_run_only() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.Core.System"] = json!({"path": "entry.cgi", "minVersion": 1, "maxVersion": 3});
+    mock.set_api_info(info);
+    mock.on("SYNO.Core.System", "shutdown", ok(serde_json::Value::Null));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["shutdown_after_success"] = json!(true);
+
+    mock.once("SYNO.FileStation.Upload", "upload", err(1800));
+    let output = run(&dir, &config, &[]);
+    assert!(!output.status.success());
+    assert!(mock.calls("SYNO.Core.System", "shutdown").is_empty());
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains("Shut down target primary after the run"),
+        "{err}"
+    );
+    let shutdowns = mock.calls("SYNO.Core.System", "shutdown");
+    assert_eq!(shutdowns.len(), 1);
+    assert_eq!(shutdowns[0].params["version"], "1");
+}
+
+#[test]
+fn a_refused_shutdown_is_reported_after_the_backup() {
+    let mock = MockDsm::start();
+    let mut info = default_api_info();
+    info["SYNO.Core.System"] = json!({"path": "entry.cgi", "minVersion": 1, "maxVersion": 3});
+    mock.set_api_info(info);
+    mock.on("SYNO.Core.System", "shutdown", err(105));
+    let dir = TempDir::new();
+    let mut config = base_config(&mock, &dir);
+    config["shutdown_after_success"] = json!(true);
+
+    let output = run(&dir, &config, &[]);
+    let err = stderr(&output);
+    assert!(output.status.success(), "{err}");
+    assert!(
+        err.contains(
+            "Could not shut down target primary: 105 - The logged in session does not have permission"
+        ),
+        "{err}"
+    );
+    assert_eq!(mock.calls("SYNO.FileStation.Upload", "upload").len(), 1);
+}
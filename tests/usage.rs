@@ -0,0 +1,44 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[test]
+fn reports_job_and_share_usage() {
+    let mock = MockDsm::start();
+    mock.on(
+        "SYNO.FileStation.List",
+        "list",
+        ok(json!({"offset": 0, "total": 3, "files": [
+            {"name": "notes.txt_20240101_030000.zip", "path": "/backup/notes.txt_20240101_030000.zip", "isdir": false},
+            {"name": "notes.txt_20240102_030000.zip", "path": "/backup/notes.txt_20240102_030000.zip", "isdir": false},
+            {"name": "notes.txt_old_20240102_030000.zip", "path": "/backup/notes.txt_old_20240102_030000.zip", "isdir": false},
+        ]})),
+    );
+    mock.once(
+        "SYNO.FileStation.DirSize",
+        "status",
+        ok(json!({"finished": true, "total_size": 3 << 20})),
+    );
+    let dir = TempDir::new();
+    let config = base_config(&mock, &dir);
+
+    let output = run(&dir, &config, &["usage"]);
+    let out = stdout(&output);
+    assert!(output.status.success(), "{out}{}", stderr(&output));
+    let job = out.lines().find(|l| l.starts_with("default")).unwrap();
+    assert!(job.contains("primary") && job.contains("backup"), "{job}");
+    assert!(job.contains(" 2  3.0 MiB"), "{job}");
+    let share = out.lines().rfind(|l| l.starts_with("primary")).unwrap();
+    assert!(share.ends_with("1.0 KiB"), "{share}");
+
+    let list = &mock.calls("SYNO.FileStation.List", "list")[0];
+    assert_eq!(list.params["folder_path"], "/backup");
+    assert_eq!(list.params["pattern"], "notes.txt_*");
+    let sizes = mock.calls("SYNO.FileStation.DirSize", "start");
+    assert_eq!(
+        sizes[0].params["path"],
+        r#"["/backup/notes.txt_20240102_030000.zip","/backup/notes.txt_20240101_030000.zip"]"#
+    );
+    assert_eq!(sizes[1].params["path"], r#"["/backup"]"#);
+}